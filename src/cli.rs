@@ -3,11 +3,17 @@ use qrcode::QrcodeCommand;
 use std::{env, path::PathBuf};
 use vapid::VapidCommand;
 
-use crate::cli::{connection::ConnectionCommand, test::TestCommand};
-use crate::config;
+use crate::cli::{
+    connection::ConnectionCommand, db::DbCommand, invite::InviteCommand,
+    registration::RegistrationCommand, test::TestCommand,
+};
+use crate::{config, control};
 
 mod connection;
+mod db;
+mod invite;
 mod qrcode;
+mod registration;
 mod server;
 mod test;
 mod vapid;
@@ -51,6 +57,24 @@ enum Command {
         command: ConnectionCommand,
     },
 
+    /// List, approve and reject the registrations waiting for approval
+    Registration {
+        #[command(subcommand)]
+        command: RegistrationCommand,
+    },
+
+    /// Create, list and revoke invite tokens
+    Invite {
+        #[command(subcommand)]
+        command: InviteCommand,
+    },
+
+    /// Manage the database
+    Db {
+        #[command(subcommand)]
+        command: DbCommand,
+    },
+
     /// Test account and endpoint validity
     Test {
         #[command(subcommand)]
@@ -58,6 +82,28 @@ enum Command {
     },
 }
 
+/**
+(Re)start the connection in the running server. Returns false if no server
+is running: the connection starts with the server.
+*/
+async fn restart_connection(uuid: &str, device_id: u32) -> bool {
+    let command = control::Command::Restart {
+        uuid: uuid.into(),
+        device_id,
+    };
+    match control::send(&command).await {
+        Ok(Some(response)) => {
+            println!("{}", response.message);
+            true
+        }
+        Ok(None) => false,
+        Err(e) => {
+            println!("Could not reach the server: {}", e);
+            false
+        }
+    }
+}
+
 pub async fn cli() {
     let cli = Cli::parse();
 
@@ -93,6 +139,9 @@ pub async fn cli() {
         Command::Server {} => server::server().await,
         Command::QRCode { command } => qrcode::qrcode(command),
         Command::Connection { command } => connection::connection(command).await,
+        Command::Registration { command } => registration::registration(command).await,
+        Command::Invite { command } => invite::invite(command),
+        Command::Db { command } => db::db(command),
         Command::Test { command } => test::test(command).await,
         Command::Vapid { command } => vapid::vapid(command),
     }
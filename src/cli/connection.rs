@@ -1,13 +1,14 @@
-use std::str::FromStr;
-
+use super::restart_connection;
 use crate::{
-    config, db,
-    utils::{self, anonymize_url},
+    config, control,
+    db::{self, ConnectionId},
+    utils::{self, anonymize_url, push_policy::PushPolicyOverride},
+    vapid,
+    webpush::WebPushKeys,
 };
 use clap::Subcommand;
 use lazy_static::lazy_static;
 use regex::Regex;
-use url::Url;
 
 #[derive(Subcommand)]
 pub enum ConnectionCommand {
@@ -25,6 +26,18 @@ pub enum ConnectionCommand {
 
         /// UnifiedPush endpoint
         endpoint: String,
+
+        /// Public key of the push subscription, to encrypt push messages
+        #[arg(long, requires = "auth")]
+        p256dh: Option<String>,
+
+        /// Authentication secret of the push subscription, to encrypt push messages
+        #[arg(long, requires = "p256dh")]
+        auth: Option<String>,
+
+        /// VAPID public key of the push subscription, the primary key by default
+        #[arg(long)]
+        vapid: Option<String>,
     },
 
     /// List all account connections
@@ -34,16 +47,57 @@ pub enum ConnectionCommand {
         anonymized: bool,
     },
 
+    /// Show an account connection, and its status
+    Show {
+        /// Account UUID, or <uuid>.<device_id> if the account has several devices
+        connection_id: ConnectionId,
+    },
+
     /// Remove account connection
     Remove {
-        /// Account UUID
-        account_id: String,
+        /// Account UUID, or <uuid>.<device_id> if the account has several devices
+        connection_id: ConnectionId,
     },
 
     /// Send test notification to the endpoint associated
     Ping {
-        /// Account UUID
-        account_id: String,
+        /// Account UUID, or <uuid>.<device_id> if the account has several devices
+        connection_id: ConnectionId,
+    },
+
+    /// Show or change the push policy of an account connection
+    PushPolicy {
+        /// Account UUID, or <uuid>.<device_id> if the account has several devices
+        connection_id: ConnectionId,
+
+        /// A push isn't sent if another one, as urgent, was sent during this time, in seconds
+        #[arg(long)]
+        debounce: Option<u64>,
+
+        /// TTL of the push messages, in seconds
+        #[arg(long)]
+        ttl: Option<u32>,
+
+        /// Urgency of the pushes for the urgent envelopes: none, very-low, low, normal or high
+        #[arg(long)]
+        urgent: Option<String>,
+
+        /// Urgency of the pushes for the non-urgent envelopes: none, very-low, low, normal or high
+        #[arg(long)]
+        non_urgent: Option<String>,
+
+        /// Delay of the low urgency push for the non-urgent envelopes, if they aren't pushed,
+        /// in seconds. 0 to disable
+        #[arg(long)]
+        non_urgent_delay: Option<u64>,
+
+        /// Topic of the push messages, empty for no topic
+        #[arg(long)]
+        topic: Option<String>,
+
+        /// Use the push policy of the config
+        #[arg(long, conflicts_with_all = ["debounce", "ttl", "urgent", "non_urgent", "non_urgent_delay", "topic"])]
+        reset: bool,
     },
 }
 
@@ -54,14 +108,61 @@ pub async fn connection(command: &ConnectionCommand) {
             device_id,
             password,
             endpoint,
-        } => add(account_id, device_id, password, endpoint).await,
+            p256dh,
+            auth,
+            vapid,
+        } => {
+            add(
+                account_id,
+                device_id,
+                password,
+                endpoint,
+                p256dh.as_deref(),
+                auth.as_deref(),
+                vapid.as_deref(),
+            )
+            .await
+        }
         ConnectionCommand::List { anonymized } => list(*anonymized),
-        ConnectionCommand::Remove { account_id } => rm(account_id),
-        ConnectionCommand::Ping { account_id } => ping(account_id).await,
+        ConnectionCommand::Show { connection_id } => show(connection_id),
+        ConnectionCommand::Remove { connection_id } => rm(connection_id).await,
+        ConnectionCommand::Ping { connection_id } => ping(connection_id).await,
+        ConnectionCommand::PushPolicy {
+            connection_id,
+            debounce,
+            ttl,
+            urgent,
+            non_urgent,
+            non_urgent_delay,
+            topic,
+            reset,
+        } => {
+            push_policy(
+                connection_id,
+                PushPolicyOverride {
+                    debounce: *debounce,
+                    ttl: *ttl,
+                    urgent: urgent.clone(),
+                    non_urgent: non_urgent.clone(),
+                    non_urgent_delay: *non_urgent_delay,
+                    topic: topic.clone(),
+                },
+                *reset,
+            )
+            .await
+        }
     }
 }
 
-async fn add(uuid: &str, device_id: &u32, password: &str, endpoint: &str) {
+async fn add(
+    uuid: &str,
+    device_id: &u32,
+    password: &str,
+    endpoint: &str,
+    p256dh: Option<&str>,
+    auth: Option<&str>,
+    vapid: Option<&str>,
+) {
     if !config::is_uuid_valid(uuid) {
         println!("UUID invalid or forbidden: {}", uuid);
         return;
@@ -70,16 +171,34 @@ async fn add(uuid: &str, device_id: &u32, password: &str, endpoint: &str) {
         println!("Endpoint invalid or forbidden: {}", endpoint);
         return;
     }
-    let _ = db::MollySocketDb::new().unwrap().add(&db::Connection::new(
+    if WebPushKeys::from_options(p256dh, auth).is_err() {
+        println!("Push keys invalid: p256dh={:?}, auth={:?}", p256dh, auth);
+        return;
+    }
+    if vapid.is_some_and(|k| !vapid::is_active_key(k)) {
+        println!(
+            "VAPID key unknown or retired: {}",
+            vapid.unwrap_or_default()
+        );
+        return;
+    }
+    let mut connection = db::Connection::new(
         uuid.to_string(),
         *device_id,
         password.to_string(),
         endpoint.to_string(),
-    ));
-    if let Err(e) = utils::ping(Url::from_str(endpoint).unwrap()).await {
+        p256dh.map(String::from),
+        auth.map(String::from),
+    );
+    connection.vapid_key = vapid
+        .map(String::from)
+        .or_else(|| vapid::get_vapid_pubkey().ok());
+    let _ = db::MollySocketDb::new().unwrap().add(&connection);
+    if let Err(e) = utils::delivery::ping(&connection).await {
         log::warn!("Cound not ping the new connection (uuid={}): {e:?}", uuid);
     }
-    println!("Connection for {} added.", uuid);
+    println!("Connection for {} added.", connection.id());
+    restart_connection(uuid, *device_id).await;
 }
 
 fn list(anonymized: bool) {
@@ -106,25 +225,195 @@ This is required to help to debug some setups. You should unregister Molly from
                 );
                 connection.password = RE.replace_all(&connection.password, "x").into();
                 connection.endpoint = anonymize_url(&connection.endpoint);
+                connection.auth = connection
+                    .auth
+                    .as_ref()
+                    .map(|auth| RE.replace_all(auth, "x").into());
+                for extra in connection.extra_endpoints.iter_mut() {
+                    extra.endpoint = anonymize_url(&extra.endpoint);
+                    extra.auth = extra
+                        .auth
+                        .as_ref()
+                        .map(|auth| RE.replace_all(auth, "x").into());
+                }
             }
             dbg!(&connection);
         });
 }
 
-fn rm(uuid: &str) {
-    db::MollySocketDb::new().unwrap().rm(uuid).unwrap();
-    println!("Connection for {} successfully removed.", uuid)
+/**
+The connection [id], or None once the reason it isn't found is printed.
+*/
+fn find(db: &db::MollySocketDb, id: &ConnectionId) -> Option<db::Connection> {
+    match db.find(id) {
+        Ok(co) => Some(co),
+        Err(e) => {
+            match e.downcast_ref::<db::Error>() {
+                Some(e) => println!("{}", e),
+                None => println!("No connection found with this Id"),
+            }
+            None
+        }
+    }
+}
+
+fn show(id: &ConnectionId) {
+    let db = db::MollySocketDb::new().unwrap();
+    let connection = match find(&db, id) {
+        Some(c) => c,
+        None => return,
+    };
+    let status = db
+        .get_status(&connection.uuid, connection.device_id)
+        .unwrap();
+    let time = |t: &db::OptTime| match t.0 {
+        Some(t) => httpdate::fmt_http_date(t),
+        None => String::from("never"),
+    };
+    println!("Account:           {}", connection.uuid);
+    println!("Device:            {}", connection.device_id);
+    println!("Delivery:          {}", connection.delivery);
+    for (i, endpoint) in connection.endpoints().iter().enumerate() {
+        let health = db
+            .get_endpoint_status(&connection.uuid, connection.device_id, &endpoint.endpoint)
+            .unwrap();
+        println!("Endpoint {}:        {}", i + 1, endpoint.endpoint);
+        println!("  Encrypted:       {}", endpoint.p256dh.is_some());
+        match health.last_status {
+            Some(code) => println!("  Last status:     {} ({} failures)", code, health.failures),
+            None if health.failures > 0 => println!(
+                "  Last status:     push server not reached ({} failures)",
+                health.failures
+            ),
+            None => println!("  Last status:     -"),
+        }
+        println!("  Last success:    {}", time(&health.last_success));
+        println!("  Last failure:    {}", time(&health.last_failure));
+    }
+    println!(
+        "VAPID key:         {}",
+        connection.vapid_key.as_deref().unwrap_or("-")
+    );
+    println!("Forbidden:         {}", connection.forbidden);
+    println!("Disabled:          {}", connection.disabled);
+    println!("Last registration: {}", time(&connection.last_registration));
+    println!("Websocket:         {}", status.ws_state);
+    println!("Last connected:    {}", time(&status.last_connected));
+    println!("Last envelope:     {}", time(&status.last_envelope));
+    println!("Last push:         {}", time(&status.last_push));
+    match status.last_push_status {
+        Some(code) => println!("Last push status:  {}", code),
+        None if status.last_push.0.is_some() => {
+            println!("Last push status:  push server not reached")
+        }
+        None => println!("Last push status:  -"),
+    }
+    match &status.last_error {
+        Some(error) => println!(
+            "Last error:        {} ({})",
+            error,
+            time(&status.last_error_time)
+        ),
+        None => println!("Last error:        -"),
+    }
 }
 
-async fn ping(uuid: &str) {
-    let connection = match db::MollySocketDb::new().unwrap().get(uuid) {
-        Ok(c) => c,
-        Err(_) => {
-            println!("No connection found with this Id");
-            return;
+/**
+Remove the connection with the running server, to stop it, or in the DB.
+*/
+async fn rm(id: &ConnectionId) {
+    let db = db::MollySocketDb::new().unwrap();
+    let co = match find(&db, id) {
+        Some(co) => co,
+        None => return,
+    };
+    let command = control::Command::Remove {
+        uuid: co.uuid.clone(),
+        device_id: co.device_id,
+    };
+    match control::send(&command).await {
+        Ok(Some(response)) => println!("{}", response.message),
+        Ok(None) => {
+            db.rm(&co.uuid, co.device_id).unwrap();
+            println!("Connection for {} successfully removed.", co.id())
         }
+        Err(e) => println!("Could not reach the server: {}", e),
+    }
+}
+
+async fn ping(id: &ConnectionId) {
+    let connection = match find(&db::MollySocketDb::new().unwrap(), id) {
+        Some(c) => c,
+        None => return,
     };
-    let url = url::Url::parse(&connection.endpoint).unwrap();
     // We unwrap to catch some config errors
-    utils::ping(url).await.unwrap();
+    utils::delivery::ping(&connection).await.unwrap();
+}
+
+/**
+Merge the [changes] into the push policy of the connection, or remove it if [reset].
+*/
+async fn push_policy(id: &ConnectionId, changes: PushPolicyOverride, reset: bool) {
+    let db = db::MollySocketDb::new().unwrap();
+    let co = match find(&db, id) {
+        Some(co) => co,
+        None => return,
+    };
+    if let Err(e) = changes.validate() {
+        println!("{}", e);
+        return;
+    }
+    let changed = reset || !changes.is_empty();
+    let mut policy = db.get_push_policy(&co.uuid, co.device_id).unwrap();
+    if reset {
+        policy = PushPolicyOverride::default();
+    } else {
+        policy = PushPolicyOverride {
+            debounce: changes.debounce.or(policy.debounce),
+            ttl: changes.ttl.or(policy.ttl),
+            urgent: changes.urgent.or(policy.urgent),
+            non_urgent: changes.non_urgent.or(policy.non_urgent),
+            non_urgent_delay: changes.non_urgent_delay.or(policy.non_urgent_delay),
+            topic: changes.topic.or(policy.topic),
+        };
+    }
+    if changed {
+        db.set_push_policy(&co.uuid, co.device_id, &policy).unwrap();
+        println!("Push policy for {} saved.", co.id());
+        restart_connection(&co.uuid, co.device_id).await;
+    }
+    let effective = config::get_push_policy().with_override(&policy);
+    let urgency =
+        |u: Option<utils::push_policy::Urgency>| u.map_or(String::from("none"), |u| u.to_string());
+    let overridden = |o: bool| if o { "" } else { " (config)" };
+    println!(
+        "Debounce:           {}s{}",
+        effective.debounce.as_secs(),
+        overridden(policy.debounce.is_some())
+    );
+    println!(
+        "TTL:                {}s{}",
+        effective.ttl,
+        overridden(policy.ttl.is_some())
+    );
+    println!(
+        "Urgent envelopes:   {}{}",
+        urgency(effective.urgent),
+        overridden(policy.urgent.is_some())
+    );
+    println!(
+        "Other envelopes:    {}{}",
+        urgency(effective.non_urgent),
+        overridden(policy.non_urgent.is_some())
+    );
+    println!(
+        "Deferred push:      {}s{}",
+        effective.non_urgent_delay.as_secs(),
+        overridden(policy.non_urgent_delay.is_some())
+    );
+    println!(
+        "Topic:              {}{}",
+        effective.topic.as_deref().unwrap_or("-"),
+        overridden(policy.topic.is_some())
+    );
 }
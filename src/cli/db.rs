@@ -0,0 +1,95 @@
+use crate::{
+    config,
+    db::{crypto, MollySocketDb},
+};
+use clap::Subcommand;
+use std::path::{Path, PathBuf};
+
+#[derive(Subcommand)]
+pub enum DbCommand {
+    /// Apply the pending migrations to the DB
+    Migrate {
+        /// Print the pending migrations without applying them
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+    },
+
+    /// Generate a key to encrypt the passwords and print to STDOUT
+    GenKey {},
+
+    /// Encrypt the passwords with a new key. MollySocket must be stopped
+    RotateKey {
+        /// File with the new key
+        new_key_file: PathBuf,
+    },
+}
+
+pub fn db(command: &DbCommand) {
+    match command {
+        DbCommand::Migrate { dry_run } => migrate(*dry_run),
+        DbCommand::GenKey {} => println!("{}", crypto::gen_key()),
+        DbCommand::RotateKey { new_key_file } => rotate_key(new_key_file),
+    }
+}
+
+fn rotate_key(new_key_file: &Path) {
+    let new_key = match std::fs::read_to_string(new_key_file) {
+        Ok(key) => key.trim_end().to_string(),
+        Err(e) => {
+            println!("Cannot read {}: {}", new_key_file.display(), e);
+            return;
+        }
+    };
+    if let Err(e) = crypto::check_key(&new_key) {
+        println!("{}", e);
+        return;
+    }
+    let db = MollySocketDb::new().unwrap();
+    match db.rotate_db_key(config::get_db_key().as_deref(), &new_key) {
+        Ok(n) => {
+            println!("{} secret(s) encrypted with the new key.", n);
+            println!(
+                "Set db_key_file to {} before starting MollySocket.",
+                new_key_file.display()
+            );
+        }
+        Err(e) => println!("Could not encrypt the secrets, nothing changed: {}", e),
+    }
+}
+
+fn migrate(dry_run: bool) {
+    let db = MollySocketDb::open().unwrap();
+    let pending = match db.pending_migrations() {
+        Ok(p) => p,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+    println!("Current DB version: {}", db.version().unwrap());
+    if pending.is_empty() {
+        println!("The DB is up to date.");
+        return;
+    }
+    for migration in &pending {
+        println!(
+            "Pending migration to version {}: {}",
+            migration.version, migration.description
+        );
+        if dry_run {
+            println!("{}", migration.up.trim());
+        }
+    }
+    if dry_run {
+        return;
+    }
+    match db.migrate() {
+        Ok(backup) => {
+            if let Some(backup) = backup {
+                println!("Backup saved to {}", backup.display());
+            }
+            println!("DB migrated to version {}.", db.version().unwrap());
+        }
+        Err(e) => println!("Migration failed: {}", e),
+    }
+}
@@ -0,0 +1,128 @@
+use crate::{
+    db::{self, OptTime},
+    qrcode, vapid,
+};
+use clap::Subcommand;
+use std::time::{Duration, SystemTime};
+
+#[derive(Subcommand)]
+pub enum InviteCommand {
+    /// Create an invite token, allowing accounts to register without being in allowed_uuids
+    Create {
+        /// Number of accounts the token may allow
+        #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+        uses: u32,
+
+        /// Validity of the token, for instance 12h, 7d or 2w
+        #[arg(long, default_value = "7d", value_parser = parse_duration)]
+        expires: Duration,
+
+        /// URL of mollysocket, to print the link with the token and its QR code
+        #[arg(long)]
+        url: Option<String>,
+    },
+
+    /// List the invites, and the accounts they have allowed
+    List {},
+
+    /// Revoke an invite, the accounts it has allowed stay allowed
+    Revoke {
+        /// Id of the invite, see `invite list`
+        id: String,
+    },
+}
+
+pub fn invite(command: &InviteCommand) {
+    match command {
+        InviteCommand::Create { uses, expires, url } => create(*uses, *expires, url.as_deref()),
+        InviteCommand::List {} => list(),
+        InviteCommand::Revoke { id } => revoke(id),
+    }
+}
+
+/**
+Parse a duration like 30m, 12h, 7d or 2w.
+*/
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let unit = match s.chars().last() {
+        Some('s') => 1,
+        Some('m') => 60,
+        Some('h') => 3600,
+        Some('d') => 86400,
+        Some('w') => 604800,
+        _ => return Err(String::from("The unit must be s, m, h, d or w")),
+    };
+    let n: u64 = s[..s.len() - 1]
+        .parse()
+        .map_err(|_| format!("Invalid duration: {}", s))?;
+    n.checked_mul(unit)
+        .map(Duration::from_secs)
+        .ok_or(format!("Invalid duration: {}", s))
+}
+
+fn create(uses: u32, expires: Duration, url: Option<&str>) {
+    let token = db::crypto::gen_token();
+    let expires = OptTime::from(SystemTime::now() + expires);
+    let invite = db::MollySocketDb::new()
+        .unwrap()
+        .add_invite(&token, uses, &expires)
+        .unwrap();
+    println!("Invite {} created.", invite.id());
+    println!("Token: {}", token);
+    let url = match url {
+        Some(url) => qrcode::gen_url(url, Some(&token)),
+        None => return,
+    };
+    match url {
+        Ok(url) => println!(
+            "{}\n{}\n{}",
+            url,
+            qrcode::INTRO,
+            qrcode::url_to_printable_qr(&url)
+        ),
+        Err(e) if matches!(e.downcast_ref(), Some(vapid::Error::VapidKeyError)) => {
+            println!("{}", e)
+        }
+        Err(e) => println!("Could not generate the link: {}", e),
+    }
+}
+
+fn list() {
+    let db = db::MollySocketDb::new().unwrap();
+    let time = |t: &OptTime| t.0.map(httpdate::fmt_http_date).unwrap_or_default();
+    for invite in db.list_invites().unwrap() {
+        println!(
+            "{} {}/{} uses, expires {}",
+            invite.id(),
+            invite.uses,
+            invite.max_uses,
+            time(&invite.expires)
+        );
+    }
+    for (uuid, invite) in db.list_invited_uuids().unwrap() {
+        println!("{} allowed by {}", uuid, invite);
+    }
+}
+
+fn revoke(id: &str) {
+    match db::MollySocketDb::new().unwrap().rm_invite(id) {
+        Ok(()) => println!("Invite {} revoked.", id),
+        Err(_) => println!("No invite found with this Id"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("30m"), Ok(Duration::from_secs(1800)));
+        assert_eq!(parse_duration("7d"), Ok(Duration::from_secs(604800)));
+        assert_eq!(parse_duration("2w"), Ok(Duration::from_secs(1209600)));
+        assert!(parse_duration("7").is_err());
+        assert!(parse_duration("d").is_err());
+        assert!(parse_duration("-1d").is_err());
+        assert!(parse_duration("99999999999999999w").is_err());
+    }
+}
@@ -7,6 +7,10 @@ pub enum QrcodeCommand {
     Url {
         /// URL of mollysocket
         url: String,
+
+        /// Invite token, see `mollysocket invite create`
+        #[arg(long)]
+        invite: Option<String>,
     },
 
     /// Generate link QR code for mollysocket used in airgapped mode
@@ -16,7 +20,7 @@ pub enum QrcodeCommand {
 /// Print mollysocket link URL and show the associated QR Code
 pub fn qrcode(command: &QrcodeCommand) {
     let url = match command {
-        QrcodeCommand::Url { url } => qrcode::gen_url(url),
+        QrcodeCommand::Url { url, invite } => qrcode::gen_url(url, invite.as_deref()),
         QrcodeCommand::Airgapped {} => qrcode::gen_url_airgapped(),
     };
     if let Err(e) = &url {
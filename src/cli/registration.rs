@@ -0,0 +1,91 @@
+use super::restart_connection;
+use crate::{db, utils};
+use clap::Subcommand;
+
+#[derive(Subcommand)]
+pub enum RegistrationCommand {
+    /// List the registrations waiting for approval, and the rejected ones
+    List {},
+
+    /// Approve a registration: its connection is added
+    Approve {
+        /// Registration Id: <uuid>.<device_id>, or <uuid> if the account has a single device
+        account_id: String,
+    },
+
+    /// Reject a registration: the next registrations of the account are rejected too
+    Reject {
+        /// Registration Id: <uuid>.<device_id>, or <uuid> if the account has a single device
+        account_id: String,
+    },
+}
+
+pub async fn registration(command: &RegistrationCommand) {
+    match command {
+        RegistrationCommand::List {} => list(),
+        RegistrationCommand::Approve { account_id } => approve(account_id).await,
+        RegistrationCommand::Reject { account_id } => reject(account_id),
+    }
+}
+
+fn list() {
+    for registration in db::MollySocketDb::new()
+        .unwrap()
+        .list_registrations()
+        .unwrap()
+    {
+        let co = &registration.connection;
+        let requested = co
+            .last_registration
+            .0
+            .map(httpdate::fmt_http_date)
+            .unwrap_or_default();
+        println!(
+            "{}.{} {:<8} {} {}",
+            co.uuid,
+            co.device_id,
+            registration.status,
+            requested,
+            utils::anonymize_url(&co.endpoint)
+        );
+    }
+}
+
+async fn approve(id: &str) {
+    let id: db::ConnectionId = id.parse().unwrap();
+    let co = match db::MollySocketDb::new().unwrap().approve_registration(&id) {
+        Ok(co) => co,
+        Err(e) => {
+            print_not_found(e);
+            return;
+        }
+    };
+    if let Err(e) = utils::delivery::ping(&co).await {
+        log::warn!(
+            "Cound not ping the new connection (uuid={}): {e:?}",
+            co.uuid
+        );
+    }
+    println!("Registration for {} approved.", co.id());
+    if !restart_connection(&co.uuid, co.device_id).await {
+        println!("The connection starts with the server.");
+    }
+}
+
+fn reject(id: &str) {
+    let id: db::ConnectionId = id.parse().unwrap();
+    match db::MollySocketDb::new()
+        .unwrap()
+        .set_registration_status(&id, db::ApprovalStatus::Rejected)
+    {
+        Ok(()) => println!("Registration for {} rejected.", id),
+        Err(e) => print_not_found(e),
+    }
+}
+
+fn print_not_found(e: eyre::Report) {
+    match e.downcast_ref::<db::Error>() {
+        Some(e) => println!("{}", e),
+        None => println!("No registration found with this Id"),
+    }
+}
@@ -38,18 +38,28 @@ fn test_uuid(uuid: &str) {
             return;
         }
     };
-    let co = match db.get(uuid) {
-        Ok(co) => co,
-        Err(_) => {
+    let connections = match db.list_account(uuid) {
+        Ok(connections) if !connections.is_empty() => connections,
+        _ => {
             println!("  No connection is registered with this UUID.");
             return;
         }
     };
-    if co.forbidden {
-        println!("  The connection associated to this UUID is forbidden.");
-        return;
+    for co in connections {
+        if co.forbidden {
+            println!(
+                "  The connection of the device {} is forbidden.",
+                co.device_id
+            );
+        } else if co.disabled {
+            println!(
+                "  The connection of the device {} is disabled.",
+                co.device_id
+            );
+        } else {
+            println!("  The connection of the device {} is ok.", co.device_id);
+        }
     }
-    println!("  A connection is associated to this UUID and is ok.");
 }
 
 async fn test_endpoint(endpoint: &str) {
@@ -1,4 +1,4 @@
-use crate::vapid;
+use crate::{db, vapid};
 use clap::Subcommand;
 
 #[derive(Subcommand)]
@@ -11,12 +11,69 @@ pub enum VapidCommand {
 
     /// Generate VAPID key and print to STDOUT
     Generate {},
+
+    /// List the keys of the VAPID keyring
+    List {},
+
+    /// Add a new primary key to the VAPID keyring, the current one keeps signing for its connections
+    Rotate {},
+
+    /// Retire a key of the VAPID keyring
+    Retire {
+        /// Public key to retire
+        pubkey: String,
+
+        /// Retire the key even if some connections are still registered with it
+        #[arg(long)]
+        force: bool,
+    },
 }
 
 pub fn vapid(command: &VapidCommand) {
     match command {
         VapidCommand::Test { endpoint } => print_vapid_for_endpoint(endpoint),
         VapidCommand::Generate {} => generate_vapid(),
+        VapidCommand::List {} => list(),
+        VapidCommand::Rotate {} => rotate(),
+        VapidCommand::Retire { pubkey, force } => retire(pubkey, *force),
+    }
+}
+
+fn list() {
+    let db = db::MollySocketDb::new().unwrap();
+    for key in db.list_vapid_keys().unwrap() {
+        let created = key
+            .created
+            .0
+            .map(httpdate::fmt_http_date)
+            .unwrap_or_default();
+        println!(
+            "{} {:<9} {} ({} connections)",
+            key.pubkey,
+            key.status,
+            created,
+            db.count_vapid_key_connections(&key.pubkey).unwrap()
+        );
+    }
+}
+
+fn rotate() {
+    match vapid::rotate() {
+        Ok(pubkey) => {
+            println!("New primary VAPID key: {}", pubkey);
+            println!("Reload the server (SIGHUP) or restart it to use it.");
+        }
+        Err(e) => println!("Could not rotate the VAPID keys: {}", e),
+    }
+}
+
+fn retire(pubkey: &str, force: bool) {
+    match vapid::retire(pubkey, force) {
+        Ok(()) => {
+            println!("VAPID key {} retired.", pubkey);
+            println!("Reload the server (SIGHUP) or restart it to stop using it.");
+        }
+        Err(e) => println!("Could not retire the VAPID key: {}", e),
     }
 }
 
@@ -27,9 +84,9 @@ fn generate_vapid() {
 
 fn print_vapid_for_endpoint(endpoint: &str) {
     let origin = url::Url::parse(endpoint)
-        .expect(&format!("Could not parse {}.", endpoint))
+        .unwrap_or_else(|_| panic!("Could not parse {}.", endpoint))
         .origin();
-    let header = match vapid::get_vapid_header(origin) {
+    let header = match vapid::get_vapid_header(origin, None) {
         Err(e) if matches!(e.downcast_ref(), Some(vapid::Error::VapidKeyError)) => {
             println!("{}", e);
             return;
@@ -4,16 +4,62 @@ use figment::{
     Figment,
 };
 use serde::{Deserialize, Serialize};
-use std::{env, fmt::Debug, path::PathBuf, process, sync::OnceLock};
+use std::{
+    env,
+    fmt::Debug,
+    path::PathBuf,
+    process,
+    sync::{Arc, OnceLock, RwLock},
+    time::Duration,
+};
 
-use crate::utils::post_allowed::ResolveAllowed;
+use crate::{
+    db::crypto,
+    utils::{
+        backoff::{Backoff, ReconnectPolicy},
+        client_ip::IpNetwork,
+        post_allowed::{self, ResolveAllowed},
+        proxy::{Protocol, Proxy},
+        push_policy::{self, PushPolicy, DEFAULT_TTL},
+        rate_limit::RateLimit,
+    },
+    vapid,
+};
 
-static CONFIG: OnceLock<Config> = OnceLock::new();
+/**
+Current config, swapped when the config is reloaded.
 
-#[derive(Debug, Serialize, Deserialize)]
+The getters clone the Arc: a previous config is dropped once the tasks
+using it are done.
+*/
+static CONFIG: RwLock<Option<Arc<Config>>> = RwLock::new(None);
+/// Path of the config file given with the cli, to reload the same sources
+static CLI_CONFIG_PATH: OnceLock<Option<PathBuf>> = OnceLock::new();
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum SignalEnvironment {
     Production,
     Staging,
+    /// A compatible chat server, like a self-hosted Signal-Server
+    Custom {
+        /// URL of the websocket, for instance wss://chat.example.tld/v1/websocket/
+        url: String,
+        /// PEM bundle of the trusted CA, the system roots are used if not set
+        ca_file: Option<String>,
+        /// SHA-256 fingerprints of the allowed server certificates, in hexadecimal.
+        /// Any certificate trusted by the CA is allowed if empty.
+        #[serde(default)]
+        fingerprints: Vec<String>,
+    },
+}
+
+impl SignalEnvironment {
+    pub fn fingerprints(&self) -> &[String] {
+        match self {
+            SignalEnvironment::Custom { fingerprints, .. } => fingerprints,
+            _ => &[],
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -26,7 +72,70 @@ struct Config {
     signal_env: SignalEnvironment,
     allowed_endpoints: Vec<String>,
     allowed_uuids: Vec<String>,
+    /// The registrations of unknown accounts allowed by the "*" wildcard must be approved by an administrator
+    registration_approval: bool,
+    /// Registrations per minute from a client IP, 0 to disable.
+    /// Also the pings of the new connections per minute to an endpoint host
+    register_ip_rate: u32,
+    /// Registrations allowed at once from a client IP
+    register_ip_burst: u32,
+    /// Registrations per minute for an account, 0 to disable. Not used
+    /// by the new accounts, they are only limited by IP
+    register_uuid_rate: u32,
+    /// Registrations allowed at once for an account
+    register_uuid_burst: u32,
+    /// Reverse proxies allowed to set X-Forwarded-For, IPs or networks
+    trusted_proxies: Vec<String>,
     db: String,
+    /// Key to encrypt the passwords in the DB
+    db_key: Option<String>,
+    db_key_file: Option<String>,
+    admin_token: Option<String>,
+    admin_token_file: Option<String>,
+    /// A push isn't sent if another one, as urgent, was sent during this time, in seconds
+    push_debounce: u64,
+    /// TTL of the push messages, in seconds
+    push_ttl: u32,
+    /// Urgency of the pushes for the urgent envelopes, or none
+    push_urgency: String,
+    /// Urgency of the pushes for the non-urgent envelopes, or none
+    push_non_urgent_urgency: String,
+    /// Delay before a low urgency push for the non-urgent envelopes, in seconds, if they
+    /// aren't pushed
+    push_non_urgent_delay: u64,
+    /// Topic of the push messages, empty for no topic
+    push_topic: String,
+    /// Delay before the first retry of a failed push, in seconds
+    push_retry_initial_delay: u64,
+    /// Maximum delay between two retries of a failed push, in seconds
+    push_retry_max_delay: u64,
+    /// Failed pushes are abandoned after this duration, in seconds
+    push_retry_horizon: u64,
+    /// Maximum time to wait for the response of a push server, in seconds
+    push_timeout: u64,
+    /// Delay before the first reconnection to the Signal server, in seconds
+    reconnect_base_delay: u64,
+    /// Factor between the delays of 2 successive reconnections
+    reconnect_multiplier: f64,
+    /// Maximum delay between two reconnections, in seconds
+    reconnect_max_delay: u64,
+    /// The reconnections are reset once a connection lasted this duration, in seconds
+    reconnect_reset_after: u64,
+    /// Factor applied to the reconnection delay on HTTP 429 and 5xx
+    reconnect_server_error_factor: f64,
+    /// Maximum number of concurrent handshakes with the Signal server
+    max_concurrent_handshakes: usize,
+    /// Maximum random delay before a handshake, in seconds
+    handshake_max_delay: u64,
+    /// Maximum time to close the connections on shutdown, in seconds
+    shutdown_timeout: u64,
+    /// Unix socket to send the CLI commands to the running server,
+    /// `<db>.sock` if not set, disabled if empty
+    control_socket: Option<String>,
+    /// Proxy for the connections to the Signal server
+    signal_proxy: Option<String>,
+    /// Proxy for the push notifications
+    push_proxy: Option<String>,
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -34,6 +143,8 @@ enum EndpointValidity {
     Ok,
     NotInConfig,
     Private,
+    /// The name of the endpoint couldn't be resolved, it may be temporary
+    Unresolved,
 }
 
 impl Default for Config {
@@ -47,22 +158,61 @@ impl Default for Config {
             signal_env: SignalEnvironment::Production,
             allowed_endpoints: vec![String::from("*")],
             allowed_uuids: vec![String::from("*")],
+            registration_approval: false,
+            register_ip_rate: 10,
+            register_ip_burst: 20,
+            register_uuid_rate: 3,
+            register_uuid_burst: 10,
+            trusted_proxies: vec![],
             db: String::from("./mollysocket.db"),
+            db_key: None,
+            db_key_file: None,
+            admin_token: None,
+            admin_token_file: None,
+            push_debounce: 1,
+            push_ttl: DEFAULT_TTL,
+            push_urgency: String::from("high"),
+            push_non_urgent_urgency: String::from("none"),
+            push_non_urgent_delay: 900, // 15min
+            push_topic: String::from("mollysocket"),
+            push_retry_initial_delay: 10,
+            push_retry_max_delay: 600,
+            push_retry_horizon: 21600, // 6h
+            push_timeout: 10,
+            reconnect_base_delay: 5,
+            reconnect_multiplier: 2.0,
+            reconnect_max_delay: 600,
+            reconnect_reset_after: 60,
+            reconnect_server_error_factor: 4.0,
+            max_concurrent_handshakes: 10,
+            handshake_max_delay: 10,
+            shutdown_timeout: 10,
+            control_socket: None,
+            signal_proxy: None,
+            push_proxy: None,
         }
     }
 }
 
-fn get_cfg() -> &'static Config {
-    CONFIG.get().expect("Config is not initialized yet.")
+fn get_cfg() -> Arc<Config> {
+    CONFIG
+        .read()
+        .unwrap()
+        .clone()
+        .expect("Config is not initialized yet.")
+}
+
+fn set_cfg(config: Config) {
+    *CONFIG.write().unwrap() = Some(Arc::new(config));
 }
 
 /// Get db filename
-pub fn get_db() -> &'static str {
-    &get_cfg().db
+pub fn get_db() -> String {
+    get_cfg().db.clone()
 }
 
-pub fn get_host() -> &'static str {
-    &get_cfg().host
+pub fn get_host() -> String {
+    get_cfg().host.clone()
 }
 
 pub fn get_port() -> u16 {
@@ -73,22 +223,160 @@ pub fn is_uuid_valid(uuid: &str) -> bool {
     get_cfg().is_uuid_valid(uuid)
 }
 
+/**
+The account is listed by its uuid in allowed_uuids, not only allowed by the wildcard.
+*/
+pub fn is_uuid_listed(uuid: &str) -> bool {
+    get_cfg().is_uuid_listed(uuid)
+}
+
+pub fn requires_registration_approval() -> bool {
+    get_cfg().registration_approval
+}
+
+pub fn get_register_ip_limit() -> RateLimit {
+    let cfg = get_cfg();
+    RateLimit {
+        per_minute: cfg.register_ip_rate,
+        burst: cfg.register_ip_burst,
+    }
+}
+
+pub fn get_register_uuid_limit() -> RateLimit {
+    let cfg = get_cfg();
+    RateLimit {
+        per_minute: cfg.register_uuid_rate,
+        burst: cfg.register_uuid_burst,
+    }
+}
+
+/// Networks of the reverse proxies, their X-Forwarded-For header is trusted
+pub fn get_trusted_proxies() -> Vec<IpNetwork> {
+    get_cfg()
+        .trusted_proxies
+        .iter()
+        .filter_map(|net| net.parse().ok())
+        .collect()
+}
+
 pub fn should_start_webserver() -> bool {
     get_cfg().webserver
 }
 
-pub fn get_vapid_privkey() -> Option<&'static str> {
-    get_cfg().vapid_privkey.as_deref()
+pub fn get_vapid_privkey() -> Option<String> {
+    get_cfg().vapid_privkey.clone()
+}
+
+/// Key to encrypt the passwords in the DB, they are in cleartext if it is not set
+pub fn get_db_key() -> Option<String> {
+    get_cfg().db_key.clone()
+}
+
+/// Token of the admin API, the API is disabled if it is not set
+pub fn get_admin_token() -> Option<String> {
+    get_cfg()
+        .admin_token
+        .clone()
+        .filter(|token| !token.is_empty())
+}
+
+/// Default push policy, the connections may override it
+pub fn get_push_policy() -> PushPolicy {
+    let cfg = get_cfg();
+    PushPolicy {
+        debounce: Duration::from_secs(cfg.push_debounce),
+        ttl: cfg.push_ttl,
+        urgent: push_policy::parse_urgency_mapping(&cfg.push_urgency).unwrap_or_default(),
+        non_urgent: push_policy::parse_urgency_mapping(&cfg.push_non_urgent_urgency)
+            .unwrap_or_default(),
+        non_urgent_delay: Duration::from_secs(cfg.push_non_urgent_delay),
+        topic: Some(cfg.push_topic.clone()).filter(|t| !t.is_empty()),
+    }
+}
+
+pub fn get_push_retry_backoff() -> Backoff {
+    let cfg = get_cfg();
+    Backoff {
+        initial: Duration::from_secs(cfg.push_retry_initial_delay),
+        multiplier: 2.0,
+        max: Duration::from_secs(cfg.push_retry_max_delay),
+    }
+}
+
+pub fn get_push_retry_horizon() -> Duration {
+    Duration::from_secs(get_cfg().push_retry_horizon)
 }
 
-pub fn get_ws_endpoint() -> &'static str {
-    get_cfg().get_ws_endpoint()
+pub fn get_push_timeout() -> Duration {
+    Duration::from_secs(get_cfg().push_timeout)
+}
+
+pub fn get_reconnect_policy() -> ReconnectPolicy {
+    let cfg = get_cfg();
+    ReconnectPolicy {
+        backoff: Backoff {
+            initial: Duration::from_secs(cfg.reconnect_base_delay),
+            multiplier: cfg.reconnect_multiplier,
+            max: Duration::from_secs(cfg.reconnect_max_delay),
+        },
+        reset_after: Duration::from_secs(cfg.reconnect_reset_after),
+        server_error_factor: cfg.reconnect_server_error_factor,
+    }
+}
+
+pub fn get_max_concurrent_handshakes() -> usize {
+    get_cfg().max_concurrent_handshakes
+}
+
+pub fn get_handshake_max_delay() -> Duration {
+    Duration::from_secs(get_cfg().handshake_max_delay)
+}
+
+pub fn get_shutdown_timeout() -> Duration {
+    Duration::from_secs(get_cfg().shutdown_timeout)
+}
+
+/// Control socket of the server, if enabled
+pub fn get_control_socket() -> Option<PathBuf> {
+    let cfg = get_cfg();
+    match cfg.control_socket.as_deref() {
+        Some("") => None,
+        Some(path) => Some(PathBuf::from(path)),
+        None => Some(PathBuf::from(format!("{}.sock", cfg.db))),
+    }
+}
+
+/// Proxy for the Signal websocket, if any
+pub fn get_signal_proxy() -> Option<Proxy> {
+    get_cfg().signal_proxy.as_deref()?.parse().ok()
+}
+
+/// Proxy for the push notifications, if any
+pub fn get_push_proxy() -> Option<Proxy> {
+    get_cfg().push_proxy.as_deref()?.parse().ok()
+}
+
+pub fn get_ws_endpoint() -> String {
+    get_cfg().get_ws_endpoint().into()
+}
+
+pub fn get_signal_env() -> SignalEnvironment {
+    get_cfg().signal_env.clone()
 }
 
 pub async fn is_endpoint_valid(url: &str) -> bool {
     get_cfg().is_endpoint_valid(url).await
 }
 
+/**
+The endpoint isn't allowed by the config. Unlike [is_endpoint_valid], an
+endpoint whose name can't be resolved isn't considered disallowed: the
+resolution may fail temporarily.
+*/
+pub async fn is_endpoint_disallowed(url: &str) -> bool {
+    get_cfg().is_endpoint_disallowed(url).await
+}
+
 pub fn is_endpoint_allowed_by_user(url: &url::Url) -> bool {
     get_cfg().is_endpoint_allowed_by_user(url)
 }
@@ -99,50 +387,214 @@ pub fn print() {
 }
 
 pub fn load_config(cli_config_path: Option<PathBuf>) {
-    CONFIG.get_or_init(move || {
-        let mut figment = Figment::new();
-
-        figment = figment.merge(Serialized::defaults(Config::default()));
-
-        if let Some(path) = get_config_path(cli_config_path) {
-            log::info!("Config file: {}", path.display());
-            figment = figment.merge(Toml::file(path));
-        } else {
-            log::info!("No config file supplied");
+    if CONFIG.read().unwrap().is_some() {
+        return;
+    }
+    match read_config(cli_config_path.clone()) {
+        Ok(config) => {
+            let _ = CLI_CONFIG_PATH.set(cli_config_path);
+            set_cfg(config);
         }
+        Err(errors) => {
+            for err in errors {
+                log::error!("Config parse error: {}", err);
+            }
+            process::exit(0x0001);
+        }
+    }
+}
 
-        figment = figment.merge(Env::prefixed("MOLLY_").ignore(&["conf"]));
+/**
+What has changed with a config reload, and must be applied by the server.
+*/
+pub struct Reloaded {
+    /// The endpoints of the connections must be checked again
+    pub allowed_endpoints: bool,
+}
 
-        let mut config: Config = match figment.extract() {
-            Ok(config) => config,
-            Err(figment_err) => {
-                for err in figment_err {
-                    log::error!("Config parse error: {}", err);
-                }
-                process::exit(0x0001);
+/**
+Read the config sources again, and swap the config if they are valid.
+Else, the errors are logged and the current config is kept.
+
+Returns what has changed if the config has been reloaded.
+*/
+pub fn reload() -> Option<Reloaded> {
+    let cli_config_path = CLI_CONFIG_PATH.get().cloned().flatten();
+    let mut config = match read_config(cli_config_path) {
+        Ok(config) => config,
+        Err(errors) => {
+            for err in errors {
+                log::error!("Config parse error: {}", err);
             }
-        };
-        if let Some(file) = &config.vapid_key_file {
-            config.vapid_privkey = Some(
-                std::fs::read_to_string(file)
-                    .expect("Cannot read MOLLY_VAPID_KEY_FILE")
-                    .trim_end()
-                    .to_string(),
+            log::error!("The config has not been reloaded.");
+            return None;
+        }
+    };
+    let current = get_cfg();
+    if config.db_key != current.db_key {
+        // The passwords are encrypted with the current key
+        log::warn!(
+            "db_key has changed: it is ignored, use `mollysocket db rotate-key` to change it."
+        );
+        config.db_key = current.db_key.clone();
+    }
+    if config.vapid_privkey != current.vapid_privkey {
+        if let Some(Err(e)) = config.vapid_privkey.as_deref().map(vapid::check_privkey) {
+            log::error!("Config parse error: {}", e);
+            log::error!("The config has not been reloaded.");
+            return None;
+        }
+    }
+    for (name, changed) in [
+        ("host", config.host != current.host),
+        ("port", config.port != current.port),
+        ("webserver", config.webserver != current.webserver),
+        ("db", config.db != current.db),
+        (
+            "max_concurrent_handshakes",
+            config.max_concurrent_handshakes != current.max_concurrent_handshakes,
+        ),
+        ("signal_env", config.signal_env != current.signal_env),
+    ] {
+        if changed {
+            log::warn!(
+                "{} has changed: MollySocket must be restarted to apply it.",
+                name
             );
         }
-        config
-    });
+    }
+    let reloaded = Reloaded {
+        allowed_endpoints: config.allowed_endpoints != current.allowed_endpoints,
+    };
+    set_cfg(config);
+    log::info!("Config reloaded");
+    Some(reloaded)
 }
 
-fn get_config_path(cli_config_path: Option<PathBuf>) -> Option<PathBuf> {
+/**
+Read the config from the defaults, the config file and the environment.
+Returns the errors if it isn't valid.
+*/
+fn read_config(cli_config_path: Option<PathBuf>) -> Result<Config, Vec<String>> {
+    let mut figment = Figment::new();
+
+    figment = figment.merge(Serialized::defaults(Config::default()));
+
+    if let Some(path) = get_config_path(cli_config_path).map_err(|e| vec![e])? {
+        log::info!("Config file: {}", path.display());
+        figment = figment.merge(Toml::file(path));
+    } else {
+        log::info!("No config file supplied");
+    }
+
+    figment = figment.merge(Env::prefixed("MOLLY_").ignore(&["conf"]));
+
+    let mut config: Config = figment.extract().map_err(|figment_err| {
+        figment_err
+            .into_iter()
+            .map(|e| e.to_string())
+            .collect::<Vec<_>>()
+    })?;
+    for proxy in [&config.signal_proxy, &config.push_proxy]
+        .into_iter()
+        .flatten()
+    {
+        proxy.parse::<Proxy>().map_err(|e| vec![e.to_string()])?;
+    }
+    // The credentials of the linked devices would be sent in cleartext over ws://
+    if let SignalEnvironment::Custom { url, .. } = &config.signal_env {
+        if !url::Url::parse(url).is_ok_and(|url| url.scheme() == "wss") {
+            return Err(vec![format!(
+                "The url of signal_env must be a wss:// URL: {}",
+                url
+            )]);
+        }
+    }
+    // An HTTP proxy would resolve the names of the push endpoints again,
+    // instead of connecting to the addresses checked by MollySocket
+    if let Some(proxy) = &config.push_proxy {
+        if proxy
+            .parse::<Proxy>()
+            .map_err(|e| vec![e.to_string()])?
+            .protocol
+            == Protocol::Http
+        {
+            return Err(vec![String::from(
+                "push_proxy must be a socks5 or socks5h proxy, HTTP proxies aren't supported",
+            )]);
+        }
+    }
+    for urgency in [&config.push_urgency, &config.push_non_urgent_urgency] {
+        push_policy::parse_urgency_mapping(urgency).map_err(|e| vec![e.to_string()])?;
+    }
+    for net in &config.trusted_proxies {
+        net.parse::<IpNetwork>().map_err(|e| vec![e.to_string()])?;
+    }
+    for (name, factor) in [
+        ("reconnect_multiplier", config.reconnect_multiplier),
+        (
+            "reconnect_server_error_factor",
+            config.reconnect_server_error_factor,
+        ),
+    ] {
+        if !(1.0..=100.0).contains(&factor) {
+            return Err(vec![format!("{} must be between 1 and 100", name)]);
+        }
+    }
+    if let Some(file) = &config.vapid_key_file {
+        config.vapid_privkey = Some(read_secret_file(file, "MOLLY_VAPID_KEY_FILE")?);
+    }
+    if let Some(file) = &config.db_key_file {
+        config.db_key = Some(read_secret_file(file, "MOLLY_DB_KEY_FILE")?);
+    }
+    if let Some(key) = &config.db_key {
+        crypto::check_key(key).map_err(|e| vec![e.to_string()])?;
+    }
+    if let Some(file) = &config.admin_token_file {
+        config.admin_token = Some(read_secret_file(file, "MOLLY_ADMIN_TOKEN_FILE")?);
+    }
+    Ok(config)
+}
+
+fn read_secret_file(file: &str, name: &str) -> Result<String, Vec<String>> {
+    std::fs::read_to_string(file)
+        .map(|s| s.trim_end().to_string())
+        .map_err(|e| vec![format!("Cannot read {}: {}", name, e)])
+}
+
+/**
+Load the config used by the tests, pointing to the fake servers
+of [crate::test_support].
+*/
+/// Admin token of the tests
+#[cfg(test)]
+pub const TEST_ADMIN_TOKEN: &str = "test-admin-token";
+
+#[cfg(test)]
+pub fn load_test_config(signal_env: SignalEnvironment, allowed_endpoints: Vec<String>, db: String) {
+    let mut current = CONFIG.write().unwrap();
+    if current.is_none() {
+        *current = Some(Arc::new(Config {
+            signal_env,
+            allowed_endpoints,
+            db,
+            // The tests connect immediately
+            handshake_max_delay: 0,
+            admin_token: Some(String::from(TEST_ADMIN_TOKEN)),
+            ..Config::default()
+        }));
+    }
+}
+
+fn get_config_path(cli_config_path: Option<PathBuf>) -> Result<Option<PathBuf>, String> {
     let mut paths: Vec<PathBuf> = Vec::new();
 
     // from cli argument
     if let Some(cli_path) = cli_config_path {
         if cli_path.exists() {
-            return Some(cli_path);
+            return Ok(Some(cli_path));
         } else {
-            panic!("{} not found.", cli_path.display());
+            return Err(format!("{} not found.", cli_path.display()));
         }
     }
 
@@ -150,9 +602,9 @@ fn get_config_path(cli_config_path: Option<PathBuf>) -> Option<PathBuf> {
     if let Some(env_path) = env::var_os("MOLLY_CONF") {
         let path = Into::<PathBuf>::into(env_path);
         if path.exists() {
-            return Some(path);
+            return Ok(Some(path));
         } else {
-            panic!("MOLLY_CONF={}, file not found.", path.display());
+            return Err(format!("MOLLY_CONF={}, file not found.", path.display()));
         }
     }
 
@@ -171,13 +623,17 @@ fn get_config_path(cli_config_path: Option<PathBuf>) -> Option<PathBuf> {
 
     for p in paths.iter() {
         if p.exists() {
-            return Some(p.to_path_buf());
+            return Ok(Some(p.to_path_buf()));
         }
     }
-    None
+    Ok(None)
 }
 
 impl Config {
+    fn is_uuid_listed(&self, uuid: &str) -> bool {
+        self.allowed_uuids.iter().any(|allowed| allowed == uuid)
+    }
+
     fn is_uuid_valid(&self, uuid: &str) -> bool {
         self.allowed_uuids
             .clone()
@@ -218,16 +674,31 @@ You may want to add \"{}\" to allowed_endpoints",
                     );
                     false
                 }
+                EndpointValidity::Unresolved => {
+                    log::warn!("Endpoint could not be resolved: {}", url);
+                    false
+                }
             }
         } else {
             false
         }
     }
 
-    fn get_ws_endpoint(&self) -> &'static str {
-        match self.signal_env {
+    async fn is_endpoint_disallowed(&self, url: &str) -> bool {
+        match url::Url::parse(url) {
+            Ok(url) => matches!(
+                self.is_url_endpoint_valid(&url).await,
+                EndpointValidity::NotInConfig | EndpointValidity::Private
+            ),
+            Err(_) => true,
+        }
+    }
+
+    fn get_ws_endpoint(&self) -> &str {
+        match &self.signal_env {
             SignalEnvironment::Production => "wss://chat.signal.org/v1/websocket/",
             SignalEnvironment::Staging => "wss://chat.staging.signal.org/v1/websocket/",
+            SignalEnvironment::Custom { url, .. } => url,
         }
     }
     async fn is_url_endpoint_valid(&self, url: &url::Url) -> EndpointValidity {
@@ -235,10 +706,14 @@ You may want to add \"{}\" to allowed_endpoints",
             EndpointValidity::Ok
         } else {
             if self.allowed_endpoints.contains(&"*".into()) {
-                if url.resolve_allowed().await.unwrap_or(vec![]).len().gt(&0) {
-                    EndpointValidity::Ok
-                } else {
-                    EndpointValidity::Private
+                match url.resolve_allowed().await {
+                    Ok(ips) if !ips.is_empty() => EndpointValidity::Ok,
+                    Err(e)
+                        if matches!(e.downcast_ref(), Some(post_allowed::Error::ResolveFailed)) =>
+                    {
+                        EndpointValidity::Unresolved
+                    }
+                    _ => EndpointValidity::Private,
                 }
             } else {
                 EndpointValidity::NotInConfig
@@ -275,6 +750,31 @@ mod tests {
         })
     }
 
+    #[test]
+    fn check_read_config() {
+        let path = env::temp_dir().join(format!("mollysocket-test-{}.toml", std::process::id()));
+        let read = |content: &str| {
+            std::fs::write(&path, content).unwrap();
+            read_config(Some(path.clone()))
+        };
+
+        let cfg = read("allowed_uuids = ['abc']\npush_proxy = 'socks5h://127.0.0.1:9050'").unwrap();
+        assert!(cfg.is_uuid_valid("abc"));
+        assert!(!cfg.is_uuid_valid("def"));
+        assert!(read("port = 'not a port'").is_err());
+        assert!(read("push_proxy = 'ftp://127.0.0.1'").is_err());
+        assert!(read("push_proxy = 'http://127.0.0.1:3128'").is_err());
+        assert!(read("signal_proxy = 'http://127.0.0.1:3128'").is_ok());
+        let custom = |url: &str| format!("[signal_env.Custom]\nurl = '{}'", url);
+        assert!(read(&custom("wss://chat.example.tld/v1/websocket/")).is_ok());
+        assert!(read(&custom("ws://chat.example.tld/v1/websocket/")).is_err());
+        assert!(read("vapid_key_file = '/non/existent'").is_err());
+        assert!(read("db_key = 'not a key'").is_err());
+        assert!(read("reconnect_multiplier = 0.5").is_err());
+        std::fs::remove_file(&path).unwrap();
+        assert!(read_config(Some(path)).is_err());
+    }
+
     #[test]
     fn check_wildcard_uuid() {
         let cfg = test_config("*", "");
@@ -329,4 +829,34 @@ mod tests {
             EndpointValidity::Private
         );
     }
+
+    #[tokio::test]
+    async fn check_unresolved_endpoint() {
+        let cfg = test_config("", "*");
+        let url = "http://mollysocket.invalid/foo";
+        assert_eq!(
+            cfg.is_url_endpoint_valid(&url::Url::parse(url).unwrap())
+                .await,
+            EndpointValidity::Unresolved
+        );
+        assert!(!cfg.is_endpoint_valid(url).await);
+        // A resolution error isn't enough to stop a connection
+        assert!(!cfg.is_endpoint_disallowed(url).await);
+        assert!(cfg.is_endpoint_disallowed("http://10.10.1.1/").await);
+        assert!(
+            test_config("", "http://other.tld")
+                .is_endpoint_disallowed(url)
+                .await
+        );
+    }
+
+    #[test]
+    fn check_uuid_listed() {
+        let cfg = test_config("abc", "*");
+        assert!(cfg.is_uuid_listed("abc"));
+        assert!(!cfg.is_uuid_listed("def"));
+        let cfg = test_config("*", "*");
+        assert!(cfg.is_uuid_valid("abc"));
+        assert!(!cfg.is_uuid_listed("abc"));
+    }
 }
@@ -0,0 +1,81 @@
+use eyre::Result;
+use rocket::serde::json::serde_json;
+use serde::{Deserialize, Serialize};
+#[cfg(unix)]
+use std::{io::ErrorKind, path::Path, time::Duration};
+#[cfg(unix)]
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::UnixStream,
+    time,
+};
+
+use crate::config;
+
+/// Time given to the server to handle a command
+#[cfg(unix)]
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/**
+Command sent by the CLI to a running server, on the control socket.
+
+The requests and the responses are JSON, one per line.
+*/
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Command {
+    /// (Re)start the connection, after it has been saved in the DB
+    Restart { uuid: String, device_id: u32 },
+    /// Stop and remove the connection
+    Remove { uuid: String, device_id: u32 },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Response {
+    pub ok: bool,
+    pub message: String,
+}
+
+impl Response {
+    pub fn ok(message: String) -> Self {
+        Response { ok: true, message }
+    }
+
+    pub fn error(message: String) -> Self {
+        Response { ok: false, message }
+    }
+}
+
+/**
+Send the [command] to the running server. Returns None if no server
+is listening on the control socket: the CLI then uses the DB directly.
+*/
+pub async fn send(command: &Command) -> Result<Option<Response>> {
+    match config::get_control_socket() {
+        #[cfg(unix)]
+        Some(path) => send_to(&path, command).await,
+        _ => Ok(None),
+    }
+}
+
+#[cfg(unix)]
+pub async fn send_to(path: &Path, command: &Command) -> Result<Option<Response>> {
+    let mut stream = match UnixStream::connect(path).await {
+        Ok(stream) => stream,
+        Err(e) if matches!(e.kind(), ErrorKind::NotFound | ErrorKind::ConnectionRefused) => {
+            log::debug!("No server listening on {}", path.display());
+            return Ok(None);
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let mut request = serde_json::to_vec(command)?;
+    request.push(b'\n');
+    let line = time::timeout(TIMEOUT, async {
+        stream.write_all(&request).await?;
+        let mut line = String::new();
+        BufReader::new(stream).read_line(&mut line).await?;
+        Ok::<String, std::io::Error>(line)
+    })
+    .await??;
+    Ok(Some(serde_json::from_str(&line)?))
+}
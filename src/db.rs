@@ -1,42 +1,528 @@
 use eyre::Result;
+use rocket::serde::json::serde_json;
 use rusqlite::{self, Row};
+use serde::{Deserialize, Serialize};
 use std::{
+    fmt::{Display, Formatter},
+    path::PathBuf,
+    str::FromStr,
     sync::{Arc, Mutex},
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use crate::config;
+use crate::{
+    config,
+    utils::{
+        delivery::DeliveryMode,
+        push_policy::{PushPolicyOverride, Urgency},
+    },
+};
+pub use crypto::{constant_time_eq, decrypt_secret, password_matches};
+use migrations::Migrate;
+pub use migrations::Migration;
 
+pub mod crypto;
 mod migrations;
 
 pub struct MollySocketDb {
     db: Arc<Mutex<rusqlite::Connection>>,
 }
 
+#[derive(Debug)]
+pub enum Error {
+    /// The account has several devices, the device id is required
+    SeveralDevices(String),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::SeveralDevices(uuid) => write!(
+                f,
+                "The account {} has several devices, use <uuid>.<device_id>",
+                uuid
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/**
+Id of a connection given by the administrator: `<uuid>.<device_id>`, like
+the Signal login, or `<uuid>` if the account has a single device.
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionId {
+    pub uuid: String,
+    pub device_id: Option<u32>,
+}
+
+impl Display for ConnectionId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.device_id {
+            Some(device_id) => write!(f, "{}.{}", self.uuid, device_id),
+            None => write!(f, "{}", self.uuid),
+        }
+    }
+}
+
+impl FromStr for ConnectionId {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(match s.rsplit_once('.') {
+            Some((uuid, device_id)) if device_id.parse::<u32>().is_ok() => ConnectionId {
+                uuid: uuid.into(),
+                device_id: device_id.parse().ok(),
+            },
+            _ => ConnectionId {
+                uuid: s.into(),
+                device_id: None,
+            },
+        })
+    }
+}
+
+/**
+A push endpoint, with the keys of its push subscription.
+*/
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PushEndpoint {
+    pub endpoint: String,
+    /// Public key of the push subscription, base64url encoded
+    pub p256dh: Option<String>,
+    /// Authentication secret of the push subscription, base64url encoded
+    pub auth: Option<String>,
+}
+
 #[derive(Debug)]
 pub struct Connection {
     pub uuid: String,
     pub device_id: u32,
+    /// Password of the linked device, as saved in the DB: encrypted if
+    /// db_key is set. See [decrypt_secret]
     pub password: String,
+    /// Whether the password is encrypted with db_key
+    pub password_encrypted: bool,
+    /// First endpoint of the connection
     pub endpoint: String,
     pub forbidden: bool,
+    /// Disabled by an administrator: only enabling it again restarts it
+    pub disabled: bool,
     pub last_registration: OptTime,
+    /// Public key of the push subscription, base64url encoded
+    pub p256dh: Option<String>,
+    /// Authentication secret of the push subscription, base64url encoded
+    pub auth: Option<String>,
+    /// Public key of the VAPID key the push subscriptions were created with
+    pub vapid_key: Option<String>,
+    /// Endpoints after the first one, in the order they are tried
+    pub extra_endpoints: Vec<PushEndpoint>,
+    /// How the push messages are sent to the endpoints
+    pub delivery: DeliveryMode,
 }
 
 impl Connection {
-    pub fn new(uuid: String, device_id: u32, password: String, endpoint: String) -> Self {
+    pub fn new(
+        uuid: String,
+        device_id: u32,
+        password: String,
+        endpoint: String,
+        p256dh: Option<String>,
+        auth: Option<String>,
+    ) -> Self {
         Connection {
             uuid,
             device_id,
             password,
+            password_encrypted: false,
             endpoint,
             forbidden: false,
+            disabled: false,
             last_registration: OptTime::from(SystemTime::now()),
+            p256dh,
+            auth,
+            vapid_key: None,
+            extra_endpoints: vec![],
+            delivery: DeliveryMode::default(),
+        }
+    }
+
+    pub fn id(&self) -> ConnectionId {
+        ConnectionId {
+            uuid: self.uuid.clone(),
+            device_id: Some(self.device_id),
+        }
+    }
+
+    /**
+    All the endpoints of the connection, in order.
+    */
+    pub fn endpoints(&self) -> Vec<PushEndpoint> {
+        let first = PushEndpoint {
+            endpoint: self.endpoint.clone(),
+            p256dh: self.p256dh.clone(),
+            auth: self.auth.clone(),
+        };
+        [vec![first], self.extra_endpoints.clone()].concat()
+    }
+
+    /**
+    Replace the endpoints of the connection. A connection has at least
+    one endpoint: an empty list is ignored.
+    */
+    pub fn set_endpoints(&mut self, endpoints: Vec<PushEndpoint>) {
+        let mut endpoints = endpoints.into_iter();
+        if let Some(first) = endpoints.next() {
+            self.endpoint = first.endpoint;
+            self.p256dh = first.p256dh;
+            self.auth = first.auth;
+            self.extra_endpoints = endpoints.collect();
+        }
+    }
+}
+
+/**
+A push notification that failed, and that will be sent again.
+*/
+#[derive(Debug)]
+pub struct PushRetry {
+    pub uuid: String,
+    pub device_id: u32,
+    pub topic: String,
+    /// JSON body of the push notification
+    pub body: String,
+    pub attempts: u32,
+    pub first_failure: OptTime,
+    pub next_attempt: OptTime,
+    /// Urgency the push notification was sent with, None if it was queued by a previous version
+    pub urgency: Option<Urgency>,
+}
+
+impl PushRetry {
+    fn map(row: &Row) -> Result<PushRetry> {
+        Ok(PushRetry {
+            uuid: row.get(0)?,
+            device_id: row.get(1)?,
+            topic: row.get(2)?,
+            body: row.get(3)?,
+            attempts: row.get(4)?,
+            first_failure: OptTime::from(row.get::<usize, i64>(5)?),
+            next_attempt: OptTime::from(row.get::<usize, i64>(6)?),
+            urgency: row
+                .get::<usize, Option<String>>(7)?
+                .and_then(|u| u.parse().ok()),
+        })
+    }
+}
+
+/**
+Status of a key of the VAPID keyring.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VapidKeyStatus {
+    /// Advertised to the new registrations
+    Primary,
+    /// Still used by the connections registered with it
+    Accepting,
+    /// Not used anymore
+    Retired,
+}
+
+impl Display for VapidKeyStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let status = match self {
+            VapidKeyStatus::Primary => "primary",
+            VapidKeyStatus::Accepting => "accepting",
+            VapidKeyStatus::Retired => "retired",
+        };
+        write!(f, "{}", status)
+    }
+}
+
+impl FromStr for VapidKeyStatus {
+    type Err = rusqlite::Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "primary" => Ok(VapidKeyStatus::Primary),
+            "accepting" => Ok(VapidKeyStatus::Accepting),
+            "retired" => Ok(VapidKeyStatus::Retired),
+            _ => Err(rusqlite::Error::InvalidColumnType(
+                3,
+                String::from("status"),
+                rusqlite::types::Type::Text,
+            )),
+        }
+    }
+}
+
+/**
+A key of the VAPID keyring.
+*/
+#[derive(Debug)]
+pub struct VapidKey {
+    /// Public key, base64url encoded. This is the id of the key
+    pub pubkey: String,
+    /// Private key, base64url encoded, as saved in the DB: encrypted if
+    /// db_key is set. See [decrypt_secret]
+    pub privkey: String,
+    /// Whether the private key is encrypted with db_key
+    pub privkey_encrypted: bool,
+    pub created: OptTime,
+    pub status: VapidKeyStatus,
+}
+
+impl VapidKey {
+    fn map(row: &Row) -> Result<VapidKey> {
+        Ok(VapidKey {
+            pubkey: row.get(0)?,
+            privkey: row.get(1)?,
+            privkey_encrypted: row.get(4)?,
+            created: OptTime::from(row.get::<usize, i64>(2)?),
+            status: row.get::<usize, String>(3)?.parse()?,
+        })
+    }
+}
+
+/**
+Decision of the administrator on a registration, when the registrations must be approved.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApprovalStatus {
+    Pending,
+    Rejected,
+}
+
+impl Display for ApprovalStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let status = match self {
+            ApprovalStatus::Pending => "pending",
+            ApprovalStatus::Rejected => "rejected",
+        };
+        write!(f, "{}", status)
+    }
+}
+
+impl FromStr for ApprovalStatus {
+    type Err = rusqlite::Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "pending" => Ok(ApprovalStatus::Pending),
+            "rejected" => Ok(ApprovalStatus::Rejected),
+            _ => Err(rusqlite::Error::InvalidColumnType(
+                8,
+                String::from("status"),
+                rusqlite::types::Type::Text,
+            )),
+        }
+    }
+}
+
+/**
+A registration of an unknown account, waiting for the approval of an administrator.
+
+The connection is added once approved, its last_registration is the time of the request.
+*/
+#[derive(Debug)]
+pub struct Registration {
+    pub connection: Connection,
+    pub status: ApprovalStatus,
+}
+
+impl Registration {
+    fn map(row: &Row) -> Result<Registration> {
+        Ok(Registration {
+            connection: Connection {
+                uuid: row.get(0)?,
+                device_id: row.get(1)?,
+                password: row.get(2)?,
+                password_encrypted: row.get(11)?,
+                endpoint: row.get(3)?,
+                forbidden: false,
+                disabled: false,
+                last_registration: OptTime::from(row.get::<usize, i64>(4)?),
+                p256dh: row.get(5)?,
+                auth: row.get(6)?,
+                vapid_key: row.get(7)?,
+                extra_endpoints: serde_json::from_str(&row.get::<usize, String>(9)?)?,
+                delivery: row.get::<usize, String>(10)?.parse()?,
+            },
+            status: row.get::<usize, String>(8)?.parse()?,
+        })
+    }
+}
+
+/**
+An invite, allowing accounts to register without being in allowed_uuids.
+*/
+#[derive(Debug)]
+pub struct Invite {
+    /// Hash of the token, see [crypto::hash_token]
+    pub hash: String,
+    pub uses: u32,
+    pub max_uses: u32,
+    pub created: OptTime,
+    pub expires: OptTime,
+}
+
+impl Invite {
+    /**
+    Short id of the invite, to show and revoke it.
+    */
+    pub fn id(&self) -> &str {
+        &self.hash[..INVITE_ID_LEN]
+    }
+
+    fn map(row: &Row) -> Result<Invite> {
+        Ok(Invite {
+            hash: row.get(0)?,
+            uses: row.get(1)?,
+            max_uses: row.get(2)?,
+            created: OptTime::from(row.get::<usize, i64>(3)?),
+            expires: OptTime::from(row.get::<usize, i64>(4)?),
+        })
+    }
+}
+
+/// Length of the id of an invite
+const INVITE_ID_LEN: usize = 8;
+
+/**
+State of the websocket of a connection.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WsState {
+    Connecting,
+    Connected,
+    /// Disconnected, waiting to reconnect
+    Disconnected,
+    /// The connection isn't running: killed, forbidden or server stopped
+    Stopped,
+}
+
+impl Display for WsState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let state = match self {
+            WsState::Connecting => "connecting",
+            WsState::Connected => "connected",
+            WsState::Disconnected => "disconnected",
+            WsState::Stopped => "stopped",
+        };
+        write!(f, "{}", state)
+    }
+}
+
+impl FromStr for WsState {
+    type Err = rusqlite::Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "connecting" => Ok(WsState::Connecting),
+            "connected" => Ok(WsState::Connected),
+            "disconnected" => Ok(WsState::Disconnected),
+            "stopped" => Ok(WsState::Stopped),
+            _ => Err(rusqlite::Error::InvalidColumnType(
+                2,
+                String::from("ws_state"),
+                rusqlite::types::Type::Text,
+            )),
         }
     }
 }
 
+/**
+What happened recently to a connection, to debug missing notifications.
+*/
+#[derive(Debug, Clone)]
+pub struct ConnectionStatus {
+    pub uuid: String,
+    pub device_id: u32,
+    pub ws_state: WsState,
+    pub last_connected: OptTime,
+    pub last_envelope: OptTime,
+    pub last_push: OptTime,
+    /// HTTP status of the last push, None if the push server wasn't reached
+    pub last_push_status: Option<u16>,
+    pub last_error: Option<String>,
+    pub last_error_time: OptTime,
+}
+
+impl ConnectionStatus {
+    pub fn new(uuid: &str, device_id: u32) -> Self {
+        ConnectionStatus {
+            uuid: uuid.into(),
+            device_id,
+            ws_state: WsState::Stopped,
+            last_connected: OptTime(None),
+            last_envelope: OptTime(None),
+            last_push: OptTime(None),
+            last_push_status: None,
+            last_error: None,
+            last_error_time: OptTime(None),
+        }
+    }
+
+    fn map(row: &Row) -> Result<ConnectionStatus> {
+        Ok(ConnectionStatus {
+            uuid: row.get(0)?,
+            device_id: row.get(1)?,
+            ws_state: row.get::<usize, String>(2)?.parse()?,
+            last_connected: OptTime::from(row.get::<usize, i64>(3)?),
+            last_envelope: OptTime::from(row.get::<usize, i64>(4)?),
+            last_push: OptTime::from(row.get::<usize, i64>(5)?),
+            last_push_status: row.get(6)?,
+            last_error: row.get(7)?,
+            last_error_time: OptTime::from(row.get::<usize, i64>(8)?),
+        })
+    }
+}
+
+/**
+Health of an endpoint of a connection, from the responses of its push server.
+*/
 #[derive(Debug)]
+pub struct EndpointStatus {
+    pub uuid: String,
+    pub device_id: u32,
+    pub endpoint: String,
+    /// HTTP status of the last push, None if the push server wasn't reached
+    pub last_status: Option<u16>,
+    /// Failed pushes since the last one delivered
+    pub failures: u32,
+    pub last_success: OptTime,
+    pub last_failure: OptTime,
+}
+
+impl EndpointStatus {
+    pub fn new(uuid: &str, device_id: u32, endpoint: &str) -> Self {
+        EndpointStatus {
+            uuid: uuid.into(),
+            device_id,
+            endpoint: endpoint.into(),
+            last_status: None,
+            failures: 0,
+            last_success: OptTime(None),
+            last_failure: OptTime(None),
+        }
+    }
+
+    fn map(row: &Row) -> Result<EndpointStatus> {
+        Ok(EndpointStatus {
+            uuid: row.get(0)?,
+            device_id: row.get(1)?,
+            endpoint: row.get(2)?,
+            last_status: row.get(3)?,
+            failures: row.get(4)?,
+            last_success: OptTime::from(row.get::<usize, i64>(5)?),
+            last_failure: OptTime::from(row.get::<usize, i64>(6)?),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
 pub struct OptTime(pub Option<SystemTime>);
 
 impl From<&OptTime> for i64 {
@@ -74,49 +560,225 @@ impl Connection {
             uuid: row.get(0)?,
             device_id: row.get(1)?,
             password: row.get(2)?,
+            password_encrypted: row.get(12)?,
             endpoint: row.get(3)?,
             forbidden: row.get(4)?,
+            disabled: row.get(11)?,
             last_registration: OptTime::from(row.get::<usize, i64>(5)?),
+            p256dh: row.get(6)?,
+            auth: row.get(7)?,
+            vapid_key: row.get(8)?,
+            extra_endpoints: serde_json::from_str(&row.get::<usize, String>(9)?)?,
+            delivery: row.get::<usize, String>(10)?.parse()?,
         })
     }
 }
 
+/// Tables and columns of the secrets encrypted with db_key: the passwords
+/// of the linked devices, and the VAPID private keys
+const SECRET_COLUMNS: [(&str, &str); 3] = [
+    ("connections", "password"),
+    ("registrations", "password"),
+    ("vapid_keys", "privkey"),
+];
+
+/**
+The rowids and secrets of [column] in [table], and whether they are encrypted:
+saved in the column `<column>_encrypted`.
+*/
+fn secrets(
+    tx: &rusqlite::Transaction,
+    table: &str,
+    column: &str,
+) -> Result<Vec<(i64, String, bool)>> {
+    Ok(tx
+        .prepare(&format!(
+            "SELECT rowid, {column}, {column}_encrypted FROM {table};",
+            column = column,
+            table = table
+        ))?
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+        .collect::<rusqlite::Result<Vec<_>>>()?)
+}
+
+/**
+Save the encrypted [secret] of [column] in the row [rowid] of [table].
+*/
+fn set_encrypted_secret(
+    tx: &rusqlite::Transaction,
+    table: &str,
+    column: &str,
+    rowid: i64,
+    secret: &str,
+) -> Result<()> {
+    tx.execute(
+        &format!(
+            "UPDATE {table} SET {column} = ?1, {column}_encrypted = 1 WHERE rowid = ?2;",
+            column = column,
+            table = table
+        ),
+        rusqlite::params![secret, rowid],
+    )?;
+    Ok(())
+}
+
+/**
+The health of the endpoints of the connection, with [db] or a transaction.
+*/
+fn list_endpoint_status(
+    db: &rusqlite::Connection,
+    uuid: &str,
+    device_id: u32,
+) -> Result<Vec<EndpointStatus>> {
+    db.prepare("SELECT * FROM endpoint_status WHERE uuid=?1 AND device_id=?2;")?
+        .query_and_then(rusqlite::params![uuid, device_id], EndpointStatus::map)?
+        .collect::<Result<Vec<EndpointStatus>>>()
+}
+
 impl MollySocketDb {
+    /**
+    Open the DB, apply the pending migrations, and encrypt the secrets
+    saved in cleartext if a key is configured.
+    */
     pub fn new() -> Result<MollySocketDb> {
-        let db = rusqlite::Connection::open(config::get_db())?;
-        db.execute_batch(
-            "
-CREATE TABLE IF NOT EXISTS connections(
-    uuid TEXT UNIQUE ON CONFLICT REPLACE,
-    device_id INTEGER,
-    password TEXT,
-    endpoint TEXT,
-    forbidden BOOLEAN NOT NULL CHECK (forbidden IN (0, 1)),
-    last_registration INTEGER
-)
-            ",
-        )?;
+        MollySocketDb::new_at(&config::get_db())
+    }
+
+    /**
+    Open the DB at [path], like [MollySocketDb::new].
+    */
+    pub fn new_at(path: &str) -> Result<MollySocketDb> {
+        let db = MollySocketDb::open_at(path)?;
+        db.migrate()?;
+        if config::get_db_key().is_some() {
+            let n = db.encrypt_secrets()?;
+            if n > 0 {
+                log::info!("{} secret(s) encrypted in the DB", n);
+            }
+        }
+        Ok(db)
+    }
+
+    /**
+    Open the DB without applying the pending migrations.
+    */
+    pub fn open() -> Result<MollySocketDb> {
+        MollySocketDb::open_at(&config::get_db())
+    }
+
+    fn open_at(path: &str) -> Result<MollySocketDb> {
+        let db = rusqlite::Connection::open(path)?;
         Ok(MollySocketDb {
             db: Arc::new(Mutex::new(db)),
         })
     }
 
+    pub fn version(&self) -> Result<i32> {
+        self.db.lock().unwrap().user_version()
+    }
+
+    pub fn pending_migrations(&self) -> Result<Vec<&'static Migration>> {
+        self.db.lock().unwrap().pending_migrations()
+    }
+
+    /**
+    Apply the pending migrations, after a backup of the DB file.
+
+    Returns the path of the backup, if any.
+    */
+    pub fn migrate(&self) -> Result<Option<PathBuf>> {
+        self.db.lock().unwrap().migrate()
+    }
+
+    /**
+    Save the connection, it replaces the previous one of the same device. The
+    health of the endpoints it doesn't have anymore is removed.
+    */
     pub fn add(&self, co: &Connection) -> Result<()> {
-        self.db.lock().unwrap().execute(
-            "INSERT INTO connections(uuid, device_id, password, endpoint, forbidden, last_registration)
-            VALUES (?, ?, ?, ?, ?, ?);",
-            [&co.uuid, &co.device_id.to_string(), &co.password, &co.endpoint, &String::from(if co.forbidden { "1" } else { "0" }), &i64::from(&co.last_registration).to_string()]
+        let (password, password_encrypted) =
+            crypto::encrypt_secret(&co.password, co.password_encrypted)?;
+        let extra_endpoints = serde_json::to_string(&co.extra_endpoints)?;
+        let db = self.db.lock().unwrap();
+        let tx = db.unchecked_transaction()?;
+        tx.execute(
+            "INSERT INTO connections(uuid, device_id, password, endpoint, forbidden, last_registration, p256dh, auth, vapid_key, extra_endpoints, delivery, disabled, password_encrypted)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?);",
+            rusqlite::params![&co.uuid, &co.device_id, &password, &co.endpoint, &co.forbidden, &i64::from(&co.last_registration), &co.p256dh, &co.auth, &co.vapid_key, &extra_endpoints, &co.delivery.to_string(), &co.disabled, &password_encrypted]
         )?;
+        let endpoints = co.endpoints();
+        for status in list_endpoint_status(&tx, &co.uuid, co.device_id)? {
+            if !endpoints.iter().any(|e| e.endpoint == status.endpoint) {
+                tx.execute(
+                    "DELETE FROM endpoint_status WHERE uuid=?1 AND device_id=?2 AND endpoint=?3;",
+                    rusqlite::params![&co.uuid, &co.device_id, &status.endpoint],
+                )?;
+            }
+        }
+        tx.commit()?;
         Ok(())
     }
 
-    pub fn update_last_registration(&self, uuid: &str) -> Result<()> {
+    /**
+    Encrypt the secrets saved in cleartext with the configured key.
+
+    Returns the number of secrets encrypted.
+    */
+    pub fn encrypt_secrets(&self) -> Result<usize> {
+        let mut db = self.db.lock().unwrap();
+        let tx = db.transaction()?;
+        let mut n = 0;
+        for (table, column) in SECRET_COLUMNS {
+            for (rowid, secret, encrypted) in secrets(&tx, table, column)? {
+                if encrypted {
+                    continue;
+                }
+                let (secret, _) = crypto::encrypt_secret(&secret, false)?;
+                set_encrypted_secret(&tx, table, column, rowid, &secret)?;
+                n += 1;
+            }
+        }
+        tx.commit()?;
+        Ok(n)
+    }
+
+    /**
+    Encrypt all the secrets with [new_key]. They are decrypted with [old_key],
+    unless they are in cleartext.
+
+    Returns the number of secrets encrypted.
+    */
+    pub fn rotate_db_key(&self, old_key: Option<&str>, new_key: &str) -> Result<usize> {
+        let mut db = self.db.lock().unwrap();
+        let tx = db.transaction()?;
+        let mut n = 0;
+        for (table, column) in SECRET_COLUMNS {
+            for (rowid, secret, encrypted) in secrets(&tx, table, column)? {
+                let secret = if encrypted {
+                    crypto::decrypt(&secret, old_key.ok_or(crypto::Error::NoKey)?)?
+                } else {
+                    secret
+                };
+                set_encrypted_secret(
+                    &tx,
+                    table,
+                    column,
+                    rowid,
+                    &crypto::encrypt(&secret, new_key)?,
+                )?;
+                n += 1;
+            }
+        }
+        tx.commit()?;
+        Ok(n)
+    }
+
+    pub fn update_last_registration(&self, uuid: &str, device_id: u32) -> Result<()> {
         let now = OptTime::from(SystemTime::now());
         self.db.lock().unwrap().execute(
             "UPDATE connections
             SET last_registration = ?
-            WHERE uuid = ?;",
-            [&i64::from(&now).to_string(), uuid],
+            WHERE uuid = ? AND device_id = ?;",
+            rusqlite::params![&i64::from(&now), uuid, device_id],
         )?;
         Ok(())
     }
@@ -130,21 +792,602 @@ CREATE TABLE IF NOT EXISTS connections(
             .collect::<Result<Vec<Connection>>>()
     }
 
-    pub fn get(&self, uuid: &str) -> Result<Connection> {
+    /**
+    List the connections of the account [uuid], one per linked device.
+    */
+    pub fn list_account(&self, uuid: &str) -> Result<Vec<Connection>> {
         self.db
             .lock()
             .unwrap()
-            .prepare("SELECT * FROM connections WHERE uuid=?1 LIMIT 1")?
+            .prepare("SELECT * FROM connections WHERE uuid=?1 ORDER BY device_id;")?
             .query_and_then([uuid], Connection::map)?
+            .collect::<Result<Vec<Connection>>>()
+    }
+
+    pub fn get(&self, uuid: &str, device_id: u32) -> Result<Connection> {
+        self.db
+            .lock()
+            .unwrap()
+            .prepare("SELECT * FROM connections WHERE uuid=?1 AND device_id=?2 LIMIT 1")?
+            .query_and_then(rusqlite::params![uuid, device_id], Connection::map)?
             .next()
             .ok_or(rusqlite::Error::QueryReturnedNoRows)?
     }
 
-    pub fn rm(&self, uuid: &str) -> Result<()> {
+    /**
+    Get the connection [id]. Without a device id, the account must have a single device.
+    */
+    pub fn find(&self, id: &ConnectionId) -> Result<Connection> {
+        if let Some(device_id) = id.device_id {
+            return self.get(&id.uuid, device_id);
+        }
+        let mut connections = self.list_account(&id.uuid)?;
+        match connections.len() {
+            0 => Err(rusqlite::Error::QueryReturnedNoRows.into()),
+            1 => Ok(connections.remove(0)),
+            _ => Err(Error::SeveralDevices(id.uuid.clone()).into()),
+        }
+    }
+
+    /**
+    Remove the connection, and what is saved for it. The registration and the invite
+    of the account are removed with its last connection.
+    */
+    pub fn rm(&self, uuid: &str, device_id: u32) -> Result<()> {
+        let db = self.db.lock().unwrap();
+        let tx = db.unchecked_transaction()?;
+        for table in [
+            "connections",
+            "push_retries",
+            "connection_status",
+            "push_policies",
+            "endpoint_status",
+        ] {
+            tx.execute(
+                &format!("DELETE FROM {} WHERE uuid=?1 AND device_id=?2;", table),
+                rusqlite::params![uuid, device_id],
+            )?;
+        }
+        let remaining: i64 = tx.query_row(
+            "SELECT COUNT(*) FROM connections WHERE uuid=?1;",
+            [uuid],
+            |row| row.get(0),
+        )?;
+        if remaining == 0 {
+            tx.execute("DELETE FROM registrations WHERE uuid=?1;", [uuid])?;
+            tx.execute("DELETE FROM invited_uuids WHERE uuid=?1;", [uuid])?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /**
+    Get the status of the connection, a new one if it isn't saved yet.
+    */
+    pub fn get_status(&self, uuid: &str, device_id: u32) -> Result<ConnectionStatus> {
         self.db
             .lock()
             .unwrap()
-            .execute("DELETE FROM connections WHERE uuid=?1;", [&uuid])?;
+            .prepare("SELECT * FROM connection_status WHERE uuid=?1 AND device_id=?2 LIMIT 1")?
+            .query_and_then(rusqlite::params![uuid, device_id], ConnectionStatus::map)?
+            .next()
+            .unwrap_or_else(|| Ok(ConnectionStatus::new(uuid, device_id)))
+    }
+
+    pub fn set_status(&self, status: &ConnectionStatus) -> Result<()> {
+        self.db.lock().unwrap().execute(
+            "INSERT OR REPLACE INTO connection_status(uuid, device_id, ws_state, last_connected, last_envelope, last_push, last_push_status, last_error, last_error_time)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?);",
+            rusqlite::params![
+                &status.uuid,
+                &status.device_id,
+                &status.ws_state.to_string(),
+                &i64::from(&status.last_connected),
+                &i64::from(&status.last_envelope),
+                &i64::from(&status.last_push),
+                &status.last_push_status,
+                &status.last_error,
+                &i64::from(&status.last_error_time)
+            ],
+        )?;
+        Ok(())
+    }
+
+    /**
+    Remove the [endpoint] of the connection, and its health. The last
+    endpoint of a connection isn't removed: returns false.
+    */
+    pub fn rm_push_endpoint(&self, uuid: &str, device_id: u32, endpoint: &str) -> Result<bool> {
+        let db = self.db.lock().unwrap();
+        let tx = db.unchecked_transaction()?;
+        let mut co = tx
+            .prepare("SELECT * FROM connections WHERE uuid=?1 AND device_id=?2 LIMIT 1")?
+            .query_and_then(rusqlite::params![uuid, device_id], Connection::map)?
+            .next()
+            .ok_or(rusqlite::Error::QueryReturnedNoRows)??;
+        let mut endpoints = co.endpoints();
+        endpoints.retain(|e| e.endpoint != endpoint);
+        if endpoints.is_empty() {
+            return Ok(false);
+        }
+        co.set_endpoints(endpoints);
+        tx.execute(
+            "UPDATE connections
+            SET endpoint = ?1, p256dh = ?2, auth = ?3, extra_endpoints = ?4
+            WHERE uuid = ?5 AND device_id = ?6;",
+            rusqlite::params![
+                &co.endpoint,
+                &co.p256dh,
+                &co.auth,
+                &serde_json::to_string(&co.extra_endpoints)?,
+                uuid,
+                device_id
+            ],
+        )?;
+        tx.execute(
+            "DELETE FROM endpoint_status WHERE uuid=?1 AND device_id=?2 AND endpoint=?3;",
+            rusqlite::params![uuid, device_id, endpoint],
+        )?;
+        tx.commit()?;
+        Ok(true)
+    }
+
+    /**
+    Get the health of the [endpoint], a new one if it isn't saved yet.
+    */
+    pub fn get_endpoint_status(
+        &self,
+        uuid: &str,
+        device_id: u32,
+        endpoint: &str,
+    ) -> Result<EndpointStatus> {
+        self.db
+            .lock()
+            .unwrap()
+            .prepare(
+                "SELECT * FROM endpoint_status WHERE uuid=?1 AND device_id=?2 AND endpoint=?3 LIMIT 1",
+            )?
+            .query_and_then(
+                rusqlite::params![uuid, device_id, endpoint],
+                EndpointStatus::map,
+            )?
+            .next()
+            .unwrap_or_else(|| Ok(EndpointStatus::new(uuid, device_id, endpoint)))
+    }
+
+    pub fn set_endpoint_status(&self, status: &EndpointStatus) -> Result<()> {
+        self.db.lock().unwrap().execute(
+            "INSERT OR REPLACE INTO endpoint_status(uuid, device_id, endpoint, last_status, failures, last_success, last_failure)
+            VALUES (?, ?, ?, ?, ?, ?, ?);",
+            rusqlite::params![
+                &status.uuid,
+                &status.device_id,
+                &status.endpoint,
+                &status.last_status,
+                &status.failures,
+                &i64::from(&status.last_success),
+                &i64::from(&status.last_failure)
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn list_vapid_keys(&self) -> Result<Vec<VapidKey>> {
+        self.db
+            .lock()
+            .unwrap()
+            .prepare("SELECT * FROM vapid_keys ORDER BY created;")?
+            .query_and_then([], VapidKey::map)?
+            .collect::<Result<Vec<VapidKey>>>()
+    }
+
+    /**
+    Add [key] as the primary key: the current primary key is now accepting.
+    */
+    pub fn add_primary_vapid_key(&self, key: &VapidKey) -> Result<()> {
+        let (privkey, privkey_encrypted) =
+            crypto::encrypt_secret(&key.privkey, key.privkey_encrypted)?;
+        let db = self.db.lock().unwrap();
+        let tx = db.unchecked_transaction()?;
+        tx.execute(
+            "UPDATE vapid_keys SET status = ?1 WHERE status = ?2;",
+            [
+                VapidKeyStatus::Accepting.to_string(),
+                VapidKeyStatus::Primary.to_string(),
+            ],
+        )?;
+        tx.execute(
+            "INSERT INTO vapid_keys(pubkey, privkey, created, status, privkey_encrypted)
+            VALUES (?, ?, ?, ?, ?);",
+            rusqlite::params![
+                &key.pubkey,
+                &privkey,
+                &i64::from(&key.created),
+                &VapidKeyStatus::Primary.to_string(),
+                &privkey_encrypted
+            ],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn set_vapid_key_status(&self, pubkey: &str, status: VapidKeyStatus) -> Result<()> {
+        self.db.lock().unwrap().execute(
+            "UPDATE vapid_keys SET status = ?1 WHERE pubkey = ?2;",
+            [&status.to_string(), pubkey],
+        )?;
+        Ok(())
+    }
+
+    /**
+    Set the VAPID key of the connections registered before the keyring.
+    */
+    pub fn set_legacy_vapid_key(&self, pubkey: &str) -> Result<()> {
+        self.db.lock().unwrap().execute(
+            "UPDATE connections SET vapid_key = ?1 WHERE vapid_key IS NULL;",
+            [pubkey],
+        )?;
+        Ok(())
+    }
+
+    /**
+    Count the connections registered with the VAPID key [pubkey].
+    */
+    pub fn count_vapid_key_connections(&self, pubkey: &str) -> Result<i64> {
+        Ok(self.db.lock().unwrap().query_row(
+            "SELECT COUNT(*) FROM connections WHERE vapid_key = ?1;",
+            [pubkey],
+            |row| row.get(0),
+        )?)
+    }
+
+    /**
+    Save the registration, it replaces the previous one of the same device.
+    */
+    pub fn add_registration(&self, registration: &Registration) -> Result<()> {
+        let co = &registration.connection;
+        let (password, password_encrypted) =
+            crypto::encrypt_secret(&co.password, co.password_encrypted)?;
+        let extra_endpoints = serde_json::to_string(&co.extra_endpoints)?;
+        self.db.lock().unwrap().execute(
+            "INSERT OR REPLACE INTO registrations(uuid, device_id, password, endpoint, requested, p256dh, auth, vapid_key, status, extra_endpoints, delivery, password_encrypted)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?);",
+            rusqlite::params![&co.uuid, &co.device_id, &password, &co.endpoint, &i64::from(&co.last_registration), &co.p256dh, &co.auth, &co.vapid_key, &registration.status.to_string(), &extra_endpoints, &co.delivery.to_string(), &password_encrypted]
+        )?;
+        Ok(())
+    }
+
+    pub fn list_registrations(&self) -> Result<Vec<Registration>> {
+        self.db
+            .lock()
+            .unwrap()
+            .prepare("SELECT * FROM registrations ORDER BY requested;")?
+            .query_and_then([], Registration::map)?
+            .collect::<Result<Vec<Registration>>>()
+    }
+
+    /**
+    List the registrations of the devices of the account [uuid].
+    */
+    pub fn list_account_registrations(&self, uuid: &str) -> Result<Vec<Registration>> {
+        self.db
+            .lock()
+            .unwrap()
+            .prepare("SELECT * FROM registrations WHERE uuid=?1 ORDER BY device_id;")?
+            .query_and_then([uuid], Registration::map)?
+            .collect::<Result<Vec<Registration>>>()
+    }
+
+    /**
+    Get the registration [id], like the connections with [MollySocketDb::find].
+    */
+    pub fn get_registration(&self, id: &ConnectionId) -> Result<Registration> {
+        let mut registrations = self.list_account_registrations(&id.uuid)?;
+        if let Some(device_id) = id.device_id {
+            registrations.retain(|r| r.connection.device_id == device_id);
+        }
+        match registrations.len() {
+            0 => Err(rusqlite::Error::QueryReturnedNoRows.into()),
+            1 => Ok(registrations.remove(0)),
+            _ => Err(Error::SeveralDevices(id.uuid.clone()).into()),
+        }
+    }
+
+    pub fn set_registration_status(&self, id: &ConnectionId, status: ApprovalStatus) -> Result<()> {
+        let co = self.get_registration(id)?.connection;
+        self.db.lock().unwrap().execute(
+            "UPDATE registrations SET status = ?1 WHERE uuid = ?2 AND device_id = ?3;",
+            rusqlite::params![&status.to_string(), &co.uuid, &co.device_id],
+        )?;
+        Ok(())
+    }
+
+    /**
+    Move the registration [id] to the connections, returns the new connection.
+    */
+    pub fn approve_registration(&self, id: &ConnectionId) -> Result<Connection> {
+        let co = self.get_registration(id)?.connection;
+        {
+            let db = self.db.lock().unwrap();
+            let tx = db.unchecked_transaction()?;
+            tx.execute(
+                "INSERT INTO connections(uuid, device_id, password, endpoint, forbidden, last_registration, p256dh, auth, vapid_key, extra_endpoints, delivery, password_encrypted)
+                SELECT uuid, device_id, password, endpoint, 0, requested, p256dh, auth, vapid_key, extra_endpoints, delivery, password_encrypted
+                FROM registrations WHERE uuid = ?1 AND device_id = ?2;",
+                rusqlite::params![&co.uuid, &co.device_id],
+            )?;
+            tx.execute(
+                "DELETE FROM registrations WHERE uuid = ?1 AND device_id = ?2;",
+                rusqlite::params![&co.uuid, &co.device_id],
+            )?;
+            tx.commit()?;
+        }
+        self.get(&co.uuid, co.device_id)
+    }
+
+    pub fn rm_registration(&self, id: &ConnectionId) -> Result<()> {
+        let co = self.get_registration(id)?.connection;
+        self.db.lock().unwrap().execute(
+            "DELETE FROM registrations WHERE uuid = ?1 AND device_id = ?2;",
+            rusqlite::params![&co.uuid, &co.device_id],
+        )?;
+        Ok(())
+    }
+
+    /**
+    Save a new invite for the [token].
+    */
+    pub fn add_invite(&self, token: &str, max_uses: u32, expires: &OptTime) -> Result<Invite> {
+        let invite = Invite {
+            hash: crypto::hash_token(token),
+            uses: 0,
+            max_uses,
+            created: OptTime::from(SystemTime::now()),
+            expires: OptTime(expires.0),
+        };
+        self.db.lock().unwrap().execute(
+            "INSERT INTO invites(hash, uses, max_uses, created, expires)
+            VALUES (?, ?, ?, ?, ?);",
+            rusqlite::params![
+                &invite.hash,
+                &invite.uses,
+                &invite.max_uses,
+                &i64::from(&invite.created),
+                &i64::from(&invite.expires)
+            ],
+        )?;
+        Ok(invite)
+    }
+
+    pub fn list_invites(&self) -> Result<Vec<Invite>> {
+        self.db
+            .lock()
+            .unwrap()
+            .prepare("SELECT * FROM invites ORDER BY created;")?
+            .query_and_then([], Invite::map)?
+            .collect::<Result<Vec<Invite>>>()
+    }
+
+    /**
+    The [token] is known, not expired, and may still be used.
+    */
+    pub fn is_invite_valid(&self, token: &str) -> Result<bool> {
+        let now = OptTime::from(SystemTime::now());
+        Ok(self.db.lock().unwrap().query_row(
+            "SELECT COUNT(*) FROM invites WHERE hash = ?1 AND uses < max_uses AND expires > ?2;",
+            rusqlite::params![&crypto::hash_token(token), &i64::from(&now)],
+            |row| row.get::<usize, i64>(0),
+        )? > 0)
+    }
+
+    /**
+    Use the invite [token] to allow [uuid].
+
+    Returns false if the token isn't valid anymore.
+    */
+    pub fn redeem_invite(&self, token: &str, uuid: &str) -> Result<bool> {
+        let now = i64::from(&OptTime::from(SystemTime::now()));
+        let hash = crypto::hash_token(token);
+        let db = self.db.lock().unwrap();
+        let tx = db.unchecked_transaction()?;
+        let n = tx.execute(
+            "UPDATE invites SET uses = uses + 1
+            WHERE hash = ?1 AND uses < max_uses AND expires > ?2;",
+            rusqlite::params![&hash, &now],
+        )?;
+        if n == 0 {
+            return Ok(false);
+        }
+        tx.execute(
+            "INSERT OR REPLACE INTO invited_uuids(uuid, invite, added) VALUES (?, ?, ?);",
+            rusqlite::params![uuid, &hash, &now],
+        )?;
+        tx.commit()?;
+        Ok(true)
+    }
+
+    /**
+    Give back the use of the invite [token] by [uuid], when its registration
+    couldn't be saved.
+    */
+    pub fn unredeem_invite(&self, token: &str, uuid: &str) -> Result<()> {
+        let hash = crypto::hash_token(token);
+        let db = self.db.lock().unwrap();
+        let tx = db.unchecked_transaction()?;
+        let n = tx.execute(
+            "DELETE FROM invited_uuids WHERE uuid = ?1 AND invite = ?2;",
+            [uuid, &hash],
+        )?;
+        if n > 0 {
+            tx.execute(
+                "UPDATE invites SET uses = uses - 1 WHERE hash = ?1 AND uses > 0;",
+                [&hash],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /**
+    Remove the invite with this [id]: it can't be used anymore,
+    the accounts it has allowed stay allowed.
+    */
+    pub fn rm_invite(&self, id: &str) -> Result<()> {
+        if id.len() != INVITE_ID_LEN {
+            return Err(rusqlite::Error::QueryReturnedNoRows.into());
+        }
+        let n = self.db.lock().unwrap().execute(
+            "DELETE FROM invites WHERE substr(hash, 1, length(?1)) = ?1;",
+            [id],
+        )?;
+        if n == 0 {
+            return Err(rusqlite::Error::QueryReturnedNoRows.into());
+        }
+        Ok(())
+    }
+
+    /**
+    List the accounts allowed by an invite, with the id of their invite.
+    */
+    pub fn list_invited_uuids(&self) -> Result<Vec<(String, String)>> {
+        Ok(self
+            .db
+            .lock()
+            .unwrap()
+            .prepare("SELECT uuid, invite FROM invited_uuids ORDER BY added;")?
+            .query_map([], |row| {
+                let invite: String = row.get(1)?;
+                Ok((row.get(0)?, invite[..INVITE_ID_LEN].to_string()))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?)
+    }
+
+    pub fn is_uuid_invited(&self, uuid: &str) -> Result<bool> {
+        Ok(self.db.lock().unwrap().query_row(
+            "SELECT COUNT(*) FROM invited_uuids WHERE uuid = ?1;",
+            [uuid],
+            |row| row.get::<usize, i64>(0),
+        )? > 0)
+    }
+
+    /**
+    Queue the push retry, if there isn't already one for this connection and topic.
+    */
+    pub fn add_push_retry(&self, retry: &PushRetry) -> Result<()> {
+        self.db.lock().unwrap().execute(
+            "INSERT OR IGNORE INTO push_retries(uuid, device_id, topic, body, attempts, first_failure, next_attempt, urgency)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?);",
+            rusqlite::params![&retry.uuid, &retry.device_id, &retry.topic, &retry.body, &retry.attempts, &i64::from(&retry.first_failure), &i64::from(&retry.next_attempt), &retry.urgency.map(|u| u.as_str())]
+        )?;
+        Ok(())
+    }
+
+    pub fn update_push_retry(&self, retry: &PushRetry) -> Result<()> {
+        self.db.lock().unwrap().execute(
+            "UPDATE push_retries
+            SET attempts = ?, next_attempt = ?
+            WHERE uuid = ? AND device_id = ? AND topic = ?;",
+            rusqlite::params![
+                &retry.attempts,
+                &i64::from(&retry.next_attempt),
+                &retry.uuid,
+                &retry.device_id,
+                &retry.topic
+            ],
+        )?;
+        Ok(())
+    }
+
+    /**
+    List the push retries to send now.
+    */
+    pub fn due_push_retries(&self) -> Result<Vec<PushRetry>> {
+        let now = OptTime::from(SystemTime::now());
+        self.db
+            .lock()
+            .unwrap()
+            .prepare("SELECT * FROM push_retries WHERE next_attempt <= ?1;")?
+            .query_and_then([i64::from(&now)], PushRetry::map)?
+            .collect::<Result<Vec<PushRetry>>>()
+    }
+
+    pub fn count_push_retries(&self) -> Result<i64> {
+        Ok(self
+            .db
+            .lock()
+            .unwrap()
+            .query_row("SELECT COUNT(*) FROM push_retries;", [], |row| row.get(0))?)
+    }
+
+    pub fn rm_push_retry(&self, uuid: &str, device_id: u32, topic: &str) -> Result<()> {
+        self.db.lock().unwrap().execute(
+            "DELETE FROM push_retries WHERE uuid=?1 AND device_id=?2 AND topic=?3;",
+            rusqlite::params![uuid, device_id, topic],
+        )?;
+        Ok(())
+    }
+
+    /**
+    Get the push policy of the connection, an empty one if it isn't set.
+    */
+    pub fn get_push_policy(&self, uuid: &str, device_id: u32) -> Result<PushPolicyOverride> {
+        Ok(self
+            .db
+            .lock()
+            .unwrap()
+            .prepare(
+                "SELECT debounce, ttl, urgent, non_urgent, topic, non_urgent_delay
+                FROM push_policies WHERE uuid=?1 AND device_id=?2;",
+            )?
+            .query_and_then(
+                rusqlite::params![uuid, device_id],
+                |row| -> Result<PushPolicyOverride> {
+                    Ok(PushPolicyOverride {
+                        debounce: row.get::<usize, Option<i64>>(0)?.map(|d| d as u64),
+                        ttl: row.get(1)?,
+                        urgent: row.get(2)?,
+                        non_urgent: row.get(3)?,
+                        topic: row.get(4)?,
+                        non_urgent_delay: row.get::<usize, Option<i64>>(5)?.map(|d| d as u64),
+                    })
+                },
+            )?
+            .next()
+            .transpose()?
+            .unwrap_or_default())
+    }
+
+    /**
+    Save the push policy of the connection, it is removed if it is empty.
+    */
+    pub fn set_push_policy(
+        &self,
+        uuid: &str,
+        device_id: u32,
+        policy: &PushPolicyOverride,
+    ) -> Result<()> {
+        let db = self.db.lock().unwrap();
+        if policy.is_empty() {
+            db.execute(
+                "DELETE FROM push_policies WHERE uuid=?1 AND device_id=?2;",
+                rusqlite::params![uuid, device_id],
+            )?;
+        } else {
+            db.execute(
+                "INSERT OR REPLACE INTO push_policies(uuid, device_id, debounce, ttl, urgent, non_urgent, topic, non_urgent_delay)
+                VALUES (?, ?, ?, ?, ?, ?, ?, ?);",
+                rusqlite::params![
+                    uuid,
+                    device_id,
+                    policy.debounce.map(|d| d as i64),
+                    policy.ttl,
+                    policy.urgent,
+                    policy.non_urgent,
+                    policy.topic,
+                    policy.non_urgent_delay.map(|d| d as i64)
+                ],
+            )?;
+        }
         Ok(())
     }
 }
@@ -152,10 +1395,11 @@ CREATE TABLE IF NOT EXISTS connections(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_support;
 
     #[test]
     fn test_db() {
-        config::load_config(None);
+        test_support::load_config();
         let db = MollySocketDb::new().unwrap();
         let uuid = "0d2ff653-3d88-43de-bcdb-f6657d3484e4";
         db.add(&Connection::new(
@@ -163,6 +1407,8 @@ mod tests {
             1,
             String::from("pass"),
             String::from("http://0.0.0.0/"),
+            None,
+            None,
         ))
         .unwrap();
         assert!(db
@@ -171,6 +1417,355 @@ mod tests {
             .iter()
             .map(|co| &co.uuid)
             .any(|row_uuid| row_uuid == uuid));
-        db.rm(uuid).unwrap();
+        db.rm(uuid, 1).unwrap();
+    }
+
+    /**
+    Test a password in cleartext isn't taken for an encrypted one, even with the
+    prefix of the ciphertext. The test has its own DB: the secrets are rotated.
+    */
+    #[test]
+    fn test_secrets() {
+        test_support::load_config();
+        let path = std::env::temp_dir().join(format!(
+            "mollysocket-test-secrets-{}.db",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let db = MollySocketDb::new_at(&path.display().to_string()).unwrap();
+        let uuid = test_support::new_uuid();
+        db.add(&Connection::new(
+            uuid.clone(),
+            1,
+            String::from("aes256gcm:pass"),
+            String::from("http://0.0.0.0/"),
+            None,
+            None,
+        ))
+        .unwrap();
+        let co = db.get(&uuid, 1).unwrap();
+        assert!(!co.password_encrypted);
+        assert!(password_matches(&co.password, false, "aes256gcm:pass"));
+        assert!(!password_matches(&co.password, false, "aes256gcm:other"));
+        assert_eq!(
+            decrypt_secret(&co.password, false).unwrap(),
+            "aes256gcm:pass"
+        );
+
+        let key = crypto::gen_key();
+        assert_eq!(db.rotate_db_key(None, &key).unwrap(), 1);
+        let co = db.get(&uuid, 1).unwrap();
+        assert!(co.password_encrypted);
+        assert_eq!(
+            crypto::decrypt(&co.password, &key).unwrap(),
+            "aes256gcm:pass"
+        );
+        // The password can't be compared without the key
+        assert!(!password_matches(&co.password, true, "aes256gcm:pass"));
+        assert!(decrypt_secret(&co.password, true).is_err());
+        drop(db);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_devices() {
+        test_support::load_config();
+        let db = MollySocketDb::new().unwrap();
+        let uuid = test_support::new_uuid();
+        let id = |s: &str| s.parse::<ConnectionId>().unwrap();
+        assert_eq!(id(&format!("{}.2", uuid)).device_id, Some(2));
+        assert_eq!(id(&uuid).device_id, None);
+        assert_eq!(id(&uuid).uuid, uuid);
+
+        for device_id in [1, 2] {
+            db.add(&Connection::new(
+                uuid.clone(),
+                device_id,
+                format!("pass{}", device_id),
+                String::from("http://0.0.0.0/"),
+                None,
+                None,
+            ))
+            .unwrap();
+        }
+        assert_eq!(db.list_account(&uuid).unwrap().len(), 2);
+        assert_eq!(db.get(&uuid, 2).unwrap().password, "pass2");
+        assert!(matches!(
+            db.find(&id(&uuid)).unwrap_err().downcast_ref(),
+            Some(Error::SeveralDevices(_))
+        ));
+        assert_eq!(
+            db.find(&id(&format!("{}.1", uuid))).unwrap().password,
+            "pass1"
+        );
+
+        // The account stays invited until its last connection is removed
+        let token = crypto::gen_token();
+        let tomorrow = OptTime::from(SystemTime::now() + Duration::from_secs(86400));
+        db.add_invite(&token, 1, &tomorrow).unwrap();
+        assert!(db.redeem_invite(&token, &uuid).unwrap());
+        db.rm(&uuid, 1).unwrap();
+        assert!(db.get(&uuid, 1).is_err());
+        assert_eq!(db.find(&id(&uuid)).unwrap().device_id, 2);
+        assert!(db.is_uuid_invited(&uuid).unwrap());
+        db.rm(&uuid, 2).unwrap();
+        assert!(db.find(&id(&uuid)).is_err());
+        assert!(!db.is_uuid_invited(&uuid).unwrap());
+    }
+
+    #[test]
+    fn test_status() {
+        test_support::load_config();
+        let db = MollySocketDb::new().unwrap();
+        let uuid = test_support::new_uuid();
+        let mut status = db.get_status(&uuid, 1).unwrap();
+        assert_eq!(status.ws_state, WsState::Stopped);
+        assert!(status.last_push.0.is_none());
+
+        status.ws_state = WsState::Connected;
+        status.last_push = OptTime::from(SystemTime::now());
+        status.last_push_status = Some(201);
+        status.last_error = Some(String::from("error"));
+        db.set_status(&status).unwrap();
+        let saved = db.get_status(&uuid, 1).unwrap();
+        assert_eq!(saved.ws_state, WsState::Connected);
+        assert!(saved.last_push.0.is_some());
+        assert_eq!(saved.last_push_status, Some(201));
+        assert_eq!(saved.last_error.as_deref(), Some("error"));
+
+        db.rm(&uuid, 1).unwrap();
+        assert!(db.get_status(&uuid, 1).unwrap().last_push.0.is_none());
+    }
+
+    #[test]
+    fn test_push_policy() {
+        test_support::load_config();
+        let db = MollySocketDb::new().unwrap();
+        let uuid = test_support::new_uuid();
+        assert!(db.get_push_policy(&uuid, 1).unwrap().is_empty());
+        let policy = PushPolicyOverride {
+            debounce: Some(30),
+            urgent: Some(String::from("normal")),
+            non_urgent_delay: Some(60),
+            topic: Some(String::new()),
+            ..Default::default()
+        };
+        db.set_push_policy(&uuid, 1, &policy).unwrap();
+        assert_eq!(db.get_push_policy(&uuid, 1).unwrap(), policy);
+        db.set_push_policy(&uuid, 1, &PushPolicyOverride::default())
+            .unwrap();
+        assert!(db.get_push_policy(&uuid, 1).unwrap().is_empty());
+
+        db.set_push_policy(&uuid, 1, &policy).unwrap();
+        db.rm(&uuid, 1).unwrap();
+        assert!(db.get_push_policy(&uuid, 1).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_push_retries() {
+        test_support::load_config();
+        let db = MollySocketDb::new().unwrap();
+        let uuid = test_support::new_uuid();
+        let now = SystemTime::now();
+        db.add_push_retry(&PushRetry {
+            uuid: uuid.clone(),
+            device_id: 1,
+            topic: String::from("mollysocket"),
+            body: String::from(r#"{"urgent":false}"#),
+            attempts: 0,
+            first_failure: OptTime::from(now),
+            next_attempt: OptTime::from(now - Duration::from_secs(1)),
+            urgency: Some(Urgency::Low),
+        })
+        .unwrap();
+        let retry = db
+            .due_push_retries()
+            .unwrap()
+            .into_iter()
+            .find(|r| r.uuid == uuid)
+            .unwrap();
+        // The deferred push is sent again with a low urgency
+        assert_eq!(retry.urgency, Some(Urgency::Low));
+        db.rm(&uuid, 1).unwrap();
+        assert!(db
+            .due_push_retries()
+            .unwrap()
+            .iter()
+            .all(|r| r.uuid != uuid));
+    }
+
+    #[test]
+    fn test_endpoints() {
+        test_support::load_config();
+        let db = MollySocketDb::new().unwrap();
+        let uuid = test_support::new_uuid();
+        let endpoint = |name: &str| PushEndpoint {
+            endpoint: format!("http://0.0.0.0/{}", name),
+            p256dh: None,
+            auth: None,
+        };
+        let mut co = Connection::new(
+            uuid.clone(),
+            1,
+            String::from("pass"),
+            String::from("http://0.0.0.0/1"),
+            None,
+            None,
+        );
+        co.set_endpoints(vec![endpoint("1"), endpoint("2")]);
+        co.delivery = DeliveryMode::All;
+        db.add(&co).unwrap();
+        let saved = db.get(&uuid, 1).unwrap();
+        assert_eq!(saved.endpoints(), vec![endpoint("1"), endpoint("2")]);
+        assert_eq!(saved.delivery, DeliveryMode::All);
+
+        let mut status = db
+            .get_endpoint_status(&uuid, 1, &endpoint("1").endpoint)
+            .unwrap();
+        assert_eq!(status.failures, 0);
+        status.last_status = Some(404);
+        status.failures = 1;
+        status.last_failure = OptTime::from(SystemTime::now());
+        db.set_endpoint_status(&status).unwrap();
+        let saved = db
+            .get_endpoint_status(&uuid, 1, &endpoint("1").endpoint)
+            .unwrap();
+        assert_eq!(saved.last_status, Some(404));
+        assert_eq!(saved.failures, 1);
+        assert!(saved.last_failure.0.is_some());
+
+        // The first endpoint is removed, the next one takes its place
+        assert!(db
+            .rm_push_endpoint(&uuid, 1, &endpoint("1").endpoint)
+            .unwrap());
+        let saved = db.get(&uuid, 1).unwrap();
+        assert_eq!(saved.endpoint, endpoint("2").endpoint);
+        assert_eq!(saved.endpoints(), vec![endpoint("2")]);
+        let status = db
+            .get_endpoint_status(&uuid, 1, &endpoint("1").endpoint)
+            .unwrap();
+        assert_eq!(status.failures, 0);
+        // The last endpoint is never removed
+        assert!(!db
+            .rm_push_endpoint(&uuid, 1, &endpoint("2").endpoint)
+            .unwrap());
+        assert_eq!(db.get(&uuid, 1).unwrap().endpoints(), vec![endpoint("2")]);
+        db.rm(&uuid, 1).unwrap();
+    }
+
+    #[test]
+    fn test_registrations() {
+        test_support::load_config();
+        let db = MollySocketDb::new().unwrap();
+        let uuid = test_support::new_uuid();
+        let mut registration = Registration {
+            connection: Connection::new(
+                uuid.clone(),
+                1,
+                String::from("pass"),
+                String::from("http://0.0.0.0/"),
+                None,
+                None,
+            ),
+            status: ApprovalStatus::Pending,
+        };
+        let id = |s: String| s.parse::<ConnectionId>().unwrap();
+        db.add_registration(&registration).unwrap();
+        registration.connection.device_id = 2;
+        db.add_registration(&registration).unwrap();
+        // Both devices stay pending
+        let saved = db.list_account_registrations(&uuid).unwrap();
+        assert_eq!(saved.len(), 2);
+        assert!(saved.iter().all(|r| r.status == ApprovalStatus::Pending));
+        assert_eq!(
+            db.get_registration(&id(format!("{}.2", uuid)))
+                .unwrap()
+                .connection
+                .device_id,
+            2
+        );
+        let err = db.get_registration(&id(uuid.clone())).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::SeveralDevices(_))
+        ));
+        assert!(db.get(&uuid, 2).is_err());
+
+        db.set_registration_status(&id(format!("{}.2", uuid)), ApprovalStatus::Rejected)
+            .unwrap();
+        assert_eq!(
+            db.get_registration(&id(format!("{}.2", uuid)))
+                .unwrap()
+                .status,
+            ApprovalStatus::Rejected
+        );
+        assert_eq!(
+            db.get_registration(&id(format!("{}.1", uuid)))
+                .unwrap()
+                .status,
+            ApprovalStatus::Pending
+        );
+
+        let co = db.approve_registration(&id(format!("{}.2", uuid))).unwrap();
+        assert_eq!(co.device_id, 2);
+        assert!(!co.forbidden);
+        assert!(db.get_registration(&id(format!("{}.2", uuid))).is_err());
+        assert!(db.approve_registration(&id(format!("{}.2", uuid))).is_err());
+        // The other device is the single registration left
+        assert_eq!(
+            db.get_registration(&id(uuid.clone()))
+                .unwrap()
+                .connection
+                .device_id,
+            1
+        );
+        db.rm_registration(&id(uuid.clone())).unwrap();
+        assert!(db.rm_registration(&id(uuid.clone())).is_err());
+        db.rm(&uuid, 2).unwrap();
+
+        db.add_registration(&registration).unwrap();
+        db.rm(&uuid, 1).unwrap();
+        assert!(db.list_account_registrations(&uuid).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_invites() {
+        test_support::load_config();
+        let db = MollySocketDb::new().unwrap();
+        let uuid = test_support::new_uuid();
+        let token = crypto::gen_token();
+        let tomorrow = OptTime::from(SystemTime::now() + Duration::from_secs(86400));
+        let invite = db.add_invite(&token, 1, &tomorrow).unwrap();
+        assert!(!invite.hash.contains(&token));
+        assert!(db.is_invite_valid(&token).unwrap());
+        assert!(!db.is_invite_valid("unknown").unwrap());
+
+        assert!(!db.is_uuid_invited(&uuid).unwrap());
+        assert!(db.redeem_invite(&token, &uuid).unwrap());
+        assert!(db.is_uuid_invited(&uuid).unwrap());
+        // The token has been used
+        assert!(!db.is_invite_valid(&token).unwrap());
+        assert!(!db.redeem_invite(&token, &test_support::new_uuid()).unwrap());
+        // The use is given back
+        db.unredeem_invite(&token, &uuid).unwrap();
+        assert!(!db.is_uuid_invited(&uuid).unwrap());
+        assert!(db.is_invite_valid(&token).unwrap());
+        assert!(db.redeem_invite(&token, &uuid).unwrap());
+
+        let expired = crypto::gen_token();
+        db.add_invite(
+            &expired,
+            1,
+            &OptTime::from(UNIX_EPOCH + Duration::from_secs(1)),
+        )
+        .unwrap();
+        assert!(!db.is_invite_valid(&expired).unwrap());
+
+        db.rm_invite(invite.id()).unwrap();
+        assert!(db.rm_invite(invite.id()).is_err());
+        // The uuid stays allowed, until its connection is removed
+        assert!(db.is_uuid_invited(&uuid).unwrap());
+        db.rm(&uuid, 1).unwrap();
+        assert!(!db.is_uuid_invited(&uuid).unwrap());
     }
 }
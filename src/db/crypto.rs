@@ -0,0 +1,233 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use eyre::{eyre, Result};
+use openssl::{
+    hash::MessageDigest,
+    pkey::PKey,
+    rand::rand_bytes,
+    sha::sha256,
+    sign::Signer,
+    symm::{decrypt_aead, encrypt_aead, Cipher},
+};
+use std::fmt::{Display, Formatter};
+use subtle::ConstantTimeEq;
+
+use crate::config;
+
+/// Prefix of the encrypted secrets. Whether a secret is encrypted is saved
+/// in its own column: the prefix is only the format of the ciphertext
+const PREFIX: &str = "aes256gcm:";
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The key isn't 32 bytes encoded in base64url
+    InvalidKey,
+    /// A secret is encrypted, but no key is configured
+    NoKey,
+    /// The secret can't be decrypted with this key
+    Decrypt,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::InvalidKey => write!(f, "The DB key must be 32 bytes, encoded in base64url. Generate one with `mollysocket db gen-key`"),
+            Error::NoKey => write!(f, "The secret is encrypted, but db_key is not set"),
+            Error::Decrypt => write!(f, "The secret can't be decrypted, is db_key the right key?"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/**
+Keys derived from the DB key: one to encrypt, one to derive the nonces.
+*/
+struct Keys {
+    enc: Vec<u8>,
+    nonce: Vec<u8>,
+}
+
+impl Keys {
+    fn from(key: &str) -> Result<Keys> {
+        let key = URL_SAFE_NO_PAD
+            .decode(key)
+            .ok()
+            .filter(|k| k.len() == 32)
+            .ok_or(Error::InvalidKey)?;
+        Ok(Keys {
+            enc: hmac(&key, b"mollysocket db encryption")?,
+            nonce: hmac(&key, b"mollysocket db nonce")?,
+        })
+    }
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+    let key = PKey::hmac(key)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(data)?;
+    Ok(signer.sign_to_vec()?)
+}
+
+/**
+Generate a new DB key.
+*/
+pub fn gen_key() -> String {
+    let mut key = [0u8; 32];
+    rand_bytes(&mut key).unwrap();
+    URL_SAFE_NO_PAD.encode(key)
+}
+
+/**
+Generate a new invite token.
+*/
+pub fn gen_token() -> String {
+    let mut token = [0u8; 16];
+    rand_bytes(&mut token).unwrap();
+    URL_SAFE_NO_PAD.encode(token)
+}
+
+/**
+Hash of an invite [token]: only the hash is saved in the DB.
+*/
+pub fn hash_token(token: &str) -> String {
+    URL_SAFE_NO_PAD.encode(sha256(token.as_bytes()))
+}
+
+/**
+Check that [key] is a valid DB key.
+*/
+pub fn check_key(key: &str) -> Result<()> {
+    Keys::from(key).map(|_| ())
+}
+
+/**
+Encrypt [secret] with AES-256-GCM.
+
+The nonce is derived from the secret (synthetic IV): the same secret
+gives the same ciphertext, so the passwords can be compared without decrypting them.
+*/
+pub fn encrypt(secret: &str, key: &str) -> Result<String> {
+    let keys = Keys::from(key)?;
+    let nonce = &hmac(&keys.nonce, secret.as_bytes())?[..NONCE_LEN];
+    let mut tag = [0u8; TAG_LEN];
+    let ciphertext = encrypt_aead(
+        Cipher::aes_256_gcm(),
+        &keys.enc,
+        Some(nonce),
+        &[],
+        secret.as_bytes(),
+        &mut tag,
+    )?;
+    Ok(format!(
+        "{}{}",
+        PREFIX,
+        URL_SAFE_NO_PAD.encode([nonce, &ciphertext, &tag].concat())
+    ))
+}
+
+/**
+Decrypt the [encrypted] secret.
+*/
+pub fn decrypt(encrypted: &str, key: &str) -> Result<String> {
+    let keys = Keys::from(key)?;
+    let data = encrypted
+        .strip_prefix(PREFIX)
+        .and_then(|data| URL_SAFE_NO_PAD.decode(data).ok())
+        .filter(|d| d.len() >= NONCE_LEN + TAG_LEN)
+        .ok_or(Error::Decrypt)?;
+    let (nonce, rest) = data.split_at(NONCE_LEN);
+    let (ciphertext, tag) = rest.split_at(rest.len() - TAG_LEN);
+    let secret = decrypt_aead(
+        Cipher::aes_256_gcm(),
+        &keys.enc,
+        Some(nonce),
+        &[],
+        ciphertext,
+        tag,
+    )
+    .map_err(|_| eyre!(Error::Decrypt))?;
+    Ok(String::from_utf8(secret).map_err(|_| Error::Decrypt)?)
+}
+
+/**
+Encrypt [secret], a password or a VAPID private key, with the configured key to save
+it in the DB, unless it is already [encrypted]. Returns the secret to save, and whether
+it is encrypted: it is saved in cleartext if no key is configured.
+*/
+pub fn encrypt_secret(secret: &str, encrypted: bool) -> Result<(String, bool)> {
+    match config::get_db_key() {
+        Some(key) if !encrypted => Ok((encrypt(secret, &key)?, true)),
+        _ => Ok((secret.into(), encrypted)),
+    }
+}
+
+/**
+Decrypt the [stored] secret with the configured key, if it is [encrypted].
+*/
+pub fn decrypt_secret(stored: &str, encrypted: bool) -> Result<String> {
+    if !encrypted {
+        return Ok(stored.into());
+    }
+    decrypt(stored, &config::get_db_key().ok_or(Error::NoKey)?)
+}
+
+/**
+Compare the secrets [a] and [b] in constant time.
+*/
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.as_bytes().ct_eq(b.as_bytes()).into()
+}
+
+/**
+Check the cleartext [password] is the [stored] one, without decrypting it.
+The comparison is in constant time.
+*/
+pub fn password_matches(stored: &str, encrypted: bool, password: &str) -> bool {
+    let password = match config::get_db_key() {
+        Some(key) if encrypted => match encrypt(password, &key) {
+            Ok(password) => password,
+            Err(_) => return false,
+        },
+        // It can't be compared without the key
+        None if encrypted => return false,
+        _ => password.into(),
+    };
+    constant_time_eq(stored, &password)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encrypt() {
+        let key = gen_key();
+        let encrypted = encrypt("password", &key).unwrap();
+        assert!(encrypted.starts_with(PREFIX));
+        assert!(!encrypted.contains("password"));
+        assert_eq!(encrypted, encrypt("password", &key).unwrap());
+        assert_ne!(encrypted, encrypt("password2", &key).unwrap());
+        assert_eq!(decrypt(&encrypted, &key).unwrap(), "password");
+        // A password in cleartext, even with the prefix, isn't decrypted
+        assert!(decrypt("password", &key).is_err());
+        assert!(decrypt(&format!("{}password", PREFIX), &key).is_err());
+    }
+
+    #[test]
+    fn test_decrypt_errors() {
+        let encrypted = encrypt("password", &gen_key()).unwrap();
+        let err = decrypt(&encrypted, &gen_key()).unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(Error::Decrypt)));
+        let err = decrypt(&encrypted, "short").unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(Error::InvalidKey)));
+
+        let key = gen_key();
+        let mut tampered: Vec<char> = encrypt("password", &key).unwrap().chars().collect();
+        let i = PREFIX.len() + NONCE_LEN * 2;
+        tampered[i] = if tampered[i] == 'A' { 'B' } else { 'A' };
+        let tampered: String = tampered.into_iter().collect();
+        assert!(decrypt(&tampered, &key).is_err());
+    }
+}
@@ -1,19 +1,694 @@
-use eyre::Result;
+use eyre::{eyre, Result};
+use std::{
+    fmt::{Display, Formatter},
+    fs,
+    path::PathBuf,
+};
 
-const CURRENT_VERSION: i32 = 1;
+/**
+A step of the DB schema.
 
-pub trait Migration {
-    fn migrate(&self) -> Result<()>;
+Migrations are applied in order, each in its own transaction, and the
+version is saved in `user_version` once the migration is done.
+*/
+#[derive(Debug)]
+pub struct Migration {
+    pub version: i32,
+    pub description: &'static str,
+    pub up: &'static str,
 }
 
-impl Migration for rusqlite::Connection {
-    fn migrate(&self) -> Result<()> {
-        let _user_version: i32 =
+/// Migrations to apply, ordered by version.
+///
+/// Never edit a released migration, add a new one instead.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "Create connections table",
+        // The table may already exist: it was created outside of the
+        // migrations before the version 2
+        up: "
+CREATE TABLE IF NOT EXISTS connections(
+    uuid TEXT UNIQUE ON CONFLICT REPLACE,
+    device_id INTEGER,
+    password TEXT,
+    endpoint TEXT,
+    forbidden BOOLEAN NOT NULL CHECK (forbidden IN (0, 1)),
+    last_registration INTEGER
+);
+        ",
+    },
+    Migration {
+        version: 2,
+        description: "Add the keys of the push subscription",
+        up: "
+ALTER TABLE connections ADD COLUMN p256dh TEXT;
+ALTER TABLE connections ADD COLUMN auth TEXT;
+        ",
+    },
+    Migration {
+        version: 3,
+        description: "Add the queue of push retries",
+        up: "
+CREATE TABLE push_retries(
+    uuid TEXT NOT NULL,
+    topic TEXT NOT NULL,
+    body TEXT NOT NULL,
+    attempts INTEGER NOT NULL,
+    first_failure INTEGER NOT NULL,
+    next_attempt INTEGER NOT NULL,
+    PRIMARY KEY (uuid, topic)
+);
+        ",
+    },
+    Migration {
+        version: 4,
+        description: "Add the status of the connections",
+        up: "
+CREATE TABLE connection_status(
+    uuid TEXT PRIMARY KEY,
+    ws_state TEXT NOT NULL,
+    last_connected INTEGER NOT NULL,
+    last_envelope INTEGER NOT NULL,
+    last_push INTEGER NOT NULL,
+    last_push_status INTEGER,
+    last_error TEXT,
+    last_error_time INTEGER NOT NULL
+);
+        ",
+    },
+    Migration {
+        version: 5,
+        description: "Add the VAPID keyring",
+        up: "
+CREATE TABLE vapid_keys(
+    pubkey TEXT PRIMARY KEY,
+    privkey TEXT NOT NULL,
+    created INTEGER NOT NULL,
+    status TEXT NOT NULL
+);
+ALTER TABLE connections ADD COLUMN vapid_key TEXT;
+        ",
+    },
+    Migration {
+        version: 6,
+        description: "Add the registrations waiting for approval",
+        up: "
+CREATE TABLE registrations(
+    uuid TEXT PRIMARY KEY,
+    device_id INTEGER NOT NULL,
+    password TEXT NOT NULL,
+    endpoint TEXT NOT NULL,
+    requested INTEGER NOT NULL,
+    p256dh TEXT,
+    auth TEXT,
+    vapid_key TEXT,
+    status TEXT NOT NULL
+);
+        ",
+    },
+    Migration {
+        version: 7,
+        description: "Add the invites and the accounts they allow",
+        up: "
+CREATE TABLE invites(
+    hash TEXT PRIMARY KEY,
+    uses INTEGER NOT NULL,
+    max_uses INTEGER NOT NULL,
+    created INTEGER NOT NULL,
+    expires INTEGER NOT NULL
+);
+CREATE TABLE invited_uuids(
+    uuid TEXT PRIMARY KEY,
+    invite TEXT NOT NULL,
+    added INTEGER NOT NULL
+);
+        ",
+    },
+    Migration {
+        version: 8,
+        description: "Add the push policies of the connections",
+        up: "
+CREATE TABLE push_policies(
+    uuid TEXT PRIMARY KEY,
+    debounce INTEGER,
+    ttl INTEGER,
+    urgent TEXT,
+    non_urgent TEXT,
+    topic TEXT
+);
+        ",
+    },
+    Migration {
+        version: 9,
+        description: "Add the delay of the pushes for the non-urgent envelopes",
+        up: "
+ALTER TABLE push_policies ADD COLUMN non_urgent_delay INTEGER;
+        ",
+    },
+    Migration {
+        version: 10,
+        description: "Key the connections by account and device",
+        // The rows of the other tables get the device of the connection of their uuid
+        up: "
+CREATE TABLE connections_v10(
+    uuid TEXT NOT NULL,
+    device_id INTEGER NOT NULL,
+    password TEXT,
+    endpoint TEXT,
+    forbidden BOOLEAN NOT NULL CHECK (forbidden IN (0, 1)),
+    last_registration INTEGER,
+    p256dh TEXT,
+    auth TEXT,
+    vapid_key TEXT,
+    UNIQUE (uuid, device_id) ON CONFLICT REPLACE
+);
+INSERT INTO connections_v10
+SELECT uuid, COALESCE(device_id, 1), password, endpoint, forbidden, last_registration, p256dh, auth, vapid_key
+FROM connections WHERE uuid IS NOT NULL;
+DROP TABLE connections;
+ALTER TABLE connections_v10 RENAME TO connections;
+
+CREATE TABLE connection_status_v10(
+    uuid TEXT NOT NULL,
+    device_id INTEGER NOT NULL,
+    ws_state TEXT NOT NULL,
+    last_connected INTEGER NOT NULL,
+    last_envelope INTEGER NOT NULL,
+    last_push INTEGER NOT NULL,
+    last_push_status INTEGER,
+    last_error TEXT,
+    last_error_time INTEGER NOT NULL,
+    PRIMARY KEY (uuid, device_id)
+);
+INSERT INTO connection_status_v10
+SELECT s.uuid, c.device_id, s.ws_state, s.last_connected, s.last_envelope, s.last_push, s.last_push_status, s.last_error, s.last_error_time
+FROM connection_status s JOIN connections c ON c.uuid = s.uuid;
+DROP TABLE connection_status;
+ALTER TABLE connection_status_v10 RENAME TO connection_status;
+
+CREATE TABLE push_retries_v10(
+    uuid TEXT NOT NULL,
+    device_id INTEGER NOT NULL,
+    topic TEXT NOT NULL,
+    body TEXT NOT NULL,
+    attempts INTEGER NOT NULL,
+    first_failure INTEGER NOT NULL,
+    next_attempt INTEGER NOT NULL,
+    PRIMARY KEY (uuid, device_id, topic)
+);
+INSERT INTO push_retries_v10
+SELECT r.uuid, c.device_id, r.topic, r.body, r.attempts, r.first_failure, r.next_attempt
+FROM push_retries r JOIN connections c ON c.uuid = r.uuid;
+DROP TABLE push_retries;
+ALTER TABLE push_retries_v10 RENAME TO push_retries;
+
+CREATE TABLE push_policies_v10(
+    uuid TEXT NOT NULL,
+    device_id INTEGER NOT NULL,
+    debounce INTEGER,
+    ttl INTEGER,
+    urgent TEXT,
+    non_urgent TEXT,
+    topic TEXT,
+    non_urgent_delay INTEGER,
+    PRIMARY KEY (uuid, device_id)
+);
+INSERT INTO push_policies_v10
+SELECT p.uuid, c.device_id, p.debounce, p.ttl, p.urgent, p.non_urgent, p.topic, p.non_urgent_delay
+FROM push_policies p JOIN connections c ON c.uuid = p.uuid;
+DROP TABLE push_policies;
+ALTER TABLE push_policies_v10 RENAME TO push_policies;
+        ",
+    },
+    Migration {
+        version: 11,
+        description: "Add the endpoints after the first one, and the health of the endpoints",
+        // The next endpoints are a JSON array of {endpoint, p256dh, auth}
+        up: "
+ALTER TABLE connections ADD COLUMN extra_endpoints TEXT NOT NULL DEFAULT '[]';
+ALTER TABLE connections ADD COLUMN delivery TEXT NOT NULL DEFAULT 'failover';
+ALTER TABLE registrations ADD COLUMN extra_endpoints TEXT NOT NULL DEFAULT '[]';
+ALTER TABLE registrations ADD COLUMN delivery TEXT NOT NULL DEFAULT 'failover';
+CREATE TABLE endpoint_status(
+    uuid TEXT NOT NULL,
+    device_id INTEGER NOT NULL,
+    endpoint TEXT NOT NULL,
+    last_status INTEGER,
+    failures INTEGER NOT NULL,
+    last_success INTEGER NOT NULL,
+    last_failure INTEGER NOT NULL,
+    PRIMARY KEY (uuid, device_id, endpoint)
+);
+        ",
+    },
+    Migration {
+        version: 12,
+        description: "Add the urgency of the push notifications to send again",
+        up: "
+ALTER TABLE push_retries ADD COLUMN urgency TEXT;
+        ",
+    },
+    Migration {
+        version: 13,
+        description: "Add the connections disabled by an administrator",
+        up: "
+ALTER TABLE connections ADD COLUMN disabled BOOLEAN NOT NULL DEFAULT 0 CHECK (disabled IN (0, 1));
+        ",
+    },
+    Migration {
+        version: 14,
+        description: "Key the registrations by uuid and device_id",
+        up: "
+CREATE TABLE registrations_v14(
+    uuid TEXT NOT NULL,
+    device_id INTEGER NOT NULL,
+    password TEXT NOT NULL,
+    endpoint TEXT NOT NULL,
+    requested INTEGER NOT NULL,
+    p256dh TEXT,
+    auth TEXT,
+    vapid_key TEXT,
+    status TEXT NOT NULL,
+    extra_endpoints TEXT NOT NULL DEFAULT '[]',
+    delivery TEXT NOT NULL DEFAULT 'failover',
+    PRIMARY KEY (uuid, device_id)
+);
+INSERT INTO registrations_v14
+SELECT uuid, device_id, password, endpoint, requested, p256dh, auth, vapid_key, status, extra_endpoints, delivery
+FROM registrations;
+DROP TABLE registrations;
+ALTER TABLE registrations_v14 RENAME TO registrations;
+        ",
+    },
+    Migration {
+        version: 15,
+        description: "Save whether the secrets are encrypted",
+        // The secrets encrypted before this version are recognized by the
+        // prefix of their ciphertext, for the last time
+        up: "
+ALTER TABLE connections ADD COLUMN password_encrypted BOOLEAN NOT NULL DEFAULT 0 CHECK (password_encrypted IN (0, 1));
+ALTER TABLE registrations ADD COLUMN password_encrypted BOOLEAN NOT NULL DEFAULT 0 CHECK (password_encrypted IN (0, 1));
+ALTER TABLE vapid_keys ADD COLUMN privkey_encrypted BOOLEAN NOT NULL DEFAULT 0 CHECK (privkey_encrypted IN (0, 1));
+UPDATE connections SET password_encrypted = 1 WHERE substr(password, 1, 10) = 'aes256gcm:';
+UPDATE registrations SET password_encrypted = 1 WHERE substr(password, 1, 10) = 'aes256gcm:';
+UPDATE vapid_keys SET privkey_encrypted = 1 WHERE substr(privkey, 1, 10) = 'aes256gcm:';
+        ",
+    },
+];
+
+#[derive(Debug)]
+pub enum Error {
+    /// The DB has been migrated by a newer version of mollysocket
+    UnknownVersion(i32),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::UnknownVersion(v) => write!(
+                f,
+                "The DB version ({}) is newer than the latest known version ({}). Has it been used by a newer version of mollysocket?",
+                v,
+                latest_version()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+pub fn latest_version() -> i32 {
+    MIGRATIONS.last().map(|m| m.version).unwrap_or(0)
+}
+
+pub trait Migrate {
+    fn user_version(&self) -> Result<i32>;
+    fn pending_migrations(&self) -> Result<Vec<&'static Migration>>;
+    /// Apply the pending migrations, returns the path of the backup
+    /// if one has been made
+    fn migrate(&self) -> Result<Option<PathBuf>>;
+}
+
+impl Migrate for rusqlite::Connection {
+    fn user_version(&self) -> Result<i32> {
+        Ok(
             self.query_row("SELECT user_version FROM pragma_user_version;", [], |row| {
                 row.get(0)
-            })?;
+            })?,
+        )
+    }
+
+    fn pending_migrations(&self) -> Result<Vec<&'static Migration>> {
+        let user_version = self.user_version()?;
+        if user_version > latest_version() {
+            return Err(eyre!(Error::UnknownVersion(user_version)));
+        }
+        Ok(MIGRATIONS
+            .iter()
+            .filter(|m| m.version > user_version)
+            .collect())
+    }
+
+    fn migrate(&self) -> Result<Option<PathBuf>> {
+        let pending = self.pending_migrations()?;
+        if pending.is_empty() {
+            return Ok(None);
+        }
+        let backup = self.backup()?;
+        for migration in pending {
+            log::info!(
+                "Migrating DB to version {}: {}",
+                migration.version,
+                migration.description
+            );
+            let tx = self.unchecked_transaction()?;
+            tx.execute_batch(migration.up)?;
+            tx.pragma_update(None, "user_version", migration.version)?;
+            tx.commit()?;
+        }
+        Ok(backup)
+    }
+}
+
+trait Backup {
+    fn backup(&self) -> Result<Option<PathBuf>>;
+}
+
+impl Backup for rusqlite::Connection {
+    /**
+    Copy the DB file next to it, with the current version in its name.
+
+    Nothing is done for in-memory and newly created DB.
+    */
+    fn backup(&self) -> Result<Option<PathBuf>> {
+        let path = match self.path() {
+            Some(p) if !p.is_empty() => PathBuf::from(p),
+            _ => return Ok(None),
+        };
+        if fs::metadata(&path)?.len() == 0 {
+            return Ok(None);
+        }
+        let mut backup = path.clone().into_os_string();
+        backup.push(format!(".v{}.bak", self.user_version()?));
+        let backup = PathBuf::from(backup);
+        fs::copy(&path, &backup)?;
+        log::info!("DB backed up to {}", backup.display());
+        Ok(Some(backup))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /**
+    Test the migrations are ordered.
+    */
+    #[test]
+    fn test_ordered() {
+        assert!(MIGRATIONS.windows(2).all(|w| w[0].version < w[1].version));
+    }
+
+    /**
+    Test a new DB is migrated to the latest version.
+    */
+    #[test]
+    fn test_new_db() {
+        let db = rusqlite::Connection::open_in_memory().unwrap();
+        assert_eq!(db.pending_migrations().unwrap().len(), MIGRATIONS.len());
+        assert!(db.migrate().unwrap().is_none());
+        assert_eq!(db.user_version().unwrap(), latest_version());
+        assert!(db.pending_migrations().unwrap().is_empty());
+        db.execute(
+            "INSERT INTO connections(uuid, device_id, password, endpoint, forbidden, last_registration, p256dh, auth)
+            VALUES ('uuid', 1, 'pass', 'http://0.0.0.0/', 0, 0, NULL, NULL);",
+            [],
+        )
+        .unwrap();
+    }
+
+    /**
+    Test a DB created before the migrations keeps its connections.
+    */
+    #[test]
+    fn test_legacy_db() {
+        let db = rusqlite::Connection::open_in_memory().unwrap();
+        db.execute_batch(MIGRATIONS[0].up).unwrap();
+        db.execute(
+            "INSERT INTO connections VALUES ('uuid', 1, 'pass', 'http://0.0.0.0/', 0, 0);",
+            [],
+        )
+        .unwrap();
+        assert_eq!(db.user_version().unwrap(), 0);
+        db.migrate().unwrap();
+        assert_eq!(db.user_version().unwrap(), latest_version());
+        let count: i32 = db
+            .query_row(
+                "SELECT COUNT(*) FROM connections WHERE auth IS NULL;",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(count, 1);
+    }
+
+    /// Schema of the DB created by the first release, with the version it saved
+    const BASELINE_DB: &str = "
+CREATE TABLE IF NOT EXISTS connections(
+    uuid TEXT UNIQUE ON CONFLICT REPLACE,
+    device_id INTEGER,
+    password TEXT,
+    endpoint TEXT,
+    forbidden BOOLEAN NOT NULL CHECK (forbidden IN (0, 1)),
+    last_registration INTEGER
+);
+PRAGMA user_version = 1;
+INSERT INTO connections(uuid, device_id, password, endpoint, forbidden, last_registration)
+VALUES ('uuid1', 1, 'pass1', 'http://0.0.0.0/1', 0, 1700000000);
+INSERT INTO connections(uuid, device_id, password, endpoint, forbidden, last_registration)
+VALUES ('uuid2', 2, 'pass2', 'http://0.0.0.0/2', 1, 1700000001);
+    ";
+
+    /**
+    Test a DB created by the first release is migrated through all the versions, and keeps its connections.
+    */
+    #[test]
+    fn test_baseline_db() {
+        let db = rusqlite::Connection::open_in_memory().unwrap();
+        db.execute_batch(BASELINE_DB).unwrap();
+        assert_eq!(db.user_version().unwrap(), 1);
+        assert_eq!(db.pending_migrations().unwrap().len(), MIGRATIONS.len() - 1);
+        db.migrate().unwrap();
+        assert_eq!(db.user_version().unwrap(), latest_version());
+
+        let mut stmt = db
+            .prepare(
+                "SELECT uuid, device_id, password, endpoint, forbidden, last_registration, password_encrypted, disabled, delivery
+                FROM connections ORDER BY uuid;",
+            )
+            .unwrap();
+        type Row = (String, u32, String, String, bool, i64, bool, bool, String);
+        let rows: Vec<Row> = stmt
+            .query_map([], |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                    row.get(5)?,
+                    row.get(6)?,
+                    row.get(7)?,
+                    row.get(8)?,
+                ))
+            })
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(
+            rows,
+            vec![
+                (
+                    String::from("uuid1"),
+                    1,
+                    String::from("pass1"),
+                    String::from("http://0.0.0.0/1"),
+                    false,
+                    1700000000,
+                    false,
+                    false,
+                    String::from("failover"),
+                ),
+                (
+                    String::from("uuid2"),
+                    2,
+                    String::from("pass2"),
+                    String::from("http://0.0.0.0/2"),
+                    true,
+                    1700000001,
+                    false,
+                    false,
+                    String::from("failover"),
+                ),
+            ]
+        );
+
+        // The connections and the registrations are keyed by (uuid, device_id)
+        db.execute_batch(
+            "INSERT INTO connections(uuid, device_id, password, endpoint, forbidden, last_registration)
+            VALUES ('uuid1', 3, 'pass3', 'http://0.0.0.0/3', 0, 0);
+            INSERT INTO registrations(uuid, device_id, password, endpoint, requested, status)
+            VALUES ('uuid1', 1, 'pass1', 'http://0.0.0.0/1', 0, 'pending');
+            INSERT INTO registrations(uuid, device_id, password, endpoint, requested, status)
+            VALUES ('uuid1', 3, 'pass3', 'http://0.0.0.0/3', 0, 'pending');",
+        )
+        .unwrap();
+        for table in ["connections", "registrations"] {
+            let count: i32 = db
+                .query_row(
+                    &format!("SELECT COUNT(*) FROM {} WHERE uuid = 'uuid1';", table),
+                    [],
+                    |row| row.get(0),
+                )
+                .unwrap();
+            assert_eq!(count, 2, "{}", table);
+        }
+        let password: String = db
+            .query_row(
+                "SELECT password FROM connections WHERE uuid = 'uuid1' AND device_id = 1;",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(password, "pass1");
+    }
+
+    /**
+    Test the connections keep their status, and an account may have several devices, after the version 10.
+    */
+    #[test]
+    fn test_device_key() {
+        let db = rusqlite::Connection::open_in_memory().unwrap();
+        let tx = db.unchecked_transaction().unwrap();
+        for migration in MIGRATIONS.iter().filter(|m| m.version < 10) {
+            tx.execute_batch(migration.up).unwrap();
+        }
+        tx.pragma_update(None, "user_version", 9).unwrap();
+        tx.commit().unwrap();
+        db.execute_batch(
+            "INSERT INTO connections(uuid, device_id, password, endpoint, forbidden, last_registration)
+            VALUES ('uuid', 2, 'pass', 'http://0.0.0.0/', 0, 0);
+            INSERT INTO connection_status VALUES ('uuid', 'connected', 0, 0, 0, 201, NULL, 0);
+            INSERT INTO push_policies(uuid, ttl) VALUES ('uuid', 60);",
+        )
+        .unwrap();
+        db.migrate().unwrap();
+        let device_id: u32 = db
+            .query_row(
+                "SELECT device_id FROM connection_status WHERE uuid = 'uuid' AND last_push_status = 201;",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(device_id, 2);
+        let ttl: u32 = db
+            .query_row(
+                "SELECT ttl FROM push_policies WHERE uuid = 'uuid' AND device_id = 2;",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(ttl, 60);
+        db.execute(
+            "INSERT INTO connections(uuid, device_id, password, endpoint, forbidden, last_registration)
+            VALUES ('uuid', 3, 'pass', 'http://0.0.0.0/', 0, 0);",
+            [],
+        )
+        .unwrap();
+        let count: i32 = db
+            .query_row(
+                "SELECT COUNT(*) FROM connections WHERE uuid = 'uuid';",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(count, 2);
+    }
+
+    /**
+    Test an account may have several registrations, after the version 14.
+    */
+    #[test]
+    fn test_registration_device_key() {
+        let db = rusqlite::Connection::open_in_memory().unwrap();
+        let tx = db.unchecked_transaction().unwrap();
+        for migration in MIGRATIONS.iter().filter(|m| m.version < 14) {
+            tx.execute_batch(migration.up).unwrap();
+        }
+        tx.pragma_update(None, "user_version", 13).unwrap();
+        tx.commit().unwrap();
+        db.execute(
+            "INSERT INTO registrations(uuid, device_id, password, endpoint, requested, status, delivery)
+            VALUES ('uuid', 1, 'pass', 'http://0.0.0.0/', 0, 'pending', 'all');",
+            [],
+        )
+        .unwrap();
+        db.migrate().unwrap();
+        db.execute(
+            "INSERT INTO registrations(uuid, device_id, password, endpoint, requested, status)
+            VALUES ('uuid', 2, 'pass', 'http://0.0.0.0/', 0, 'pending');",
+            [],
+        )
+        .unwrap();
+        let delivery: Vec<String> = db
+            .prepare("SELECT delivery FROM registrations WHERE uuid = 'uuid' ORDER BY device_id;")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(delivery, vec!["all", "failover"]);
+    }
+
+    /**
+    Test the secrets encrypted before the version 15 are marked as encrypted.
+    */
+    #[test]
+    fn test_secrets_encrypted() {
+        let db = rusqlite::Connection::open_in_memory().unwrap();
+        let tx = db.unchecked_transaction().unwrap();
+        for migration in MIGRATIONS.iter().filter(|m| m.version < 15) {
+            tx.execute_batch(migration.up).unwrap();
+        }
+        tx.pragma_update(None, "user_version", 14).unwrap();
+        tx.commit().unwrap();
+        db.execute_batch(
+            "INSERT INTO connections(uuid, device_id, password, endpoint, forbidden, last_registration)
+            VALUES ('uuid', 1, 'aes256gcm:AAAA', 'http://0.0.0.0/', 0, 0);
+            INSERT INTO connections(uuid, device_id, password, endpoint, forbidden, last_registration)
+            VALUES ('uuid', 2, 'pass', 'http://0.0.0.0/', 0, 0);",
+        )
+        .unwrap();
+        db.migrate().unwrap();
+        let encrypted: Vec<bool> = db
+            .prepare("SELECT password_encrypted FROM connections ORDER BY device_id;")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(encrypted, vec![true, false]);
+    }
 
-        // Upgrade version
-        Ok(self.pragma_update(None, "user_version", CURRENT_VERSION)?)
+    /**
+    Test a DB from a newer version is refused.
+    */
+    #[test]
+    fn test_unknown_version() {
+        let db = rusqlite::Connection::open_in_memory().unwrap();
+        db.pragma_update(None, "user_version", latest_version() + 1)
+            .unwrap();
+        assert!(db.migrate().is_err());
     }
 }
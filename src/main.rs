@@ -1,10 +1,14 @@
 mod cli;
 mod config;
+mod control;
 mod db;
 mod qrcode;
 mod server;
+#[cfg(test)]
+mod test_support;
 mod utils;
 mod vapid;
+mod webpush;
 mod ws;
 
 #[tokio::main]
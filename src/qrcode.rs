@@ -6,13 +6,16 @@ use crate::vapid;
 
 pub const INTRO: &str = "Scan the following QR code to link mollysocket:";
 
-/// Generate deep link to link mollysocket to molly with url
-pub fn gen_url(ms_url: &str) -> Result<Url> {
+/// Generate deep link to link mollysocket to molly with url, and the invite token if any
+pub fn gen_url(ms_url: &str, invite: Option<&str>) -> Result<Url> {
     let mut url = Url::parse("mollysocket://link")?;
     let vapid = vapid::get_vapid_pubkey()?;
-    url.query_pairs_mut().append_pair("vapid", vapid);
+    url.query_pairs_mut().append_pair("vapid", &vapid);
     url.query_pairs_mut().append_pair("url", ms_url);
     url.query_pairs_mut().append_pair("type", "webserver");
+    if let Some(invite) = invite {
+        url.query_pairs_mut().append_pair("invite", invite);
+    }
     Ok(url)
 }
 
@@ -20,14 +23,14 @@ pub fn gen_url(ms_url: &str) -> Result<Url> {
 pub fn gen_url_airgapped() -> Result<Url> {
     let mut url = Url::parse("mollysocket://link")?;
     let vapid = vapid::get_vapid_pubkey()?;
-    url.query_pairs_mut().append_pair("vapid", vapid);
+    url.query_pairs_mut().append_pair("vapid", &vapid);
     url.query_pairs_mut().append_pair("type", "airgapped");
     Ok(url)
 }
 
 /// Return QRCode made with characters
 pub fn url_to_printable_qr(url: &Url) -> String {
-    let qr = QrCode::encode_text(&url.as_str(), QrCodeEcc::Low).unwrap();
+    let qr = QrCode::encode_text(url.as_str(), QrCodeEcc::Low).unwrap();
     let mut result = String::new();
     let border: i32 = 4;
     for y in (-border..qr.size() + border).step_by(2) {
@@ -55,7 +58,7 @@ pub fn url_to_printable_qr(url: &Url) -> String {
 
 /// Return QRCode in svg format
 pub fn url_to_svg_qr(url: &Url) -> String {
-    let qr = QrCode::encode_text(&url.as_str(), QrCodeEcc::Low).unwrap();
+    let qr = QrCode::encode_text(url.as_str(), QrCodeEcc::Low).unwrap();
     let mut result = String::new();
     let border: i32 = 4;
     result += "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n";
@@ -1,36 +1,66 @@
-use crate::{db::MollySocketDb, server::metrics::Metrics};
-use futures_util::{future::join, pin_mut, select, FutureExt};
+use crate::{config, db::MollySocketDb, server::metrics::Metrics, utils::push_policy::PushPolicy};
+use futures_util::{future::join5, pin_mut, select, FutureExt};
 use lazy_static::lazy_static;
-use std::sync::{Arc, Mutex};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
 use tokio::signal;
 #[cfg(unix)]
 use tokio::signal::unix::{self, SignalKind};
 
 mod connections;
+mod control;
+mod health;
 mod metrics;
+mod push_retries;
+mod reload;
+mod status;
 mod web;
 
 lazy_static! {
     static ref DB: MollySocketDb = MollySocketDb::new().unwrap();
     static ref METRICS: Metrics = Metrics::new().unwrap();
+    static ref HEALTH: health::Health = health::Health::default();
     /**
     Vec of [connections::KillLoopRef].
 
     Filled by [connections].
 
-    When a message is sent to the kill channel associated to the uuid, the loop for the registration stops.
+    When a message is sent to the kill channel associated to the uuid and device id, the loop for the registration stops.
     */
     static ref KILL_VEC: Arc<Mutex<Vec<connections::KillLoopRef>>> = Arc::new(Mutex::new(vec![]));
+    /**
+    Live [status::LiveStatus] of the connections, by uuid and device id.
+
+    Filled by [status], and read by the status endpoints.
+    */
+    static ref STATUSES: Arc<Mutex<HashMap<(String, u32), status::LiveStatus>>> = Arc::new(Mutex::new(HashMap::new()));
     /**
      Channel to do action when a new connection is registered.
 
      Bounded by [connections].
 
-     When a new connection is sent, loops for connection with this [Connection][crate::db::Connection]#uuid is kill, and a new loop is started.
+     When a new connection is sent, loops for connection with this [Connection][crate::db::Connection]#uuid and #device_id is kill, and a new loop is started.
      */
     static ref NEW_CO_TX: Arc<Mutex<connections::OptSender>> = Arc::new(Mutex::new(None));
 }
 
+/**
+The account is in allowed_uuids, or has been allowed by an invite.
+*/
+fn is_uuid_allowed(uuid: &str) -> bool {
+    config::is_uuid_valid(uuid) || DB.is_uuid_invited(uuid).unwrap_or(false)
+}
+
+/**
+Push policy of the connection: the one of the config, with the overrides of the connection.
+*/
+fn get_push_policy(uuid: &str, device_id: u32) -> PushPolicy {
+    config::get_push_policy()
+        .with_override(&DB.get_push_policy(uuid, device_id).unwrap_or_default())
+}
+
 pub async fn run() {
     let sigint_future = signal::ctrl_c().fuse();
     #[cfg(unix)]
@@ -39,13 +69,31 @@ pub async fn run() {
     let sigterm_future = sigterm_stream.recv().fuse();
     #[cfg(not(unix))]
     let sigterm_future = std::future::pending::<()>().fuse();
-    let joined_future = join(web::launch().fuse(), connections::run().fuse());
+    let joined_future = join5(
+        web::launch().fuse(),
+        connections::run().fuse(),
+        push_retries::run().fuse(),
+        reload::run().fuse(),
+        control::run().fuse(),
+    );
 
     pin_mut!(sigint_future, sigterm_future, joined_future);
 
     select!(
         _ = sigint_future => log::info!("SIGINT received"),
         _ = sigterm_future => log::info!("SIGTERM received"),
+        _ = joined_future => {
+            log::warn!("Server stopped");
+            return;
+        },
+    );
+
+    // The tasks keep running until the connections are closed
+    HEALTH.on_shutdown();
+    log::info!("Closing the connections");
+    select!(
+        _ = connections::close_all(config::get_shutdown_timeout()).fuse() => log::info!("Connections closed"),
         _ = joined_future => log::warn!("Server stopped"),
-    )
+    );
+    control::cleanup();
 }
@@ -1,27 +1,48 @@
 use crate::{
-    db::Connection,
-    server::{DB, KILL_VEC, METRICS, NEW_CO_TX},
-    ws::{SignalWebSocket, SignalWebSocketError},
+    db::{Connection, WsState},
+    server::{
+        get_push_policy, health::RestoreGuard, push_retries, status, DB, HEALTH, KILL_VEC, METRICS,
+        NEW_CO_TX,
+    },
+    ws::{CloseHandle, PushResult, SignalWebSocket, SignalWebSocketError, StatusEvent},
 };
 use eyre::Result;
 use futures_channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
 use futures_util::{future::join_all, join, select, Future, FutureExt, StreamExt};
+use std::time::Duration;
+use tokio::time;
+
+/// Interval between two checks of the closed connections, on shutdown
+const CLOSE_INTERVAL: Duration = Duration::from_millis(100);
 
 /**
-Associates the kill channel to the [Connection][crate::db::Connection]#uuid.
+Associates the kill channel, and the handle to close the websocket,
+to the [Connection][crate::db::Connection]#uuid and #device_id.
 */
 pub struct KillLoopRef {
     uuid: String,
+    device_id: u32,
     tx: UnboundedSender<bool>,
+    close: CloseHandle,
+}
+
+impl KillLoopRef {
+    fn is(&self, uuid: &str, device_id: u32) -> bool {
+        self.uuid == uuid && self.device_id == device_id
+    }
 }
 
 pub type OptSender = Option<UnboundedSender<Connection>>;
 
 pub async fn run() {
+    let _running = HEALTH.on_connections_started();
     let mut connections = DB.list().unwrap();
+    // The connections are restored once each has tried to connect
+    let guards = HEALTH.on_connections_restoring(connections.len());
     let loops: Vec<_> = connections
         .iter_mut()
-        .map(|co| connection_loop(co).fuse())
+        .zip(guards)
+        .map(|(co, guard)| connection_loop(co, Some(guard)).fuse())
         .collect();
 
     let (new_connections_tx, new_connections_rx) = mpsc::unbounded();
@@ -37,35 +58,66 @@ pub async fn run() {
 
 pub async fn gen_new_loops(rx: UnboundedReceiver<Connection>) {
     rx.for_each_concurrent(None, |mut co| async move {
-        kill(&co.uuid).await;
-        connection_loop(&mut co).await;
+        if HEALTH.is_shutting_down() {
+            log::info!("Shutting down, not starting connection for {}", co.id());
+            return;
+        }
+        kill(&co.uuid, co.device_id).await;
+        connection_loop(&mut co, None).await;
     })
     .await;
 }
 
-async fn connection_loop(co: &mut Connection) {
+/**
+Run the connection until it is killed, forbidden or closed. The [restoring]
+guard, if any, is dropped after the first connection attempt.
+*/
+async fn connection_loop(co: &mut Connection, mut restoring: Option<RestoreGuard<'static>>) {
     loop {
+        if co.disabled {
+            log::info!("Ignoring disabled connection for {}", co.id());
+            status::on_stopped(&co.uuid, co.device_id);
+            return;
+        }
         if co.forbidden {
-            log::info!("Ignoring connection for {}", &co.uuid);
+            log::info!("Ignoring connection for {}", co.id());
             METRICS.forbiddens.inc();
+            status::on_stopped(&co.uuid, co.device_id);
+            return;
+        }
+        if HEALTH.is_shutting_down() {
+            status::on_stopped(&co.uuid, co.device_id);
             return;
         }
-        log::info!("Starting connection for {}", &co.uuid);
-        let mut socket =
-            match SignalWebSocket::new(&co.uuid, co.device_id, &co.password, &co.endpoint) {
-                Ok(s) => s,
-                Err(e) => {
-                    log::info!("An error occured for {}: {}", co.uuid, e);
-                    return;
-                }
-            };
+        log::info!("Starting connection for {}", co.id());
+        let mut socket = match SignalWebSocket::new(
+            &co.uuid,
+            co.device_id,
+            &co.password,
+            co.password_encrypted,
+            &co.endpoints(),
+            co.delivery,
+            co.vapid_key.as_deref(),
+        ) {
+            Ok(s) => s,
+            Err(e) => {
+                log::info!("An error occured for {}: {}", co.id(), e);
+                return;
+            }
+        };
+        socket.set_push_policy(get_push_policy(&co.uuid, co.device_id));
         let metrics_future = set_metrics(&mut socket);
+        let mut push_results_rx = set_push_results(&mut socket);
+        let mut status_rx = set_status(&mut socket);
+        let close_handle = socket.close_handle();
         // Add the channel to kill the connection if needed
         let (kill_tx, mut kill_rx) = mpsc::unbounded();
         {
             KILL_VEC.lock().unwrap().push(KillLoopRef {
                 uuid: co.uuid.clone(),
+                device_id: co.device_id,
                 tx: kill_tx,
+                close: close_handle.clone(),
             });
         }
         METRICS.connections.inc();
@@ -74,21 +126,49 @@ async fn connection_loop(co: &mut Connection) {
         // loop connection
         select!(
             res = socket.connection_loop().fuse() => handle_connection_closed(res, co),
-            _ = metrics_future.fuse() => log::warn!("[{}] One of the metrics channel has been closed.", co.uuid),
+            _ = metrics_future.fuse() => log::warn!("[{}] One of the metrics channel has been closed.", co.id()),
+            _ = save_push_results(&mut push_results_rx, &co.uuid, co.device_id).fuse() => log::warn!("[{}] The push results channel has been closed.", co.id()),
+            _ = save_status(&mut status_rx, &co.uuid, co.device_id, &mut restoring).fuse() => log::warn!("[{}] The status channel has been closed.", co.id()),
             _ = kill_rx.next().fuse() => {
-                log::info!("[{}] Connection killed", co.uuid);
+                log::info!("[{}] Connection killed", co.id());
                 // We don't want the loop to restart if the connection has been killed.
                 stop_loop = true;
                 },
         );
+        restoring.take();
+        // The websocket has been closed, on shutdown
+        if close_handle.is_closed() {
+            log::info!("[{}] Connection closed", co.id());
+            stop_loop = true;
+        }
+        // Save the events and the push results sent just before the end of the connection
+        while let Ok(event) = status_rx.try_recv() {
+            status::on_event(&co.uuid, co.device_id, event);
+        }
+        while let Ok(result) = push_results_rx.try_recv() {
+            push_retries::on_push_result(&co.uuid, co.device_id, result);
+        }
+        // The pending deferred push is sent by the queue
+        if let Some(result) = socket.take_deferred_push() {
+            push_retries::on_push_result(&co.uuid, co.device_id, result);
+        }
+        refresh_endpoints(co);
         // Remove the channel to kill the connection
         let mut refs = KILL_VEC.lock().unwrap();
-        if let Some(i_ref) = refs.iter().position(|l_ref| l_ref.uuid.eq(&co.uuid)) {
+        if let Some(i_ref) = refs
+            .iter()
+            .position(|l_ref| l_ref.is(&co.uuid, co.device_id))
+        {
             refs.remove(i_ref);
         }
+        // A new loop may have replaced this one, for a new registration
+        let replaced = refs.iter().any(|l_ref| l_ref.is(&co.uuid, co.device_id));
         METRICS.connections.dec();
         // the connection has been killed, we don't loop.
         if stop_loop {
+            if !replaced {
+                status::on_stopped(&co.uuid, co.device_id);
+            }
             return;
         }
     }
@@ -122,6 +202,42 @@ fn set_metrics(socket: &mut SignalWebSocket) -> impl Future<Output = ()> {
     }
 }
 
+fn set_push_results(socket: &mut SignalWebSocket) -> UnboundedReceiver<PushResult> {
+    let (on_push_result_tx, on_push_result_rx) = mpsc::unbounded::<PushResult>();
+    socket.channels.on_push_result_tx = Some(on_push_result_tx);
+    on_push_result_rx
+}
+
+async fn save_push_results(rx: &mut UnboundedReceiver<PushResult>, uuid: &str, device_id: u32) {
+    while let Some(result) = rx.next().await {
+        push_retries::on_push_result(uuid, device_id, result);
+    }
+}
+
+fn set_status(socket: &mut SignalWebSocket) -> UnboundedReceiver<StatusEvent> {
+    let (on_status_tx, on_status_rx) = mpsc::unbounded::<StatusEvent>();
+    socket.channels.on_status_tx = Some(on_status_tx);
+    on_status_rx
+}
+
+async fn save_status(
+    rx: &mut UnboundedReceiver<StatusEvent>,
+    uuid: &str,
+    device_id: u32,
+    restoring: &mut Option<RestoreGuard<'_>>,
+) {
+    while let Some(event) = rx.next().await {
+        // The first connection attempt is over
+        if matches!(
+            event,
+            StatusEvent::Connected | StatusEvent::Disconnected { .. }
+        ) {
+            restoring.take();
+        }
+        status::on_event(uuid, device_id, event);
+    }
+}
+
 fn handle_connection_closed(res: Result<()>, co: &mut Connection) {
     log::debug!("Connection closed.");
 
@@ -131,7 +247,8 @@ fn handle_connection_closed(res: Result<()>, co: &mut Connection) {
             if let Some(SignalWebSocketError::RegistrationRemoved) =
                 error.downcast_ref::<SignalWebSocketError>()
             {
-                log::info!("Disabling connection for {}", &co.uuid);
+                log::info!("Disabling connection for {}", co.id());
+                refresh_endpoints(co);
                 co.forbidden = true;
                 let _ = DB.add(co);
             }
@@ -139,9 +256,415 @@ fn handle_connection_closed(res: Result<()>, co: &mut Connection) {
     }
 }
 
-async fn kill(uuid: &str) {
+/**
+Use the endpoints saved in the DB: the ones gone have been pruned during the connection.
+*/
+fn refresh_endpoints(co: &mut Connection) {
+    if let Ok(saved) = DB.get(&co.uuid, co.device_id) {
+        co.set_endpoints(saved.endpoints());
+    }
+}
+
+pub fn is_running(uuid: &str, device_id: u32) -> bool {
+    KILL_VEC
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|l_ref| l_ref.is(uuid, device_id))
+}
+
+/**
+The loop of the connection is running, and its websocket is connected.
+*/
+pub fn is_connected(uuid: &str, device_id: u32) -> bool {
+    is_running(uuid, device_id)
+        && status::get(uuid, device_id).is_ok_and(|s| s.ws_state == WsState::Connected)
+}
+
+pub async fn kill(uuid: &str, device_id: u32) {
     let refs = KILL_VEC.lock().unwrap();
-    if let Some(l_ref) = refs.iter().find(|&l_ref| l_ref.uuid.eq(uuid)) {
+    if let Some(l_ref) = refs.iter().find(|&l_ref| l_ref.is(uuid, device_id)) {
         let _ = l_ref.tx.clone().unbounded_send(true);
     }
 }
+
+/**
+Remove the connection from the DB, and stop its loop.
+*/
+pub async fn remove(co: &Connection) -> Result<()> {
+    DB.rm(&co.uuid, co.device_id)?;
+    kill(&co.uuid, co.device_id).await;
+    if co.forbidden {
+        METRICS.forbiddens.dec();
+    }
+    Ok(())
+}
+
+/**
+Close all the websockets, and wait up to [timeout] for the loops to stop.
+
+The connections still running after the timeout are marked as stopped.
+*/
+pub async fn close_all(timeout: Duration) {
+    for l_ref in KILL_VEC.lock().unwrap().iter() {
+        l_ref.close.close();
+    }
+    let closed = time::timeout(timeout, async {
+        while !KILL_VEC.lock().unwrap().is_empty() {
+            time::sleep(CLOSE_INTERVAL).await;
+        }
+    })
+    .await;
+    if closed.is_err() {
+        for l_ref in KILL_VEC.lock().unwrap().iter() {
+            log::warn!(
+                "[{}.{}] Connection not closed in time",
+                l_ref.uuid,
+                l_ref.device_id
+            );
+            status::on_stopped(&l_ref.uuid, l_ref.device_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::PushEndpoint;
+    use crate::server::health::Health;
+    use crate::test_support::{self, wait_until, with_timeout, FAKE_PUSH, FAKE_SIGNAL};
+    use crate::utils::push_policy::{PushPolicyOverride, Urgency};
+    use futures_util::join;
+
+    /// New connection to the fake servers, saved in the DB
+    fn test_connection() -> Connection {
+        test_support::load_config();
+        let uuid = test_support::new_uuid();
+        let co = Connection::new(
+            uuid.clone(),
+            1,
+            String::from("pass"),
+            FAKE_PUSH.endpoint(&uuid),
+            None,
+            None,
+        );
+        DB.add(&co).unwrap();
+        co
+    }
+
+    #[tokio::test]
+    async fn test_push_on_urgent_envelope() {
+        let mut co = test_connection();
+        let uuid = co.uuid.clone();
+        let test = async {
+            assert!(wait_until(|| FAKE_SIGNAL.is_connected(&uuid)).await);
+            assert!(FAKE_SIGNAL.send_envelope(&uuid, false));
+            assert!(wait_until(|| FAKE_SIGNAL.acks(&uuid) == 1).await);
+            assert!(FAKE_PUSH.requests(&uuid).is_empty());
+
+            assert!(FAKE_SIGNAL.send_envelope(&uuid, true));
+            assert!(wait_until(|| FAKE_PUSH.requests(&uuid).len() == 1).await);
+            assert_eq!(FAKE_SIGNAL.acks(&uuid), 2);
+            let request = &FAKE_PUSH.requests(&uuid)[0];
+            assert_eq!(request.body, br#"{"urgent":true}"#);
+            assert_eq!(request.headers["topic"], "mollysocket");
+
+            assert!(wait_until(|| status::get(&uuid, 1).unwrap().last_push.0.is_some()).await);
+            let status = status::get(&uuid, 1).unwrap();
+            assert_eq!(status.ws_state, WsState::Connected);
+            assert!(status.last_connected.0.is_some());
+            assert!(status.last_envelope.0.is_some());
+            assert_eq!(status.last_push_status, Some(201));
+            assert_eq!(status.last_error, None);
+            kill(&uuid, 1).await;
+        };
+        with_timeout(async { join!(connection_loop(&mut co, None), test) })
+            .await
+            .unwrap();
+        assert!(!DB.get(&uuid, 1).unwrap().forbidden);
+        assert_eq!(DB.get_status(&uuid, 1).unwrap().ws_state, WsState::Stopped);
+    }
+
+    #[tokio::test]
+    async fn test_restored_after_first_attempt() {
+        let mut co = test_connection();
+        let uuid = co.uuid.clone();
+        let health: &'static Health = Box::leak(Box::default());
+        let _running = health.on_connections_started();
+        let guard = health.on_connections_restoring(1).pop();
+        // The connection loop hasn't started yet
+        assert!(!health.readiness()["connections_restored"]);
+        let test = async {
+            assert!(wait_until(|| health.readiness()["connections_restored"]).await);
+            assert!(FAKE_SIGNAL.is_connected(&uuid));
+            kill(&uuid, 1).await;
+        };
+        with_timeout(async { join!(connection_loop(&mut co, guard), test) })
+            .await
+            .unwrap();
+        DB.rm(&uuid, 1).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_several_devices() {
+        let mut co = test_connection();
+        let uuid = co.uuid.clone();
+        let mut co2 = Connection::new(
+            uuid.clone(),
+            2,
+            String::from("pass"),
+            FAKE_PUSH.endpoint(&uuid),
+            None,
+            None,
+        );
+        DB.add(&co2).unwrap();
+        assert_eq!(DB.list_account(&uuid).unwrap().len(), 2);
+        let test = async {
+            assert!(wait_until(|| is_running(&uuid, 1) && is_running(&uuid, 2)).await);
+            // Only the loop of this device stops
+            kill(&uuid, 2).await;
+            assert!(wait_until(|| !is_running(&uuid, 2)).await);
+            assert!(is_running(&uuid, 1));
+            kill(&uuid, 1).await;
+        };
+        with_timeout(async {
+            join!(
+                connection_loop(&mut co, None),
+                connection_loop(&mut co2, None),
+                test
+            )
+        })
+        .await
+        .unwrap();
+        assert!(DB.get(&uuid, 1).is_ok());
+        assert_eq!(DB.get_status(&uuid, 2).unwrap().ws_state, WsState::Stopped);
+    }
+
+    #[tokio::test]
+    async fn test_push_policy() {
+        let mut co = test_connection();
+        let uuid = co.uuid.clone();
+        DB.set_push_policy(
+            &uuid,
+            1,
+            &PushPolicyOverride {
+                debounce: Some(60),
+                ttl: Some(60),
+                non_urgent: Some(String::from("low")),
+                topic: Some(String::new()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let test = async {
+            assert!(wait_until(|| FAKE_SIGNAL.is_connected(&uuid)).await);
+            assert!(FAKE_SIGNAL.send_envelope(&uuid, false));
+            assert!(wait_until(|| FAKE_PUSH.requests(&uuid).len() == 1).await);
+            // The low urgency push doesn't debounce the urgent envelopes
+            assert!(FAKE_SIGNAL.send_envelope(&uuid, true));
+            assert!(wait_until(|| FAKE_PUSH.requests(&uuid).len() == 2).await);
+            assert!(FAKE_SIGNAL.send_envelope(&uuid, true));
+            assert!(FAKE_SIGNAL.send_envelope(&uuid, false));
+            assert!(wait_until(|| FAKE_SIGNAL.acks(&uuid) == 4).await);
+            kill(&uuid, 1).await;
+        };
+        with_timeout(async { join!(connection_loop(&mut co, None), test) })
+            .await
+            .unwrap();
+        let requests = FAKE_PUSH.requests(&uuid);
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].body, br#"{"urgent":false}"#);
+        assert_eq!(requests[0].headers["urgency"], "low");
+        assert_eq!(requests[1].headers["urgency"], "high");
+        assert_eq!(requests[1].headers["ttl"], "60");
+        assert!(!requests[1].headers.contains_key("topic"));
+    }
+
+    #[tokio::test]
+    async fn test_deferred_push() {
+        let mut co = test_connection();
+        let uuid = co.uuid.clone();
+        DB.set_push_policy(
+            &uuid,
+            1,
+            &PushPolicyOverride {
+                non_urgent_delay: Some(1),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let test = async {
+            assert!(wait_until(|| FAKE_SIGNAL.is_connected(&uuid)).await);
+            // A single push for the non-urgent envelopes
+            assert!(FAKE_SIGNAL.send_envelope(&uuid, false));
+            assert!(FAKE_SIGNAL.send_envelope(&uuid, false));
+            assert!(wait_until(|| FAKE_PUSH.requests(&uuid).len() == 1).await);
+            // The deferred push is cancelled by an urgent push
+            assert!(FAKE_SIGNAL.send_envelope(&uuid, false));
+            assert!(FAKE_SIGNAL.send_envelope(&uuid, true));
+            assert!(wait_until(|| FAKE_PUSH.requests(&uuid).len() == 2).await);
+            time::sleep(Duration::from_millis(1500)).await;
+            kill(&uuid, 1).await;
+        };
+        with_timeout(async { join!(connection_loop(&mut co, None), test) })
+            .await
+            .unwrap();
+        let requests = FAKE_PUSH.requests(&uuid);
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].body, br#"{"urgent":false}"#);
+        assert_eq!(requests[0].headers["urgency"], "low");
+        assert_eq!(requests[1].headers["urgency"], "high");
+    }
+
+    #[tokio::test]
+    async fn test_deferred_push_queued() {
+        let mut co = test_connection();
+        let uuid = co.uuid.clone();
+        DB.set_push_policy(
+            &uuid,
+            1,
+            &PushPolicyOverride {
+                non_urgent_delay: Some(1),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let test = async {
+            assert!(wait_until(|| FAKE_SIGNAL.is_connected(&uuid)).await);
+            assert!(FAKE_SIGNAL.send_envelope(&uuid, false));
+            assert!(wait_until(|| FAKE_SIGNAL.acks(&uuid) == 1).await);
+            time::sleep(Duration::from_millis(100)).await;
+            kill(&uuid, 1).await;
+        };
+        with_timeout(async { join!(connection_loop(&mut co, None), test) })
+            .await
+            .unwrap();
+        // The deferred push pending when the connection ends is queued
+        assert!(FAKE_PUSH.requests(&uuid).is_empty());
+        time::sleep(Duration::from_millis(1000)).await;
+        let retry = DB
+            .due_push_retries()
+            .unwrap()
+            .into_iter()
+            .find(|r| r.uuid == uuid)
+            .unwrap();
+        assert_eq!(retry.urgency, Some(Urgency::Low));
+        assert_eq!(retry.body, r#"{"urgent":false}"#);
+        DB.rm(&uuid, 1).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_close() {
+        let mut co = test_connection();
+        let uuid = co.uuid.clone();
+        let test = async {
+            assert!(wait_until(|| FAKE_SIGNAL.is_connected(&uuid)).await);
+            assert!(FAKE_SIGNAL.send_envelope(&uuid, true));
+            assert!(wait_until(|| FAKE_SIGNAL.acks(&uuid) == 1).await);
+            let refs = KILL_VEC.lock().unwrap();
+            refs.iter()
+                .find(|l_ref| l_ref.uuid == uuid)
+                .unwrap()
+                .close
+                .close();
+        };
+        with_timeout(async { join!(connection_loop(&mut co, None), test) })
+            .await
+            .unwrap();
+        // The push in progress has been sent
+        assert_eq!(FAKE_PUSH.requests(&uuid).len(), 1);
+        assert_eq!(FAKE_SIGNAL.close_code(&uuid), Some(1001));
+        assert_eq!(FAKE_SIGNAL.connections(&uuid), 1);
+        assert!(!DB.get(&uuid, 1).unwrap().forbidden);
+        assert_eq!(DB.get_status(&uuid, 1).unwrap().ws_state, WsState::Stopped);
+    }
+
+    /// The time is paused and advanced when idle: the keepalives
+    /// and reconnections don't wait
+    #[tokio::test(start_paused = true)]
+    async fn test_keepalive_and_reconnection() {
+        let mut co = test_connection();
+        let uuid = co.uuid.clone();
+        let test = async {
+            assert!(wait_until(|| FAKE_SIGNAL.keepalives(&uuid) > 0).await);
+            assert!(FAKE_SIGNAL.drop_connection(&uuid));
+            assert!(wait_until(|| FAKE_SIGNAL.connections(&uuid) == 2).await);
+            kill(&uuid, 1).await;
+        };
+        // with_timeout would be reached immediately with the paused time,
+        // the conditions are waited with the real time
+        join!(connection_loop(&mut co, None), test);
+        assert!(!DB.get(&uuid, 1).unwrap().forbidden);
+    }
+
+    #[tokio::test]
+    async fn test_forbidden_on_403() {
+        let mut co = test_connection();
+        FAKE_SIGNAL.reject(&co.uuid);
+        with_timeout(connection_loop(&mut co, None)).await.unwrap();
+        assert!(DB.get(&co.uuid, 1).unwrap().forbidden);
+        assert_eq!(FAKE_SIGNAL.connections(&co.uuid), 0);
+        let status = DB.get_status(&co.uuid, 1).unwrap();
+        assert_eq!(status.ws_state, WsState::Stopped);
+        assert!(status.last_error.unwrap().contains("403"));
+    }
+
+    #[tokio::test]
+    async fn test_connected_elsewhere() {
+        let mut co = test_connection();
+        let uuid = co.uuid.clone();
+        // The endpoint has been removed: the delivery check disables the connection
+        FAKE_PUSH.set_status(&uuid, 404);
+        let test = async {
+            assert!(wait_until(|| FAKE_SIGNAL.is_connected(&uuid)).await);
+            assert!(FAKE_SIGNAL.close(&uuid, 4409));
+        };
+        with_timeout(async { join!(connection_loop(&mut co, None), test) })
+            .await
+            .unwrap();
+        assert!(DB.get(&uuid, 1).unwrap().forbidden);
+        let requests = FAKE_PUSH.requests(&uuid);
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].body, br#"{"code":4409}"#);
+    }
+
+    #[tokio::test]
+    async fn test_prune_endpoint() {
+        let mut co = test_connection();
+        let uuid = co.uuid.clone();
+        let second = format!("{}-2", uuid);
+        co.set_endpoints(vec![
+            PushEndpoint {
+                endpoint: FAKE_PUSH.endpoint(&uuid),
+                p256dh: None,
+                auth: None,
+            },
+            PushEndpoint {
+                endpoint: FAKE_PUSH.endpoint(&second),
+                p256dh: None,
+                auth: None,
+            },
+        ]);
+        DB.add(&co).unwrap();
+        // The first endpoint has been removed: it is pruned, the next one gets the push
+        FAKE_PUSH.set_status(&uuid, 404);
+        let test = async {
+            assert!(wait_until(|| FAKE_SIGNAL.is_connected(&uuid)).await);
+            assert!(FAKE_SIGNAL.send_envelope(&uuid, true));
+            assert!(wait_until(|| FAKE_PUSH.requests(&second).len() == 1).await);
+            assert!(wait_until(|| DB.get(&uuid, 1).unwrap().endpoints().len() == 1).await);
+            kill(&uuid, 1).await;
+        };
+        with_timeout(async { join!(connection_loop(&mut co, None), test) })
+            .await
+            .unwrap();
+        let saved = DB.get(&uuid, 1).unwrap();
+        assert!(!saved.forbidden);
+        assert_eq!(saved.endpoint, FAKE_PUSH.endpoint(&second));
+        let health = DB
+            .get_endpoint_status(&uuid, 1, &FAKE_PUSH.endpoint(&second))
+            .unwrap();
+        assert_eq!(health.last_status, Some(201));
+        assert_eq!(health.failures, 0);
+    }
+}
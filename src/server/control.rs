@@ -0,0 +1,274 @@
+use crate::{
+    config,
+    control::{Command, Response},
+    server::{connections, DB, NEW_CO_TX},
+};
+#[cfg(unix)]
+use rocket::serde::json::serde_json;
+#[cfg(unix)]
+use std::{
+    fs,
+    os::unix::fs::{DirBuilderExt, PermissionsExt},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+#[cfg(unix)]
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+    time,
+};
+
+/// The socket has been created by this server
+#[cfg(unix)]
+static BOUND: AtomicBool = AtomicBool::new(false);
+
+/// Maximum time to wait for the command of a client
+#[cfg(unix)]
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Maximum length of a command, in bytes
+#[cfg(unix)]
+const MAX_COMMAND_LEN: u64 = 64 * 1024;
+
+/**
+Listen on the control socket, for the commands of the CLI.
+
+Only the owner of the server can connect to the socket.
+*/
+pub async fn run() {
+    #[cfg(unix)]
+    if let Some(path) = config::get_control_socket() {
+        match bind(&path).await {
+            Ok(listener) => {
+                BOUND.store(true, Ordering::Relaxed);
+                log::info!("Control socket: {}", path.display());
+                serve(listener).await;
+            }
+            Err(e) => log::warn!("Could not listen on {}: {}", path.display(), e),
+        }
+    }
+    std::future::pending::<()>().await;
+}
+
+/**
+Remove the control socket, when the server stops.
+*/
+pub fn cleanup() {
+    #[cfg(unix)]
+    if BOUND.load(Ordering::Relaxed) {
+        if let Some(path) = config::get_control_socket() {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/**
+Bind the socket, after removing a stale one. It fails if another
+server is listening on it.
+
+The socket is bound in a directory only the owner can access, and moved
+to [path] once its permissions are restricted: no one else can connect
+in between.
+*/
+#[cfg(unix)]
+async fn bind(path: &Path) -> eyre::Result<UnixListener> {
+    if path.exists() {
+        if UnixStream::connect(path).await.is_ok() {
+            return Err(eyre::eyre!("Another server is running"));
+        }
+        fs::remove_file(path)?;
+    }
+    let dir = private_dir(path);
+    if dir.exists() {
+        fs::remove_dir_all(&dir)?;
+    }
+    fs::DirBuilder::new().mode(0o700).create(&dir)?;
+    let tmp_path = dir.join("control.sock");
+    let listener = UnixListener::bind(&tmp_path).and_then(|listener| {
+        fs::set_permissions(&tmp_path, fs::Permissions::from_mode(0o600))?;
+        fs::rename(&tmp_path, path)?;
+        Ok(listener)
+    });
+    let _ = fs::remove_dir_all(&dir);
+    Ok(listener?)
+}
+
+/**
+The directory where the socket [path] is bound, next to it.
+*/
+#[cfg(unix)]
+fn private_dir(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".{}.tmp", name))
+}
+
+/**
+Handle the clients concurrently: a client that doesn't send its
+command doesn't block the others.
+*/
+#[cfg(unix)]
+async fn serve(listener: UnixListener) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(async move {
+                    if let Err(e) = handle_stream(stream).await {
+                        log::warn!("Error on the control socket: {}", e);
+                    }
+                });
+            }
+            Err(e) => log::warn!("Could not accept a control connection: {}", e),
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn handle_stream(stream: UnixStream) -> eyre::Result<()> {
+    let (read, mut write) = stream.into_split();
+    let mut line = String::new();
+    let mut reader = BufReader::new(read.take(MAX_COMMAND_LEN));
+    let len = time::timeout(READ_TIMEOUT, reader.read_line(&mut line)).await??;
+    let response = if len as u64 == MAX_COMMAND_LEN && !line.ends_with('\n') {
+        Response::error(format!(
+            "Invalid command: longer than {} bytes",
+            MAX_COMMAND_LEN
+        ))
+    } else {
+        match serde_json::from_str::<Command>(&line) {
+            Ok(command) => handle(command).await,
+            Err(e) => Response::error(format!("Invalid command: {}", e)),
+        }
+    };
+    let mut response = serde_json::to_vec(&response)?;
+    response.push(b'\n');
+    write.write_all(&response).await?;
+    Ok(())
+}
+
+async fn handle(command: Command) -> Response {
+    log::debug!("Control command: {:?}", command);
+    match command {
+        Command::Restart { uuid, device_id } => {
+            let co = match DB.get(&uuid, device_id) {
+                Ok(co) => co,
+                Err(_) => {
+                    return Response::error(format!(
+                        "No connection found for {}.{}",
+                        uuid, device_id
+                    ))
+                }
+            };
+            if co.forbidden {
+                return Response::error(format!("The connection for {} is forbidden", co.id()));
+            }
+            if co.disabled {
+                return Response::error(format!("The connection for {} is disabled", co.id()));
+            }
+            let id = co.id();
+            // The current loop, if any, is killed and a new one is started
+            match &*NEW_CO_TX.lock().unwrap() {
+                Some(tx) if tx.unbounded_send(co).is_ok() => (),
+                _ => {
+                    return Response::error(format!(
+                        "The connections aren't started yet, the connection for {} can't be restarted. Retry when the server is running.",
+                        id
+                    ))
+                }
+            }
+            log::info!("Connection for {} (re)started with the CLI", id);
+            Response::ok(format!("Connection for {} (re)started.", id))
+        }
+        Command::Remove { uuid, device_id } => {
+            let co = match DB.get(&uuid, device_id) {
+                Ok(co) => co,
+                Err(_) => {
+                    return Response::error(format!(
+                        "No connection found for {}.{}",
+                        uuid, device_id
+                    ))
+                }
+            };
+            if let Err(e) = connections::remove(&co).await {
+                return Response::error(format!("Could not remove the connection: {}", e));
+            }
+            log::info!("Connection for {} removed with the CLI", co.id());
+            Response::ok(format!("Connection for {} successfully removed.", co.id()))
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::{control, db::Connection, test_support};
+
+    #[tokio::test]
+    async fn test_control_socket() {
+        test_support::load_config();
+        let path = std::env::temp_dir().join(format!("{}.sock", test_support::new_uuid()));
+        let listener = bind(&path).await.unwrap();
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+        assert!(!private_dir(&path).exists());
+        // Only one server may listen
+        assert!(bind(&path).await.is_err());
+        let serve_future = serve(listener);
+        tokio::pin!(serve_future);
+
+        let uuid = test_support::new_uuid();
+        let remove = Command::Remove {
+            uuid: uuid.clone(),
+            device_id: 1,
+        };
+        let test = async {
+            // A client that doesn't send anything doesn't block the others
+            let _idle = UnixStream::connect(&path).await.unwrap();
+            let response = control::send_to(&path, &remove).await.unwrap().unwrap();
+            assert!(!response.ok);
+            // The commands are limited in length
+            let mut stream = UnixStream::connect(&path).await.unwrap();
+            stream
+                .write_all(&vec![b'a'; MAX_COMMAND_LEN as usize + 1])
+                .await
+                .unwrap();
+            let mut response = String::new();
+            BufReader::new(stream)
+                .read_line(&mut response)
+                .await
+                .unwrap();
+            let response: Response = serde_json::from_str(&response).unwrap();
+            assert!(!response.ok);
+            assert!(response.message.contains("longer than"));
+            DB.add(&Connection::new(
+                uuid.clone(),
+                1,
+                String::from("pass"),
+                String::from("http://0.0.0.0/"),
+                None,
+                None,
+            ))
+            .unwrap();
+            // The connections aren't started in this test
+            let restart = Command::Restart {
+                uuid: uuid.clone(),
+                device_id: 1,
+            };
+            let response = control::send_to(&path, &restart).await.unwrap().unwrap();
+            assert!(!response.ok);
+            let response = control::send_to(&path, &remove).await.unwrap().unwrap();
+            assert!(response.ok);
+            assert!(DB.get(&uuid, 1).is_err());
+        };
+        tokio::select! {
+            _ = &mut serve_future => panic!("The control socket stopped"),
+            _ = test => (),
+        }
+        fs::remove_file(&path).unwrap();
+        // No server: the CLI uses the DB
+        assert!(control::send_to(&path, &remove).await.unwrap().is_none());
+    }
+}
@@ -0,0 +1,140 @@
+use crate::{server::DB, vapid};
+use std::{
+    collections::BTreeMap,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+/**
+State of the server tasks, for the liveness and readiness probes.
+*/
+#[derive(Default)]
+pub struct Health {
+    /// [connections::run][crate::server::connections::run] is running
+    connections_running: AtomicBool,
+    /// The connections saved in the DB have been restored
+    connections_restored: AtomicBool,
+    /// Number of the restored connections that haven't tried to connect yet
+    connections_pending: AtomicUsize,
+    /// The server is shutting down, the registrations are refused
+    shutting_down: AtomicBool,
+}
+
+/**
+Marks the connections task as stopped when dropped, so a panic is detected too.
+*/
+pub struct RunningGuard<'a>(&'a Health);
+
+impl Drop for RunningGuard<'_> {
+    fn drop(&mut self) {
+        self.0.connections_running.store(false, Ordering::SeqCst);
+        self.0.connections_restored.store(false, Ordering::SeqCst);
+    }
+}
+
+/**
+Held by a restored connection until its first connection attempt. The connections
+are restored when all the guards are dropped.
+*/
+pub struct RestoreGuard<'a>(&'a Health);
+
+impl Drop for RestoreGuard<'_> {
+    fn drop(&mut self) {
+        if self.0.connections_pending.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.connections_restored.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Result of each sub-check, by name
+pub type Checks = BTreeMap<&'static str, bool>;
+
+impl Health {
+    /**
+    To call when the connections task starts, the guard must live as long as the task.
+    */
+    pub fn on_connections_started(&self) -> RunningGuard<'_> {
+        self.connections_running.store(true, Ordering::SeqCst);
+        RunningGuard(self)
+    }
+
+    /**
+    To call when the [count] connections saved in the DB are started: returns
+    a guard for each of them.
+    */
+    pub fn on_connections_restoring(&self, count: usize) -> Vec<RestoreGuard<'_>> {
+        self.connections_pending.store(count, Ordering::SeqCst);
+        self.connections_restored
+            .store(count == 0, Ordering::SeqCst);
+        (0..count).map(|_| RestoreGuard(self)).collect()
+    }
+
+    pub fn on_shutdown(&self) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::SeqCst)
+    }
+
+    /**
+    The connections task and the DB are alive.
+    */
+    pub fn liveness(&self) -> Checks {
+        Checks::from([
+            (
+                "connections",
+                self.connections_running.load(Ordering::SeqCst),
+            ),
+            ("db", DB.version().is_ok()),
+        ])
+    }
+
+    /**
+    The server is alive, has restored the connections, has a VAPID key,
+    and isn't shutting down.
+    */
+    pub fn readiness(&self) -> Checks {
+        let mut checks = self.liveness();
+        checks.insert("accepting", !self.is_shutting_down());
+        checks.insert(
+            "connections_restored",
+            self.connections_restored.load(Ordering::SeqCst),
+        );
+        checks.insert("vapid", vapid::get_vapid_pubkey().is_ok());
+        checks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+
+    #[test]
+    fn test_health() {
+        test_support::load_config();
+        let health = Health::default();
+        assert!(!health.liveness()["connections"]);
+        assert!(health.liveness()["db"]);
+        {
+            let _guard = health.on_connections_started();
+            assert!(health.liveness()["connections"]);
+            assert!(!health.readiness()["connections_restored"]);
+            let mut guards = health.on_connections_restoring(2);
+            assert!(!health.readiness()["connections_restored"]);
+            guards.pop();
+            assert!(!health.readiness()["connections_restored"]);
+            guards.pop();
+            assert!(health.readiness()["connections_restored"]);
+            health.on_connections_restoring(0);
+            assert!(health.readiness()["connections_restored"]);
+        }
+        assert!(!health.liveness()["connections"]);
+        assert!(!health.readiness()["connections_restored"]);
+
+        assert!(health.readiness()["accepting"]);
+        health.on_shutdown();
+        assert!(health.is_shutting_down());
+        assert!(!health.readiness()["accepting"]);
+    }
+}
@@ -3,7 +3,10 @@ use std::fmt::Display;
 use eyre::Result;
 use rocket::{http::uri::Origin, Build, Rocket};
 use rocket_prometheus::{
-    prometheus::{register_int_counter, register_int_gauge, IntCounter, IntGauge},
+    prometheus::{
+        register_int_counter, register_int_counter_vec, register_int_gauge, IntCounter,
+        IntCounterVec, IntGauge,
+    },
     PrometheusMetrics,
 };
 
@@ -13,6 +16,12 @@ pub struct Metrics {
     pub reconnections: IntCounter,
     pub messages: IntCounter,
     pub pushs: IntCounter,
+    pub push_retry_queue: IntGauge,
+    pub push_retries: IntCounter,
+    pub push_retries_abandoned: IntCounter,
+    pub registrations_rate_limited: IntCounterVec,
+    pub unregistrations_rate_limited: IntCounterVec,
+    pub status_requests_rate_limited: IntCounterVec,
 }
 
 impl Metrics {
@@ -31,6 +40,33 @@ impl Metrics {
             "mollysocket_pushs",
             "Push messages sent to UnifiedPush endpoint"
         )?;
+        let push_retry_queue = register_int_gauge!(
+            "mollysocket_push_retry_queue",
+            "Push messages waiting to be sent again"
+        )?;
+        let push_retries = register_int_counter!(
+            "mollysocket_push_retries",
+            "Push messages sent again after a failure"
+        )?;
+        let push_retries_abandoned = register_int_counter!(
+            "mollysocket_push_retries_abandoned",
+            "Push messages abandoned after too many failures"
+        )?;
+        let registrations_rate_limited = register_int_counter_vec!(
+            "mollysocket_registrations_rate_limited",
+            "Registrations refused by the rate limiter",
+            &["limit"]
+        )?;
+        let unregistrations_rate_limited = register_int_counter_vec!(
+            "mollysocket_unregistrations_rate_limited",
+            "Unregistrations refused by the rate limiter",
+            &["limit"]
+        )?;
+        let status_requests_rate_limited = register_int_counter_vec!(
+            "mollysocket_status_requests_rate_limited",
+            "Connection status requests refused by the rate limiter",
+            &["limit"]
+        )?;
 
         Ok(Self {
             connections,
@@ -38,6 +74,12 @@ impl Metrics {
             reconnections,
             messages,
             pushs,
+            push_retry_queue,
+            push_retries,
+            push_retries_abandoned,
+            registrations_rate_limited,
+            unregistrations_rate_limited,
+            status_requests_rate_limited,
         })
     }
 }
@@ -72,6 +114,24 @@ impl MountMetrics for Rocket<Build> {
         prom_registry
             .register(Box::new(metrics.pushs.clone()))
             .unwrap();
+        prom_registry
+            .register(Box::new(metrics.push_retry_queue.clone()))
+            .unwrap();
+        prom_registry
+            .register(Box::new(metrics.push_retries.clone()))
+            .unwrap();
+        prom_registry
+            .register(Box::new(metrics.push_retries_abandoned.clone()))
+            .unwrap();
+        prom_registry
+            .register(Box::new(metrics.registrations_rate_limited.clone()))
+            .unwrap();
+        prom_registry
+            .register(Box::new(metrics.unregistrations_rate_limited.clone()))
+            .unwrap();
+        prom_registry
+            .register(Box::new(metrics.status_requests_rate_limited.clone()))
+            .unwrap();
 
         self.attach(prometheus.clone()).mount(base, prometheus)
     }
@@ -0,0 +1,253 @@
+use crate::{
+    config,
+    db::{OptTime, PushRetry},
+    server::{get_push_policy, status, DB, HEALTH, METRICS},
+    utils::{
+        delivery::{self, deliver, Target},
+        post_allowed::{PushHeaders, Retryable},
+    },
+    ws::{PushResult, StatusEvent},
+};
+use futures_util::future::join_all;
+use rocket::serde::json::serde_json;
+use std::time::{Duration, SystemTime};
+use tokio::time;
+
+/// Interval between two checks of the queue
+const QUEUE_INTERVAL: Duration = Duration::from_secs(1);
+
+/**
+Queue the failed and the deferred push notifications of the connection, and
+remove the pending ones when a new notification is accepted.
+*/
+pub fn on_push_result(uuid: &str, device_id: u32, result: PushResult) {
+    match result {
+        PushResult::Sent { topic } => {
+            let _ = DB.rm_push_retry(uuid, device_id, &topic);
+        }
+        PushResult::Failed {
+            topic,
+            body,
+            retry_after,
+            urgency,
+        } => {
+            let now = SystemTime::now();
+            let delay = retry_after.unwrap_or(config::get_push_retry_backoff().delay(1));
+            // The push server may ask for a delay past the horizon
+            if delay > config::get_push_retry_horizon() {
+                log::warn!(
+                    "[{}] Push failed, and can't be retried before {} seconds: giving up.",
+                    uuid,
+                    delay.as_secs()
+                );
+                METRICS.push_retries_abandoned.inc();
+                return;
+            }
+            log::info!(
+                "[{}] Push failed, retrying in {} seconds.",
+                uuid,
+                delay.as_secs()
+            );
+            let _ = DB.add_push_retry(&PushRetry {
+                uuid: uuid.into(),
+                device_id,
+                topic,
+                body,
+                attempts: 0,
+                first_failure: OptTime::from(now),
+                next_attempt: OptTime::from(now + delay),
+                urgency: Some(urgency),
+            });
+        }
+        PushResult::Deferred {
+            topic,
+            body,
+            delay,
+            urgency,
+        } => {
+            let due = SystemTime::now() + delay;
+            log::info!(
+                "[{}] Connection ended, the deferred push is queued, due in {} seconds.",
+                uuid,
+                delay.as_secs()
+            );
+            // A failed push already queued is kept, it wakes the client up too
+            let _ = DB.add_push_retry(&PushRetry {
+                uuid: uuid.into(),
+                device_id,
+                topic,
+                body,
+                attempts: 0,
+                first_failure: OptTime::from(due),
+                next_attempt: OptTime::from(due),
+                urgency: Some(urgency),
+            });
+        }
+    }
+    update_queue_metrics();
+}
+
+/**
+Send the queued push notifications when they are due.
+*/
+pub async fn run() {
+    update_queue_metrics();
+    loop {
+        time::sleep(QUEUE_INTERVAL).await;
+        // The queue is kept in the DB for the next start
+        if HEALTH.is_shutting_down() {
+            continue;
+        }
+        let retries = match DB.due_push_retries() {
+            Ok(r) => r,
+            Err(e) => {
+                log::warn!("Could not read the push retries: {}", e);
+                continue;
+            }
+        };
+        if retries.is_empty() {
+            continue;
+        }
+        join_all(retries.into_iter().map(retry)).await;
+        update_queue_metrics();
+    }
+}
+
+async fn retry(mut retry: PushRetry) {
+    let co = match DB.get(&retry.uuid, retry.device_id) {
+        Ok(co) if !co.forbidden && !co.disabled => co,
+        _ => {
+            log::debug!(
+                "[{}] Connection not found, forbidden or disabled.",
+                retry.uuid
+            );
+            let _ = DB.rm_push_retry(&retry.uuid, retry.device_id, &retry.topic);
+            return;
+        }
+    };
+    let (targets, body) = match (
+        Target::from_connection(&co),
+        serde_json::from_str::<serde_json::Value>(&retry.body),
+    ) {
+        (Ok(targets), Ok(body)) => (targets, body),
+        _ => {
+            let _ = DB.rm_push_retry(&retry.uuid, retry.device_id, &retry.topic);
+            return;
+        }
+    };
+
+    // The push is sent again with its urgency, like the deferred pushes of the
+    // non-urgent envelopes. The body is the one of the connection: {"urgent": bool}
+    let policy = get_push_policy(&retry.uuid, retry.device_id);
+    let urgent = body["urgent"].as_bool().unwrap_or(true);
+    let urgency = match retry.urgency.or_else(|| policy.push_urgency(urgent)) {
+        Some(urgency) => urgency,
+        None => {
+            log::debug!("[{}] These envelopes aren't pushed anymore.", retry.uuid);
+            let _ = DB.rm_push_retry(&retry.uuid, retry.device_id, &retry.topic);
+            return;
+        }
+    };
+
+    retry.attempts += 1;
+    METRICS.push_retries.inc();
+    let attempts = deliver(
+        &targets,
+        co.delivery,
+        &body,
+        &PushHeaders {
+            topic: Some(retry.topic.as_str()).filter(|t| !t.is_empty()),
+            ttl: policy.ttl,
+            urgency,
+        },
+        co.vapid_key.as_deref(),
+    )
+    .await;
+    for attempt in &attempts {
+        status::on_event(
+            &retry.uuid,
+            retry.device_id,
+            StatusEvent::from_attempt(attempt),
+        );
+    }
+
+    let retry_after = match delivery::retryable(&attempts) {
+        Retryable::Yes(retry_after) => retry_after,
+        Retryable::No => {
+            if delivery::is_delivered(&attempts) {
+                log::info!(
+                    "[{}] Push sent after {} retries.",
+                    retry.uuid,
+                    retry.attempts
+                );
+            } else {
+                // The endpoints gone aren't pruned here: the live loop prunes
+                // them the next time it pushes, when it gets their 404 or 410
+                log::info!("[{}] Push retry failed, giving up.", retry.uuid);
+            }
+            let _ = DB.rm_push_retry(&retry.uuid, retry.device_id, &retry.topic);
+            return;
+        }
+    };
+
+    let now = SystemTime::now();
+    let delay =
+        retry_after.unwrap_or_else(|| config::get_push_retry_backoff().delay(retry.attempts + 1));
+    let horizon = retry
+        .first_failure
+        .0
+        .unwrap_or(now)
+        .checked_add(config::get_push_retry_horizon());
+    if horizon.is_some_and(|h| now + delay > h) {
+        log::warn!(
+            "[{}] Push still failing after {} retries, giving up.",
+            retry.uuid,
+            retry.attempts
+        );
+        METRICS.push_retries_abandoned.inc();
+        let _ = DB.rm_push_retry(&retry.uuid, retry.device_id, &retry.topic);
+        return;
+    }
+    log::info!(
+        "[{}] Push retry failed, retrying in {} seconds.",
+        retry.uuid,
+        delay.as_secs()
+    );
+    retry.next_attempt = OptTime::from(now + delay);
+    let _ = DB.update_push_retry(&retry);
+}
+
+fn update_queue_metrics() {
+    if let Ok(count) = DB.count_push_retries() {
+        METRICS.push_retry_queue.set(count);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_support, utils::push_policy::Urgency};
+
+    #[test]
+    fn test_retry_after_horizon() {
+        test_support::load_config();
+        let uuid = test_support::new_uuid();
+        let failed = |retry_after: Duration| PushResult::Failed {
+            topic: String::from("mollysocket"),
+            body: String::from(r#"{"urgent":true}"#),
+            retry_after: Some(retry_after),
+            urgency: Urgency::High,
+        };
+        let queued = || {
+            DB.due_push_retries()
+                .unwrap()
+                .iter()
+                .any(|r| r.uuid == uuid)
+        };
+        on_push_result(&uuid, 1, failed(config::get_push_retry_horizon() * 2));
+        assert!(!queued());
+        on_push_result(&uuid, 1, failed(Duration::ZERO));
+        assert!(queued());
+        DB.rm_push_retry(&uuid, 1, "mollysocket").unwrap();
+    }
+}
@@ -0,0 +1,190 @@
+use crate::{
+    config,
+    db::Connection,
+    server::{connections, is_uuid_allowed, DB, NEW_CO_TX},
+    vapid,
+};
+#[cfg(unix)]
+use tokio::signal::unix::{self, SignalKind};
+
+/**
+Reload the config when SIGHUP is received.
+*/
+pub async fn run() {
+    #[cfg(unix)]
+    {
+        let mut sighup_stream = unix::signal(SignalKind::hangup()).unwrap();
+        while sighup_stream.recv().await.is_some() {
+            log::info!("SIGHUP received, reloading the config");
+            reload().await;
+        }
+    }
+    #[cfg(not(unix))]
+    std::future::pending::<()>().await;
+}
+
+async fn reload() {
+    let reloaded = match config::reload() {
+        Some(reloaded) => reloaded,
+        None => return,
+    };
+    vapid::reload_keyring();
+    apply_to_connections(reloaded.allowed_endpoints).await;
+}
+
+/**
+Stop the live connections whose uuid or one of the endpoints isn't allowed anymore,
+and start the allowed connections that aren't running, like the ones approved with the CLI.
+
+The endpoints of the live connections are checked again only if [endpoints_changed]:
+each check resolves the names of the endpoints. The stopped connections are not
+marked as forbidden: they start again on the next reload, or with the server,
+if they are allowed again.
+*/
+async fn apply_to_connections(endpoints_changed: bool) {
+    let connections = match DB.list() {
+        Ok(connections) => connections,
+        Err(e) => {
+            log::warn!("Could not read the connections: {}", e);
+            return;
+        }
+    };
+    for co in connections
+        .into_iter()
+        .filter(|co| !co.forbidden && !co.disabled)
+    {
+        let running = connections::is_running(&co.uuid, co.device_id);
+        if !is_uuid_allowed(&co.uuid) {
+            if running {
+                log::info!(
+                    "[{}] The uuid is not allowed anymore: stopping the connection.",
+                    co.id()
+                );
+                connections::kill(&co.uuid, co.device_id).await;
+            }
+            continue;
+        }
+        if running && !endpoints_changed {
+            continue;
+        }
+        if let Some(endpoint) = disallowed_endpoint(&co).await {
+            if running {
+                log::info!(
+                    "[{}] The endpoint {} is not allowed anymore: stopping the connection.",
+                    co.id(),
+                    endpoint
+                );
+                connections::kill(&co.uuid, co.device_id).await;
+            }
+            continue;
+        }
+        if !running {
+            log::info!("[{}] Starting the connection.", co.id());
+            if let Some(tx) = &*NEW_CO_TX.lock().unwrap() {
+                let _ = tx.unbounded_send(co);
+            }
+        }
+    }
+}
+
+/**
+The first endpoint of the connection that isn't allowed anymore, if any.
+An endpoint whose name can't be resolved is kept.
+*/
+async fn disallowed_endpoint(co: &Connection) -> Option<String> {
+    for endpoint in co.endpoints() {
+        if config::is_endpoint_disallowed(&endpoint.endpoint).await {
+            return Some(endpoint.endpoint);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        db::PushEndpoint,
+        test_support::{self, wait_until, with_timeout, FAKE_PUSH, FAKE_SIGNAL},
+    };
+    use futures_channel::mpsc;
+    use futures_util::join;
+    use std::time::Duration;
+    use tokio::time;
+
+    #[tokio::test]
+    async fn test_disallowed_endpoint() {
+        test_support::load_config();
+        let uuid = test_support::new_uuid();
+        let endpoint = |endpoint: String| PushEndpoint {
+            endpoint,
+            p256dh: None,
+            auth: None,
+        };
+        let mut co = Connection::new(
+            uuid.clone(),
+            1,
+            String::from("pass"),
+            FAKE_PUSH.endpoint(&uuid),
+            None,
+            None,
+        );
+        assert_eq!(disallowed_endpoint(&co).await, None);
+        co.set_endpoints(vec![
+            endpoint(FAKE_PUSH.endpoint(&uuid)),
+            endpoint(String::from("http://10.10.1.1/")),
+        ]);
+        assert_eq!(
+            disallowed_endpoint(&co).await.as_deref(),
+            Some("http://10.10.1.1/")
+        );
+        co.set_endpoints(vec![
+            endpoint(FAKE_PUSH.endpoint(&uuid)),
+            endpoint(String::from("http://mollysocket.invalid/")),
+        ]);
+        assert_eq!(disallowed_endpoint(&co).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_unresolved_endpoint_kept() {
+        test_support::load_config();
+        let uuid = test_support::new_uuid();
+        let mut co = Connection::new(
+            uuid.clone(),
+            1,
+            String::from("pass"),
+            FAKE_PUSH.endpoint(&uuid),
+            None,
+            None,
+        );
+        co.set_endpoints(vec![
+            PushEndpoint {
+                endpoint: FAKE_PUSH.endpoint(&uuid),
+                p256dh: None,
+                auth: None,
+            },
+            PushEndpoint {
+                endpoint: String::from("http://mollysocket.invalid/"),
+                p256dh: None,
+                auth: None,
+            },
+        ]);
+        DB.add(&co).unwrap();
+        let (tx, rx) = mpsc::unbounded();
+        tx.unbounded_send(co).unwrap();
+        drop(tx);
+        let test = async {
+            assert!(wait_until(|| FAKE_SIGNAL.is_connected(&uuid)).await);
+            // The endpoint can't be resolved: the connection isn't stopped
+            apply_to_connections(true).await;
+            time::sleep(Duration::from_millis(200)).await;
+            assert!(connections::is_running(&uuid, 1));
+            assert!(FAKE_SIGNAL.is_connected(&uuid));
+            connections::kill(&uuid, 1).await;
+        };
+        with_timeout(async { join!(connections::gen_new_loops(rx), test) })
+            .await
+            .unwrap();
+        DB.rm(&uuid, 1).unwrap();
+    }
+}
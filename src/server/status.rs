@@ -0,0 +1,225 @@
+use crate::{
+    db::{ConnectionStatus, OptTime, WsState},
+    server::{DB, STATUSES},
+    ws::StatusEvent,
+};
+use eyre::Result;
+use std::time::{Duration, Instant, SystemTime};
+
+/// The live status is saved at least this often. Between two saves, the
+/// envelopes and the pushes with the same response are only kept in memory
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/**
+Status of a running connection, and when it has been saved in the DB.
+*/
+pub struct LiveStatus {
+    status: ConnectionStatus,
+    saved: Instant,
+}
+
+impl LiveStatus {
+    /**
+    Apply the [event] to the status.
+
+    Returns a copy of the status if it must be saved, so it is saved after the
+    statuses are unlocked.
+    */
+    fn update(&mut self, event: StatusEvent) -> Option<ConnectionStatus> {
+        let status = &mut self.status;
+        let previous = (status.ws_state, status.last_push_status);
+        let now = OptTime::from(SystemTime::now());
+        let error = match event {
+            StatusEvent::Connecting => {
+                status.ws_state = WsState::Connecting;
+                None
+            }
+            StatusEvent::Connected => {
+                status.ws_state = WsState::Connected;
+                status.last_connected = now;
+                None
+            }
+            StatusEvent::Disconnected { error } => {
+                status.ws_state = WsState::Disconnected;
+                error
+            }
+            StatusEvent::Envelope => {
+                status.last_envelope = now;
+                None
+            }
+            StatusEvent::Push {
+                status: push_status,
+                error,
+                ..
+            } => {
+                status.last_push = now;
+                status.last_push_status = push_status;
+                error
+            }
+            StatusEvent::EndpointRemoved { .. } => None,
+        };
+        let changed = error.is_some() || previous != (status.ws_state, status.last_push_status);
+        if let Some(error) = error {
+            status.last_error = Some(error);
+            status.last_error_time = OptTime::from(SystemTime::now());
+        }
+        if changed || self.saved.elapsed() >= SAVE_INTERVAL {
+            self.saved = Instant::now();
+            Some(self.status.clone())
+        } else {
+            None
+        }
+    }
+}
+
+fn save(status: &ConnectionStatus) {
+    if let Err(e) = DB.set_status(status) {
+        log::warn!("[{}] Could not save the status: {}", status.uuid, e);
+    }
+}
+
+/**
+Status of the connection: the live one if it is known, else the saved one.
+*/
+pub fn get(uuid: &str, device_id: u32) -> Result<ConnectionStatus> {
+    if let Some(live) = STATUSES.lock().unwrap().get(&(uuid.into(), device_id)) {
+        return Ok(live.status.clone());
+    }
+    DB.get_status(uuid, device_id)
+}
+
+/**
+Save the [event] in the status of the connection.
+
+The status is kept in memory, and saved in the DB when the state of the
+websocket, the response of the push server or the error changes. The DB is
+never used while the statuses are locked, not to block the other connections.
+*/
+pub fn on_event(uuid: &str, device_id: u32, event: StatusEvent) {
+    match &event {
+        StatusEvent::Push {
+            endpoint,
+            status: push_status,
+            error,
+        } => on_endpoint_push(uuid, device_id, endpoint, *push_status, error.is_none()),
+        StatusEvent::EndpointRemoved { endpoint } => {
+            log::info!("[{}] Endpoint gone, removing it", uuid);
+            if let Err(e) = DB.rm_push_endpoint(uuid, device_id, endpoint) {
+                log::warn!("[{}] Could not remove the endpoint: {}", uuid, e);
+            }
+        }
+        _ => (),
+    }
+    let key = (uuid.to_string(), device_id);
+    let known = STATUSES.lock().unwrap().contains_key(&key);
+    if !known {
+        let status = match DB.get_status(uuid, device_id) {
+            Ok(status) => status,
+            Err(e) => {
+                log::warn!("[{}] Could not read the status: {}", uuid, e);
+                return;
+            }
+        };
+        STATUSES
+            .lock()
+            .unwrap()
+            .entry(key.clone())
+            .or_insert(LiveStatus {
+                status,
+                saved: Instant::now(),
+            });
+    }
+    let to_save = match STATUSES.lock().unwrap().get_mut(&key) {
+        Some(live) => live.update(event),
+        None => None,
+    };
+    if let Some(status) = to_save {
+        save(&status);
+    }
+}
+
+/**
+Save the response of the push server in the health of the [endpoint].
+*/
+fn on_endpoint_push(
+    uuid: &str,
+    device_id: u32,
+    endpoint: &str,
+    push_status: Option<u16>,
+    delivered: bool,
+) {
+    let mut status = match DB.get_endpoint_status(uuid, device_id, endpoint) {
+        Ok(status) => status,
+        Err(e) => {
+            log::warn!("[{}] Could not read the endpoint status: {}", uuid, e);
+            return;
+        }
+    };
+    let now = OptTime::from(SystemTime::now());
+    status.last_status = push_status;
+    if delivered {
+        status.failures = 0;
+        status.last_success = now;
+    } else {
+        status.failures += 1;
+        status.last_failure = now;
+    }
+    if let Err(e) = DB.set_endpoint_status(&status) {
+        log::warn!("[{}] Could not save the endpoint status: {}", uuid, e);
+    }
+}
+
+/**
+Mark the connection as stopped, and save its last status.
+*/
+pub fn on_stopped(uuid: &str, device_id: u32) {
+    let live = STATUSES.lock().unwrap().remove(&(uuid.into(), device_id));
+    let mut status = match live {
+        Some(live) => live.status,
+        None => match DB.get_status(uuid, device_id) {
+            Ok(status) => status,
+            Err(_) => return,
+        },
+    };
+    status.ws_state = WsState::Stopped;
+    save(&status);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+
+    #[test]
+    fn test_live_status() {
+        test_support::load_config();
+        let uuid = test_support::new_uuid();
+        on_event(&uuid, 1, StatusEvent::Connected);
+        assert_eq!(
+            DB.get_status(&uuid, 1).unwrap().ws_state,
+            WsState::Connected
+        );
+
+        // The envelopes are only kept in memory
+        on_event(&uuid, 1, StatusEvent::Envelope);
+        assert!(get(&uuid, 1).unwrap().last_envelope.0.is_some());
+        assert!(DB.get_status(&uuid, 1).unwrap().last_envelope.0.is_none());
+
+        on_event(
+            &uuid,
+            1,
+            StatusEvent::Disconnected {
+                error: Some(String::from("closed")),
+            },
+        );
+        let saved = DB.get_status(&uuid, 1).unwrap();
+        assert_eq!(saved.ws_state, WsState::Disconnected);
+        assert!(saved.last_envelope.0.is_some());
+        assert_eq!(saved.last_error.as_deref(), Some("closed"));
+
+        on_stopped(&uuid, 1);
+        assert!(!STATUSES.lock().unwrap().contains_key(&(uuid.clone(), 1)));
+        assert_eq!(get(&uuid, 1).unwrap().ws_state, WsState::Stopped);
+        DB.rm(&uuid, 1).unwrap();
+    }
+}
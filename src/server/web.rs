@@ -1,24 +1,163 @@
-use crate::{config, db::Connection, qrcode, utils::ping, vapid};
+use crate::{
+    config,
+    db::{self, ApprovalStatus, Connection, ConnectionId, PushEndpoint, Registration},
+    qrcode,
+    utils::{
+        client_ip::client_ip,
+        delivery::{self, DeliveryMode},
+        rate_limit::{RateLimit, RateLimiter},
+    },
+    vapid,
+    webpush::WebPushKeys,
+};
 use eyre::Result;
 use html::get_index;
+use lazy_static::lazy_static;
 use rocket::{
-    get, post,
+    delete, get,
+    http::Status,
+    post,
+    request::{FromRequest, Outcome, Request},
     response::{content::RawHtml, Responder},
     routes,
     serde::{json::Json, Deserialize, Serialize},
 };
-use std::{collections::HashMap, env, str::FromStr};
-use url::Url;
+use rocket_prometheus::prometheus::IntCounterVec;
+use std::{
+    collections::HashMap,
+    env,
+    fmt::{Display, Formatter},
+    net::{IpAddr, Ipv6Addr},
+};
 
-use super::{metrics::MountMetrics, DB, METRICS, NEW_CO_TX};
+use super::{
+    connections, health::Checks, metrics::MountMetrics, status as live_status, DB, HEALTH, METRICS,
+    NEW_CO_TX,
+};
 
+mod admin;
 mod html;
 
+/// Maximum number of endpoints of a connection
+const MAX_ENDPOINTS: usize = 5;
+
+/// The IPv6 clients are rate limited by network of this prefix length: a client
+/// usually gets a /64, and may use any address in it
+const IPV6_PREFIX_LEN: u32 = 64;
+
+lazy_static! {
+    static ref REGISTER_LIMITERS: RouteLimiters = RouteLimiters::default();
+    static ref UNREGISTER_LIMITERS: RouteLimiters = RouteLimiters::default();
+    static ref STATUS_LIMITERS: RouteLimiters = RouteLimiters::default();
+    /// Pings of the new connections, by host of the endpoints
+    static ref PING_LIMITER: RateLimiter = RateLimiter::default();
+}
+
+/**
+Rate limiters of a route, by client IP and by account.
+*/
+#[derive(Debug, Default)]
+struct RouteLimiters {
+    ip: RateLimiter,
+    uuid: RateLimiter,
+}
+
+/**
+The routes of the clients, each one is rate limited on its own.
+*/
+#[derive(Debug, Clone, Copy)]
+enum ClientRoute {
+    Register,
+    Unregister,
+    Status,
+}
+
+impl ClientRoute {
+    fn limiters(&self) -> &'static RouteLimiters {
+        match self {
+            ClientRoute::Register => &REGISTER_LIMITERS,
+            ClientRoute::Unregister => &UNREGISTER_LIMITERS,
+            ClientRoute::Status => &STATUS_LIMITERS,
+        }
+    }
+
+    fn rate_limited_metric(&self) -> &'static IntCounterVec {
+        match self {
+            ClientRoute::Register => &METRICS.registrations_rate_limited,
+            ClientRoute::Unregister => &METRICS.unregistrations_rate_limited,
+            ClientRoute::Status => &METRICS.status_requests_rate_limited,
+        }
+    }
+}
+
+impl Display for ClientRoute {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let route = match self {
+            ClientRoute::Register => "Registration",
+            ClientRoute::Unregister => "Unregistration",
+            ClientRoute::Status => "Status request",
+        };
+        write!(f, "{}", route)
+    }
+}
+
 #[derive(Serialize)]
 struct ApiResponse {
     mollysocket: HashMap<String, String>,
 }
 
+#[derive(Serialize)]
+struct HealthResponse {
+    status: &'static str,
+    checks: Checks,
+}
+
+/**
+200 if all the [checks] are ok, else 503. The checks are detailed in the body.
+*/
+fn health_response(checks: Checks) -> (Status, Json<HealthResponse>) {
+    if checks.values().all(|ok| *ok) {
+        (
+            Status::Ok,
+            Json(HealthResponse {
+                status: "ok",
+                checks,
+            }),
+        )
+    } else {
+        (
+            Status::ServiceUnavailable,
+            Json(HealthResponse {
+                status: "error",
+                checks,
+            }),
+        )
+    }
+}
+
+/**
+Request guard with the IP of the client, taken from X-Forwarded-For
+if the request comes from a trusted proxy.
+*/
+struct ClientIp(Option<IpAddr>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ClientIp {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<ClientIp, ()> {
+        let forwarded_for = request
+            .headers()
+            .get("X-Forwarded-For")
+            .collect::<Vec<_>>()
+            .join(",");
+        let forwarded_for = Some(forwarded_for.as_str()).filter(|h| !h.is_empty());
+        Outcome::Success(ClientIp(request.remote().map(|peer| {
+            client_ip(peer.ip(), forwarded_for, &config::get_trusted_proxies())
+        })))
+    }
+}
+
 #[derive(Debug, Deserialize)]
 struct ConnectionData {
     pub uuid: String,
@@ -26,15 +165,188 @@ struct ConnectionData {
     pub password: String,
     pub endpoint: String,
     pub ping: Option<bool>,
+    /// Public key of the push subscription, to encrypt push messages
+    pub p256dh: Option<String>,
+    /// Authentication secret of the push subscription, to encrypt push messages
+    pub auth: Option<String>,
+    /// VAPID public key the push subscription was created with
+    pub vapid: Option<String>,
+    /// Invite token, for an account not in allowed_uuids
+    pub invite: Option<String>,
+    /// Endpoints after the first one, in the order they are tried
+    pub extra_endpoints: Option<Vec<PushEndpoint>>,
+    /// How the push messages are sent to the endpoints: all or failover
+    pub delivery: Option<String>,
+}
+
+/**
+Credentials of the linked device, to authenticate the requests of a client.
+*/
+#[derive(Debug, Deserialize)]
+struct Credentials {
+    pub uuid: String,
+    pub device_id: u32,
+    pub password: String,
+}
+
+impl Credentials {
+    fn matches(&self, co: &Connection) -> bool {
+        co.device_id == self.device_id
+            && db::password_matches(&co.password, co.password_encrypted, &self.password)
+    }
+
+    fn id(&self) -> ConnectionId {
+        ConnectionId {
+            uuid: self.uuid.clone(),
+            device_id: Some(self.device_id),
+        }
+    }
+}
+
+/**
+What the credentials of a client are for.
+*/
+enum Authenticated {
+    Connection(Connection),
+    /// The registration waiting for approval, or rejected
+    Registration(Registration),
+}
+
+/**
+The connection, or else the registration, of the device of [creds], if the password matches.
+*/
+fn authenticate(creds: &Credentials) -> Option<Authenticated> {
+    match DB.get(&creds.uuid, creds.device_id) {
+        Ok(co) if creds.matches(&co) => Some(Authenticated::Connection(co)),
+        Ok(_) => None,
+        Err(_) => match DB.get_registration(&creds.id()) {
+            Ok(r) if creds.matches(&r.connection) => Some(Authenticated::Registration(r)),
+            _ => None,
+        },
+    }
+}
+
+/**
+Why the account may register, or not.
+*/
+enum UuidAccess<'a> {
+    /// The uuid of the account is in allowed_uuids
+    Listed,
+    /// The account is allowed by the wildcard of allowed_uuids
+    Wildcard,
+    /// The account has been allowed by an invite
+    Invited,
+    /// The account is allowed by this invite token, redeemed once registered
+    Invite(&'a str),
+    Denied,
+}
+
+impl UuidAccess<'_> {
+    /**
+    With registration_approval, only the accounts allowed by the wildcard must be
+    approved: the administrator has already allowed the listed and the invited ones.
+    */
+    fn requires_approval(&self) -> bool {
+        matches!(self, UuidAccess::Wildcard)
+    }
+}
+
+impl ConnectionData {
+    /**
+    All the endpoints of the registration, in order.
+    */
+    fn endpoints(&self) -> Vec<PushEndpoint> {
+        let first = PushEndpoint {
+            endpoint: self.endpoint.clone(),
+            p256dh: self.p256dh.clone(),
+            auth: self.auth.clone(),
+        };
+        [
+            vec![first],
+            self.extra_endpoints.clone().unwrap_or_default(),
+        ]
+        .concat()
+    }
+
+    fn are_push_keys_valid(&self) -> bool {
+        self.endpoints()
+            .iter()
+            .all(|e| WebPushKeys::from_options(e.p256dh.as_deref(), e.auth.as_deref()).is_ok())
+    }
+
+    /**
+    Delivery mode sent by the client, failover by default.
+    */
+    fn delivery(&self) -> Result<DeliveryMode, delivery::Error> {
+        Ok(self
+            .delivery
+            .as_deref()
+            .map(str::parse)
+            .transpose()?
+            .unwrap_or_default())
+    }
+
+    /**
+    The connection of the registration.
+    */
+    fn connection(&self) -> Connection {
+        let mut co = Connection::new(
+            self.uuid.clone(),
+            self.device_id,
+            self.password.clone(),
+            self.endpoint.clone(),
+            self.p256dh.clone(),
+            self.auth.clone(),
+        );
+        co.extra_endpoints = self.extra_endpoints.clone().unwrap_or_default();
+        co.delivery = self.delivery().unwrap_or_default();
+        co.vapid_key = self.vapid_key();
+        co
+    }
+
+    fn uuid_access(&self) -> UuidAccess<'_> {
+        if config::is_uuid_listed(&self.uuid) {
+            UuidAccess::Listed
+        } else if config::is_uuid_valid(&self.uuid) {
+            UuidAccess::Wildcard
+        } else if DB.is_uuid_invited(&self.uuid).unwrap_or(false) {
+            UuidAccess::Invited
+        } else {
+            match self.invite.as_deref() {
+                Some(token) if DB.is_invite_valid(token).unwrap_or(false) => {
+                    UuidAccess::Invite(token)
+                }
+                _ => UuidAccess::Denied,
+            }
+        }
+    }
+
+    /**
+    VAPID key of the push subscription: the one sent by the client, else the key
+    of the current connection, else the primary key.
+    */
+    fn vapid_key(&self) -> Option<String> {
+        self.vapid
+            .clone()
+            .or_else(|| {
+                DB.get(&self.uuid, self.device_id)
+                    .ok()
+                    .and_then(|co| co.vapid_key)
+            })
+            .or_else(|| vapid::get_vapid_pubkey().ok())
+    }
 }
 
 /**
 Order of the status:
 1. If the connection is refused: [Refused]
-2. If this is a new connection: [New]
-3. If the credentials are updated: [CredsUpdated]
+2. If this is a new device: [New], or [Pending] or [Rejected] if the
+   registrations must be approved, and the account isn't invited and
+   doesn't have any other device. [Waiting] if the device is pending
+   with other credentials
+3. If the credentials of the device are updated: [CredsUpdated]
 4. If the connection is known and forbidden: [Forbidden]
-5. If the endpoint is updated: [EndpointUpdated]
+5. If the endpoints, their keys, or the delivery mode are updated: [EndpointUpdated]
 6. Else: [Running]
 
 If an error occured during the process: [InternalError]
@@ -45,11 +357,18 @@ enum RegistrationStatus {
     Refused(RefusedStatus),
     /// This is a new connection
     New,
+    /// This is a new connection, waiting for the approval of an administrator
+    Pending,
+    /// The device is waiting for the approval with other credentials:
+    /// its registration isn't updated
+    Waiting,
+    /// This is a new connection, rejected by an administrator
+    Rejected,
     /// The registration credentials are updated,
     CredsUpdated(CredsUpdateStatus),
     /// The credentials are the same, and the connection in forbidden
     Forbidden,
-    /// The endpoint is updated
+    /// The endpoints, their keys, or the delivery mode are updated
     EndpointUpdated,
     /// The credentials and the endpoint are the same, and the connection in healthy
     Running,
@@ -57,6 +376,21 @@ enum RegistrationStatus {
     InternalError,
 }
 
+impl RegistrationStatus {
+    /**
+    The registration is saved: a new connection, a pending registration, or an update.
+    */
+    fn stores_registration(&self) -> bool {
+        matches!(
+            self,
+            RegistrationStatus::New
+                | RegistrationStatus::Pending
+                | RegistrationStatus::CredsUpdated(CredsUpdateStatus::Ok)
+                | RegistrationStatus::EndpointUpdated
+        )
+    }
+}
+
 // This is used to send the reponse to Molly
 impl From<RegistrationStatus> for String {
     fn from(s: RegistrationStatus) -> Self {
@@ -66,6 +400,8 @@ impl From<RegistrationStatus> for String {
             | RegistrationStatus::EndpointUpdated
             | RegistrationStatus::Running => "ok",
             RegistrationStatus::CredsUpdated(s) => s.into(),
+            RegistrationStatus::Pending | RegistrationStatus::Waiting => "pending",
+            RegistrationStatus::Rejected => "rejected",
             RegistrationStatus::Forbidden => "forbidden",
             RegistrationStatus::InternalError => "internal_error",
         }
@@ -75,22 +411,42 @@ impl From<RegistrationStatus> for String {
 
 /**
 Order of the status:
-1. If UUID is forbidden [InvalidUuid]
-2. If endpoint is forbidden [InvalidEndpoint]
+1. If UUID is forbidden [InvalidUuid], or [InvalidInvite] if an invite is sent
+2. If an endpoint is forbidden, or there are too many endpoints [InvalidEndpoint]
+3. If the push keys can't be parsed [InvalidKeys]
+4. If the delivery mode is unknown [InvalidDelivery]
+5. If the VAPID key isn't primary or accepting [InvalidVapid]
+6. If the connection is disabled by an administrator [Disabled]
 */
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 enum RefusedStatus {
     /// The account id is forbidden
     InvalidUuid,
-    /// The endpoint is forbidden
+    /// The account id isn't allowed, and the invite is unknown, expired or used
+    InvalidInvite,
+    /// An endpoint is forbidden, or there are more than [MAX_ENDPOINTS]
     InvalidEndpoint,
+    /// The push keys (p256dh and auth) are invalid
+    InvalidKeys,
+    /// The delivery mode isn't all or failover
+    InvalidDelivery,
+    /// The VAPID key is unknown or retired
+    InvalidVapid,
+    /// The connection is disabled by an administrator
+    Disabled,
 }
 
-impl Into<&str> for RefusedStatus {
-    fn into(self) -> &'static str {
-        match &self {
+impl From<RefusedStatus> for &str {
+    fn from(s: RefusedStatus) -> Self {
+        match s {
             RefusedStatus::InvalidUuid => "invalid_uuid",
+            RefusedStatus::InvalidInvite => "invalid_invite",
             RefusedStatus::InvalidEndpoint => "invalid_endpoint",
+            RefusedStatus::InvalidKeys => "invalid_keys",
+            RefusedStatus::InvalidDelivery => "invalid_delivery",
+            RefusedStatus::InvalidVapid => "invalid_vapid",
+            RefusedStatus::Disabled => "disabled",
         }
     }
 }
@@ -108,9 +464,9 @@ enum CredsUpdateStatus {
     Ok,
 }
 
-impl Into<&str> for CredsUpdateStatus {
-    fn into(self) -> &'static str {
-        match &self {
+impl From<CredsUpdateStatus> for &str {
+    fn from(s: CredsUpdateStatus) -> Self {
+        match s {
             CredsUpdateStatus::Ok => "ok",
             // If someone tries to register new creds for an healthy connection,
             // we return an internal_error.
@@ -137,14 +493,11 @@ impl<'r> rocket::request::FromRequest<'r> for Req<'r> {
         let origin = request
             .headers()
             .get_one("X-Original-URL")
-            .map(|h| rocket::http::uri::Origin::parse(h).ok())
-            .flatten()
+            .and_then(|h| rocket::http::uri::Origin::parse(h).ok())
             .unwrap_or_else(|| request.uri().clone());
         let path = origin.path().as_str();
         // We assume this is https
-        let uri = request
-            .host()
-            .map(|h| format!("https://{}{}", h.to_string(), path));
+        let uri = request.host().map(|h| format!("https://{}{}", h, path));
         rocket::request::Outcome::Success(Req { ua, uri, airgapped })
     }
 }
@@ -177,17 +530,87 @@ fn discover() -> Json<ApiResponse> {
     gen_api_rep(HashMap::new())
 }
 
+/**
+Liveness probe: the connections task and the DB are alive.
+*/
+#[get("/healthz")]
+fn healthz() -> (Status, Json<HealthResponse>) {
+    health_response(HEALTH.liveness())
+}
+
+/**
+Readiness probe: the connections are restored, the VAPID key is loaded,
+and the server isn't shutting down.
+*/
+#[get("/readyz")]
+fn readyz() -> (Status, Json<HealthResponse>) {
+    health_response(HEALTH.readiness())
+}
+
 #[post("/", format = "application/json", data = "<co_data>")]
-async fn register(co_data: Json<ConnectionData>) -> Json<ApiResponse> {
-    let mut status = registration_status(&co_data).await;
+async fn register(
+    client_ip: ClientIp,
+    co_data: Json<ConnectionData>,
+) -> Result<Json<ApiResponse>, Status> {
+    // The connection wouldn't be started
+    if HEALTH.is_shutting_down() {
+        return Err(Status::ServiceUnavailable);
+    }
+    check_ip_rate_limit(ClientRoute::Register, &client_ip)?;
+    let access = co_data.uuid_access();
+    let status = registration_status(&co_data, &access).await;
+    // The password of the connection has been checked
+    if matches!(
+        status,
+        RegistrationStatus::EndpointUpdated
+            | RegistrationStatus::Running
+            | RegistrationStatus::Forbidden
+    ) {
+        check_uuid_rate_limit(ClientRoute::Register, &co_data.uuid)?;
+    }
+    let status = apply_registration(&co_data, &access, status).await;
+
+    log::debug!("Status: {status:?}");
+    Ok(gen_api_rep(HashMap::from([(
+        String::from("status"),
+        String::from(status),
+    )])))
+}
+
+/**
+Store the registration with this [status], and return the status for the client.
+
+With an invite, the invite is only used by a registration stored: a new connection,
+a pending registration, or an update. Its use is reserved before, so concurrent
+registrations don't exceed its uses, and given back if the registration can't be stored.
+*/
+async fn apply_registration(
+    co_data: &Json<ConnectionData>,
+    access: &UuidAccess<'_>,
+    mut status: RegistrationStatus,
+) -> RegistrationStatus {
+    let mut invite = None;
+    if let UuidAccess::Invite(token) = access {
+        if status.stores_registration() {
+            if DB.redeem_invite(token, &co_data.uuid).unwrap_or(false) {
+                invite = Some(*token);
+            } else {
+                status = RegistrationStatus::Refused(RefusedStatus::InvalidInvite);
+            }
+        }
+    }
     // Any error will be turned into internal_error
-    match status {
-        RegistrationStatus::New => handle_new_connection(&co_data, true, false).await,
+    let res = match status {
+        RegistrationStatus::New => {
+            rm_forbidden_devices(co_data).await;
+            handle_new_connection(co_data, true, false).await
+        }
+        RegistrationStatus::Pending => add_pending_registration(co_data),
         RegistrationStatus::CredsUpdated(CredsUpdateStatus::Ok) => {
-            handle_new_connection(&co_data, true, true).await
+            handle_new_connection(co_data, true, true).await
         }
         RegistrationStatus::EndpointUpdated => {
-            handle_new_connection(&co_data, co_data.ping.unwrap_or(false), false).await
+            handle_new_connection(co_data, co_data.ping.unwrap_or(false), false).await
         }
         RegistrationStatus::Running => {
             // If the connection is "Running" then the device creds still exists,
@@ -195,22 +618,253 @@ async fn register(co_data: Json<ConnectionData>) -> Json<ApiResponse> {
             // then the connection ends with a 403 Forbidden
             // If the connection is for an invalid uuid or an error occured : we
             // have nothing to do, except if the request ask for a ping
-            DB.update_last_registration(&co_data.uuid).unwrap();
+            DB.update_last_registration(&co_data.uuid, co_data.device_id)
+                .unwrap();
             if co_data.ping.unwrap_or(false) {
-                ping_endpoint(&co_data).await;
+                ping_endpoint(co_data).await;
             }
             Ok(())
         }
         // Else, do nothing
         _ => Ok(()),
+    };
+    match (res, invite) {
+        (Ok(()), Some(_)) => {
+            log::info!("Account {} allowed by an invite", co_data.uuid);
+            status
+        }
+        (Ok(()), None) => status,
+        (Err(_), Some(token)) => {
+            if let Err(e) = DB.unredeem_invite(token, &co_data.uuid) {
+                log::warn!("Could not give back the invite of {}: {}", co_data.uuid, e);
+            }
+            RegistrationStatus::InternalError
+        }
+        (Err(_), None) => RegistrationStatus::InternalError,
+    }
+}
+
+/**
+Status of an unregistration.
+*/
+#[derive(Debug)]
+enum UnregistrationStatus {
+    /// The connection, or the pending registration, is removed
+    Removed,
+    /// No connection or registration with these credentials
+    NotFound,
+    /// An error occurred
+    InternalError,
+}
+
+impl From<UnregistrationStatus> for String {
+    fn from(s: UnregistrationStatus) -> Self {
+        match s {
+            UnregistrationStatus::Removed => "ok",
+            UnregistrationStatus::NotFound => "not_found",
+            UnregistrationStatus::InternalError => "internal_error",
+        }
+        .into()
     }
-    .unwrap_or_else(|_| status = RegistrationStatus::InternalError);
+}
 
+/**
+Remove the connection of the client, and stop it. Unknown accounts and invalid
+credentials both get [NotFound], to not leak which accounts are registered.
+*/
+#[delete("/", format = "application/json", data = "<creds>")]
+async fn unregister(
+    client_ip: ClientIp,
+    creds: Json<Credentials>,
+) -> Result<Json<ApiResponse>, Status> {
+    check_ip_rate_limit(ClientRoute::Unregister, &client_ip)?;
+    let authenticated = authenticate(&creds);
+    if authenticated.is_some() {
+        check_uuid_rate_limit(ClientRoute::Unregister, &creds.uuid)?;
+    }
+    let status = unregistration_status(authenticated).await;
     log::debug!("Status: {status:?}");
-    gen_api_rep(HashMap::from([(
+    Ok(gen_api_rep(HashMap::from([(
         String::from("status"),
         String::from(status),
-    )]))
+    )])))
+}
+
+async fn unregistration_status(authenticated: Option<Authenticated>) -> UnregistrationStatus {
+    match authenticated {
+        Some(Authenticated::Connection(co)) => match connections::remove(&co).await {
+            Ok(()) => {
+                log::info!("Connection for {} unregistered by the client", co.uuid);
+                UnregistrationStatus::Removed
+            }
+            Err(e) => {
+                log::warn!("Could not remove the connection for {}: {}", co.uuid, e);
+                UnregistrationStatus::InternalError
+            }
+        },
+        Some(Authenticated::Registration(r)) => match DB.rm_registration(&r.connection.id()) {
+            Ok(()) => {
+                log::info!(
+                    "Registration for {} withdrawn by the client",
+                    r.connection.uuid
+                );
+                UnregistrationStatus::Removed
+            }
+            Err(_) => UnregistrationStatus::InternalError,
+        },
+        None => UnregistrationStatus::NotFound,
+    }
+}
+
+/**
+Diagnostics of a connection, for the client.
+*/
+#[derive(Debug, Serialize)]
+struct ClientStatus {
+    /// The websocket of the connection is connected to Signal
+    connected: bool,
+    /// Signal has refused the credentials of the linked device
+    forbidden: bool,
+    /// Unix timestamp of the last envelope received from Signal, in seconds
+    last_envelope: Option<i64>,
+    /// Unix timestamp of the last push message, in seconds
+    last_push: Option<i64>,
+    /// HTTP status returned by the push endpoint for the last push message
+    last_push_status: Option<u16>,
+}
+
+#[derive(Serialize)]
+struct StatusResponseData {
+    /// ok, or the status of the registration: pending, rejected or not_found
+    status: String,
+    version: &'static str,
+    #[serde(flatten)]
+    connection: Option<ClientStatus>,
+}
+
+#[derive(Serialize)]
+struct StatusResponse {
+    mollysocket: StatusResponseData,
+}
+
+/**
+Status of the connection of the client, to diagnose missing notifications.
+Unknown accounts and invalid credentials both get `not_found`.
+*/
+#[post("/status", format = "application/json", data = "<creds>")]
+fn status(client_ip: ClientIp, creds: Json<Credentials>) -> Result<Json<StatusResponse>, Status> {
+    check_ip_rate_limit(ClientRoute::Status, &client_ip)?;
+    let authenticated = authenticate(&creds);
+    if authenticated.is_some() {
+        check_uuid_rate_limit(ClientRoute::Status, &creds.uuid)?;
+    }
+    let (status, connection) = client_status(authenticated);
+    Ok(Json(StatusResponse {
+        mollysocket: StatusResponseData {
+            status: status.into(),
+            version: env!("CARGO_PKG_VERSION"),
+            connection,
+        },
+    }))
+}
+
+fn client_status(authenticated: Option<Authenticated>) -> (&'static str, Option<ClientStatus>) {
+    match authenticated {
+        Some(Authenticated::Connection(co)) => {
+            let status = match live_status::get(&co.uuid, co.device_id) {
+                Ok(status) => status,
+                Err(_) => return ("internal_error", None),
+            };
+            let connection = ClientStatus {
+                connected: connections::is_connected(&co.uuid, co.device_id),
+                forbidden: co.forbidden,
+                last_envelope: admin::timestamp(&status.last_envelope),
+                last_push: admin::timestamp(&status.last_push),
+                last_push_status: status.last_push_status,
+            };
+            ("ok", Some(connection))
+        }
+        Some(Authenticated::Registration(r)) => match r.status {
+            ApprovalStatus::Rejected => ("rejected", None),
+            _ => ("pending", None),
+        },
+        None => ("not_found", None),
+    }
+}
+
+/**
+429 if the client IP has exceeded its limit for the [route], before any work is done for the request.
+*/
+fn check_ip_rate_limit(route: ClientRoute, client_ip: &ClientIp) -> Result<(), Status> {
+    match client_ip.0 {
+        Some(ip) => check_rate_limit(
+            route,
+            &route.limiters().ip,
+            "ip",
+            &ip_key(ip),
+            &config::get_register_ip_limit(),
+        ),
+        None => Ok(()),
+    }
+}
+
+/**
+Key of the bucket of the client [ip]: the IPv6 addresses are grouped by network
+of [IPV6_PREFIX_LEN], so a client doesn't get a new bucket with each address.
+*/
+fn ip_key(ip: IpAddr) -> String {
+    match ip.to_canonical() {
+        IpAddr::V6(ip) => {
+            let network = u128::from(ip) & (u128::MAX << (128 - IPV6_PREFIX_LEN));
+            format!("{}/{}", Ipv6Addr::from(network), IPV6_PREFIX_LEN)
+        }
+        ip => ip.to_string(),
+    }
+}
+
+/**
+429 if the account [uuid] has exceeded its limit for the [route]. Only charged once the
+credentials are checked, so another client can't use the limit of an account.
+*/
+fn check_uuid_rate_limit(route: ClientRoute, uuid: &str) -> Result<(), Status> {
+    check_rate_limit(
+        route,
+        &route.limiters().uuid,
+        "uuid",
+        uuid,
+        &config::get_register_uuid_limit(),
+    )
+}
+
+fn check_rate_limit(
+    route: ClientRoute,
+    limiter: &RateLimiter,
+    name: &str,
+    key: &str,
+    limit: &RateLimit,
+) -> Result<(), Status> {
+    if limiter.check(key, limit) {
+        Ok(())
+    } else {
+        log::warn!("{} rate limited ({}={})", route, name, key);
+        route.rate_limited_metric().with_label_values(&[name]).inc();
+        Err(Status::TooManyRequests)
+    }
+}
+
+/**
+Remove the forbidden connections of the other devices of the account, when a new
+device registers: they have been unlinked, like when Molly is linked again.
+The connections disabled by an administrator are kept.
+*/
+async fn rm_forbidden_devices(co_data: &ConnectionData) {
+    let connections = DB.list_account(&co_data.uuid).unwrap_or_default();
+    for co in connections.iter().filter(|co| co.forbidden && !co.disabled) {
+        match connections::remove(co).await {
+            Ok(()) => log::info!("Forbidden connection for {} removed", co.id()),
+            Err(e) => log::warn!("Could not remove the connection for {}: {}", co.id(), e),
+        }
+    }
 }
 
 /**
@@ -223,36 +877,50 @@ async fn handle_new_connection(
     ping: bool,
     dec_forbidden: bool,
 ) -> Result<()> {
-    if new_connection(&co_data).is_ok() {
-        log::debug!("Connection successfully added.");
-        if ping {
-            ping_endpoint(&co_data).await;
-        }
-        if dec_forbidden {
-            METRICS.forbiddens.dec();
-        }
-    } else {
-        log::debug!("Could not start new connection");
+    if let Err(e) = new_connection(co_data) {
+        log::warn!("Could not add the connection for {}: {}", co_data.uuid, e);
+        return Err(e);
+    }
+    log::debug!("Connection successfully added.");
+    if ping {
+        ping_endpoint(co_data).await;
+    }
+    if dec_forbidden {
+        METRICS.forbiddens.dec();
     }
     Ok(())
 }
 
 fn new_connection(co_data: &Json<ConnectionData>) -> Result<()> {
-    let co = Connection::new(
-        co_data.uuid.clone(),
-        co_data.device_id,
-        co_data.password.clone(),
-        co_data.endpoint.clone(),
-    );
-    DB.add(&co).unwrap();
+    let co = co_data.connection();
+    DB.add(&co)?;
     if let Some(tx) = &*NEW_CO_TX.lock().unwrap() {
         let _ = tx.unbounded_send(co);
     }
     Ok(())
 }
 
+/**
+Save the registration until an administrator approves or rejects it.
+*/
+fn add_pending_registration(co_data: &ConnectionData) -> Result<()> {
+    DB.add_registration(&Registration {
+        connection: co_data.connection(),
+        status: ApprovalStatus::Pending,
+    })?;
+    log::info!("Registration for {} waiting for approval", co_data.uuid);
+    Ok(())
+}
+
 async fn ping_endpoint(co_data: &ConnectionData) {
-    if let Err(e) = ping(Url::from_str(&co_data.endpoint).unwrap()).await {
+    let co = co_data.connection();
+    // The new accounts aren't limited before the ping: without this limit, a client
+    // could make the server push to any host with many accounts
+    if !ping_allowed(&co) {
+        log::info!("Ping of the connection skipped (uuid={})", co_data.uuid);
+        return;
+    }
+    if let Err(e) = delivery::ping(&co).await {
         log::warn!(
             "Cound not ping the connection (uuid={}): {e:?}",
             &co_data.uuid
@@ -260,11 +928,81 @@ async fn ping_endpoint(co_data: &ConnectionData) {
     }
 }
 
-async fn registration_status(co_data: &ConnectionData) -> RegistrationStatus {
-    let endpoint_valid = config::is_endpoint_valid(&co_data.endpoint).await;
-    let uuid_valid = config::is_uuid_valid(&co_data.uuid);
+/**
+Take a token of the bucket of each host of the endpoints, limited like a client IP.
+*/
+fn ping_allowed(co: &Connection) -> bool {
+    let mut hosts: Vec<String> = co
+        .endpoints()
+        .iter()
+        .filter_map(|e| {
+            url::Url::parse(&e.endpoint)
+                .ok()?
+                .host_str()
+                .map(String::from)
+        })
+        .collect();
+    hosts.sort();
+    hosts.dedup();
+    hosts.iter().all(|host| {
+        check_rate_limit(
+            ClientRoute::Register,
+            &PING_LIMITER,
+            "endpoint",
+            host,
+            &config::get_register_ip_limit(),
+        )
+        .is_ok()
+    })
+}
+
+/**
+Status of a new device that must be approved: [Rejected] if a device of the account
+is rejected, [Waiting] if the device is pending with other credentials, else [Pending].
+*/
+fn approval_status(co_data: &ConnectionData) -> RegistrationStatus {
+    let registrations = DB
+        .list_account_registrations(&co_data.uuid)
+        .unwrap_or_default();
+    // A rejected device rejects the account
+    if registrations
+        .iter()
+        .any(|r| r.status == ApprovalStatus::Rejected)
+    {
+        return RegistrationStatus::Rejected;
+    }
+    // Only the device that requested the approval can update its registration
+    match registrations
+        .iter()
+        .find(|r| r.connection.device_id == co_data.device_id)
+    {
+        Some(r)
+            if !db::password_matches(
+                &r.connection.password,
+                r.connection.password_encrypted,
+                &co_data.password,
+            ) =>
+        {
+            RegistrationStatus::Waiting
+        }
+        _ => RegistrationStatus::Pending,
+    }
+}
+
+async fn registration_status(
+    co_data: &ConnectionData,
+    access: &UuidAccess<'_>,
+) -> RegistrationStatus {
+    let endpoints = co_data.endpoints();
+    let mut endpoint_valid = endpoints.len() <= MAX_ENDPOINTS;
+    for endpoint in &endpoints {
+        endpoint_valid = endpoint_valid && config::is_endpoint_valid(&endpoint.endpoint).await;
+    }
 
-    if !uuid_valid {
+    if let UuidAccess::Denied = access {
+        if co_data.invite.is_some() {
+            return RegistrationStatus::Refused(RefusedStatus::InvalidInvite);
+        }
         return RegistrationStatus::Refused(RefusedStatus::InvalidUuid);
     }
 
@@ -272,18 +1010,53 @@ async fn registration_status(co_data: &ConnectionData) -> RegistrationStatus {
         return RegistrationStatus::Refused(RefusedStatus::InvalidEndpoint);
     }
 
-    let co = match DB.get(&co_data.uuid) {
+    if !co_data.are_push_keys_valid() {
+        return RegistrationStatus::Refused(RefusedStatus::InvalidKeys);
+    }
+
+    let delivery = match co_data.delivery() {
+        Ok(delivery) => delivery,
+        Err(_) => return RegistrationStatus::Refused(RefusedStatus::InvalidDelivery),
+    };
+
+    if co_data
+        .vapid
+        .as_deref()
+        .is_some_and(|k| !vapid::is_active_key(k))
+    {
+        return RegistrationStatus::Refused(RefusedStatus::InvalidVapid);
+    }
+
+    let co = match DB.get(&co_data.uuid, co_data.device_id) {
         Ok(co) => co,
+        // The approval is for the accounts, not for each of their devices
+        Err(_)
+            if config::requires_registration_approval()
+                && access.requires_approval()
+                && DB
+                    .list_account(&co_data.uuid)
+                    .is_ok_and(|connections| connections.is_empty()) =>
+        {
+            return approval_status(co_data);
+        }
         Err(_) => {
             return RegistrationStatus::New;
         }
     };
 
-    if co.device_id == co_data.device_id && co.password == co_data.password {
+    // Only the administrator can enable it again, the credentials aren't updated
+    if co.disabled {
+        return RegistrationStatus::Refused(RefusedStatus::Disabled);
+    }
+
+    if db::password_matches(&co.password, co.password_encrypted, &co_data.password) {
         // Credentials are not updated
         if co.forbidden {
             RegistrationStatus::Forbidden
-        } else if co.endpoint != co_data.endpoint {
+        } else if co.endpoints() != endpoints
+            || co.delivery != delivery
+            || (co_data.vapid.is_some() && co.vapid_key != co_data.vapid)
+        {
             RegistrationStatus::EndpointUpdated
         } else {
             RegistrationStatus::Running
@@ -321,7 +1094,11 @@ pub async fn launch() {
 
     let _ = rocket::build()
         .configure(rocket_cfg)
-        .mount("/", routes![index, discover, register])
+        .mount(
+            "/",
+            routes![index, discover, register, unregister, status, healthz, readyz],
+        )
+        .mount("/admin/v1", admin::routes())
         .mount_metrics("/metrics", &METRICS)
         .launch()
         .await;
@@ -340,3 +1117,294 @@ fn log_qr_code() {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{db::OptTime, test_support};
+    use rocket::serde::json::serde_json;
+    use std::time::{Duration, SystemTime};
+
+    fn new_co(uuid: &str) -> Connection {
+        Connection::new(
+            uuid.into(),
+            1,
+            String::from("pass"),
+            String::from("http://0.0.0.0/"),
+            None,
+            None,
+        )
+    }
+
+    fn creds(uuid: &str, device_id: u32, password: &str) -> Credentials {
+        Credentials {
+            uuid: uuid.into(),
+            device_id,
+            password: password.into(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_invite_redemption() {
+        test_support::load_config();
+        let uuid = test_support::new_uuid();
+        let token = db::crypto::gen_token();
+        let tomorrow = OptTime::from(SystemTime::now() + Duration::from_secs(86400));
+        DB.add_invite(&token, 1, &tomorrow).unwrap();
+        DB.add(&new_co(&uuid)).unwrap();
+        let co_data: ConnectionData = serde_json::from_value(serde_json::json!({
+            "uuid": uuid,
+            "device_id": 1,
+            "password": "other",
+            "endpoint": "http://0.0.0.0/",
+            "invite": token,
+        }))
+        .unwrap();
+        let co_data = Json(co_data);
+        let access = UuidAccess::Invite(&token);
+
+        // An update ignored doesn't use the invite
+        let status = apply_registration(
+            &co_data,
+            &access,
+            RegistrationStatus::CredsUpdated(CredsUpdateStatus::Ignore),
+        )
+        .await;
+        assert_eq!(String::from(status), "internal_error");
+        assert!(DB.is_invite_valid(&token).unwrap());
+        assert!(!DB.is_uuid_invited(&uuid).unwrap());
+
+        let status = apply_registration(&co_data, &access, RegistrationStatus::Pending).await;
+        assert_eq!(String::from(status), "pending");
+        assert!(!DB.is_invite_valid(&token).unwrap());
+        assert!(DB.is_uuid_invited(&uuid).unwrap());
+        DB.rm(&uuid, 1).unwrap();
+    }
+
+    fn co_data(uuid: &str, device_id: u32, password: &str) -> ConnectionData {
+        serde_json::from_value(serde_json::json!({
+            "uuid": uuid,
+            "device_id": device_id,
+            "password": password,
+            "endpoint": test_support::FAKE_PUSH.endpoint(uuid),
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_approval_status() {
+        test_support::load_config();
+        let uuid = test_support::new_uuid();
+        assert!(matches!(
+            approval_status(&co_data(&uuid, 1, "pass")),
+            RegistrationStatus::Pending
+        ));
+        DB.add_registration(&Registration {
+            connection: new_co(&uuid),
+            status: ApprovalStatus::Pending,
+        })
+        .unwrap();
+
+        // Other credentials don't replace the pending registration
+        let other = Json(co_data(&uuid, 1, "other"));
+        let status = approval_status(&other);
+        assert!(matches!(status, RegistrationStatus::Waiting));
+        let status = apply_registration(&other, &other.uuid_access(), status).await;
+        assert_eq!(String::from(status), "pending");
+        let registration = DB.get_registration(&creds(&uuid, 1, "pass").id()).unwrap();
+        assert_eq!(registration.connection.endpoint, new_co(&uuid).endpoint);
+
+        // The same credentials update it, and other devices can request the approval
+        assert!(matches!(
+            approval_status(&co_data(&uuid, 1, "pass")),
+            RegistrationStatus::Pending
+        ));
+        assert!(matches!(
+            approval_status(&co_data(&uuid, 2, "other")),
+            RegistrationStatus::Pending
+        ));
+        DB.rm_registration(&creds(&uuid, 1, "pass").id()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_disabled() {
+        test_support::load_config();
+        let uuid = test_support::new_uuid();
+        let mut co = new_co(&uuid);
+        co.endpoint = test_support::FAKE_PUSH.endpoint(&uuid);
+        co.forbidden = true;
+        co.disabled = true;
+        DB.add(&co).unwrap();
+
+        // The same or new credentials don't enable it again
+        for password in ["pass", "other"] {
+            let co_data = co_data(&uuid, 1, password);
+            let status = registration_status(&co_data, &co_data.uuid_access()).await;
+            assert!(matches!(
+                status,
+                RegistrationStatus::Refused(RefusedStatus::Disabled)
+            ));
+        }
+
+        // Another device doesn't remove it
+        rm_forbidden_devices(&co_data(&uuid, 2, "pass")).await;
+        assert!(DB.get(&uuid, 1).unwrap().disabled);
+        DB.rm(&uuid, 1).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unregistration() {
+        test_support::load_config();
+        let uuid = test_support::new_uuid();
+        DB.add(&new_co(&uuid)).unwrap();
+        for wrong in [creds(&uuid, 2, "pass"), creds(&uuid, 1, "other")] {
+            assert!(matches!(
+                unregistration_status(authenticate(&wrong)).await,
+                UnregistrationStatus::NotFound
+            ));
+        }
+        assert!(DB.get(&uuid, 1).is_ok());
+        assert!(matches!(
+            unregistration_status(authenticate(&creds(&uuid, 1, "pass"))).await,
+            UnregistrationStatus::Removed
+        ));
+        assert!(DB.get(&uuid, 1).is_err());
+        assert!(matches!(
+            unregistration_status(authenticate(&creds(&uuid, 1, "pass"))).await,
+            UnregistrationStatus::NotFound
+        ));
+
+        // A pending registration is withdrawn
+        DB.add_registration(&Registration {
+            connection: new_co(&uuid),
+            status: ApprovalStatus::Pending,
+        })
+        .unwrap();
+        assert!(matches!(
+            unregistration_status(authenticate(&creds(&uuid, 1, "pass"))).await,
+            UnregistrationStatus::Removed
+        ));
+        assert!(DB.get_registration(&creds(&uuid, 1, "pass").id()).is_err());
+    }
+
+    #[test]
+    fn test_route_limiters() {
+        test_support::load_config();
+        let uuid = test_support::new_uuid();
+        let burst = config::get_register_uuid_limit().burst;
+        for _ in 0..burst {
+            assert!(check_uuid_rate_limit(ClientRoute::Status, &uuid).is_ok());
+        }
+        assert_eq!(
+            check_uuid_rate_limit(ClientRoute::Status, &uuid),
+            Err(Status::TooManyRequests)
+        );
+        // Polling the status doesn't use the limit of the registrations
+        assert!(check_uuid_rate_limit(ClientRoute::Register, &uuid).is_ok());
+        assert!(check_uuid_rate_limit(ClientRoute::Unregister, &uuid).is_ok());
+    }
+
+    #[test]
+    fn test_client_status() {
+        test_support::load_config();
+        let uuid = test_support::new_uuid();
+        assert_eq!(
+            client_status(authenticate(&creds(&uuid, 1, "pass"))).0,
+            "not_found"
+        );
+
+        DB.add_registration(&Registration {
+            connection: new_co(&uuid),
+            status: ApprovalStatus::Pending,
+        })
+        .unwrap();
+        assert_eq!(
+            client_status(authenticate(&creds(&uuid, 1, "pass"))).0,
+            "pending"
+        );
+        DB.rm_registration(&creds(&uuid, 1, "pass").id()).unwrap();
+
+        DB.add(&new_co(&uuid)).unwrap();
+        let mut status = DB.get_status(&uuid, 1).unwrap();
+        status.last_push = OptTime::from(std::time::SystemTime::now());
+        status.last_push_status = Some(201);
+        DB.set_status(&status).unwrap();
+        assert_eq!(
+            client_status(authenticate(&creds(&uuid, 1, "other"))).0,
+            "not_found"
+        );
+        let (status, connection) = client_status(authenticate(&creds(&uuid, 1, "pass")));
+        assert_eq!(status, "ok");
+        let connection = connection.unwrap();
+        // The connection loop isn't running
+        assert!(!connection.connected);
+        assert!(!connection.forbidden);
+        assert_eq!(connection.last_envelope, None);
+        assert!(connection.last_push.is_some());
+        assert_eq!(connection.last_push_status, Some(201));
+        DB.rm(&uuid, 1).unwrap();
+    }
+
+    #[test]
+    fn test_ip_key() {
+        let key = |ip: &str| ip_key(ip.parse().unwrap());
+        assert_eq!(key("203.0.113.7"), "203.0.113.7");
+        assert_eq!(key("::ffff:203.0.113.7"), "203.0.113.7");
+        assert_eq!(key("2001:db8:1:2::1"), "2001:db8:1:2::/64");
+        // The addresses of a /64 share their bucket
+        assert_eq!(
+            key("2001:db8:1:2:aaaa::1"),
+            key("2001:db8:1:2:bbbb:cccc:dddd:2")
+        );
+        assert_ne!(key("2001:db8:1:2::1"), key("2001:db8:1:3::1"));
+
+        let limiter = RateLimiter::default();
+        let limit = RateLimit {
+            per_minute: 1,
+            burst: 2,
+        };
+        for ip in ["2001:db8::1", "2001:db8::2", "2001:db8::3"] {
+            let allowed = limiter.check(&key(ip), &limit);
+            assert_eq!(allowed, ip != "2001:db8::3");
+        }
+    }
+
+    #[test]
+    fn test_ping_allowed() {
+        test_support::load_config();
+        let host = format!("{}.example.tld", test_support::new_uuid());
+        let co = || {
+            Connection::new(
+                test_support::new_uuid(),
+                1,
+                String::from("pass"),
+                format!("https://{}/push", host),
+                None,
+                None,
+            )
+        };
+        for _ in 0..config::get_register_ip_limit().burst {
+            assert!(ping_allowed(&co()));
+        }
+        // The new accounts with an endpoint on the same host are limited together
+        assert!(!ping_allowed(&co()));
+    }
+
+    #[test]
+    fn test_uuid_access() {
+        test_support::load_config();
+        let uuid = test_support::new_uuid();
+        // The tests allow all the accounts with the wildcard
+        let co_data = co_data(&uuid, 1, "pass");
+        let access = co_data.uuid_access();
+        assert!(matches!(access, UuidAccess::Wildcard));
+        assert!(access.requires_approval());
+        for access in [
+            UuidAccess::Listed,
+            UuidAccess::Invited,
+            UuidAccess::Invite("token"),
+        ] {
+            assert!(!access.requires_approval());
+        }
+    }
+}
@@ -0,0 +1,661 @@
+use crate::{
+    config,
+    db::{
+        self, ApprovalStatus, Connection, ConnectionId, ConnectionStatus, EndpointStatus, OptTime,
+        PushEndpoint, Registration,
+    },
+    server::{connections, status as live_status, DB, METRICS, NEW_CO_TX},
+    utils::{delivery, push_policy::PushPolicyOverride},
+};
+use rocket::{
+    delete, get,
+    http::Status,
+    post, put,
+    request::{FromRequest, Outcome, Request},
+    routes,
+    serde::{json::Json, Serialize},
+    Route,
+};
+
+const REDACTED: &str = "[redacted]";
+
+/**
+Request guard checking the `Authorization: Bearer <admin_token>` header.
+*/
+pub struct Admin;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Admin {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Admin, ()> {
+        let token = config::get_admin_token();
+        match authorize(token.as_deref(), request.headers().get_one("Authorization")) {
+            Ok(()) => Outcome::Success(Admin),
+            Err(status) => Outcome::Error((status, ())),
+        }
+    }
+}
+
+/**
+Check the [authorization] header against the admin [token]: the admin API
+doesn't exist (404) if no token is configured.
+*/
+fn authorize(token: Option<&str>, authorization: Option<&str>) -> Result<(), Status> {
+    let token = token.ok_or(Status::NotFound)?;
+    let authorized = authorization
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .is_some_and(|h| db::constant_time_eq(h, token));
+    if !authorized {
+        log::warn!("Unauthorized request to the admin API");
+        return Err(Status::Unauthorized);
+    }
+    Ok(())
+}
+
+/**
+[Connection] as returned by the admin API, without the secrets.
+*/
+#[derive(Serialize)]
+struct ConnectionInfo {
+    uuid: String,
+    device_id: u32,
+    password: &'static str,
+    endpoint: String,
+    forbidden: bool,
+    disabled: bool,
+    /// Unix timestamp of the last registration, in seconds
+    last_registration: Option<i64>,
+    p256dh: Option<String>,
+    auth: Option<&'static str>,
+    extra_endpoints: Vec<EndpointInfo>,
+    delivery: String,
+}
+
+/**
+[PushEndpoint] as returned by the admin API, without the secrets.
+*/
+#[derive(Serialize)]
+struct EndpointInfo {
+    endpoint: String,
+    p256dh: Option<String>,
+    auth: Option<&'static str>,
+}
+
+impl From<PushEndpoint> for EndpointInfo {
+    fn from(endpoint: PushEndpoint) -> Self {
+        EndpointInfo {
+            endpoint: endpoint.endpoint,
+            p256dh: endpoint.p256dh,
+            auth: endpoint.auth.map(|_| REDACTED),
+        }
+    }
+}
+
+impl From<Connection> for ConnectionInfo {
+    fn from(co: Connection) -> Self {
+        let last_registration = timestamp(&co.last_registration);
+        ConnectionInfo {
+            uuid: co.uuid,
+            device_id: co.device_id,
+            password: REDACTED,
+            endpoint: co.endpoint,
+            forbidden: co.forbidden,
+            disabled: co.disabled,
+            last_registration,
+            p256dh: co.p256dh,
+            auth: co.auth.map(|_| REDACTED),
+            extra_endpoints: co.extra_endpoints.into_iter().map(Into::into).collect(),
+            delivery: co.delivery.to_string(),
+        }
+    }
+}
+
+/**
+[ConnectionStatus] as returned by the admin API, the times are Unix timestamps in seconds.
+*/
+#[derive(Serialize)]
+struct StatusInfo {
+    uuid: String,
+    device_id: u32,
+    ws_state: String,
+    last_connected: Option<i64>,
+    last_envelope: Option<i64>,
+    last_push: Option<i64>,
+    last_push_status: Option<u16>,
+    last_error: Option<String>,
+    last_error_time: Option<i64>,
+    /// Health of the endpoints, in order
+    endpoints: Vec<EndpointStatusInfo>,
+}
+
+/**
+[EndpointStatus] as returned by the admin API.
+*/
+#[derive(Serialize)]
+struct EndpointStatusInfo {
+    endpoint: String,
+    last_status: Option<u16>,
+    failures: u32,
+    last_success: Option<i64>,
+    last_failure: Option<i64>,
+}
+
+impl From<EndpointStatus> for EndpointStatusInfo {
+    fn from(status: EndpointStatus) -> Self {
+        EndpointStatusInfo {
+            endpoint: status.endpoint,
+            last_status: status.last_status,
+            failures: status.failures,
+            last_success: timestamp(&status.last_success),
+            last_failure: timestamp(&status.last_failure),
+        }
+    }
+}
+
+pub(super) fn timestamp(t: &OptTime) -> Option<i64> {
+    Some(i64::from(t)).filter(|t| *t != 0)
+}
+
+impl From<ConnectionStatus> for StatusInfo {
+    fn from(status: ConnectionStatus) -> Self {
+        StatusInfo {
+            uuid: status.uuid,
+            device_id: status.device_id,
+            ws_state: status.ws_state.to_string(),
+            last_connected: timestamp(&status.last_connected),
+            last_envelope: timestamp(&status.last_envelope),
+            last_push: timestamp(&status.last_push),
+            last_push_status: status.last_push_status,
+            last_error: status.last_error,
+            last_error_time: timestamp(&status.last_error_time),
+            endpoints: vec![],
+        }
+    }
+}
+
+/**
+[Registration] waiting for approval, as returned by the admin API.
+*/
+#[derive(Serialize)]
+struct RegistrationInfo {
+    uuid: String,
+    device_id: u32,
+    endpoint: String,
+    /// Unix timestamp of the registration, in seconds
+    requested: Option<i64>,
+    status: String,
+}
+
+impl From<Registration> for RegistrationInfo {
+    fn from(registration: Registration) -> Self {
+        let co = registration.connection;
+        RegistrationInfo {
+            requested: timestamp(&co.last_registration),
+            uuid: co.uuid,
+            device_id: co.device_id,
+            endpoint: co.endpoint,
+            status: registration.status.to_string(),
+        }
+    }
+}
+
+#[get("/connections")]
+fn list(_admin: Admin) -> Result<Json<Vec<ConnectionInfo>>, Status> {
+    let connections = DB.list().map_err(|_| Status::InternalServerError)?;
+    Ok(Json(connections.into_iter().map(Into::into).collect()))
+}
+
+#[get("/connections/<id>")]
+fn get(_admin: Admin, id: &str) -> Result<Json<ConnectionInfo>, Status> {
+    Ok(Json(get_connection(id)?.into()))
+}
+
+#[get("/connections/<id>/status")]
+fn status(_admin: Admin, id: &str) -> Result<Json<StatusInfo>, Status> {
+    let co = get_connection(id)?;
+    let status =
+        live_status::get(&co.uuid, co.device_id).map_err(|_| Status::InternalServerError)?;
+    let mut info = StatusInfo::from(status);
+    for endpoint in co.endpoints() {
+        let endpoint_status = DB
+            .get_endpoint_status(&co.uuid, co.device_id, &endpoint.endpoint)
+            .map_err(|_| Status::InternalServerError)?;
+        info.endpoints.push(endpoint_status.into());
+    }
+    Ok(Json(info))
+}
+
+#[delete("/connections/<id>")]
+async fn delete(_admin: Admin, id: &str) -> Result<Json<ConnectionInfo>, Status> {
+    let co = get_connection(id)?;
+    connections::remove(&co)
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+    log::info!("Connection for {} removed with the admin API", co.id());
+    Ok(Json(co.into()))
+}
+
+/**
+Stop the connection and mark it as disabled: the registrations of the
+device are refused, until it is enabled again.
+*/
+#[post("/connections/<id>/disable")]
+async fn disable(_admin: Admin, id: &str) -> Result<Json<ConnectionInfo>, Status> {
+    let mut co = get_connection(id)?;
+    if !co.disabled {
+        co.disabled = true;
+        DB.add(&co).map_err(|_| Status::InternalServerError)?;
+        connections::kill(&co.uuid, co.device_id).await;
+        log::info!("Connection for {} disabled with the admin API", co.id());
+    }
+    Ok(Json(co.into()))
+}
+
+/**
+Clear the disabled and forbidden flags and (re)start the connection.
+*/
+#[post("/connections/<id>/enable")]
+fn enable(_admin: Admin, id: &str) -> Result<Json<ConnectionInfo>, Status> {
+    let mut co = get_connection(id)?;
+    if co.disabled || co.forbidden {
+        let forbidden = co.forbidden;
+        co.disabled = false;
+        co.forbidden = false;
+        DB.add(&co).map_err(|_| Status::InternalServerError)?;
+        if forbidden {
+            METRICS.forbiddens.dec();
+        }
+    }
+    log::info!("Connection for {} (re)started with the admin API", co.id());
+    // The connection is sent to the channel of new connections:
+    // the current loop, if any, is killed and a new one is started
+    if let Some(tx) = &*NEW_CO_TX.lock().unwrap() {
+        let _ = tx.unbounded_send(
+            DB.get(&co.uuid, co.device_id)
+                .map_err(|_| Status::InternalServerError)?,
+        );
+    }
+    Ok(Json(co.into()))
+}
+
+#[post("/connections/<id>/ping")]
+async fn ping_connection(_admin: Admin, id: &str) -> Status {
+    let co = match get_connection(id) {
+        Ok(co) => co,
+        Err(s) => return s,
+    };
+    match delivery::ping(&co).await {
+        Ok(_) => Status::NoContent,
+        Err(e) => {
+            log::warn!("Could not ping the connection (id={}): {e:?}", co.id());
+            Status::BadGateway
+        }
+    }
+}
+
+#[get("/connections/<id>/push-policy")]
+fn get_push_policy(_admin: Admin, id: &str) -> Result<Json<PushPolicyOverride>, Status> {
+    let co = get_connection(id)?;
+    let policy = DB
+        .get_push_policy(&co.uuid, co.device_id)
+        .map_err(|_| Status::InternalServerError)?;
+    Ok(Json(policy))
+}
+
+/**
+Override the push policy of the config for this connection, and restart it
+to apply the policy.
+*/
+#[put("/connections/<id>/push-policy", format = "json", data = "<policy>")]
+fn set_push_policy(
+    _admin: Admin,
+    id: &str,
+    policy: Json<PushPolicyOverride>,
+) -> Result<Json<PushPolicyOverride>, Status> {
+    let co = get_connection(id)?;
+    policy.validate().map_err(|_| Status::BadRequest)?;
+    DB.set_push_policy(&co.uuid, co.device_id, &policy)
+        .map_err(|_| Status::InternalServerError)?;
+    log::info!("Push policy for {} updated with the admin API", co.id());
+    restart(co);
+    Ok(policy)
+}
+
+/**
+Use the push policy of the config for this connection.
+*/
+#[delete("/connections/<id>/push-policy")]
+fn delete_push_policy(_admin: Admin, id: &str) -> Result<Status, Status> {
+    let co = get_connection(id)?;
+    DB.set_push_policy(&co.uuid, co.device_id, &PushPolicyOverride::default())
+        .map_err(|_| Status::InternalServerError)?;
+    log::info!("Push policy for {} removed with the admin API", co.id());
+    restart(co);
+    Ok(Status::NoContent)
+}
+
+/**
+Restart the loop of the connection, if it is running.
+*/
+fn restart(co: Connection) {
+    if co.forbidden || co.disabled || !connections::is_running(&co.uuid, co.device_id) {
+        return;
+    }
+    if let Some(tx) = &*NEW_CO_TX.lock().unwrap() {
+        let _ = tx.unbounded_send(co);
+    }
+}
+
+#[get("/registrations")]
+fn list_registrations(_admin: Admin) -> Result<Json<Vec<RegistrationInfo>>, Status> {
+    let registrations = DB
+        .list_registrations()
+        .map_err(|_| Status::InternalServerError)?;
+    Ok(Json(registrations.into_iter().map(Into::into).collect()))
+}
+
+/**
+Add the connection of the registration, start it and ping its endpoint.
+*/
+#[post("/registrations/<id>/approve")]
+async fn approve(_admin: Admin, id: &str) -> Result<Json<ConnectionInfo>, Status> {
+    let registration = get_registration(id)?;
+    let co = DB
+        .approve_registration(&registration.connection.id())
+        .map_err(|_| Status::InternalServerError)?;
+    log::info!("Registration for {} approved with the admin API", co.id());
+    if let Err(e) = delivery::ping(&co).await {
+        log::warn!("Could not ping the connection (uuid={}): {e:?}", co.uuid);
+    }
+    if let Some(tx) = &*NEW_CO_TX.lock().unwrap() {
+        let _ = tx.unbounded_send(
+            DB.get(&co.uuid, co.device_id)
+                .map_err(|_| Status::InternalServerError)?,
+        );
+    }
+    Ok(Json(co.into()))
+}
+
+/**
+Reject the registration: the next registrations of this account are rejected too,
+until it is approved.
+*/
+#[post("/registrations/<id>/reject")]
+fn reject(_admin: Admin, id: &str) -> Result<Json<RegistrationInfo>, Status> {
+    let id = get_registration(id)?.connection.id();
+    DB.set_registration_status(&id, ApprovalStatus::Rejected)
+        .map_err(|_| Status::InternalServerError)?;
+    log::info!("Registration for {} rejected with the admin API", id);
+    let registration = DB
+        .get_registration(&id)
+        .map_err(|_| Status::InternalServerError)?;
+    Ok(Json(registration.into()))
+}
+
+/**
+Forget the registration: the account can register again, for a new approval.
+*/
+#[delete("/registrations/<id>")]
+fn delete_registration(_admin: Admin, id: &str) -> Status {
+    let id = match get_registration(id) {
+        Ok(registration) => registration.connection.id(),
+        Err(status) => return status,
+    };
+    match DB.rm_registration(&id) {
+        Ok(()) => {
+            log::info!("Registration for {} removed with the admin API", id);
+            Status::NoContent
+        }
+        Err(_) => Status::InternalServerError,
+    }
+}
+
+/**
+The registration `<uuid>.<device_id>`, or `<uuid>`: 409 if the account has several devices.
+*/
+fn get_registration(id: &str) -> Result<Registration, Status> {
+    let id: ConnectionId = id.parse().unwrap();
+    DB.get_registration(&id).map_err(|e| find_error_status(&e))
+}
+
+/**
+The connection `<uuid>.<device_id>`, or `<uuid>`: 409 if the account has several devices.
+*/
+fn get_connection(id: &str) -> Result<Connection, Status> {
+    let id: ConnectionId = id.parse().unwrap();
+    DB.find(&id).map_err(|e| find_error_status(&e))
+}
+
+/**
+Status of the error of a lookup by [ConnectionId]: 409 if the account has several
+devices, 404 if nothing matches, and 500 if the DB failed.
+*/
+fn find_error_status(e: &eyre::Report) -> Status {
+    if let Some(db::Error::SeveralDevices(_)) = e.downcast_ref::<db::Error>() {
+        return Status::Conflict;
+    }
+    match e.downcast_ref::<rusqlite::Error>() {
+        Some(rusqlite::Error::QueryReturnedNoRows) => Status::NotFound,
+        _ => {
+            log::warn!("Could not query the DB: {}", e);
+            Status::InternalServerError
+        }
+    }
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![
+        list,
+        get,
+        status,
+        delete,
+        disable,
+        enable,
+        ping_connection,
+        get_push_policy,
+        set_push_policy,
+        delete_push_policy,
+        list_registrations,
+        approve,
+        reject,
+        delete_registration
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::TEST_ADMIN_TOKEN,
+        test_support::{self, FAKE_PUSH},
+    };
+    use rocket::{
+        http::{Header, Method},
+        local::asynchronous::{Client, LocalResponse},
+        serde::json::serde_json::Value,
+    };
+
+    async fn client() -> Client {
+        test_support::load_config();
+        Client::tracked(rocket::build().mount("/admin/v1", routes()))
+            .await
+            .unwrap()
+    }
+
+    fn new_co(uuid: &str) -> Connection {
+        Connection::new(
+            uuid.into(),
+            1,
+            String::from("pass"),
+            FAKE_PUSH.endpoint(uuid),
+            None,
+            None,
+        )
+    }
+
+    /// Request of the admin API, with the admin token
+    async fn request<'a>(client: &'a Client, method: Method, path: &str) -> LocalResponse<'a> {
+        client
+            .req(method, format!("/admin/v1{}", path))
+            .header(Header::new(
+                "Authorization",
+                format!("Bearer {}", TEST_ADMIN_TOKEN),
+            ))
+            .dispatch()
+            .await
+    }
+
+    #[test]
+    fn test_authorize() {
+        assert_eq!(authorize(None, Some("Bearer token")), Err(Status::NotFound));
+        assert_eq!(authorize(Some("token"), None), Err(Status::Unauthorized));
+        assert_eq!(
+            authorize(Some("token"), Some("Bearer other")),
+            Err(Status::Unauthorized)
+        );
+        assert_eq!(
+            authorize(Some("token"), Some("Bearer tok")),
+            Err(Status::Unauthorized)
+        );
+        assert_eq!(
+            authorize(Some("token"), Some("token")),
+            Err(Status::Unauthorized)
+        );
+        assert_eq!(authorize(Some("token"), Some("Bearer token")), Ok(()));
+    }
+
+    #[tokio::test]
+    async fn test_guard() {
+        let client = client().await;
+        let response = client.get("/admin/v1/connections").dispatch().await;
+        assert_eq!(response.status(), Status::Unauthorized);
+        let response = client
+            .get("/admin/v1/connections")
+            .header(Header::new("Authorization", "Bearer wrong"))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Unauthorized);
+        assert_eq!(
+            request(&client, Method::Get, "/connections").await.status(),
+            Status::Ok
+        );
+    }
+
+    #[tokio::test]
+    async fn test_connections() {
+        let client = client().await;
+        let uuid = test_support::new_uuid();
+        DB.add(&new_co(&uuid)).unwrap();
+
+        let list: Vec<Value> = request(&client, Method::Get, "/connections")
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        let co = list.iter().find(|co| co["uuid"] == uuid.as_str()).unwrap();
+        assert_eq!(co["password"], REDACTED);
+
+        let response = request(&client, Method::Get, &format!("/connections/{}", uuid)).await;
+        assert_eq!(response.status(), Status::Ok);
+        let co: Value = response.into_json().await.unwrap();
+        assert_eq!(co["device_id"], 1);
+        assert_eq!(co["disabled"], false);
+        let other = test_support::new_uuid();
+        let response = request(&client, Method::Get, &format!("/connections/{}", other)).await;
+        assert_eq!(response.status(), Status::NotFound);
+
+        let co: Value = request(
+            &client,
+            Method::Post,
+            &format!("/connections/{}.1/disable", uuid),
+        )
+        .await
+        .into_json()
+        .await
+        .unwrap();
+        assert_eq!(co["disabled"], true);
+        assert!(DB.get(&uuid, 1).unwrap().disabled);
+        let co: Value = request(
+            &client,
+            Method::Post,
+            &format!("/connections/{}.1/enable", uuid),
+        )
+        .await
+        .into_json()
+        .await
+        .unwrap();
+        assert_eq!(co["disabled"], false);
+        assert!(!DB.get(&uuid, 1).unwrap().disabled);
+
+        let response = request(&client, Method::Delete, &format!("/connections/{}", uuid)).await;
+        assert_eq!(response.status(), Status::Ok);
+        assert!(DB.get(&uuid, 1).is_err());
+        let response = request(&client, Method::Get, &format!("/connections/{}", uuid)).await;
+        assert_eq!(response.status(), Status::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_registrations() {
+        let client = client().await;
+        let approved = test_support::new_uuid();
+        let rejected = test_support::new_uuid();
+        for uuid in [&approved, &rejected] {
+            DB.add_registration(&Registration {
+                connection: new_co(uuid),
+                status: ApprovalStatus::Pending,
+            })
+            .unwrap();
+        }
+        let list: Vec<Value> = request(&client, Method::Get, "/registrations")
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        let registration = list
+            .iter()
+            .find(|r| r["uuid"] == approved.as_str())
+            .unwrap();
+        assert_eq!(registration["status"], "pending");
+
+        let response = request(
+            &client,
+            Method::Post,
+            &format!("/registrations/{}/approve", approved),
+        )
+        .await;
+        assert_eq!(response.status(), Status::Ok);
+        assert!(DB.get(&approved, 1).is_ok());
+        assert!(DB.get_registration(&approved.parse().unwrap()).is_err());
+        // The endpoint is pinged
+        assert_eq!(FAKE_PUSH.requests(&approved).len(), 1);
+
+        let registration: Value = request(
+            &client,
+            Method::Post,
+            &format!("/registrations/{}/reject", rejected),
+        )
+        .await
+        .into_json()
+        .await
+        .unwrap();
+        assert_eq!(registration["status"], "rejected");
+        assert!(DB.get(&rejected, 1).is_err());
+        let response = request(
+            &client,
+            Method::Delete,
+            &format!("/registrations/{}", rejected),
+        )
+        .await;
+        assert_eq!(response.status(), Status::NoContent);
+        assert!(DB.get_registration(&rejected.parse().unwrap()).is_err());
+        let response = request(
+            &client,
+            Method::Post,
+            &format!("/registrations/{}/approve", rejected),
+        )
+        .await;
+        assert_eq!(response.status(), Status::NotFound);
+        DB.rm(&approved, 1).unwrap();
+    }
+}
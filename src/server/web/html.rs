@@ -47,7 +47,7 @@ pub fn get_index(airgapped: bool, ms_url: Option<&str>) -> String {
             Some(u) => u,
             None => return no_url(),
         };
-        qrcode::gen_url(ms_url)
+        qrcode::gen_url(ms_url, None)
     };
 
     let url = match url {
@@ -0,0 +1,83 @@
+/*!
+Fake Signal and push servers, to test the connections end-to-end without network.
+
+Both servers run in their own thread for the whole test process: the global
+config, loaded with [load_config], points to them. The tests share them,
+and are isolated by using a random account uuid and push endpoint.
+*/
+use lazy_static::lazy_static;
+use std::{
+    future::Future,
+    sync::Once,
+    time::{Duration, Instant},
+};
+
+use crate::config::{self, SignalEnvironment};
+
+pub mod certs;
+mod fake_proxy;
+mod fake_push;
+mod fake_signal;
+
+pub use fake_proxy::FakeProxy;
+pub use fake_push::FakePushServer;
+pub use fake_signal::FakeSignalServer;
+
+lazy_static! {
+    pub static ref FAKE_SIGNAL: FakeSignalServer = FakeSignalServer::start();
+    pub static ref FAKE_PUSH: FakePushServer = FakePushServer::start();
+}
+
+/// Time to wait for a condition before failing the test
+const WAIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/**
+Load the config of the tests: the Signal server is [FAKE_SIGNAL],
+the push server [FAKE_PUSH] is allowed, and the DB is a temporary file.
+*/
+pub fn load_config() {
+    static INIT: Once = Once::new();
+    let db = std::env::temp_dir().join(format!("mollysocket-test-{}.db", std::process::id()));
+    INIT.call_once(|| {
+        let _ = std::fs::remove_file(&db);
+    });
+    config::load_test_config(
+        SignalEnvironment::Custom {
+            url: FAKE_SIGNAL.url.clone(),
+            ca_file: Some(FAKE_SIGNAL.ca_file.display().to_string()),
+            fingerprints: vec![],
+        },
+        vec![String::from("*"), FAKE_PUSH.origin.clone()],
+        db.display().to_string(),
+    );
+}
+
+/// Random account uuid, to isolate the tests using the fake servers
+pub fn new_uuid() -> String {
+    let hex = format!("{:032x}", rand::random::<u128>());
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+/// Wait until [condition] is true, returns false after [WAIT_TIMEOUT]
+pub async fn wait_until(condition: impl Fn() -> bool) -> bool {
+    let start = Instant::now();
+    while !condition() {
+        if start.elapsed() > WAIT_TIMEOUT {
+            return false;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    true
+}
+
+/// Run [future] until [WAIT_TIMEOUT], returns None if it didn't finish
+pub async fn with_timeout<T>(future: impl Future<Output = T>) -> Option<T> {
+    tokio::time::timeout(WAIT_TIMEOUT, future).await.ok()
+}
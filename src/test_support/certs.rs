@@ -0,0 +1,109 @@
+use openssl::{
+    asn1::Asn1Time,
+    bn::BigNum,
+    ec::{EcGroup, EcKey},
+    hash::MessageDigest,
+    nid::Nid,
+    pkey::{PKey, Private},
+    sha::sha256,
+    x509::{
+        extension::{BasicConstraints, ExtendedKeyUsage, KeyUsage, SubjectAlternativeName},
+        X509Name, X509NameBuilder, X509,
+    },
+};
+
+/**
+Certificates of the fake Signal server: a CA, and a certificate
+for localhost and 127.0.0.1 signed by this CA.
+*/
+pub struct TestCerts {
+    /// PEM of the CA, to trust with `ca_file`
+    pub ca_pem: Vec<u8>,
+    pub identity: native_tls::Identity,
+    /// SHA-256 fingerprint of the server certificate, in hexadecimal
+    pub fingerprint: String,
+}
+
+pub fn generate() -> TestCerts {
+    let ca_key = gen_key();
+    let ca_name = gen_name("MollySocket test CA");
+    let mut builder = cert_builder(&ca_name, &ca_name, &ca_key, 1);
+    builder
+        .append_extension(BasicConstraints::new().critical().ca().build().unwrap())
+        .unwrap();
+    builder
+        .append_extension(
+            KeyUsage::new()
+                .critical()
+                .key_cert_sign()
+                .crl_sign()
+                .build()
+                .unwrap(),
+        )
+        .unwrap();
+    builder.sign(&ca_key, MessageDigest::sha256()).unwrap();
+    let ca = builder.build();
+
+    let key = gen_key();
+    let mut builder = cert_builder(&gen_name("localhost"), &ca_name, &key, 2);
+    let san = SubjectAlternativeName::new()
+        .dns("localhost")
+        .ip("127.0.0.1")
+        .build(&builder.x509v3_context(Some(&ca), None))
+        .unwrap();
+    builder.append_extension(san).unwrap();
+    builder
+        .append_extension(ExtendedKeyUsage::new().server_auth().build().unwrap())
+        .unwrap();
+    builder.sign(&ca_key, MessageDigest::sha256()).unwrap();
+    let cert = builder.build();
+
+    let fingerprint = sha256(&cert.to_der().unwrap())
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<String>>()
+        .join(":");
+    TestCerts {
+        ca_pem: ca.to_pem().unwrap(),
+        identity: native_tls::Identity::from_pkcs8(
+            &cert.to_pem().unwrap(),
+            &key.private_key_to_pem_pkcs8().unwrap(),
+        )
+        .unwrap(),
+        fingerprint,
+    }
+}
+
+fn gen_key() -> PKey<Private> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+    PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+}
+
+fn gen_name(cn: &str) -> X509Name {
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_text("CN", cn).unwrap();
+    name.build()
+}
+
+fn cert_builder(
+    subject: &X509Name,
+    issuer: &X509Name,
+    key: &PKey<Private>,
+    serial: u32,
+) -> openssl::x509::X509Builder {
+    let mut builder = X509::builder().unwrap();
+    builder.set_version(2).unwrap();
+    builder
+        .set_serial_number(&BigNum::from_u32(serial).unwrap().to_asn1_integer().unwrap())
+        .unwrap();
+    builder.set_subject_name(subject).unwrap();
+    builder.set_issuer_name(issuer).unwrap();
+    builder.set_pubkey(key).unwrap();
+    builder
+        .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+        .unwrap();
+    builder
+        .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+        .unwrap();
+    builder
+}
@@ -0,0 +1,188 @@
+use base64::{prelude::BASE64_STANDARD, Engine};
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+};
+
+#[derive(Default)]
+struct Records {
+    /// Destinations requested by the clients, host:port
+    targets: Vec<String>,
+    /// Credentials sent by the clients, user:pass
+    credentials: Vec<String>,
+}
+
+/**
+Proxy speaking SOCKS5 and HTTP (CONNECT and absolute URIs) on the same port,
+recording the requested destinations.
+*/
+pub struct FakeProxy {
+    port: u16,
+    records: Arc<Mutex<Records>>,
+}
+
+impl FakeProxy {
+    pub fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let records = Arc::new(Mutex::new(Records::default()));
+        let server_records = Arc::clone(&records);
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let records = Arc::clone(&server_records);
+                thread::spawn(move || {
+                    if let Err(e) = handle(stream, records) {
+                        log::debug!("Fake proxy: {}", e);
+                    }
+                });
+            }
+        });
+        Self { port, records }
+    }
+
+    /// URL of the proxy with [scheme], and the optional user:pass [credentials]
+    pub fn url(&self, scheme: &str, credentials: Option<&str>) -> String {
+        match credentials {
+            Some(credentials) => format!("{}://{}@127.0.0.1:{}", scheme, credentials, self.port),
+            None => format!("{}://127.0.0.1:{}", scheme, self.port),
+        }
+    }
+
+    pub fn targets(&self) -> Vec<String> {
+        self.records.lock().unwrap().targets.clone()
+    }
+
+    pub fn credentials(&self) -> Vec<String> {
+        self.records.lock().unwrap().credentials.clone()
+    }
+}
+
+fn handle(stream: TcpStream, records: Arc<Mutex<Records>>) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let socks = reader.fill_buf()?.first() == Some(&0x05);
+    let (upstream, first_bytes) = if socks {
+        socks5(&mut reader, &stream, &records)?
+    } else {
+        http(&mut reader, &stream, &records)?
+    };
+    let mut upstream = TcpStream::connect(upstream)?;
+    upstream.write_all(&first_bytes)?;
+    upstream.write_all(reader.buffer())?;
+
+    let mut to_upstream = upstream.try_clone()?;
+    let mut from_client = stream.try_clone()?;
+    thread::spawn(move || io::copy(&mut from_client, &mut to_upstream));
+    io::copy(&mut upstream, &mut &stream)?;
+    Ok(())
+}
+
+fn socks5(
+    reader: &mut BufReader<TcpStream>,
+    mut stream: &TcpStream,
+    records: &Mutex<Records>,
+) -> io::Result<(String, Vec<u8>)> {
+    let mut header = [0u8; 2];
+    reader.read_exact(&mut header)?;
+    let mut methods = vec![0u8; header[1] as usize];
+    reader.read_exact(&mut methods)?;
+    if methods.contains(&0x02) {
+        stream.write_all(&[0x05, 0x02])?;
+        let user = read_field(reader, 1)?;
+        let pass = read_field(reader, 0)?;
+        records.lock().unwrap().credentials.push(format!(
+            "{}:{}",
+            String::from_utf8_lossy(&user),
+            String::from_utf8_lossy(&pass)
+        ));
+        stream.write_all(&[0x01, 0x00])?;
+    } else {
+        stream.write_all(&[0x05, 0x00])?;
+    }
+
+    let mut request = [0u8; 4];
+    reader.read_exact(&mut request)?;
+    let host = match request[3] {
+        0x01 => {
+            let mut ip = [0u8; 4];
+            reader.read_exact(&mut ip)?;
+            IpAddr::from(Ipv4Addr::from(ip)).to_string()
+        }
+        0x04 => {
+            let mut ip = [0u8; 16];
+            reader.read_exact(&mut ip)?;
+            format!("[{}]", Ipv6Addr::from(ip))
+        }
+        _ => String::from_utf8_lossy(&read_field(reader, 0)?).to_string(),
+    };
+    let mut port = [0u8; 2];
+    reader.read_exact(&mut port)?;
+    let target = format!("{}:{}", host, u16::from_be_bytes(port));
+    records.lock().unwrap().targets.push(target.clone());
+    stream.write_all(&[0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0])?;
+    Ok((target, vec![]))
+}
+
+/// Read a field prefixed by its length, after skipping [skip] bytes
+fn read_field(reader: &mut BufReader<TcpStream>, skip: usize) -> io::Result<Vec<u8>> {
+    let mut prefix = vec![0u8; skip + 1];
+    reader.read_exact(&mut prefix)?;
+    let mut field = vec![0u8; prefix[skip] as usize];
+    reader.read_exact(&mut field)?;
+    Ok(field)
+}
+
+fn http(
+    reader: &mut BufReader<TcpStream>,
+    mut stream: &TcpStream,
+    records: &Mutex<Records>,
+) -> io::Result<(String, Vec<u8>)> {
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut headers = Vec::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        if line.trim_end().is_empty() {
+            break;
+        }
+        if let Some(auth) = line
+            .trim_end()
+            .strip_prefix("Proxy-Authorization: Basic ")
+            .or_else(|| line.trim_end().strip_prefix("proxy-authorization: Basic "))
+        {
+            let credentials = BASE64_STANDARD.decode(auth).unwrap_or_default();
+            records
+                .lock()
+                .unwrap()
+                .credentials
+                .push(String::from_utf8_lossy(&credentials).to_string());
+        } else {
+            headers.push(line);
+        }
+    }
+
+    let mut parts = request_line.split(' ');
+    let method = parts.next().unwrap_or_default().to_string();
+    let uri = parts.next().unwrap_or_default().to_string();
+    if method == "CONNECT" {
+        records.lock().unwrap().targets.push(uri.clone());
+        stream.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")?;
+        return Ok((uri, vec![]));
+    }
+    // Absolute URI: forward the request with the origin form
+    let url = url::Url::parse(&uri).map_err(|e| io::Error::other(e.to_string()))?;
+    let target = format!(
+        "{}:{}",
+        url.host_str().unwrap_or_default(),
+        url.port_or_known_default().unwrap_or_default()
+    );
+    records.lock().unwrap().targets.push(target.clone());
+    let mut first_bytes = format!("{} {} HTTP/1.1\r\n", method, url.path()).into_bytes();
+    for header in headers {
+        first_bytes.extend_from_slice(header.as_bytes());
+    }
+    first_bytes.extend_from_slice(b"\r\n");
+    Ok((target, first_bytes))
+}
@@ -0,0 +1,111 @@
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+};
+
+/// Request received by the fake push server
+#[derive(Debug, Clone)]
+pub struct PushRequest {
+    /// Headers, with lowercase names
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+}
+
+#[derive(Default)]
+struct Endpoint {
+    /// Status returned to the requests, 201 if not set
+    status: Option<u16>,
+    requests: Vec<PushRequest>,
+}
+
+/**
+Minimal HTTP server recording the push notifications, per path.
+*/
+pub struct FakePushServer {
+    /// Origin of the server, to add to the allowed endpoints
+    pub origin: String,
+    endpoints: Arc<Mutex<HashMap<String, Endpoint>>>,
+}
+
+impl FakePushServer {
+    pub fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let origin = format!("http://{}", listener.local_addr().unwrap());
+        let endpoints = Arc::new(Mutex::new(HashMap::new()));
+        let server_endpoints = Arc::clone(&endpoints);
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let endpoints = Arc::clone(&server_endpoints);
+                thread::spawn(move || {
+                    if let Err(e) = handle(stream, endpoints) {
+                        log::debug!("Fake push server: {}", e);
+                    }
+                });
+            }
+        });
+        Self { origin, endpoints }
+    }
+
+    /// URL of the endpoint [name]
+    pub fn endpoint(&self, name: &str) -> String {
+        format!("{}/{}", self.origin, name)
+    }
+
+    /// Return [status] to the requests sent to the endpoint [name]
+    pub fn set_status(&self, name: &str, status: u16) {
+        let mut endpoints = self.endpoints.lock().unwrap();
+        endpoints.entry(format!("/{}", name)).or_default().status = Some(status);
+    }
+
+    /// Requests received by the endpoint [name]
+    pub fn requests(&self, name: &str) -> Vec<PushRequest> {
+        let endpoints = self.endpoints.lock().unwrap();
+        endpoints
+            .get(&format!("/{}", name))
+            .map(|e| e.requests.clone())
+            .unwrap_or_default()
+    }
+}
+
+fn handle(
+    mut stream: TcpStream,
+    endpoints: Arc<Mutex<HashMap<String, Endpoint>>>,
+) -> std::io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let path = line.split(' ').nth(1).unwrap_or("/").to_string();
+
+    let mut headers = HashMap::new();
+    loop {
+        line.clear();
+        reader.read_line(&mut line)?;
+        match line.trim_end().split_once(':') {
+            Some((name, value)) => {
+                headers.insert(name.to_lowercase(), value.trim().to_string());
+            }
+            None => break,
+        }
+    }
+    let len = headers
+        .get("content-length")
+        .and_then(|l| l.parse().ok())
+        .unwrap_or(0);
+    let mut body = vec![0; len];
+    reader.read_exact(&mut body)?;
+
+    let status = {
+        let mut endpoints = endpoints.lock().unwrap();
+        let endpoint = endpoints.entry(path).or_default();
+        endpoint.requests.push(PushRequest { headers, body });
+        endpoint.status.unwrap_or(201)
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {} Fake\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        status
+    )
+}
@@ -0,0 +1,282 @@
+use base64::{prelude::BASE64_STANDARD, Engine};
+use futures_channel::mpsc::{self, UnboundedSender};
+use futures_util::{SinkExt, StreamExt};
+use prost::Message;
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    thread,
+};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_native_tls::TlsAcceptor;
+use tokio_tungstenite::tungstenite::{
+    self,
+    handshake::server::{ErrorResponse, Request, Response},
+    protocol::{frame::coding::CloseCode, CloseFrame},
+};
+
+use super::certs;
+use crate::ws::{
+    proto_signalservice::Envelope,
+    proto_websocketresources::{
+        web_socket_message::Type, WebSocketMessage, WebSocketRequestMessage,
+        WebSocketResponseMessage,
+    },
+};
+
+/// Action requested by a test on the live connection of an account
+enum Command {
+    /// Send an envelope, with PUT /api/v1/message
+    Envelope { urgent: bool },
+    /// Close the websocket with this code
+    Close(u16),
+    /// Drop the connection without closing the websocket
+    Drop,
+}
+
+#[derive(Default)]
+struct Account {
+    /// Reject the websocket handshake with a 403
+    reject: bool,
+    /// Channel to the live connection, if any
+    tx: Option<UnboundedSender<Command>>,
+    connections: u32,
+    keepalives: u32,
+    /// Responses to the envelopes
+    acks: u32,
+    /// Code of the last close frame sent by the client
+    close_code: Option<u16>,
+}
+
+type Accounts = Arc<Mutex<HashMap<String, Account>>>;
+
+/**
+Fake Signal chat server: a TLS websocket speaking the WebSocketResources framing.
+
+The accounts are identified by the uuid of the Basic authorization.
+*/
+pub struct FakeSignalServer {
+    /// URL of the websocket
+    pub url: String,
+    /// PEM file of the CA of the server
+    pub ca_file: PathBuf,
+    /// SHA-256 fingerprint of the server certificate
+    pub fingerprint: String,
+    accounts: Accounts,
+}
+
+impl FakeSignalServer {
+    pub fn start() -> Self {
+        let certs = certs::generate();
+        let ca_file =
+            std::env::temp_dir().join(format!("mollysocket-test-{}-ca.pem", std::process::id()));
+        std::fs::write(&ca_file, &certs.ca_pem).unwrap();
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let url = format!(
+            "wss://localhost:{}/v1/websocket/",
+            listener.local_addr().unwrap().port()
+        );
+        let acceptor = TlsAcceptor::from(native_tls::TlsAcceptor::new(certs.identity).unwrap());
+        let accounts = Accounts::default();
+        let server_accounts = Arc::clone(&accounts);
+        thread::spawn(move || {
+            tokio::runtime::Runtime::new()
+                .unwrap()
+                .block_on(async move {
+                    let listener = TcpListener::from_std(listener).unwrap();
+                    while let Ok((stream, _)) = listener.accept().await {
+                        let acceptor = acceptor.clone();
+                        let accounts = Arc::clone(&server_accounts);
+                        tokio::spawn(async move {
+                            if let Err(e) = handle(stream, acceptor, accounts).await {
+                                log::debug!("Fake Signal server: {}", e);
+                            }
+                        });
+                    }
+                })
+        });
+        Self {
+            url,
+            ca_file,
+            fingerprint: certs.fingerprint,
+            accounts,
+        }
+    }
+
+    /// Reject the next connections of [uuid] with a 403, like for a removed linked device
+    pub fn reject(&self, uuid: &str) {
+        self.accounts
+            .lock()
+            .unwrap()
+            .entry(uuid.into())
+            .or_default()
+            .reject = true;
+    }
+
+    pub fn is_connected(&self, uuid: &str) -> bool {
+        self.with_account(uuid, |a| a.tx.is_some())
+    }
+
+    /// Number of websocket handshakes completed by [uuid]
+    pub fn connections(&self, uuid: &str) -> u32 {
+        self.with_account(uuid, |a| a.connections)
+    }
+
+    pub fn keepalives(&self, uuid: &str) -> u32 {
+        self.with_account(uuid, |a| a.keepalives)
+    }
+
+    /// Number of envelopes acknowledged by [uuid]
+    pub fn acks(&self, uuid: &str) -> u32 {
+        self.with_account(uuid, |a| a.acks)
+    }
+
+    pub fn close_code(&self, uuid: &str) -> Option<u16> {
+        self.with_account(uuid, |a| a.close_code)
+    }
+
+    /// Send an envelope to the live connection of [uuid], returns false if not connected
+    pub fn send_envelope(&self, uuid: &str, urgent: bool) -> bool {
+        self.send(uuid, Command::Envelope { urgent })
+    }
+
+    /// Close the live connection of [uuid] with [code], 4409 for "connected elsewhere"
+    pub fn close(&self, uuid: &str, code: u16) -> bool {
+        self.send(uuid, Command::Close(code))
+    }
+
+    /// Drop the live connection of [uuid], without closing the websocket
+    pub fn drop_connection(&self, uuid: &str) -> bool {
+        self.send(uuid, Command::Drop)
+    }
+
+    fn send(&self, uuid: &str, command: Command) -> bool {
+        self.with_account(uuid, |a| {
+            a.tx.as_ref()
+                .is_some_and(|tx| tx.unbounded_send(command).is_ok())
+        })
+    }
+
+    fn with_account<T: Default>(&self, uuid: &str, f: impl FnOnce(&Account) -> T) -> T {
+        self.accounts
+            .lock()
+            .unwrap()
+            .get(uuid)
+            .map(f)
+            .unwrap_or_default()
+    }
+}
+
+// The handshake callback must return the error response of tungstenite
+#[allow(clippy::result_large_err)]
+async fn handle(stream: TcpStream, acceptor: TlsAcceptor, accounts: Accounts) -> eyre::Result<()> {
+    let stream = acceptor.accept(stream).await?;
+    let mut uuid = String::new();
+    let callback = |request: &Request, response: Response| -> Result<Response, ErrorResponse> {
+        uuid = request
+            .headers()
+            .get("Authorization")
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.strip_prefix("Basic "))
+            .and_then(|h| BASE64_STANDARD.decode(h).ok())
+            .and_then(|creds| String::from_utf8(creds).ok())
+            .and_then(|creds| creds.split('.').next().map(String::from))
+            .unwrap_or_default();
+        let accounts = accounts.lock().unwrap();
+        if accounts.get(&uuid).is_some_and(|a| a.reject) {
+            let mut resp = ErrorResponse::new(None);
+            *resp.status_mut() = http::StatusCode::FORBIDDEN;
+            return Err(resp);
+        }
+        Ok(response)
+    };
+    let mut ws = tokio_tungstenite::accept_hdr_async(stream, callback).await?;
+
+    let (tx, mut rx) = mpsc::unbounded();
+    {
+        let mut accounts = accounts.lock().unwrap();
+        let account = accounts.entry(uuid.clone()).or_default();
+        account.tx = Some(tx);
+        account.connections += 1;
+    }
+    let mut next_id = 0;
+    loop {
+        tokio::select! {
+            message = ws.next() => {
+                let data = match message {
+                    Some(Ok(tungstenite::Message::Binary(data))) => data,
+                    Some(Ok(tungstenite::Message::Close(frame))) => {
+                        with_account(&accounts, &uuid, |a| a.close_code = frame.map(|f| f.code.into()));
+                        // Send the reply queued by tungstenite
+                        let _ = ws.flush().await;
+                        break;
+                    }
+                    Some(Err(_)) | None => break,
+                    Some(Ok(_)) => continue,
+                };
+                let message = WebSocketMessage::decode(data)?;
+                match message.r#type.and_then(|t| Type::try_from(t).ok()) {
+                    Some(Type::Request) => {
+                        let request = message.request.unwrap_or_default();
+                        if request.path() == "/v1/keepalive" {
+                            with_account(&accounts, &uuid, |a| a.keepalives += 1);
+                            let response = WebSocketMessage {
+                                r#type: Some(Type::Response.into()),
+                                response: Some(WebSocketResponseMessage {
+                                    id: request.id,
+                                    status: Some(200),
+                                    message: Some(String::from("OK")),
+                                    ..Default::default()
+                                }),
+                                ..Default::default()
+                            };
+                            ws.send(tungstenite::Message::binary(response.encode_to_vec())).await?;
+                        }
+                    }
+                    Some(Type::Response) => with_account(&accounts, &uuid, |a| a.acks += 1),
+                    _ => (),
+                }
+            }
+            command = rx.next() => match command {
+                Some(Command::Envelope { urgent }) => {
+                    next_id += 1;
+                    let envelope = Envelope {
+                        urgent: Some(urgent),
+                        ..Default::default()
+                    };
+                    let request = WebSocketMessage {
+                        r#type: Some(Type::Request.into()),
+                        request: Some(WebSocketRequestMessage {
+                            verb: Some(String::from("PUT")),
+                            path: Some(String::from("/api/v1/message")),
+                            body: Some(envelope.encode_to_vec()),
+                            id: Some(next_id),
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    };
+                    ws.send(tungstenite::Message::binary(request.encode_to_vec())).await?;
+                }
+                Some(Command::Close(code)) => {
+                    let _ = ws
+                        .close(Some(CloseFrame {
+                            code: CloseCode::from(code),
+                            reason: "".into(),
+                        }))
+                        .await;
+                    break;
+                }
+                Some(Command::Drop) | None => break,
+            }
+        }
+    }
+    with_account(&accounts, &uuid, |a| a.tx = None);
+    Ok(())
+}
+
+fn with_account(accounts: &Accounts, uuid: &str, f: impl FnOnce(&mut Account)) {
+    f(accounts.lock().unwrap().entry(uuid.into()).or_default())
+}
@@ -1,8 +1,11 @@
-use eyre::Result;
-use rocket::serde::json::json;
-use url::Url;
-
+pub mod backoff;
+pub mod client_ip;
+pub mod delivery;
+pub mod limiter;
 pub mod post_allowed;
+pub mod proxy;
+pub mod push_policy;
+pub mod rate_limit;
 
 pub fn anonymize_url(url_in: &str) -> String {
     let mut mut_url = url::Url::parse(url_in).unwrap();
@@ -12,9 +15,3 @@ pub fn anonymize_url(url_in: &str) -> String {
     mut_url.path();
     mut_url.into()
 }
-
-pub async fn ping(url: Url) -> Result<reqwest::Response> {
-    let res = post_allowed::post_allowed(url, &json!({"test":true}), Some("test")).await?;
-    res.error_for_status_ref()?;
-    Ok(res)
-}
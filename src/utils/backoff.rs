@@ -0,0 +1,146 @@
+use rand::Rng;
+use std::time::Duration;
+
+/**
+Exponential backoff, capped to [Backoff::max], with jitter.
+*/
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    /// Delay before the first retry
+    pub initial: Duration,
+    /// Factor between the delays of 2 successive retries
+    pub multiplier: f64,
+    /// Maximum delay between two retries
+    pub max: Duration,
+}
+
+impl Backoff {
+    /**
+    Delay before the retry number [attempt], starting at 1, without jitter.
+    */
+    pub fn exponential(&self, attempt: u32) -> Duration {
+        // Computed in f64, a Duration would overflow after some attempts
+        let exp = self.initial.as_secs_f64()
+            * self
+                .multiplier
+                .powi(attempt.saturating_sub(1).min(1000) as i32);
+        Duration::from_secs_f64(exp.min(self.max.as_secs_f64()))
+    }
+
+    /**
+    Delay before the retry number [attempt], starting at 1.
+
+    The delay is randomized between the half and the full exponential delay,
+    so the clients failing at the same time don't retry in lockstep.
+    */
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exp = self.exponential(attempt);
+        exp / 2 + exp.mul_f64(rand::rng().random_range(0.0..=0.5))
+    }
+}
+
+/**
+Why a connection failed, to choose how long to wait before reconnecting.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    /// Network error, or connection closed
+    Transient,
+    /// The server replied with a 5xx
+    ServerError,
+    /// The server replied with a 429, with its Retry-After
+    RateLimited { retry_after: Option<Duration> },
+}
+
+/**
+Exponential backoff with full jitter, to reconnect to the Signal server.
+*/
+#[derive(Debug, Clone)]
+pub struct ReconnectPolicy {
+    /// Delays between two reconnections
+    pub backoff: Backoff,
+    /// The attempts are reset once a connection has been healthy for this duration
+    pub reset_after: Duration,
+    /// Factor applied to the delay when the server is failing or rate limiting
+    pub server_error_factor: f64,
+}
+
+impl ReconnectPolicy {
+    /**
+    Delay before the reconnection number [attempt], starting at 1, after [failure].
+
+    The delay is randomized between 0 and the exponential delay (full jitter),
+    so the clients disconnected at the same time don't reconnect in lockstep.
+    A Retry-After is respected, up to [Backoff::max]: a wrong value
+    mustn't stop the reconnections.
+    */
+    pub fn delay(&self, attempt: u32, failure: Failure) -> Duration {
+        let factor = match failure {
+            Failure::Transient => 1.0,
+            Failure::ServerError | Failure::RateLimited { .. } => self.server_error_factor,
+        };
+        let max = self.backoff.max;
+        let exp = self.backoff.exponential(attempt).mul_f64(factor).min(max);
+        let delay = exp.mul_f64(rand::rng().random_range(0.0..=1.0));
+        match failure {
+            Failure::RateLimited {
+                retry_after: Some(retry_after),
+            } => delay.max(retry_after.min(max)),
+            _ => delay,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delay() {
+        let backoff = Backoff {
+            initial: Duration::from_secs(10),
+            multiplier: 2.0,
+            max: Duration::from_secs(600),
+        };
+        for (attempt, exp) in [(1, 10), (2, 20), (3, 40), (7, 600), (100, 600)] {
+            let delay = backoff.delay(attempt);
+            assert!(delay >= Duration::from_secs(exp) / 2);
+            assert!(delay <= Duration::from_secs(exp));
+        }
+    }
+
+    #[test]
+    fn test_reconnect_delay() {
+        let policy = ReconnectPolicy {
+            backoff: Backoff {
+                initial: Duration::from_secs(5),
+                multiplier: 2.0,
+                max: Duration::from_secs(600),
+            },
+            reset_after: Duration::from_secs(60),
+            server_error_factor: 4.0,
+        };
+        for (attempt, failure, max) in [
+            (1, Failure::Transient, 5),
+            (3, Failure::Transient, 20),
+            (1000, Failure::Transient, 600),
+            (1, Failure::ServerError, 20),
+            (3, Failure::ServerError, 80),
+            (8, Failure::ServerError, 600),
+            (2, Failure::RateLimited { retry_after: None }, 40),
+        ] {
+            let delay = policy.delay(attempt, failure);
+            assert!(delay <= Duration::from_secs(max));
+        }
+        let retry_after = Duration::from_secs(300);
+        let failure = Failure::RateLimited {
+            retry_after: Some(retry_after),
+        };
+        assert_eq!(policy.delay(1, failure), retry_after);
+        // A Retry-After of years is capped
+        let failure = Failure::RateLimited {
+            retry_after: Some(Duration::from_secs(99999999)),
+        };
+        assert_eq!(policy.delay(1, failure), policy.backoff.max);
+    }
+}
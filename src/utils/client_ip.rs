@@ -0,0 +1,135 @@
+use std::{fmt::Display, net::IpAddr, str::FromStr};
+
+/**
+An IP network, like `10.0.0.0/8`, or a single IP.
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IpNetwork {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+#[derive(Debug)]
+pub struct ParseError(String);
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invalid IP network: {}", self.0)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl FromStr for IpNetwork {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || ParseError(s.into());
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| err())?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p.parse().ok().filter(|p| *p <= max).ok_or_else(err)?,
+            None => max,
+        };
+        Ok(IpNetwork { addr, prefix })
+    }
+}
+
+impl IpNetwork {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/**
+IP of the client. If the [peer] is a trusted proxy, this is the last address of
+X-Forwarded-For which isn't a trusted proxy: the previous ones can be forged by the client.
+*/
+pub fn client_ip(peer: IpAddr, forwarded_for: Option<&str>, trusted: &[IpNetwork]) -> IpAddr {
+    let is_trusted = |ip: &IpAddr| trusted.iter().any(|net| net.contains(ip));
+    let peer = peer.to_canonical();
+    if !is_trusted(&peer) {
+        return peer;
+    }
+    let mut ip = peer;
+    for forwarded in forwarded_for.unwrap_or_default().rsplit(',') {
+        match forwarded.trim().parse::<IpAddr>() {
+            Ok(forwarded) => {
+                ip = forwarded.to_canonical();
+                if !is_trusted(&ip) {
+                    break;
+                }
+            }
+            // Not an IP: we can't go further
+            Err(_) => break,
+        }
+    }
+    ip
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_network() {
+        let net: IpNetwork = "10.0.0.0/8".parse().unwrap();
+        assert!(net.contains(&ip("10.1.2.3")));
+        assert!(net.contains(&ip("::ffff:10.1.2.3")));
+        assert!(!net.contains(&ip("11.0.0.1")));
+        assert!(!net.contains(&ip("fd00::1")));
+        let net: IpNetwork = "fd00::/8".parse().unwrap();
+        assert!(net.contains(&ip("fd12::1")));
+        let single: IpNetwork = "127.0.0.1".parse().unwrap();
+        assert!(single.contains(&ip("127.0.0.1")));
+        assert!(!single.contains(&ip("127.0.0.2")));
+        assert!("0.0.0.0/0"
+            .parse::<IpNetwork>()
+            .unwrap()
+            .contains(&ip("1.2.3.4")));
+        assert!("10.0.0.0/33".parse::<IpNetwork>().is_err());
+        assert!("localhost".parse::<IpNetwork>().is_err());
+    }
+
+    #[test]
+    fn test_client_ip() {
+        let trusted = vec!["10.0.0.0/8".parse().unwrap()];
+        // The header of an untrusted peer is ignored
+        assert_eq!(
+            client_ip(ip("1.2.3.4"), Some("5.6.7.8"), &trusted),
+            ip("1.2.3.4")
+        );
+        assert_eq!(
+            client_ip(ip("10.0.0.1"), Some("5.6.7.8"), &trusted),
+            ip("5.6.7.8")
+        );
+        // The client can add addresses before its own
+        assert_eq!(
+            client_ip(ip("10.0.0.1"), Some("9.9.9.9, 5.6.7.8, 10.0.0.2"), &trusted),
+            ip("5.6.7.8")
+        );
+        assert_eq!(
+            client_ip(ip("10.0.0.1"), Some("garbage, 10.0.0.2"), &trusted),
+            ip("10.0.0.2")
+        );
+        assert_eq!(client_ip(ip("10.0.0.1"), None, &trusted), ip("10.0.0.1"));
+    }
+}
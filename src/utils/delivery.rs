@@ -0,0 +1,347 @@
+use eyre::Result;
+use futures_util::future::join_all;
+use rocket::serde::json::json;
+use serde::Serialize;
+use std::{
+    fmt::{Display, Formatter},
+    str::FromStr,
+};
+use url::Url;
+
+use crate::{
+    db::{Connection, PushEndpoint},
+    utils::post_allowed::{post_allowed, PushHeaders, Retryable},
+    webpush::WebPushKeys,
+};
+
+#[derive(Debug)]
+pub enum Error {
+    InvalidDeliveryMode(String),
+    /// None of the endpoints accepted the push message
+    NotDelivered,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::InvalidDeliveryMode(s) => {
+                write!(f, "Invalid delivery mode: {}, expected all or failover", s)
+            }
+            Error::NotDelivered => write!(f, "No endpoint accepted the push message"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/**
+How the push messages are sent to the endpoints of a connection.
+*/
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DeliveryMode {
+    /// Each push message is sent to all the endpoints
+    All,
+    /// Each push message is sent to the first endpoint, and to the next one
+    /// if the push server is unavailable
+    #[default]
+    Failover,
+}
+
+impl Display for DeliveryMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let mode = match self {
+            DeliveryMode::All => "all",
+            DeliveryMode::Failover => "failover",
+        };
+        write!(f, "{}", mode)
+    }
+}
+
+impl FromStr for DeliveryMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(DeliveryMode::All),
+            "failover" => Ok(DeliveryMode::Failover),
+            _ => Err(Error::InvalidDeliveryMode(s.into())),
+        }
+    }
+}
+
+/**
+What the response of a push server tells about its endpoint.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// The push server accepted the push message
+    Delivered,
+    /// The push server couldn't be reached, timed out, or responded with a 5xx:
+    /// the next endpoint is tried
+    Unavailable,
+    /// The push subscription doesn't exist anymore, the push server responded
+    /// with a 404 or a 410: the endpoint is pruned
+    Gone,
+    /// The push server refused the push message for another reason
+    Refused,
+}
+
+impl From<&Result<reqwest::Response>> for Outcome {
+    fn from(res: &Result<reqwest::Response>) -> Self {
+        match res {
+            Ok(resp) if resp.status().is_success() => Outcome::Delivered,
+            Ok(resp) if resp.status() == 404 || resp.status() == 410 => Outcome::Gone,
+            Ok(resp) if resp.status().is_server_error() => Outcome::Unavailable,
+            Ok(_) => Outcome::Refused,
+            Err(_) => Outcome::Unavailable,
+        }
+    }
+}
+
+/**
+An endpoint of a connection, parsed to send the push messages.
+*/
+#[derive(Debug, Clone)]
+pub struct Target {
+    /// The endpoint, as saved in the DB
+    pub endpoint: String,
+    url: Url,
+    keys: Option<WebPushKeys>,
+}
+
+impl Target {
+    pub fn new(endpoint: &PushEndpoint) -> Result<Self> {
+        Ok(Target {
+            endpoint: endpoint.endpoint.clone(),
+            url: Url::parse(&endpoint.endpoint)?,
+            keys: WebPushKeys::from_options(endpoint.p256dh.as_deref(), endpoint.auth.as_deref())?,
+        })
+    }
+
+    /**
+    The targets of all the endpoints of the connection, in order.
+    */
+    pub fn from_connection(co: &Connection) -> Result<Vec<Self>> {
+        co.endpoints().iter().map(Target::new).collect()
+    }
+}
+
+/**
+A push message sent to an endpoint.
+*/
+#[derive(Debug)]
+pub struct Attempt {
+    pub endpoint: String,
+    pub res: Result<reqwest::Response>,
+}
+
+impl Attempt {
+    pub fn outcome(&self) -> Outcome {
+        Outcome::from(&self.res)
+    }
+}
+
+/**
+Send the push message to the [targets], following the delivery [mode].
+
+Returns the attempts in the order of the targets: with [DeliveryMode::Failover],
+the targets after the first one delivered, or refused, aren't tried.
+*/
+pub async fn deliver<T: Serialize + ?Sized>(
+    targets: &[Target],
+    mode: DeliveryMode,
+    body: &T,
+    headers: &PushHeaders<'_>,
+    vapid_key: Option<&str>,
+) -> Vec<Attempt> {
+    match mode {
+        DeliveryMode::All => {
+            join_all(targets.iter().map(|t| send(t, body, headers, vapid_key))).await
+        }
+        DeliveryMode::Failover => {
+            let mut attempts = vec![];
+            for target in targets {
+                let attempt = send(target, body, headers, vapid_key).await;
+                let outcome = attempt.outcome();
+                attempts.push(attempt);
+                if matches!(outcome, Outcome::Delivered | Outcome::Refused) {
+                    break;
+                }
+            }
+            attempts
+        }
+    }
+}
+
+async fn send<T: Serialize + ?Sized>(
+    target: &Target,
+    body: &T,
+    headers: &PushHeaders<'_>,
+    vapid_key: Option<&str>,
+) -> Attempt {
+    let res = post_allowed(
+        target.url.clone(),
+        body,
+        headers,
+        target.keys.as_ref(),
+        vapid_key,
+    )
+    .await;
+    Attempt {
+        endpoint: target.endpoint.clone(),
+        res,
+    }
+}
+
+/**
+The push message has been accepted by at least one endpoint.
+*/
+pub fn is_delivered(attempts: &[Attempt]) -> bool {
+    attempts.iter().any(|a| a.outcome() == Outcome::Delivered)
+}
+
+/**
+Whether the push message can be sent again later: it hasn't been
+delivered, and one of the endpoints may accept it later.
+*/
+pub fn retryable(attempts: &[Attempt]) -> Retryable {
+    if is_delivered(attempts) {
+        return Retryable::No;
+    }
+    let mut retryable = Retryable::No;
+    for attempt in attempts {
+        if let Retryable::Yes(retry_after) = Retryable::from(&attempt.res) {
+            match retryable {
+                Retryable::Yes(Some(_)) => (),
+                _ => retryable = Retryable::Yes(retry_after),
+            }
+        }
+    }
+    retryable
+}
+
+/**
+Send a test push message to the endpoints of the connection.
+*/
+pub async fn ping(co: &Connection) -> Result<()> {
+    let targets = Target::from_connection(co)?;
+    let attempts = deliver(
+        &targets,
+        co.delivery,
+        &json!({"test":true}),
+        &PushHeaders::new(Some("test")),
+        co.vapid_key.as_deref(),
+    )
+    .await;
+    if is_delivered(&attempts) {
+        return Ok(());
+    }
+    // The error of the last endpoint tried
+    match attempts.into_iter().last().map(|a| a.res) {
+        Some(Err(e)) => Err(e),
+        Some(Ok(resp)) => {
+            resp.error_for_status()?;
+            Err(Error::NotDelivered.into())
+        }
+        None => Err(Error::NotDelivered.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, FAKE_PUSH};
+
+    fn targets(names: &[&str]) -> Vec<Target> {
+        names
+            .iter()
+            .map(|name| {
+                Target::new(&PushEndpoint {
+                    endpoint: FAKE_PUSH.endpoint(name),
+                    p256dh: None,
+                    auth: None,
+                })
+                .unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_delivery_mode() {
+        assert_eq!("all".parse::<DeliveryMode>().unwrap(), DeliveryMode::All);
+        assert_eq!(
+            "failover".parse::<DeliveryMode>().unwrap(),
+            DeliveryMode::Failover
+        );
+        assert!("both".parse::<DeliveryMode>().is_err());
+        assert_eq!(DeliveryMode::default().to_string(), "failover");
+    }
+
+    #[tokio::test]
+    async fn test_failover() {
+        test_support::load_config();
+        let uuid = test_support::new_uuid();
+        let names = [
+            format!("{}-down", uuid),
+            format!("{}-gone", uuid),
+            format!("{}-up", uuid),
+            format!("{}-next", uuid),
+        ];
+        FAKE_PUSH.set_status(&names[0], 503);
+        FAKE_PUSH.set_status(&names[1], 410);
+        let names: Vec<&str> = names.iter().map(String::as_str).collect();
+        let attempts = deliver(
+            &targets(&names),
+            DeliveryMode::Failover,
+            &json!({"urgent": true}),
+            &PushHeaders::new(None),
+            None,
+        )
+        .await;
+        let outcomes: Vec<Outcome> = attempts.iter().map(Attempt::outcome).collect();
+        assert_eq!(
+            outcomes,
+            vec![Outcome::Unavailable, Outcome::Gone, Outcome::Delivered]
+        );
+        assert!(is_delivered(&attempts));
+        assert_eq!(retryable(&attempts), Retryable::No);
+        assert!(FAKE_PUSH.requests(names[3]).is_empty());
+
+        // A refused push message isn't sent to the next endpoints
+        FAKE_PUSH.set_status(names[2], 400);
+        let attempts = deliver(
+            &targets(&names),
+            DeliveryMode::Failover,
+            &json!({"urgent": true}),
+            &PushHeaders::new(None),
+            None,
+        )
+        .await;
+        assert_eq!(attempts.last().unwrap().outcome(), Outcome::Refused);
+        assert!(!is_delivered(&attempts));
+        // The first endpoint may accept it later
+        assert_eq!(retryable(&attempts), Retryable::Yes(None));
+        assert!(FAKE_PUSH.requests(names[3]).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_fan_out() {
+        test_support::load_config();
+        let uuid = test_support::new_uuid();
+        let names = [format!("{}-1", uuid), format!("{}-2", uuid)];
+        FAKE_PUSH.set_status(&names[0], 404);
+        let names: Vec<&str> = names.iter().map(String::as_str).collect();
+        let attempts = deliver(
+            &targets(&names),
+            DeliveryMode::All,
+            &json!({"urgent": true}),
+            &PushHeaders::new(None),
+            None,
+        )
+        .await;
+        let outcomes: Vec<Outcome> = attempts.iter().map(Attempt::outcome).collect();
+        assert_eq!(outcomes, vec![Outcome::Gone, Outcome::Delivered]);
+        assert_eq!(FAKE_PUSH.requests(names[0]).len(), 1);
+        assert_eq!(FAKE_PUSH.requests(names[1]).len(), 1);
+    }
+}
@@ -0,0 +1,85 @@
+use rand::Rng;
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time,
+};
+
+/// The permit is released after this delay if the handshake hangs,
+/// so it doesn't block the other connections
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/**
+Permit to do a handshake, to release once the connection is established or has failed.
+*/
+#[derive(Debug, Clone, Default)]
+pub struct HandshakePermit(Arc<Mutex<Option<OwnedSemaphorePermit>>>);
+
+impl HandshakePermit {
+    pub fn release(&self) {
+        self.0.lock().unwrap().take();
+    }
+}
+
+/**
+Limits the concurrent handshakes, with a random delay before each one: a restart,
+or a network failure, doesn't open all the connections at the same time.
+*/
+pub struct HandshakeLimiter {
+    semaphore: Arc<Semaphore>,
+}
+
+impl HandshakeLimiter {
+    pub fn new(max_concurrent: usize) -> Self {
+        Self {
+            semaphore: Arc::new(Semaphore::new(max_concurrent.max(1))),
+        }
+    }
+
+    /**
+    Wait a random delay, up to [max_delay], then wait for a free slot.
+    */
+    pub async fn acquire(&self, max_delay: Duration) -> HandshakePermit {
+        if !max_delay.is_zero() {
+            time::sleep(max_delay.mul_f64(rand::rng().random_range(0.0..1.0))).await;
+        }
+        let permit = Arc::clone(&self.semaphore)
+            .acquire_owned()
+            .await
+            .expect("The semaphore is never closed");
+        let permit = HandshakePermit(Arc::new(Mutex::new(Some(permit))));
+        let timeout = permit.clone();
+        tokio::spawn(async move {
+            time::sleep(HANDSHAKE_TIMEOUT).await;
+            timeout.release();
+        });
+        permit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::FutureExt;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_limit() {
+        let limiter = HandshakeLimiter::new(2);
+        let first = limiter.acquire(Duration::ZERO).await;
+        let second = limiter.acquire(Duration::from_secs(10)).await;
+        assert!(limiter.acquire(Duration::ZERO).now_or_never().is_none());
+
+        first.release();
+        let third = limiter.acquire(Duration::ZERO).now_or_never();
+        assert!(third.is_some());
+        assert!(limiter.acquire(Duration::ZERO).now_or_never().is_none());
+
+        // A hanging handshake doesn't keep its permit
+        drop(second);
+        time::sleep(HANDSHAKE_TIMEOUT + Duration::from_secs(1)).await;
+        assert!(limiter.acquire(Duration::ZERO).now_or_never().is_some());
+    }
+}
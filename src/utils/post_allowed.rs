@@ -3,6 +3,7 @@ use eyre::{eyre, Result};
 use lazy_static::lazy_static;
 use reqwest::dns::Addrs;
 use reqwest::{dns::Resolve, redirect::Policy};
+use rocket::serde::json::serde_json;
 use serde::Serialize;
 use std::net;
 use std::{
@@ -10,20 +11,31 @@ use std::{
     iter,
     net::{IpAddr, Ipv4Addr, SocketAddr},
     sync::Arc,
+    time::{Duration, SystemTime},
 };
 use trust_dns_resolver::{lookup_ip::LookupIp, TokioAsyncResolver};
 use url::{Host, Url};
 
-use crate::{config, vapid};
+use crate::{
+    config,
+    utils::{
+        proxy::Proxy,
+        push_policy::{Urgency, DEFAULT_TTL},
+    },
+    vapid,
+    webpush::WebPushKeys,
+};
 
 lazy_static! {
     static ref RESOLVER: TokioAsyncResolver = TokioAsyncResolver::tokio_from_system_conf().unwrap();
 }
 
 #[derive(Debug)]
-enum Error {
+pub enum Error {
     SchemeNotAllowed,
     HostNotAllowed,
+    /// The name of the host couldn't be resolved
+    ResolveFailed,
 }
 
 impl Display for Error {
@@ -34,6 +46,31 @@ impl Display for Error {
 
 impl std::error::Error for Error {}
 
+/**
+Headers of a push message, RFC 8030.
+*/
+#[derive(Debug, Clone)]
+pub struct PushHeaders<'a> {
+    /// Should override previous push messages with same topic
+    pub topic: Option<&'a str>,
+    /// In seconds
+    pub ttl: u32,
+    pub urgency: Urgency,
+}
+
+impl<'a> PushHeaders<'a> {
+    /**
+    Headers of an urgent message, kept 30 days by the push server.
+    */
+    pub fn new(topic: Option<&'a str>) -> Self {
+        PushHeaders {
+            topic,
+            ttl: DEFAULT_TTL,
+            urgency: Urgency::High,
+        }
+    }
+}
+
 struct ResolveNothing;
 
 impl Resolve for ResolveNothing {
@@ -46,55 +83,38 @@ impl Resolve for ResolveNothing {
     }
 }
 
+/**
+Send a push message to [url].
+
+If the [keys] of the push subscription are known, the body is encrypted
+with RFC 8291. Else it is sent in cleartext.
+
+The VAPID header is signed with [vapid_key], the key the push subscription
+was created with, or the primary key.
+
+The request fails if the push server doesn't respond within push_timeout.
+*/
 pub async fn post_allowed<T: Serialize + ?Sized>(
     url: Url,
     body: &T,
-    topic: Option<&str>,
+    headers: &PushHeaders<'_>,
+    keys: Option<&WebPushKeys>,
+    vapid_key: Option<&str>,
 ) -> Result<reqwest::Response> {
-    let port = match url.port() {
-        Some(p) => p,
-        None if url.scheme() == "http" => 80,
-        None if url.scheme() == "https" => 443,
-        _ => return Err(eyre!(Error::SchemeNotAllowed)),
-    };
-
-    let client = if config::is_endpoint_allowed_by_user(&url) {
-        reqwest::ClientBuilder::new().redirect(Policy::none())
-    } else {
-        let resolved_socket_addrs = url
-            .resolve_allowed()
-            .await?
-            .into_iter()
-            .map(|ip| SocketAddr::new(ip, port))
-            .collect::<Vec<SocketAddr>>();
-
-        if resolved_socket_addrs.is_empty() {
-            log::info!(
-                "Ignoring request to {}: no allowed ip",
-                url.host_str().unwrap_or("No host")
-            );
-            return Err(eyre!(Error::HostNotAllowed));
-        }
-
-        reqwest::ClientBuilder::new()
-            .redirect(Policy::none())
-            .dns_resolver(Arc::new(ResolveNothing))
-            .resolve_to_addrs(url.host_str().unwrap(), &resolved_socket_addrs)
-    }
-    .build()
-    .unwrap();
+    let client = build_client(&url, config::get_push_proxy().as_ref()).await?;
 
     // That's OK to generate a new VAPID header for each request
     // It doesn't do too many calculations, and we push at most once per seconde.
-    let vapid = vapid::get_vapid_header(url.origin()).ok();
+    let vapid = vapid::get_vapid_header(url.origin(), vapid_key).ok();
 
     let mut builder = client
         .post(url)
-        .header("TTL", "2592000") // 30 days
-        .header("Content-Encoding", "aes128gcm") // Fake this encoding to be web push compliant
-        .header("Urgency", "high");
-    builder = if let Some(topic) = topic {
-        builder.header("Topic", topic) // Should override previous push messages with same topic
+        .timeout(config::get_push_timeout())
+        .header("TTL", headers.ttl)
+        .header("Content-Encoding", "aes128gcm")
+        .header("Urgency", headers.urgency.as_str());
+    builder = if let Some(topic) = headers.topic {
+        builder.header("Topic", topic)
     } else {
         builder
     };
@@ -103,7 +123,119 @@ pub async fn post_allowed<T: Serialize + ?Sized>(
     } else {
         builder
     };
-    Ok(builder.json(&body).send().await?)
+    builder = if let Some(keys) = keys {
+        let body = keys.encrypt(&serde_json::to_vec(body)?)?;
+        builder
+            .header("Content-Type", "application/octet-stream")
+            .body(body)
+    } else {
+        // The content encoding is faked to be web push compliant
+        builder.json(&body)
+    };
+    Ok(builder.send().await?)
+}
+
+/**
+Build the client for [url]: if the endpoint isn't explicitly allowed, the client
+can only connect to the global IPs the host resolves to.
+*/
+async fn build_client(url: &Url, proxy: Option<&Proxy>) -> Result<reqwest::Client> {
+    let port = match url.port() {
+        Some(p) => p,
+        None if url.scheme() == "http" => 80,
+        None if url.scheme() == "https" => 443,
+        _ => return Err(eyre!(Error::SchemeNotAllowed)),
+    };
+
+    if config::is_endpoint_allowed_by_user(url) {
+        let mut builder = reqwest::ClientBuilder::new().redirect(Policy::none());
+        if let Some(proxy) = proxy {
+            builder = builder.proxy(proxy.to_reqwest(false)?);
+        }
+        return Ok(builder.build()?);
+    }
+
+    let resolved_socket_addrs = url
+        .resolve_allowed()
+        .await?
+        .into_iter()
+        .map(|ip| SocketAddr::new(ip, port))
+        .collect::<Vec<SocketAddr>>();
+
+    if resolved_socket_addrs.is_empty() {
+        log::info!(
+            "Ignoring request to {}: no allowed ip",
+            url.host_str().unwrap_or("No host")
+        );
+        return Err(eyre!(Error::HostNotAllowed));
+    }
+    pinned_client(url, &resolved_socket_addrs, proxy)
+}
+
+/**
+Client connecting to the host of [url] only with [addrs], the addresses already checked.
+
+With a [proxy], the proxy connects to these addresses: a socks5h proxy is used
+as a socks5 one. The push_proxy can't be an HTTP proxy, it would resolve the name
+of the host again.
+*/
+fn pinned_client(
+    url: &Url,
+    addrs: &[SocketAddr],
+    proxy: Option<&Proxy>,
+) -> Result<reqwest::Client> {
+    let host = url.host_str().ok_or(Error::HostNotAllowed)?;
+    let mut builder = reqwest::ClientBuilder::new()
+        .redirect(Policy::none())
+        .resolve_to_addrs(host, addrs);
+    match proxy {
+        Some(proxy) => builder = builder.proxy(proxy.to_reqwest(true)?),
+        // The proxy host must still be resolved
+        None => builder = builder.dns_resolver(Arc::new(ResolveNothing)),
+    }
+    Ok(builder.build()?)
+}
+
+/**
+Whether a push can be sent again later.
+*/
+#[derive(Debug, PartialEq, Eq)]
+pub enum Retryable {
+    No,
+    /// The push server couldn't be reached, or responded with a 5xx or a 429.
+    /// Contains the delay requested with `Retry-After`, if any.
+    Yes(Option<Duration>),
+}
+
+impl From<&Result<reqwest::Response>> for Retryable {
+    fn from(res: &Result<reqwest::Response>) -> Self {
+        match res {
+            Ok(resp) if resp.status().is_server_error() || resp.status() == 429 => {
+                Retryable::Yes(retry_after(resp))
+            }
+            Ok(_) => Retryable::No,
+            Err(e) => match e.downcast_ref::<reqwest::Error>() {
+                Some(e) if e.is_connect() || e.is_timeout() || e.is_request() => {
+                    Retryable::Yes(None)
+                }
+                _ => Retryable::No,
+            },
+        }
+    }
+}
+
+/**
+Parse the `Retry-After` header: either a number of seconds or an HTTP date.
+*/
+fn retry_after(resp: &reqwest::Response) -> Option<Duration> {
+    let value = resp.headers().get("Retry-After")?.to_str().ok()?.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    httpdate::parse_http_date(value)
+        .ok()?
+        .duration_since(SystemTime::now())
+        .ok()
 }
 
 #[async_trait]
@@ -133,7 +265,7 @@ impl ResolveAllowed for Host<&str> {
                 RESOLVER
                     .lookup_ip(*d)
                     .await
-                    .map_err(|_| Error::HostNotAllowed)?
+                    .map_err(|_| Error::ResolveFailed)?
                     .resolve_allowed()
                     .await
             }
@@ -156,6 +288,7 @@ mod tests {
     use rocket::serde::json::serde_json::json;
 
     use super::*;
+    use crate::test_support::{self, FakeProxy, FAKE_PUSH};
     use std::str::FromStr;
 
     async fn len_from_str(url: &str) -> usize {
@@ -169,37 +302,125 @@ mod tests {
 
     #[tokio::test]
     async fn test_post() {
-        config::load_config(None);
+        test_support::load_config();
         post_allowed(
             Url::from_str("https://httpbin.org/post").unwrap(),
             &json!({"urgent": true}),
+            &PushHeaders::new(None),
+            None,
             None,
         )
         .await
         .unwrap();
     }
 
-    /*
     #[tokio::test]
     async fn test_post_localhost() {
-        env::set_var("MOLLY_ALLOWED_ENDPOINTS", "[\"http://127.0.0.1:8001\"]");
-        env::set_var(
-            "MOLLY_VAPID_PRIVKEY",
-            "DSqYuWchrB6yIMYJtidvqANeRQic4uWy34afzZRsZnI",
-        );
-        config::load_config(None);
-        post_allowed(
-            Url::from_str("http://127.0.0.1:8001/test").unwrap(),
+        test_support::load_config();
+        let name = test_support::new_uuid();
+        let resp = post_allowed(
+            Url::from_str(&FAKE_PUSH.endpoint(&name)).unwrap(),
             &json!({"urgent": true}),
+            &PushHeaders {
+                topic: Some("mollysocket"),
+                ttl: 3600,
+                urgency: Urgency::Low,
+            },
+            None,
             None,
         )
         .await
         .unwrap();
-    }*/
+        assert_eq!(resp.status(), 201);
+        let requests = FAKE_PUSH.requests(&name);
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].headers["topic"], "mollysocket");
+        assert_eq!(requests[0].headers["ttl"], "3600");
+        assert_eq!(requests[0].headers["urgency"], "low");
+        assert_eq!(requests[0].body, br#"{"urgent":true}"#);
+    }
+
+    #[tokio::test]
+    async fn test_post_through_proxy() {
+        test_support::load_config();
+        let fake_proxy = FakeProxy::start();
+        let name = test_support::new_uuid();
+        let url = Url::from_str(&FAKE_PUSH.endpoint(&name)).unwrap();
+        for scheme in ["socks5", "socks5h"] {
+            let proxy = Proxy::from_str(&fake_proxy.url(scheme, None)).unwrap();
+            let client = build_client(&url, Some(&proxy)).await.unwrap();
+            let resp = client.post(url.clone()).body("{}").send().await.unwrap();
+            assert_eq!(resp.status(), 201);
+        }
+        assert_eq!(FAKE_PUSH.requests(&name).len(), 2);
+        assert_eq!(
+            fake_proxy.targets(),
+            vec![format!("127.0.0.1:{}", url.port().unwrap()); 2]
+        );
+
+        // The private IPs are still refused
+        let proxy = Proxy::from_str(&fake_proxy.url("socks5h", None)).unwrap();
+        let url = Url::from_str("http://10.10.1.1/").unwrap();
+        assert!(build_client(&url, Some(&proxy)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_proxy_does_not_resolve() {
+        test_support::load_config();
+        let fake_proxy = FakeProxy::start();
+        let name = test_support::new_uuid();
+        let port = Url::parse(&FAKE_PUSH.origin).unwrap().port().unwrap();
+
+        // A name resolving to a private IP is refused, before reaching the proxy
+        let url = Url::parse(&format!("http://localhost:{}/{}", port, name)).unwrap();
+        for scheme in ["socks5", "socks5h"] {
+            let proxy = Proxy::from_str(&fake_proxy.url(scheme, None)).unwrap();
+            assert!(build_client(&url, Some(&proxy)).await.is_err());
+        }
+        assert!(fake_proxy.targets().is_empty());
+
+        // The name isn't resolved again: the SOCKS5 proxy gets the checked IP
+        let url = Url::parse(&format!("http://push.invalid:{}/{}", port, name)).unwrap();
+        let addrs = [SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port)];
+        for scheme in ["socks5", "socks5h"] {
+            let proxy = Proxy::from_str(&fake_proxy.url(scheme, None)).unwrap();
+            let client = pinned_client(&url, &addrs, Some(&proxy)).unwrap();
+            let resp = client.post(url.clone()).body("{}").send().await.unwrap();
+            assert_eq!(resp.status(), 201);
+        }
+        assert_eq!(fake_proxy.targets(), vec![format!("127.0.0.1:{}", port); 2]);
+    }
+
+    #[test]
+    fn test_retryable() {
+        let resp = |status: u16, retry_after: Option<&str>| -> Result<reqwest::Response> {
+            let mut builder = http::Response::builder().status(status);
+            if let Some(retry_after) = retry_after {
+                builder = builder.header("Retry-After", retry_after);
+            }
+            Ok(builder.body("").unwrap().into())
+        };
+        assert_eq!(Retryable::from(&resp(201, None)), Retryable::No);
+        assert_eq!(Retryable::from(&resp(404, None)), Retryable::No);
+        assert_eq!(Retryable::from(&resp(503, None)), Retryable::Yes(None));
+        assert_eq!(
+            Retryable::from(&resp(429, Some("120"))),
+            Retryable::Yes(Some(Duration::from_secs(120)))
+        );
+        let date = httpdate::fmt_http_date(SystemTime::now() + Duration::from_secs(3600));
+        match Retryable::from(&resp(503, Some(&date))) {
+            Retryable::Yes(Some(d)) => assert!(d > Duration::from_secs(3500)),
+            r => panic!("Unexpected {:?}", r),
+        }
+        assert_eq!(
+            Retryable::from(&Err(eyre!(Error::HostNotAllowed))),
+            Retryable::No
+        );
+    }
 
     #[tokio::test]
     async fn test_not_allowed() {
-        config::load_config(None);
+        test_support::load_config();
         assert_eq!(len_from_str("unix://signal.org").await, 0);
         assert_eq!(len_from_str("http://127.1").await, 0);
         assert_eq!(len_from_str("http://localhost").await, 0);
@@ -210,7 +431,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_allowed() {
-        config::load_config(None);
+        test_support::load_config();
         assert!(len_from_str("http://signal.org").await.gt(&0));
         assert!(len_from_str("http://signal.org:8080").await.gt(&0));
         assert!(len_from_str("https://signal.org").await.gt(&0));
@@ -0,0 +1,242 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::{Display, Formatter},
+    str::FromStr,
+    time::Duration,
+};
+
+/// Default TTL of the push messages: 30 days
+pub const DEFAULT_TTL: u32 = 2592000;
+/// Value of the urgency mappings to not send any push message
+const NO_PUSH: &str = "none";
+/// Urgency of the deferred push for the non-urgent envelopes
+pub const DEFERRED_URGENCY: Urgency = Urgency::Low;
+
+#[derive(Debug)]
+pub enum Error {
+    InvalidUrgency(String),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::InvalidUrgency(s) => write!(
+                f,
+                "Invalid urgency: {}, expected none, very-low, low, normal or high",
+                s
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/**
+Urgency header of a push message, RFC 8030. The distributor may
+delay the low urgency messages to save battery.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Urgency {
+    VeryLow,
+    Low,
+    Normal,
+    High,
+}
+
+impl Urgency {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Urgency::VeryLow => "very-low",
+            Urgency::Low => "low",
+            Urgency::Normal => "normal",
+            Urgency::High => "high",
+        }
+    }
+}
+
+impl Display for Urgency {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for Urgency {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "very-low" => Ok(Urgency::VeryLow),
+            "low" => Ok(Urgency::Low),
+            "normal" => Ok(Urgency::Normal),
+            "high" => Ok(Urgency::High),
+            _ => Err(Error::InvalidUrgency(s.into())),
+        }
+    }
+}
+
+/**
+Parse the urgency of the push messages sent for a kind of envelope:
+`none` if they don't trigger any push message.
+*/
+pub fn parse_urgency_mapping(s: &str) -> Result<Option<Urgency>, Error> {
+    if s == NO_PUSH {
+        Ok(None)
+    } else {
+        s.parse().map(Some)
+    }
+}
+
+/**
+How the envelopes of a connection are turned into push messages.
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PushPolicy {
+    /// A push message isn't sent if another one, as urgent, has been sent during this time
+    pub debounce: Duration,
+    /// TTL of the push messages, in seconds
+    pub ttl: u32,
+    /// Urgency of the push messages for the urgent envelopes, None to not push them
+    pub urgent: Option<Urgency>,
+    /// Urgency of the push messages for the other envelopes, None to not push them
+    pub non_urgent: Option<Urgency>,
+    /// If the other envelopes aren't pushed: a low urgency push is sent after this
+    /// delay, unless another push is sent before. Disabled if zero
+    pub non_urgent_delay: Duration,
+    /// Topic of the push messages: a distributor keeps only the last one of a topic
+    pub topic: Option<String>,
+}
+
+impl PushPolicy {
+    /**
+    Urgency of the push message for an envelope, None if it doesn't trigger a push.
+    */
+    pub fn urgency(&self, urgent: bool) -> Option<Urgency> {
+        if urgent {
+            self.urgent
+        } else {
+            self.non_urgent
+        }
+    }
+
+    /**
+    Urgency of the push message for an envelope, sent now or deferred,
+    None if it isn't pushed at all.
+    */
+    pub fn push_urgency(&self, urgent: bool) -> Option<Urgency> {
+        self.urgency(urgent)
+            .or_else(|| (!urgent && !self.non_urgent_delay.is_zero()).then_some(DEFERRED_URGENCY))
+    }
+
+    /**
+    Apply the overrides of a connection. They are validated before being saved,
+    an invalid urgency is ignored.
+    */
+    pub fn with_override(mut self, o: &PushPolicyOverride) -> Self {
+        if let Some(debounce) = o.debounce {
+            self.debounce = Duration::from_secs(debounce);
+        }
+        if let Some(ttl) = o.ttl {
+            self.ttl = ttl;
+        }
+        if let Some(Ok(urgent)) = o.urgent.as_deref().map(parse_urgency_mapping) {
+            self.urgent = urgent;
+        }
+        if let Some(Ok(non_urgent)) = o.non_urgent.as_deref().map(parse_urgency_mapping) {
+            self.non_urgent = non_urgent;
+        }
+        if let Some(delay) = o.non_urgent_delay {
+            self.non_urgent_delay = Duration::from_secs(delay);
+        }
+        if let Some(topic) = &o.topic {
+            self.topic = Some(topic.clone()).filter(|t| !t.is_empty());
+        }
+        self
+    }
+}
+
+/**
+Push policy of a connection, overriding the config when set.
+*/
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PushPolicyOverride {
+    /// In seconds
+    pub debounce: Option<u64>,
+    pub ttl: Option<u32>,
+    /// Urgency, or none
+    pub urgent: Option<String>,
+    /// Urgency, or none
+    pub non_urgent: Option<String>,
+    /// In seconds
+    pub non_urgent_delay: Option<u64>,
+    /// Empty for no topic
+    pub topic: Option<String>,
+}
+
+impl PushPolicyOverride {
+    pub fn validate(&self) -> Result<(), Error> {
+        for urgency in [&self.urgent, &self.non_urgent].into_iter().flatten() {
+            parse_urgency_mapping(urgency)?;
+        }
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == PushPolicyOverride::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_urgency() {
+        assert_eq!("very-low".parse::<Urgency>().unwrap(), Urgency::VeryLow);
+        assert!(Urgency::High > Urgency::Normal);
+        assert!(Urgency::Low > Urgency::VeryLow);
+        assert_eq!(parse_urgency_mapping("none").unwrap(), None);
+        assert_eq!(parse_urgency_mapping("low").unwrap(), Some(Urgency::Low));
+        assert!(parse_urgency_mapping("urgent").is_err());
+    }
+
+    #[test]
+    fn test_override() {
+        let policy = PushPolicy {
+            debounce: Duration::from_secs(1),
+            ttl: DEFAULT_TTL,
+            urgent: Some(Urgency::High),
+            non_urgent: None,
+            non_urgent_delay: Duration::from_secs(900),
+            topic: Some("mollysocket".into()),
+        };
+        assert_eq!(
+            policy.clone().with_override(&PushPolicyOverride::default()),
+            policy
+        );
+        assert_eq!(policy.urgency(false), None);
+        assert_eq!(policy.push_urgency(false), Some(DEFERRED_URGENCY));
+        let o = PushPolicyOverride {
+            debounce: Some(10),
+            ttl: None,
+            urgent: Some("normal".into()),
+            non_urgent: Some("very-low".into()),
+            non_urgent_delay: Some(0),
+            topic: Some("".into()),
+        };
+        assert!(o.validate().is_ok());
+        let overridden = policy.with_override(&o);
+        assert_eq!(overridden.debounce, Duration::from_secs(10));
+        assert_eq!(overridden.ttl, DEFAULT_TTL);
+        assert_eq!(overridden.urgency(true), Some(Urgency::Normal));
+        assert_eq!(overridden.urgency(false), Some(Urgency::VeryLow));
+        assert_eq!(overridden.non_urgent_delay, Duration::ZERO);
+        assert_eq!(overridden.push_urgency(false), Some(Urgency::VeryLow));
+        assert_eq!(overridden.topic, None);
+        assert!(PushPolicyOverride {
+            non_urgent: Some("later".into()),
+            ..Default::default()
+        }
+        .validate()
+        .is_err());
+    }
+}
@@ -0,0 +1,173 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+    time::Instant,
+};
+
+/// Above this number of keys, the least recently used buckets are removed
+const MAX_KEYS: usize = 10_000;
+
+/**
+Token bucket: up to [RateLimit::burst] requests at once, then
+[RateLimit::per_minute] requests per minute. Disabled if per_minute is 0.
+*/
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+    pub per_minute: u32,
+    pub burst: u32,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    /// Position of the bucket in [Buckets::lru]
+    last_use: u64,
+}
+
+impl Bucket {
+    fn refill(&mut self, limit: &RateLimit, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens =
+            (self.tokens + elapsed * limit.per_minute as f64 / 60.0).min(limit.burst.max(1) as f64);
+        self.updated = now;
+    }
+}
+
+#[derive(Debug, Default)]
+struct Buckets {
+    by_key: HashMap<String, Bucket>,
+    /// The keys, from the least recently used
+    lru: BTreeMap<u64, String>,
+    uses: u64,
+}
+
+/**
+Rate limiter with a token bucket per key, like an IP or a uuid. It keeps at most
+[MAX_KEYS] buckets: the least recently used one is removed for a new key.
+*/
+#[derive(Debug)]
+pub struct RateLimiter {
+    buckets: Mutex<Buckets>,
+    max_keys: usize,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::with_max_keys(MAX_KEYS)
+    }
+}
+
+impl RateLimiter {
+    fn with_max_keys(max_keys: usize) -> Self {
+        RateLimiter {
+            buckets: Mutex::new(Buckets::default()),
+            max_keys: max_keys.max(1),
+        }
+    }
+
+    /**
+    Take a token of the bucket of [key], returns false if it is empty.
+    */
+    pub fn check(&self, key: &str, limit: &RateLimit) -> bool {
+        self.check_at(key, limit, Instant::now())
+    }
+
+    fn check_at(&self, key: &str, limit: &RateLimit, now: Instant) -> bool {
+        if limit.per_minute == 0 {
+            return true;
+        }
+        let burst = limit.burst.max(1) as f64;
+        let mut guard = self.buckets.lock().unwrap();
+        let buckets = &mut *guard;
+        buckets.uses += 1;
+        let last_use = buckets.uses;
+        if let Some(bucket) = buckets.by_key.get_mut(key) {
+            buckets.lru.remove(&bucket.last_use);
+            bucket.last_use = last_use;
+        } else {
+            while buckets.by_key.len() >= self.max_keys {
+                match buckets.lru.pop_first() {
+                    Some((_, oldest)) => buckets.by_key.remove(&oldest),
+                    None => break,
+                };
+            }
+            buckets.by_key.insert(
+                key.into(),
+                Bucket {
+                    tokens: burst,
+                    updated: now,
+                    last_use,
+                },
+            );
+        }
+        buckets.lru.insert(last_use, key.into());
+        let bucket = buckets.by_key.get_mut(key).unwrap();
+        bucket.refill(limit, now);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_rate_limit() {
+        let limiter = RateLimiter::default();
+        let limit = RateLimit {
+            per_minute: 6,
+            burst: 2,
+        };
+        let now = Instant::now();
+        assert!(limiter.check_at("a", &limit, now));
+        assert!(limiter.check_at("a", &limit, now));
+        assert!(!limiter.check_at("a", &limit, now));
+        // The keys have their own bucket
+        assert!(limiter.check_at("b", &limit, now));
+        // A token every 10 seconds
+        assert!(!limiter.check_at("a", &limit, now + Duration::from_secs(5)));
+        assert!(limiter.check_at("a", &limit, now + Duration::from_secs(11)));
+        assert!(!limiter.check_at("a", &limit, now + Duration::from_secs(11)));
+        // The bucket doesn't exceed the burst
+        let later = now + Duration::from_secs(3600);
+        assert!(limiter.check_at("a", &limit, later));
+        assert!(limiter.check_at("a", &limit, later));
+        assert!(!limiter.check_at("a", &limit, later));
+
+        let disabled = RateLimit {
+            per_minute: 0,
+            burst: 0,
+        };
+        assert!((0..100).all(|_| limiter.check_at("a", &disabled, later)));
+    }
+
+    #[test]
+    fn test_max_keys() {
+        let limiter = RateLimiter::with_max_keys(2);
+        let limit = RateLimit {
+            per_minute: 1,
+            burst: 1,
+        };
+        let now = Instant::now();
+        assert!(limiter.check_at("a", &limit, now));
+        assert!(limiter.check_at("b", &limit, now));
+        // "a" is used again, "b" is the least recently used
+        assert!(!limiter.check_at("a", &limit, now));
+        assert!(limiter.check_at("c", &limit, now));
+        let buckets = limiter.buckets.lock().unwrap();
+        assert_eq!(buckets.by_key.len(), 2);
+        assert_eq!(buckets.lru.len(), 2);
+        assert!(!buckets.by_key.contains_key("b"));
+        drop(buckets);
+        assert!(!limiter.check_at("a", &limit, now));
+        // A new bucket for "b"
+        assert!(limiter.check_at("b", &limit, now));
+    }
+}
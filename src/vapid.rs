@@ -2,8 +2,8 @@ use std::{
     collections::HashMap,
     fmt::{Display, Formatter},
     ops::Add,
-    sync::{Arc, Mutex},
-    time::{Duration, Instant},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant, SystemTime},
 };
 
 use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
@@ -19,10 +19,13 @@ use openssl::{
     nid::Nid,
 };
 
-use crate::config;
+use crate::{
+    config,
+    db::{self, MollySocketDb, OptTime, VapidKey, VapidKeyStatus},
+};
 
 lazy_static! {
-    static ref KEY: Option<SignerWithPubKey> = get_signer_from_conf().ok();
+    static ref KEYRING: RwLock<Keyring> = RwLock::new(load_keyring());
     /** Cache of VAPID keys */
     static ref VAPID_CACHE: Arc<Mutex<HashMap<String, VapidCache>>> = Arc::new(Mutex::new(HashMap::new()));
 }
@@ -38,49 +41,245 @@ struct SignerWithPubKey {
     pubkey: String,
 }
 
+/**
+The keys that can sign: the primary key, and the accepting ones.
+*/
+#[derive(Default)]
+struct Keyring {
+    primary: Option<Arc<SignerWithPubKey>>,
+    /// Signing keys by public key, the primary key included
+    keys: HashMap<String, Arc<SignerWithPubKey>>,
+}
+
+impl Keyring {
+    /**
+    Get the key [pubkey], or the primary key if it isn't set, or if it
+    isn't in the keyring anymore.
+    */
+    fn get_key(&self, pubkey: Option<&str>) -> Result<Arc<SignerWithPubKey>> {
+        if let Some(pubkey) = pubkey {
+            match self.keys.get(pubkey) {
+                Some(key) => return Ok(key.clone()),
+                None => log::debug!("VAPID key {} not found, using the primary key", pubkey),
+            }
+        }
+        Ok(self.primary.clone().ok_or(Error::VapidKeyError)?)
+    }
+
+    fn pubkeys(&self) -> (Option<&str>, Vec<&String>) {
+        let mut keys: Vec<_> = self.keys.keys().collect();
+        keys.sort();
+        (self.primary.as_ref().map(|k| k.pubkey.as_str()), keys)
+    }
+}
+
 struct VapidCache {
     header: String,
     expire: Instant,
 }
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     VapidKeyError,
+    /// The public key isn't in the keyring
+    UnknownKey(String),
+    /// The primary key can't be retired, a new one must be added first
+    RetirePrimary,
+    /// This number of connections are still registered with the key
+    KeyInUse(i64),
 }
 
 impl Display for Error {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        // We have a single kind of error: VapidKeyError
-        write!(f, "VAPID key is probably missing. See https://github.com/mollyim/mollysocket?tab=readme-ov-file#vapid-key")
+        match self {
+            Error::VapidKeyError => write!(f, "VAPID key is probably missing. See https://github.com/mollyim/mollysocket?tab=readme-ov-file#vapid-key"),
+            Error::UnknownKey(pubkey) => write!(f, "Unknown VAPID key: {}", pubkey),
+            Error::RetirePrimary => write!(f, "The primary VAPID key can't be retired, rotate the keys first"),
+            Error::KeyInUse(n) => write!(f, "The VAPID key is still used by {} connection(s)", n),
+        }
     }
 }
 
 impl std::error::Error for Error {}
 
-pub fn get_vapid_pubkey() -> Result<&'static str> {
-    let key = KEY.as_ref().ok_or(Error::VapidKeyError)?;
-    Ok(&key.pubkey)
+/**
+Get the public key of the primary key, advertised to the new registrations.
+*/
+pub fn get_vapid_pubkey() -> Result<String> {
+    let key = get_key(None)?;
+    Ok(key.pubkey.clone())
+}
+
+fn get_key(pubkey: Option<&str>) -> Result<Arc<SignerWithPubKey>> {
+    KEYRING.read().unwrap().get_key(pubkey)
 }
 
 /**
-Generate VAPID header for origin.
+Check [pubkey] is the primary key or an accepting key.
 */
-pub fn get_vapid_header(origin: url::Origin) -> Result<String> {
-    let key = KEY.as_ref().ok_or(Error::VapidKeyError)?;
-    if let Some(h) = get_vapid_header_from_cache(&origin) {
+pub fn is_active_key(pubkey: &str) -> bool {
+    KEYRING.read().unwrap().keys.contains_key(pubkey)
+}
+
+/**
+Load the keyring again, after the config has been reloaded, or
+the keyring has been updated with the CLI.
+
+The cached headers are removed, as they may have been signed with a retired key.
+*/
+pub fn reload_keyring() {
+    let keyring = load_keyring();
+    let changed = KEYRING.read().unwrap().pubkeys() != keyring.pubkeys();
+    if changed {
+        *KEYRING.write().unwrap() = keyring;
+        VAPID_CACHE.lock().unwrap().clear();
+        log::info!("VAPID keyring reloaded");
+    }
+}
+
+fn load_keyring() -> Keyring {
+    match MollySocketDb::new().and_then(|db| try_load_keyring(&db)) {
+        Ok(keyring) => keyring,
+        Err(e) => {
+            log::warn!("Could not load the VAPID keyring: {}", e);
+            Keyring::default()
+        }
+    }
+}
+
+fn try_load_keyring(db: &MollySocketDb) -> Result<Keyring> {
+    import_conf_key(db)?;
+    let mut keyring = Keyring::default();
+    for key in db.list_vapid_keys()? {
+        if key.status == VapidKeyStatus::Retired {
+            continue;
+        }
+        let signer = match get_signer(&db::decrypt_secret(&key.privkey, key.privkey_encrypted)?) {
+            Ok(signer) => Arc::new(signer),
+            Err(_) => {
+                log::warn!("Invalid VAPID key in the keyring: {}", key.pubkey);
+                continue;
+            }
+        };
+        if key.status == VapidKeyStatus::Primary {
+            log::info!("VAPID public key: {:?}", key.pubkey);
+            keyring.primary = Some(signer.clone());
+        }
+        keyring.keys.insert(key.pubkey, signer);
+    }
+    Ok(keyring)
+}
+
+/**
+Add the key of the config to the keyring, as the primary key, if it isn't already there.
+
+The first key of the keyring is the key of the connections registered before it.
+*/
+fn import_conf_key(db: &MollySocketDb) -> Result<()> {
+    let signer = match get_signer_from_conf() {
+        Ok(signer) => signer,
+        Err(_) => return Ok(()),
+    };
+    let keys = db.list_vapid_keys()?;
+    if keys.iter().any(|k| k.pubkey == signer.pubkey) {
+        return Ok(());
+    }
+    log::info!("Adding the VAPID key of the config to the keyring");
+    db.add_primary_vapid_key(&VapidKey {
+        pubkey: signer.pubkey.clone(),
+        privkey: config::get_vapid_privkey().unwrap_or_default(),
+        privkey_encrypted: false,
+        created: OptTime::from(SystemTime::now()),
+        status: VapidKeyStatus::Primary,
+    })?;
+    if keys.is_empty() {
+        db.set_legacy_vapid_key(&signer.pubkey)?;
+    }
+    Ok(())
+}
+
+/**
+Add a new primary key to the keyring. The previous primary key is now accepting:
+the connections registered with it keep using it.
+
+Returns the new public key.
+*/
+pub fn rotate() -> Result<String> {
+    rotate_key(&MollySocketDb::new()?)
+}
+
+fn rotate_key(db: &MollySocketDb) -> Result<String> {
+    import_conf_key(db)?;
+    let privkey = gen_vapid_key();
+    let pubkey = get_signer(&privkey)?.pubkey;
+    db.add_primary_vapid_key(&VapidKey {
+        pubkey: pubkey.clone(),
+        privkey,
+        privkey_encrypted: false,
+        created: OptTime::from(SystemTime::now()),
+        status: VapidKeyStatus::Primary,
+    })?;
+    Ok(pubkey)
+}
+
+/**
+Retire the accepting key [pubkey]. Unless [force], the key must not be used
+by any connection anymore.
+*/
+pub fn retire(pubkey: &str, force: bool) -> Result<()> {
+    retire_key(&MollySocketDb::new()?, pubkey, force)
+}
+
+fn retire_key(db: &MollySocketDb, pubkey: &str, force: bool) -> Result<()> {
+    let key = db
+        .list_vapid_keys()?
+        .into_iter()
+        .find(|k| k.pubkey == pubkey)
+        .ok_or(Error::UnknownKey(pubkey.into()))?;
+    if key.status == VapidKeyStatus::Primary {
+        return Err(eyre!(Error::RetirePrimary));
+    }
+    let n = db.count_vapid_key_connections(pubkey)?;
+    if n > 0 && !force {
+        return Err(eyre!(Error::KeyInUse(n)));
+    }
+    db.set_vapid_key_status(pubkey, VapidKeyStatus::Retired)
+}
+
+/**
+Check that [privkey] is a valid VAPID private key.
+*/
+pub fn check_privkey(privkey: &str) -> Result<()> {
+    get_signer(privkey).map(|_| ())
+}
+
+/**
+Generate VAPID header for origin, signed with the key [pubkey] or the primary key.
+*/
+pub fn get_vapid_header(origin: url::Origin, pubkey: Option<&str>) -> Result<String> {
+    let key = get_key(pubkey)?;
+    if let Some(h) = get_vapid_header_from_cache(&origin, &key) {
         return Ok(h);
     }
-    gen_vapid_header_with_key(origin, key)
+    gen_vapid_header_with_key(origin, &key)
+}
+
+/**
+The cached headers are per key and origin.
+*/
+fn cache_id(origin_str: &str, key: &SignerWithPubKey) -> String {
+    format!("{} {}", key.pubkey, origin_str)
 }
 
 /**
 Get VAPID header from cache if not expire
 */
-fn get_vapid_header_from_cache(origin: &url::Origin) -> Option<String> {
+fn get_vapid_header_from_cache(origin: &url::Origin, key: &SignerWithPubKey) -> Option<String> {
     let origin_str = origin.unicode_serialization();
     let now = Instant::now();
     let cache = VAPID_CACHE.lock().unwrap();
-    if let Some(c) = cache.get(&origin_str) {
+    if let Some(c) = cache.get(&cache_id(&origin_str, key)) {
         if c.expire > now {
             log::debug!("Found VAPID from cache");
             Some(c.header.clone())
@@ -93,10 +292,10 @@ fn get_vapid_header_from_cache(origin: &url::Origin) -> Option<String> {
     }
 }
 
-fn add_vapid_header_to_cache(origin_str: &str, header: &str) {
+fn add_vapid_header_to_cache(origin_str: &str, key: &SignerWithPubKey, header: &str) {
     let mut cache = VAPID_CACHE.lock().unwrap();
     cache.insert(
-        origin_str.into(),
+        cache_id(origin_str, key),
         VapidCache {
             header: header.into(),
             expire: Instant::now().add(Duration::from_secs(DURATION_VAPID_CACHE)),
@@ -112,7 +311,7 @@ fn gen_vapid_header_with_key(origin: url::Origin, key: &SignerWithPubKey) -> Res
     let token = key.signer.sign(claims).unwrap();
 
     let header = format!("vapid t={},k={}", token.as_str(), &key.pubkey);
-    add_vapid_header_to_cache(&origin_str, &header);
+    add_vapid_header_to_cache(&origin_str, key, &header);
     Ok(header)
 }
 
@@ -121,7 +320,7 @@ Get [SignerWithPubKey] from the config private key.
 */
 fn get_signer_from_conf() -> Result<SignerWithPubKey> {
     match config::get_vapid_privkey() {
-        Some(k) => get_signer(k),
+        Some(k) => get_signer(&k),
         None => Err(eyre!(Error::VapidKeyError)),
     }
 }
@@ -130,7 +329,9 @@ fn get_signer_from_conf() -> Result<SignerWithPubKey> {
 Get [SignerWithPubKey] from the private key.
 */
 fn get_signer(private_bytes: &str) -> Result<SignerWithPubKey> {
-    let private_key_bytes = URL_SAFE_NO_PAD.decode(private_bytes).unwrap();
+    let private_key_bytes = URL_SAFE_NO_PAD
+        .decode(private_bytes)
+        .map_err(|_| Error::VapidKeyError)?;
     let size = private_key_bytes.len();
     if size != 32 {
         if size == 0 {
@@ -146,7 +347,7 @@ fn get_signer(private_bytes: &str) -> Result<SignerWithPubKey> {
     let kp = ES256KeyPair::from_bytes(&private_key_bytes).unwrap();
     let pubkey = URL_SAFE_NO_PAD.encode(kp.public_key().public_key().to_bytes_uncompressed());
 
-    log::info!("VAPID public key: {:?}", pubkey);
+    log::debug!("VAPID public key: {:?}", pubkey);
     Ok(SignerWithPubKey { signer: kp, pubkey })
 }
 
@@ -162,6 +363,7 @@ pub fn gen_vapid_key() -> String {
 mod tests {
 
     use super::*;
+    use crate::{db::Connection, test_support};
 
     const TEST_PRIVKEY: &str = "DSqYuWchrB6yIMYJtidvqANeRQic4uWy34afzZRsZnI";
     const TEST_PUBKEY: &str =
@@ -192,6 +394,63 @@ mod tests {
         assert!(get_signer("").is_err());
     }
 
+    /**
+    Test the connections keep their key after a rotation, until it is retired.
+
+    The test has its own DB and keyring, the other tests use the primary key
+    of the shared DB.
+    */
+    #[test]
+    fn test_keyring() {
+        test_support::load_config();
+        let path = std::env::temp_dir().join(format!(
+            "mollysocket-test-keyring-{}.db",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let db = MollySocketDb::new_at(&path.display().to_string()).unwrap();
+        let first = rotate_key(&db).unwrap();
+        let second = rotate_key(&db).unwrap();
+        let keyring = try_load_keyring(&db).unwrap();
+        assert_eq!(keyring.get_key(None).unwrap().pubkey, second);
+        assert_eq!(keyring.get_key(Some(&first)).unwrap().pubkey, first);
+        assert!(keyring.keys.contains_key(&first));
+        assert!(matches!(
+            retire_key(&db, &second, false).unwrap_err().downcast_ref(),
+            Some(Error::RetirePrimary)
+        ));
+
+        let mut co = Connection::new(
+            test_support::new_uuid(),
+            1,
+            String::from("pass"),
+            String::from("http://0.0.0.0/"),
+            None,
+            None,
+        );
+        co.vapid_key = Some(first.clone());
+        db.add(&co).unwrap();
+        assert!(matches!(
+            retire_key(&db, &first, false).unwrap_err().downcast_ref(),
+            Some(Error::KeyInUse(1))
+        ));
+        retire_key(&db, &first, true).unwrap();
+        let keyring = try_load_keyring(&db).unwrap();
+        assert!(!keyring.keys.contains_key(&first));
+        assert_eq!(keyring.get_key(Some(&first)).unwrap().pubkey, second);
+
+        /* The private keys are encrypted, and rotated with the DB key */
+        let new_key = db::crypto::gen_key();
+        assert_eq!(db.rotate_db_key(None, &new_key).unwrap(), 3);
+        for key in db.list_vapid_keys().unwrap() {
+            assert!(key.privkey_encrypted);
+            let privkey = db::crypto::decrypt(&key.privkey, &new_key).unwrap();
+            assert_eq!(get_signer(&privkey).unwrap().pubkey, key.pubkey);
+        }
+        drop(db);
+        let _ = std::fs::remove_file(&path);
+    }
+
     /**
     To verify the signature with another tool. This must be run with --nocapture:
     `cargo test vapid_other_tool -- -nocapture`
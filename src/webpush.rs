@@ -0,0 +1,117 @@
+use std::fmt::{Display, Formatter};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use eyre::{eyre, Result};
+
+/// Size of an uncompressed P-256 public key
+const P256DH_LEN: usize = 65;
+/// Size of the authentication secret
+const AUTH_LEN: usize = 16;
+
+/**
+Keys of the push subscription, used to encrypt the push messages
+with RFC 8291 (Message Encryption for Web Push).
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebPushKeys {
+    p256dh: Vec<u8>,
+    auth: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// The keys are not valid base64url, have a wrong size, or
+    /// only one of p256dh and auth is given.
+    InvalidKeys,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl WebPushKeys {
+    /**
+    Parse the keys from their base64url representation, as sent by the
+    push subscription.
+    */
+    pub fn new(p256dh: &str, auth: &str) -> Result<Self> {
+        let p256dh = decode(p256dh)?;
+        let auth = decode(auth)?;
+        if p256dh.len() != P256DH_LEN || p256dh[0] != 0x04 || auth.len() != AUTH_LEN {
+            return Err(eyre!(Error::InvalidKeys));
+        }
+        Ok(Self { p256dh, auth })
+    }
+
+    /**
+    Parse the keys if they are both present.
+
+    Returns `None` if no key is present, so the push messages are
+    sent unencrypted, and an error if only one of them is present.
+    */
+    pub fn from_options(p256dh: Option<&str>, auth: Option<&str>) -> Result<Option<Self>> {
+        match (p256dh, auth) {
+            (Some(p256dh), Some(auth)) => Ok(Some(Self::new(p256dh, auth)?)),
+            (None, None) => Ok(None),
+            _ => Err(eyre!(Error::InvalidKeys)),
+        }
+    }
+
+    /**
+    Encrypt [data] with the aes128gcm content encoding (RFC 8188).
+    */
+    pub fn encrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
+        ece::encrypt(&self.p256dh, &self.auth, data).map_err(|e| eyre!(e))
+    }
+}
+
+fn decode(key: &str) -> Result<Vec<u8>> {
+    URL_SAFE_NO_PAD
+        .decode(key.trim_end_matches('='))
+        .map_err(|_| eyre!(Error::InvalidKeys))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /**
+    Test the encrypted message can be decrypted by the subscriber.
+    */
+    #[test]
+    fn test_encrypt() {
+        let (keypair, auth) = ece::generate_keypair_and_auth_secret().unwrap();
+        let keys = WebPushKeys::new(
+            &URL_SAFE_NO_PAD.encode(keypair.pub_as_raw().unwrap()),
+            &URL_SAFE_NO_PAD.encode(auth),
+        )
+        .unwrap();
+        let data = b"{\"urgent\":true}";
+        let encrypted = keys.encrypt(data).unwrap();
+        assert_ne!(&encrypted[..], &data[..]);
+        let decrypted =
+            ece::decrypt(&keypair.raw_components().unwrap(), &auth, &encrypted).unwrap();
+        assert_eq!(&decrypted[..], &data[..]);
+    }
+
+    /**
+    Test wrong keys are refused.
+    */
+    #[test]
+    fn test_invalid_keys() {
+        let (keypair, auth) = ece::generate_keypair_and_auth_secret().unwrap();
+        let p256dh = URL_SAFE_NO_PAD.encode(keypair.pub_as_raw().unwrap());
+        let auth = URL_SAFE_NO_PAD.encode(auth);
+        assert!(WebPushKeys::new(&auth, &p256dh).is_err());
+        assert!(WebPushKeys::new("not base64", &auth).is_err());
+        assert!(WebPushKeys::from_options(Some(&p256dh), None).is_err());
+        assert!(WebPushKeys::from_options(None, None).unwrap().is_none());
+        assert!(WebPushKeys::from_options(Some(&p256dh), Some(&auth))
+            .unwrap()
+            .is_some());
+    }
+}
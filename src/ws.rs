@@ -1,8 +1,13 @@
-mod proto_signalservice;
-mod proto_websocketresources;
+#[allow(dead_code, clippy::all)]
+pub(crate) mod proto_signalservice;
+#[allow(dead_code, clippy::all)]
+pub(crate) mod proto_websocketresources;
 mod signalwebsocket;
 mod tls;
 mod websocket_connection;
 
+pub use signalwebsocket::CloseHandle;
 pub use signalwebsocket::Error as SignalWebSocketError;
+pub use signalwebsocket::PushResult;
 pub use signalwebsocket::SignalWebSocket;
+pub use signalwebsocket::StatusEvent;
@@ -1,15 +1,17 @@
 use async_trait::async_trait;
 use eyre::{eyre, Result};
 use futures_channel::mpsc;
+use futures_util::{select, FutureExt};
 use http::StatusCode;
+use lazy_static::lazy_static;
 use prost::Message;
 use rocket::serde::json::serde_json::json;
 use std::{
     fmt::{Display, Formatter},
     sync::{Arc, Mutex},
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime},
 };
-use tokio::time;
+use tokio::{sync::watch, time};
 use tokio_tungstenite::tungstenite;
 
 use super::tls;
@@ -21,13 +23,23 @@ use super::{
         WebSocketResponseMessage,
     },
 };
-use crate::{config, utils::post_allowed::post_allowed};
+use crate::{
+    config,
+    db::{self, PushEndpoint},
+    utils::{
+        backoff::Failure,
+        delivery::{self, deliver, Attempt, DeliveryMode, Outcome, Target},
+        limiter::{HandshakeLimiter, HandshakePermit},
+        post_allowed::{PushHeaders, Retryable},
+        push_policy::{PushPolicy, Urgency, DEFERRED_URGENCY},
+    },
+};
+
+lazy_static! {
+    /** Limits the handshakes of all the connections, at startup and when reconnecting */
+    static ref HANDSHAKES: HandshakeLimiter = HandshakeLimiter::new(config::get_max_concurrent_handshakes());
+}
 
-/// Time between 2 regular push notifications
-///
-/// We don't need to send a push notif per message, as
-/// the client stay connected for more than 10secs on push
-const PUSH_TIMEOUT: Duration = Duration::from_secs(1);
 /// Time between last push notification and a delivery check
 ///
 /// A delivery check is a push notif used to control the HTTP reponse of the push endpoint,
@@ -41,7 +53,9 @@ pub struct Channels {
     ws_tx: Option<mpsc::UnboundedSender<tungstenite::Message>>,
     pub on_message_tx: Option<mpsc::UnboundedSender<u32>>,
     pub on_push_tx: Option<mpsc::UnboundedSender<u32>>,
+    pub on_push_result_tx: Option<mpsc::UnboundedSender<PushResult>>,
     pub on_reconnection_tx: Option<mpsc::UnboundedSender<u32>>,
+    pub on_status_tx: Option<mpsc::UnboundedSender<StatusEvent>>,
 }
 
 impl Channels {
@@ -50,16 +64,106 @@ impl Channels {
             ws_tx: None,
             on_message_tx: None,
             on_push_tx: None,
+            on_push_result_tx: None,
             on_reconnection_tx: None,
+            on_status_tx: None,
         }
     }
 }
 
+/// Result of a push notification, used to send it again if it failed
+#[derive(Debug)]
+pub enum PushResult {
+    /// The push server accepted the notification
+    Sent { topic: String },
+    /// The notification can be sent again later
+    Failed {
+        topic: String,
+        /// JSON body of the notification
+        body: String,
+        /// Delay requested by the push server
+        retry_after: Option<Duration>,
+        /// Urgency the notification was sent with
+        urgency: Urgency,
+    },
+    /// The deferred notification hasn't been sent when the connection ended
+    Deferred {
+        topic: String,
+        /// JSON body of the notification
+        body: String,
+        /// Delay before the notification is due
+        delay: Duration,
+        urgency: Urgency,
+    },
+}
+
+/// Event changing the status of the connection
+#[derive(Debug)]
+pub enum StatusEvent {
+    Connecting,
+    Connected,
+    /// The websocket has been closed, with the error if any
+    Disconnected {
+        error: Option<String>,
+    },
+    Envelope,
+    /// A push notification has been sent to an endpoint
+    Push {
+        endpoint: String,
+        /// HTTP status, None if the push server wasn't reached
+        status: Option<u16>,
+        error: Option<String>,
+    },
+    /// The push subscription of the endpoint doesn't exist anymore: it is pruned
+    EndpointRemoved {
+        endpoint: String,
+    },
+}
+
+impl StatusEvent {
+    pub fn from_attempt(attempt: &Attempt) -> Self {
+        let endpoint = attempt.endpoint.clone();
+        match &attempt.res {
+            Ok(resp) => StatusEvent::Push {
+                endpoint,
+                status: Some(resp.status().as_u16()),
+                error: (!resp.status().is_success())
+                    .then(|| format!("Push server responded with {}", resp.status())),
+            },
+            Err(e) => StatusEvent::Push {
+                endpoint,
+                status: None,
+                error: Some(format!("{:#}", e)),
+            },
+        }
+    }
+}
+
+/**
+Handle to close the websocket of a [SignalWebSocket] and stop its connection loop.
+*/
+#[derive(Debug, Clone)]
+pub struct CloseHandle(watch::Sender<bool>);
+
+impl CloseHandle {
+    pub fn close(&self) {
+        self.0.send_replace(true);
+    }
+
+    pub fn is_closed(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    async fn wait(&self) {
+        let _ = self.0.subscribe().wait_for(|closed| *closed).await;
+    }
+}
+
 #[derive(Debug)]
 pub enum Error {
     /// We got:
-    /// \* a 403
-    /// \* or a ws response ConnectedElseWhere and our push service isn't valid anymore
+    /// \* a 403 from the Signal server
+    /// \* or a 404 or 410 from the push server of the last endpoint
     /// => the registration has migrated
     RegistrationRemoved,
 }
@@ -75,16 +179,31 @@ impl std::error::Error for Error {}
 #[derive(Debug)]
 pub struct SignalWebSocket {
     creds: String,
-    push_endpoint: url::Url,
+    /// Endpoints of the connection, the ones gone are pruned
+    push_targets: Mutex<Vec<Target>>,
+    delivery: DeliveryMode,
+    /// VAPID key of the push subscriptions
+    vapid_key: Option<String>,
     pub channels: Channels,
+    /// How the envelopes are pushed, see [SignalWebSocket::set_push_policy]
+    push_policy: PushPolicy,
     push_instant: Arc<Mutex<Instant>>,
+    /// Urgency of the last push, None if no push has been sent
+    push_urgency: Mutex<Option<Urgency>>,
+    /// When the low urgency push for the non-urgent envelopes is due, if any
+    deferred_push: watch::Sender<Option<time::Instant>>,
     last_keepalive: Arc<Mutex<Instant>>,
+    /// Held during the handshake
+    handshake_permit: HandshakePermit,
+    /// When the current connection has been established
+    connected_at: Mutex<Option<Instant>>,
+    close_handle: CloseHandle,
 }
 
 #[async_trait(?Send)]
 impl WebSocketConnection for SignalWebSocket {
-    fn get_url(&self) -> &str {
-        &config::get_ws_endpoint()
+    fn get_url(&self) -> String {
+        config::get_ws_endpoint()
     }
 
     fn get_creds(&self) -> &str {
@@ -103,6 +222,40 @@ impl WebSocketConnection for SignalWebSocket {
         Arc::clone(&self.last_keepalive)
     }
 
+    fn on_connected(&self) {
+        self.handshake_permit.release();
+        *self.connected_at.lock().unwrap() = Some(Instant::now());
+        self.send_status(StatusEvent::Connected);
+    }
+
+    async fn wait_close(&self) {
+        self.close_handle.wait().await
+    }
+
+    /**
+    Send the deferred push when it is due. The deadline is kept
+    if the connection is closed before, see [SignalWebSocket::take_deferred_push]
+    when the socket isn't used anymore.
+    */
+    async fn run_deferred(&self) -> Result<()> {
+        let mut deferred_rx = self.deferred_push.subscribe();
+        loop {
+            let deadline = *deferred_rx.borrow_and_update();
+            match deadline {
+                Some(deadline) => select!(
+                    _ = time::sleep_until(deadline).fuse() => {
+                        log::debug!("Sending the deferred notification.");
+                        self.send_push(false, DEFERRED_URGENCY).await?;
+                    },
+                    _ = deferred_rx.changed().fuse() => (),
+                ),
+                None => {
+                    let _ = deferred_rx.changed().await;
+                }
+            }
+        }
+    }
+
     async fn on_message(&self, message: WebSocketMessage) -> Result<()> {
         if let Some(type_int) = message.r#type {
             if let Ok(type_) = Type::try_from(type_int) {
@@ -119,34 +272,109 @@ impl WebSocketConnection for SignalWebSocket {
     }
 }
 
+/**
+Kind of failure of the HTTP response of the handshake, 429 and 5xx
+are backed off longer.
+*/
+fn failure_from_status<T>(resp: &http::Response<T>) -> Failure {
+    let status = resp.status();
+    if status == StatusCode::TOO_MANY_REQUESTS {
+        let retry_after = resp
+            .headers()
+            .get(http::header::RETRY_AFTER)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| match h.parse() {
+                Ok(secs) => Some(Duration::from_secs(secs)),
+                Err(_) => httpdate::parse_http_date(h)
+                    .ok()?
+                    .duration_since(SystemTime::now())
+                    .ok(),
+            });
+        Failure::RateLimited { retry_after }
+    } else if status.is_server_error() {
+        Failure::ServerError
+    } else {
+        Failure::Transient
+    }
+}
+
 impl SignalWebSocket {
+    /**
+    The [password] is the one saved in the DB, it is decrypted to build the credentials
+    if it is [password_encrypted].
+    */
     pub fn new<'a, 'b: 'a>(
         uuid: &str,
         device_id: u32,
         password: &str,
-        push_endpoint: &str,
+        password_encrypted: bool,
+        push_endpoints: &[PushEndpoint],
+        delivery: DeliveryMode,
+        vapid_key: Option<&str>,
     ) -> Result<Self> {
-        let push_endpoint = url::Url::parse(&push_endpoint)?;
+        let push_targets = push_endpoints
+            .iter()
+            .map(Target::new)
+            .collect::<Result<Vec<Target>>>()?;
+        // The password is decrypted only here
+        let password = db::decrypt_secret(password, password_encrypted)?;
         Ok(Self {
             creds: format!("{}.{}:{}", uuid, device_id, password),
-            push_endpoint,
+            push_targets: Mutex::new(push_targets),
+            delivery,
+            vapid_key: vapid_key.map(String::from),
             channels: Channels::none(),
-            push_instant: Arc::new(Mutex::new(
-                Instant::now().checked_sub(PUSH_TIMEOUT).unwrap(),
-            )),
+            push_policy: config::get_push_policy(),
+            push_instant: Arc::new(Mutex::new(Instant::now())),
+            push_urgency: Mutex::new(None),
+            deferred_push: watch::Sender::new(None),
             last_keepalive: Arc::new(Mutex::new(Instant::now())),
+            handshake_permit: HandshakePermit::default(),
+            connected_at: Mutex::new(None),
+            close_handle: CloseHandle(watch::Sender::new(false)),
         })
     }
 
+    /**
+    Use the push policy of the connection, instead of the default one of the config.
+    */
+    pub fn set_push_policy(&mut self, push_policy: PushPolicy) {
+        self.push_policy = push_policy;
+    }
+
+    pub fn close_handle(&self) -> CloseHandle {
+        self.close_handle.clone()
+    }
+
     pub async fn connection_loop(&mut self) -> Result<()> {
-        let mut count = 0;
+        let mut attempt = 0;
         loop {
-            let instant = Instant::now();
+            *self.connected_at.lock().unwrap() = None;
             {
                 let mut keepalive = self.last_keepalive.lock().unwrap();
                 *keepalive = Instant::now();
             }
-            if let Err(e) = self.connect(tls::build_tls_connector()?).await {
+            let env = config::get_signal_env();
+            let proxy = config::get_signal_proxy();
+            self.send_status(StatusEvent::Connecting);
+            let close_handle = self.close_handle.clone();
+            select!(
+                permit = HANDSHAKES.acquire(config::get_handshake_max_delay()).fuse() => self.handshake_permit = permit,
+                _ = close_handle.wait().fuse() => return Ok(()),
+            );
+            let res = self
+                .connect(tls::build_tls_connector(&env)?, &env, proxy.as_ref())
+                .await;
+            // The handshake may have failed
+            self.handshake_permit.release();
+            self.send_status(StatusEvent::Disconnected {
+                error: res.as_ref().err().map(|e| format!("{:#}", e)),
+            });
+            if self.close_handle.is_closed() {
+                return Ok(());
+            }
+            let mut failure = Failure::Transient;
+            if let Err(e) = res {
                 if let Some(Error::RegistrationRemoved) = e.downcast_ref::<Error>() {
                     log::debug!("connection_loop: got RegistrationRemoved.");
                     return Err(eyre!(Error::RegistrationRemoved));
@@ -158,6 +386,7 @@ impl SignalWebSocket {
                         return Err(eyre!(Error::RegistrationRemoved));
                     } else {
                         log::debug!("HTTP error: {:?}", e);
+                        failure = failure_from_status(resp);
                     }
                 } else if let Some(websocket_connection::Error::ConnectedElseWhere) =
                     e.downcast_ref::<websocket_connection::Error>()
@@ -165,22 +394,30 @@ impl SignalWebSocket {
                     log::debug!("connection_loop: got ConnectedElseWhere.");
                     // we try to push a simple json {"code": 4409}, if we receive a 403, 404 or 410:
                     // then the registration should be handled as removed (like a 403)
-                    let _ = self.push_delivery_check().await?;
+                    self.push_delivery_check().await?;
                 } else {
                     log::debug!("Connection error: {:?}", e);
                 }
             }
-            if let Some(duration) = Instant::now().checked_duration_since(instant) {
-                if duration > Duration::from_secs(60) {
-                    count = 0;
-                }
+            let policy = config::get_reconnect_policy();
+            let healthy = self
+                .connected_at
+                .lock()
+                .unwrap()
+                .is_some_and(|t| t.elapsed() >= policy.reset_after);
+            if healthy {
+                attempt = 0;
             }
             if let Some(tx) = &self.channels.on_reconnection_tx {
                 let _ = tx.unbounded_send(1);
             }
-            count += 1;
-            log::info!("Retrying to connect in {}0 seconds.", count);
-            time::sleep(Duration::from_secs(count * 10)).await;
+            attempt += 1;
+            let delay = policy.delay(attempt, failure);
+            log::info!("Retrying to connect in {} seconds.", delay.as_secs());
+            select!(
+                _ = time::sleep(delay).fuse() => (),
+                _ = close_handle.wait().fuse() => return Ok(()),
+            );
         }
     }
 
@@ -202,12 +439,14 @@ impl SignalWebSocket {
                 if let Some(tx) = &self.channels.on_message_tx {
                     let _ = tx.unbounded_send(1);
                 }
-                if self.waiting_timeout_reached() {
-                    if envelope.urgent() {
-                        let _ = self.send_push().await?;
+                self.send_status(StatusEvent::Envelope);
+                match self.push_policy.urgency(envelope.urgent()) {
+                    Some(urgency) if self.is_debounced(urgency) => {
+                        log::debug!("The waiting timeout is not reached: the request is ignored.")
                     }
-                } else {
-                    log::debug!("The waiting timeout is not reached: the request is ignored.");
+                    Some(urgency) => self.send_push(envelope.urgent(), urgency).await?,
+                    None if !envelope.urgent() => self.defer_push(),
+                    None => log::debug!("No push for this envelope."),
                 }
             }
         }
@@ -284,23 +523,94 @@ impl SignalWebSocket {
         }
     }
 
-    async fn send_push(&self) -> Result<()> {
+    /**
+    Send a low urgency push after the delay of the non-urgent envelopes,
+    if no push is sent before.
+    */
+    fn defer_push(&self) {
+        let delay = self.push_policy.non_urgent_delay;
+        if delay.is_zero() {
+            log::debug!("No push for this envelope.");
+            return;
+        }
+        self.deferred_push.send_if_modified(|deadline| {
+            if deadline.is_none() {
+                log::debug!("Notification deferred by {} seconds.", delay.as_secs());
+                *deadline = Some(time::Instant::now() + delay);
+                true
+            } else {
+                false
+            }
+        });
+    }
+
+    /**
+    Take the deferred push not sent yet, to queue it when the socket isn't used
+    anymore: the next one doesn't know about it.
+    */
+    pub fn take_deferred_push(&self) -> Option<PushResult> {
+        let deadline = self.deferred_push.send_replace(None)?;
+        Some(PushResult::Deferred {
+            topic: self.push_policy.topic.clone().unwrap_or_default(),
+            body: json!({ "urgent": false }).to_string(),
+            delay: deadline.saturating_duration_since(time::Instant::now()),
+            urgency: DEFERRED_URGENCY,
+        })
+    }
+
+    async fn send_push(&self, urgent: bool, urgency: Urgency) -> Result<()> {
         log::debug!("Sending the notification.");
+        // The client wakes up, and gets the pending envelopes too
+        self.deferred_push.send_replace(None);
         {
             let mut instant = self.push_instant.lock().unwrap();
             *instant = Instant::now();
+            *self.push_urgency.lock().unwrap() = Some(urgency);
         }
 
-        let url = self.push_endpoint.clone();
-        let res = post_allowed(url, &json!({"urgent": true}), Some("mollysocket")).await;
+        let targets = self.push_targets.lock().unwrap().clone();
+        let body = json!({ "urgent": urgent });
+        let topic = self.push_policy.topic.as_deref();
+        let attempts = deliver(
+            &targets,
+            self.delivery,
+            &body,
+            &PushHeaders {
+                topic,
+                ttl: self.push_policy.ttl,
+                urgency,
+            },
+            self.vapid_key.as_deref(),
+        )
+        .await;
+        for attempt in &attempts {
+            self.send_status(StatusEvent::from_attempt(attempt));
+        }
         if let Some(tx) = &self.channels.on_push_tx {
             let _ = tx.unbounded_send(1);
         }
-        self.assert_push_response(res)
+        if let Some(tx) = &self.channels.on_push_result_tx {
+            let result = match delivery::retryable(&attempts) {
+                Retryable::Yes(retry_after) => Some(PushResult::Failed {
+                    topic: topic.unwrap_or_default().into(),
+                    body: body.to_string(),
+                    retry_after,
+                    urgency,
+                }),
+                Retryable::No if delivery::is_delivered(&attempts) => Some(PushResult::Sent {
+                    topic: topic.unwrap_or_default().into(),
+                }),
+                Retryable::No => None,
+            };
+            if let Some(result) = result {
+                let _ = tx.unbounded_send(result);
+            }
+        }
+        self.prune_endpoints(&attempts)
     }
 
     /// If we received an error 4409 "connected elsewhere", we send a "delivery check" push notif:
-    /// \* if we receive a 403/404/410, then the endpoint as been removed and we should
+    /// \* if we receive a 404/410 for all the endpoints, then they have been removed and we should
     /// delete the registration
     /// \* else, the other instance is probably the one that need to unregister, which will disable the registration
     /// with the same mechanism
@@ -315,47 +625,99 @@ impl SignalWebSocket {
                 log::info!("push_delivery_check: We send a notification recently, no need to push a delivery check.");
                 return Ok(());
             }
-            // We set the last push notif to now - PUSH_TIMEOUT, so if a push notification arrives
-            // between now and now + PUSH_TIMEOUT, it can wake the client correctly
+            // We set the last push notif to now - debounce, so if a push notification arrives
+            // between now and now + debounce, it can wake the client correctly
             // We fallback to now, in case of error, but it shouldn't fail.
             *instant = Instant::now()
-                .checked_sub(PUSH_TIMEOUT)
+                .checked_sub(self.push_policy.debounce)
                 .unwrap_or(Instant::now());
         }
-        let url = self.push_endpoint.clone();
-        let res = post_allowed(url, &json!({"code": 4409}), Some("4409")).await;
-        log::trace!("{:?}", res);
-        self.assert_push_response(res)
-    }
-
-    fn assert_push_response(&self, res: Result<reqwest::Response>) -> Result<()> {
-        match res {
-            Err(err) => {
-                if let Some(e) = err.downcast_ref::<reqwest::Error>() {
-                    match e.status() {
-                        Some(StatusCode::FORBIDDEN)
-                        | Some(StatusCode::NOT_FOUND)
-                        | Some(StatusCode::GONE) => {
-                            log::debug!("Got response with status={:?}", e.status());
-                            return Err(err.wrap_err(eyre!(Error::RegistrationRemoved)));
-                        }
-                        _ => (),
-                    }
-                }
+        let targets = self.push_targets.lock().unwrap().clone();
+        let attempts = deliver(
+            &targets,
+            self.delivery,
+            &json!({"code": 4409}),
+            &PushHeaders::new(Some("4409")),
+            self.vapid_key.as_deref(),
+        )
+        .await;
+        log::trace!("{:?}", attempts);
+        for attempt in &attempts {
+            self.send_status(StatusEvent::from_attempt(attempt));
+        }
+        self.prune_endpoints(&attempts)
+    }
+
+    /**
+    Remove the endpoints whose push subscription doesn't exist anymore. The last
+    endpoint isn't removed: the registration has been removed.
+    */
+    fn prune_endpoints(&self, attempts: &[Attempt]) -> Result<()> {
+        let mut targets = self.push_targets.lock().unwrap();
+        for attempt in attempts.iter().filter(|a| a.outcome() == Outcome::Gone) {
+            if !targets.iter().any(|t| t.endpoint == attempt.endpoint) {
+                continue;
             }
-            Ok(resp) => match resp.status() {
-                StatusCode::FORBIDDEN | StatusCode::NOT_FOUND | StatusCode::GONE => {
-                    log::debug!("Got response with status={:?}", resp.status());
-                    return Err(eyre!(Error::RegistrationRemoved));
-                }
-                _ => (),
-            },
+            if targets.len() == 1 {
+                log::debug!("The last endpoint is gone.");
+                return Err(eyre!(Error::RegistrationRemoved));
+            }
+            log::info!("An endpoint is gone, it is removed.");
+            targets.retain(|t| t.endpoint != attempt.endpoint);
+            self.send_status(StatusEvent::EndpointRemoved {
+                endpoint: attempt.endpoint.clone(),
+            });
         }
         Ok(())
     }
 
-    fn waiting_timeout_reached(&self) -> bool {
+    fn send_status(&self, event: StatusEvent) {
+        if let Some(tx) = &self.channels.on_status_tx {
+            let _ = tx.unbounded_send(event);
+        }
+    }
+
+    /**
+    A push of this [urgency] isn't needed: a push at least as urgent has been sent recently.
+    */
+    fn is_debounced(&self, urgency: Urgency) -> bool {
         let instant = self.push_instant.lock().unwrap();
-        instant.elapsed() > PUSH_TIMEOUT
+        *self.push_urgency.lock().unwrap() >= Some(urgency)
+            && instant.elapsed() <= self.push_policy.debounce
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_failure_from_status() {
+        let resp = |status: u16, retry_after: Option<&str>| {
+            let mut builder = http::Response::builder().status(status);
+            if let Some(retry_after) = retry_after {
+                builder = builder.header("Retry-After", retry_after);
+            }
+            builder.body(()).unwrap()
+        };
+        assert_eq!(failure_from_status(&resp(400, None)), Failure::Transient);
+        assert_eq!(failure_from_status(&resp(503, None)), Failure::ServerError);
+        assert_eq!(
+            failure_from_status(&resp(429, Some("120"))),
+            Failure::RateLimited {
+                retry_after: Some(Duration::from_secs(120))
+            }
+        );
+        let date = httpdate::fmt_http_date(SystemTime::now() + Duration::from_secs(3600));
+        match failure_from_status(&resp(429, Some(&date))) {
+            Failure::RateLimited {
+                retry_after: Some(d),
+            } => assert!(d > Duration::from_secs(3500)),
+            f => panic!("Unexpected failure: {:?}", f),
+        }
+        assert_eq!(
+            failure_from_status(&resp(429, Some("soon"))),
+            Failure::RateLimited { retry_after: None }
+        );
     }
 }
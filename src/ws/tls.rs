@@ -1,31 +1,245 @@
+use eyre::{eyre, Result};
 use native_tls::{Certificate, TlsConnector};
+use openssl::{sha::sha256, x509::X509};
+use std::fmt::{Display, Formatter};
+use tokio::net::TcpStream;
+use tokio_tungstenite::MaybeTlsStream;
+use url::Url;
 
-pub fn build_tls_connector() -> Result<TlsConnector, native_tls::Error> {
-    let root_ca = include_bytes!("certs/signal-messenger.pem");
-    let root_ca = Certificate::from_pem(root_ca).unwrap();
+use crate::{config::SignalEnvironment, utils::proxy::Proxy};
+
+#[derive(Debug)]
+pub enum Error {
+    /// The certificate of the server doesn't match any pinned fingerprint
+    FingerprintMismatch,
+    /// A pinned fingerprint isn't a valid SHA-256 in hexadecimal
+    InvalidFingerprint(String),
+    /// The URL doesn't have a host, or its scheme isn't ws or wss
+    InvalidUrl,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::InvalidFingerprint(pin) => write!(f, "Invalid fingerprint: {}", pin),
+            _ => write!(f, "{:?}", self),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/**
+Build the [TlsConnector] trusting the root certificates of [env]:
+* the Signal CA for Production and Staging,
+* the CA bundle `ca_file`, if any, or the system roots for Custom.
+*/
+pub fn build_tls_connector(env: &SignalEnvironment) -> Result<TlsConnector> {
     let mut builder = TlsConnector::builder();
-    builder.disable_built_in_roots(true);
-    builder.add_root_certificate(root_ca);
-    builder.build()
+    match env {
+        SignalEnvironment::Production | SignalEnvironment::Staging => {
+            let root_ca = include_bytes!("certs/signal-messenger.pem");
+            let root_ca = Certificate::from_pem(root_ca).unwrap();
+            builder.disable_built_in_roots(true);
+            builder.add_root_certificate(root_ca);
+        }
+        SignalEnvironment::Custom {
+            ca_file: Some(ca_file),
+            ..
+        } => {
+            let pem = std::fs::read(ca_file)?;
+            builder.disable_built_in_roots(true);
+            for cert in X509::stack_from_pem(&pem)? {
+                builder.add_root_certificate(Certificate::from_der(&cert.to_der()?)?);
+            }
+        }
+        SignalEnvironment::Custom { ca_file: None, .. } => (),
+    }
+    Ok(builder.build()?)
+}
+
+/**
+Open the TCP connection to [url], through [proxy] if any,
+and the TLS session. The scheme must be wss.
+
+If [env] pins some certificates, the session is aborted before anything is sent
+when the server certificate doesn't match.
+*/
+pub async fn connect(
+    url: &Url,
+    tls_connector: TlsConnector,
+    env: &SignalEnvironment,
+    proxy: Option<&Proxy>,
+) -> Result<MaybeTlsStream<TcpStream>> {
+    let host = url.host_str().ok_or(Error::InvalidUrl)?;
+    let port = url.port_or_known_default().ok_or(Error::InvalidUrl)?;
+    let tcp_stream = match proxy {
+        Some(proxy) => proxy.connect(host, port).await?,
+        None => TcpStream::connect((host, port)).await?,
+    };
+    match url.scheme() {
+        "wss" => {
+            let tls_stream = tokio_native_tls::TlsConnector::from(tls_connector)
+                .connect(host, tcp_stream)
+                .await?;
+            check_fingerprints(
+                tls_stream.get_ref().peer_certificate()?.as_ref(),
+                env.fingerprints(),
+            )?;
+            Ok(MaybeTlsStream::NativeTls(tls_stream))
+        }
+        // The credentials would be sent in cleartext: only the tests may use ws
+        #[cfg(test)]
+        "ws" => Ok(MaybeTlsStream::Plain(tcp_stream)),
+        _ => Err(eyre!(Error::InvalidUrl)),
+    }
+}
+
+fn check_fingerprints(cert: Option<&Certificate>, fingerprints: &[String]) -> Result<()> {
+    if fingerprints.is_empty() {
+        return Ok(());
+    }
+    let cert = cert.ok_or(Error::FingerprintMismatch)?;
+    let fingerprint = sha256(&cert.to_der()?);
+    for pin in fingerprints {
+        if parse_fingerprint(pin)? == fingerprint {
+            return Ok(());
+        }
+    }
+    log::warn!("The certificate of the Signal server doesn't match the pinned fingerprints.");
+    Err(eyre!(Error::FingerprintMismatch))
+}
+
+/**
+Parse a SHA-256 fingerprint in hexadecimal, with or without colons,
+like the output of `openssl x509 -noout -fingerprint -sha256`.
+*/
+fn parse_fingerprint(pin: &str) -> Result<[u8; 32]> {
+    let hex: String = pin.chars().filter(|c| *c != ':').collect();
+    let invalid = || eyre!(Error::InvalidFingerprint(pin.into()));
+    if hex.len() != 64 || !hex.is_ascii() {
+        return Err(invalid());
+    }
+    let mut fingerprint = [0u8; 32];
+    for (i, byte) in fingerprint.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).map_err(|_| invalid())?;
+    }
+    Ok(fingerprint)
 }
 
 #[cfg(test)]
 mod tests {
-    use std::net::TcpStream;
-
     use super::*;
+    use crate::test_support::{certs, FakeProxy, FAKE_SIGNAL};
+
+    fn fake_signal_env(ca_file: bool, fingerprints: Vec<String>) -> SignalEnvironment {
+        SignalEnvironment::Custom {
+            url: FAKE_SIGNAL.url.clone(),
+            ca_file: ca_file.then(|| FAKE_SIGNAL.ca_file.display().to_string()),
+            fingerprints,
+        }
+    }
+
+    async fn connect_fake_server(env: &SignalEnvironment) -> Result<MaybeTlsStream<TcpStream>> {
+        let url = Url::parse(&FAKE_SIGNAL.url).unwrap();
+        connect(&url, build_tls_connector(env).unwrap(), env, None).await
+    }
+
+    #[tokio::test]
+    async fn connect_custom_server() {
+        connect_fake_server(&fake_signal_env(true, vec![]))
+            .await
+            .unwrap();
+        // The fake server isn't trusted by the system roots
+        assert!(connect_fake_server(&fake_signal_env(false, vec![]))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn connect_pinned_server() {
+        connect_fake_server(&fake_signal_env(
+            true,
+            vec![FAKE_SIGNAL.fingerprint.clone()],
+        ))
+        .await
+        .unwrap();
+        let err = connect_fake_server(&fake_signal_env(true, vec!["00".repeat(32)]))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::FingerprintMismatch)
+        ));
+    }
+
+    #[tokio::test]
+    async fn connect_through_proxy() {
+        let fake_proxy = FakeProxy::start();
+        let proxy: Proxy = fake_proxy.url("socks5h", None).parse().unwrap();
+        let env = fake_signal_env(true, vec![FAKE_SIGNAL.fingerprint.clone()]);
+        let url = Url::parse(&FAKE_SIGNAL.url).unwrap();
+        connect(&url, build_tls_connector(&env).unwrap(), &env, Some(&proxy))
+            .await
+            .unwrap();
+        assert_eq!(
+            fake_proxy.targets(),
+            vec![format!("localhost:{}", url.port().unwrap())]
+        );
+    }
+
+    /**
+    Open a TLS session with [FAKE_SIGNAL], with the connector of [env].
+    */
+    fn tls_connect_fake_server(env: &SignalEnvironment) -> Result<()> {
+        let url = Url::parse(&FAKE_SIGNAL.url).unwrap();
+        let host = url.host_str().unwrap();
+        let s = std::net::TcpStream::connect((host, url.port().unwrap())).unwrap();
+        build_tls_connector(env)?.connect(host, s)?;
+        Ok(())
+    }
 
     #[test]
     fn connect_trusted_server() {
-        let builder = build_tls_connector().unwrap();
-        let s = TcpStream::connect("chat.staging.signal.org:443").unwrap();
-        builder.connect("chat.staging.signal.org", s).unwrap();
+        tls_connect_fake_server(&fake_signal_env(true, vec![])).unwrap();
     }
 
     #[test]
     fn connect_untrusted_server() {
-        let builder = build_tls_connector().unwrap();
-        let s = TcpStream::connect("signal.org:443").unwrap();
-        builder.connect("signal.org", s).unwrap_err();
+        // The fake server isn't signed by the Signal CA
+        assert!(tls_connect_fake_server(&SignalEnvironment::Staging).is_err());
+
+        // Nor by another CA
+        let ca_file = std::env::temp_dir().join(format!(
+            "mollysocket-test-{}-other-ca.pem",
+            std::process::id()
+        ));
+        std::fs::write(&ca_file, certs::generate().ca_pem).unwrap();
+        let env = SignalEnvironment::Custom {
+            url: FAKE_SIGNAL.url.clone(),
+            ca_file: Some(ca_file.display().to_string()),
+            fingerprints: vec![],
+        };
+        let res = tls_connect_fake_server(&env);
+        let _ = std::fs::remove_file(&ca_file);
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_fingerprints() {
+        let cert = Certificate::from_pem(include_bytes!("certs/signal-messenger.pem")).unwrap();
+        let fingerprint = sha256(&cert.to_der().unwrap());
+        let hex = fingerprint
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect::<Vec<String>>();
+        assert_eq!(parse_fingerprint(&hex.join(":")).unwrap(), fingerprint);
+        assert_eq!(parse_fingerprint(&hex.join("")).unwrap(), fingerprint);
+        assert!(parse_fingerprint("AB:CD").is_err());
+
+        assert!(check_fingerprints(Some(&cert), &[]).is_ok());
+        assert!(check_fingerprints(Some(&cert), &[hex.join(":")]).is_ok());
+        assert!(check_fingerprints(Some(&cert), &["00".repeat(32)]).is_err());
+        assert!(check_fingerprints(None, &[hex.join(":")]).is_err());
     }
 }
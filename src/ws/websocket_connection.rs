@@ -11,21 +11,23 @@ use std::{
     time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 use tokio::time;
-use tokio_tungstenite::{
-    tungstenite::{
-        self,
-        protocol::{self, frame::coding::CloseCode, CloseFrame},
-        ClientRequestBuilder,
-    },
-    Connector::NativeTls,
+use tokio_tungstenite::tungstenite::{
+    self,
+    protocol::{self, frame::coding::CloseCode, CloseFrame},
+    ClientRequestBuilder,
 };
 
 use super::proto_websocketresources::{
     web_socket_message::Type, WebSocketMessage, WebSocketRequestMessage, WebSocketResponseMessage,
 };
+use super::tls;
+use crate::{config::SignalEnvironment, utils::proxy::Proxy};
 
 const KEEPALIVE: Duration = Duration::from_secs(30);
 const KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(40);
+/// Time given to the server to acknowledge our close frame,
+/// and to the pushes in progress to finish
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
 /// User agent from Signal-Android
 ///
 /// USER_AGENT = "Signal-Android/" + BuildConfig.VERSION_NAME + " Android/" + Build.VERSION.SDK_INT;
@@ -33,9 +35,10 @@ const KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(40);
 /// UA: https://github.com/signalapp/Signal-Android/blob/c7ec3ab837b3c149d5579840317b1dc6cd4629f3/app/src/main/java/org/thoughtcrime/securesms/net/StandardUserAgentInterceptor.java#L12
 /// VERSION_NAME => take latest https://github.com/signalapp/Signal-Android/releases/latest
 /// Build.VERSION.SDK_INT => take last Android SDK
-const USER_AGENT: &'static str = "Signal-Android/8.3.4 Android/36";
+const USER_AGENT: &str = "Signal-Android/8.3.4 Android/36";
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     Ws,
     PushError,
@@ -52,17 +55,33 @@ impl std::error::Error for Error {}
 
 #[async_trait(?Send)]
 pub trait WebSocketConnection {
-    fn get_url(&self) -> &str;
+    fn get_url(&self) -> String;
     /// return "login:password"
     fn get_creds(&self) -> &str;
     fn get_websocket_tx(&self) -> &Option<mpsc::UnboundedSender<tungstenite::Message>>;
     fn set_websocket_tx(&mut self, tx: Option<mpsc::UnboundedSender<tungstenite::Message>>);
     fn get_last_keepalive(&self) -> Arc<Mutex<Instant>>;
     async fn on_message(&self, message: WebSocketMessage) -> Result<()>;
+    /// Called once the websocket handshake is done
+    fn on_connected(&self);
+    /// Returns when the websocket must be closed
+    async fn wait_close(&self);
+    /// Runs the tasks deferred by the messages while connected,
+    /// returns an error if the connection must end
+    async fn run_deferred(&self) -> Result<()>;
 
     /// Connect to the server and handle messages
     /// Returns HTTP Error, or ConnectedElseWhere or () if disconnected normally
-    async fn connect(&mut self, tls_connector: TlsConnector) -> Result<()> {
+    ///
+    /// When [wait_close][Self::wait_close] returns, a close frame is sent, and the messages
+    /// being handled, like a push notification, are finished.
+    async fn connect(
+        &mut self,
+        tls_connector: TlsConnector,
+        env: &SignalEnvironment,
+        proxy: Option<&Proxy>,
+    ) -> Result<()> {
+        let url: url::Url = self.get_url().parse()?;
         let request = ClientRequestBuilder::new(self.get_url().parse()?)
             .with_header("X-Signal-Agent", "\"OWA\"")
             .with_header("User-Agent", USER_AGENT)
@@ -71,15 +90,12 @@ pub trait WebSocketConnection {
                 format!("Basic {}", BASE64_STANDARD.encode(self.get_creds())),
             );
 
-        let (ws_stream, _) = tokio_tungstenite::connect_async_tls_with_config(
-            request,
-            None,
-            false,
-            Some(NativeTls(tls_connector)),
-        )
-        .await?;
+        let stream = tls::connect(&url, tls_connector, env, proxy).await?;
+        let (ws_stream, _) =
+            tokio_tungstenite::client_async_with_config(request, stream, None).await?;
 
         log::info!("WebSocket handshake has been successfully completed");
+        self.on_connected();
 
         // Websocket I/O
         let (ws_write, ws_read) = ws_stream.split();
@@ -125,11 +141,17 @@ pub trait WebSocketConnection {
 
         let to_keepalive_handle = self.loop_keepalive(timer_tx).fuse();
 
+        let close_handle = self.wait_close().fuse();
+
+        let deferred_handle = self.run_deferred().fuse();
+
         pin_mut!(
             to_ws_handle,
             from_ws_handle,
             from_keepalive_handle,
-            to_keepalive_handle
+            to_keepalive_handle,
+            close_handle,
+            deferred_handle
         );
 
         // handle websocket
@@ -140,6 +162,30 @@ pub trait WebSocketConnection {
             _ = to_ws_handle => log::warn!("Messages finished"),
             _ = from_keepalive_handle => log::warn!("Keepalive finished"),
             _ = to_keepalive_handle => log::warn!("Keepalive finished"),
+            res = deferred_handle => res?,
+            _ = close_handle => {
+                log::debug!("Closing the websocket");
+                self.send_close().await;
+                // The server replies to the close frame, then the stream ends. The writer
+                // may stop before, when a response is sent after the close frame.
+                let closed = time::timeout(CLOSE_TIMEOUT, async {
+                    loop {
+                        select!(
+                            res = from_ws_handle => {
+                                if let Err(e) = res {
+                                    log::debug!("Error while closing: {:?}", e);
+                                }
+                                break;
+                            },
+                            _ = to_ws_handle => (),
+                        )
+                    }
+                })
+                .await;
+                if closed.is_err() {
+                    log::warn!("The server didn't acknowledge the close frame");
+                }
+            },
         );
         Ok(())
     }
@@ -175,6 +221,17 @@ pub trait WebSocketConnection {
         self.send_message(message).await;
     }
 
+    async fn send_close(&self) {
+        if let Some(mut tx) = self.get_websocket_tx().as_ref() {
+            let _ = tx
+                .send(tungstenite::Message::Close(Some(CloseFrame {
+                    code: CloseCode::Away,
+                    reason: "".into(),
+                })))
+                .await;
+        }
+    }
+
     async fn send_keepalive(&self) {
         log::debug!("send_keepalive");
         let message = WebSocketMessage {